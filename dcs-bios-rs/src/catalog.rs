@@ -0,0 +1,377 @@
+use std::{collections::BTreeMap, io, ops::RangeInclusive, string::String, vec::Vec};
+
+use serde::Deserialize;
+
+use crate::{error::Error, integer_range, mem::MemoryMap, read_integer, read_string, string_range};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ControlCatalog {
+    controls: BTreeMap<String, CatalogControl>,
+}
+
+impl ControlCatalog {
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let categories: BTreeMap<String, BTreeMap<String, CatalogControl>> =
+            serde_json::from_str(json)?;
+        Ok(Self::from_categories(categories))
+    }
+
+    pub fn from_reader<R: io::Read>(reader: R) -> Result<Self, Error> {
+        let categories: BTreeMap<String, BTreeMap<String, CatalogControl>> =
+            serde_json::from_reader(reader)?;
+        Ok(Self::from_categories(categories))
+    }
+
+    fn from_categories(categories: BTreeMap<String, BTreeMap<String, CatalogControl>>) -> Self {
+        let controls = categories
+            .into_values()
+            .flat_map(BTreeMap::into_values)
+            .map(|control| (control.identifier.clone(), control))
+            .collect();
+        Self { controls }
+    }
+
+    pub fn extend(&mut self, other: ControlCatalog) {
+        self.controls.extend(other.controls);
+    }
+
+    pub fn len(&self) -> usize {
+        self.controls.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.controls.is_empty()
+    }
+
+    pub fn control(&self, identifier: &str) -> Option<&CatalogControl> {
+        self.controls.get(identifier)
+    }
+
+    pub fn controls(&self) -> impl Iterator<Item = &CatalogControl> {
+        self.controls.values()
+    }
+
+    pub fn category<'a>(&'a self, category: &'a str) -> impl Iterator<Item = &'a CatalogControl> {
+        self.controls()
+            .filter(move |control| control.category == category)
+    }
+
+    pub fn accessor(&self, identifier: &str) -> Option<OutputAccessor> {
+        self.control(identifier)?.accessor()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct CatalogControl {
+    pub identifier: String,
+    pub category: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub control_type: String,
+    #[serde(default)]
+    pub inputs: Vec<ControlInput>,
+    #[serde(default)]
+    pub outputs: Vec<ControlOutput>,
+}
+
+impl CatalogControl {
+    pub fn accessor(&self) -> Option<OutputAccessor> {
+        self.outputs.first().map(ControlOutput::accessor)
+    }
+
+    pub fn input(&self, interface: InputInterface) -> Option<&ControlInput> {
+        self.inputs
+            .iter()
+            .find(|input| input.interface == interface)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputInterface {
+    FixedStep,
+    SetState,
+    Action,
+    VariableStep,
+    SetString,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ControlInput {
+    pub interface: InputInterface,
+    #[serde(default)]
+    pub description: String,
+    pub max_value: Option<u32>,
+    pub suggested_step: Option<u32>,
+    pub argument: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ControlOutput {
+    pub address: u16,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub suffix: String,
+    #[serde(flatten)]
+    pub kind: OutputKind,
+}
+
+impl ControlOutput {
+    pub fn accessor(&self) -> OutputAccessor {
+        match self.kind {
+            OutputKind::Integer { mask, shift_by, .. } => OutputAccessor::Integer {
+                address: self.address,
+                mask,
+                shift_by,
+            },
+            OutputKind::String { max_length } => OutputAccessor::String {
+                address: self.address,
+                max_length,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputKind {
+    Integer {
+        mask: u16,
+        shift_by: u16,
+        max_value: u16,
+    },
+    String {
+        max_length: u16,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputAccessor {
+    Integer {
+        address: u16,
+        mask: u16,
+        shift_by: u16,
+    },
+    String {
+        address: u16,
+        max_length: u16,
+    },
+}
+
+impl OutputAccessor {
+    /// 出力が占めるアドレスの範囲。アドレス空間の外にはみ出すか、長さ 0 の文字列なら `None`
+    pub fn address_range(&self) -> Option<RangeInclusive<u16>> {
+        match *self {
+            OutputAccessor::Integer { address, .. } => integer_range(address),
+            OutputAccessor::String {
+                address,
+                max_length,
+            } => string_range(address, max_length),
+        }
+    }
+
+    pub fn read<'a, M: MemoryMap>(&self, memory_map: &'a M) -> Option<OutputValue<'a>> {
+        match *self {
+            OutputAccessor::Integer {
+                address,
+                mask,
+                shift_by,
+            } => read_integer(memory_map, address, mask, shift_by).map(OutputValue::Integer),
+            OutputAccessor::String {
+                address,
+                max_length,
+            } => read_string(memory_map, address, max_length).map(OutputValue::String),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputValue<'a> {
+    Integer(u16),
+    String(&'a str),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::VecMemoryMap;
+
+    const CATALOG: &str = r#"{
+        "Master Arm": {
+            "MASTER_ARM_SW": {
+                "category": "Master Arm",
+                "control_type": "selector",
+                "description": "Master Arm Switch, ARM/SAFE",
+                "identifier": "MASTER_ARM_SW",
+                "inputs": [
+                    { "description": "switch to previous or next state", "interface": "fixed_step" },
+                    { "description": "set position", "interface": "set_state", "max_value": 1 },
+                    { "argument": "TOGGLE", "description": "Toggle switch state", "interface": "action" }
+                ],
+                "momentary_positions": "none",
+                "outputs": [
+                    {
+                        "address": 29708,
+                        "address_identifier": "FA_18C_hornet_MASTER_ARM_SW_A",
+                        "description": "selector position",
+                        "mask": 4096,
+                        "max_value": 1,
+                        "shift_by": 12,
+                        "suffix": "",
+                        "type": "integer"
+                    }
+                ],
+                "physical_variant": "toggle_switch"
+            }
+        },
+        "UFC": {
+            "UFC_COMM1_DISPLAY": {
+                "category": "UFC",
+                "control_type": "display",
+                "description": "Comm 1 Display",
+                "identifier": "UFC_COMM1_DISPLAY",
+                "inputs": [],
+                "outputs": [
+                    { "address": 29788, "description": "Comm 1 Display", "max_length": 2, "suffix": "", "type": "string" }
+                ]
+            },
+            "UFC_COMM1_VOL": {
+                "category": "UFC",
+                "control_type": "limited_dial",
+                "description": "COMM 1 Volume Control Knob",
+                "identifier": "UFC_COMM1_VOL",
+                "inputs": [
+                    { "description": "set the position of the dial", "interface": "set_state", "max_value": 65535 },
+                    { "description": "turn the dial left or right", "interface": "variable_step", "max_value": 65535, "suggested_step": 3200 }
+                ],
+                "outputs": [
+                    { "address": 29790, "description": "position of the potentiometer", "mask": 65535, "max_value": 65535, "shift_by": 0, "suffix": "", "type": "integer" }
+                ]
+            }
+        }
+    }"#;
+
+    #[test]
+    fn catalog_parses_controls_with_inputs_and_outputs() {
+        let catalog = ControlCatalog::from_json(CATALOG).unwrap();
+        assert_eq!(catalog.len(), 3);
+        assert_eq!(catalog.category("UFC").count(), 2);
+
+        let master_arm = catalog.control("MASTER_ARM_SW").unwrap();
+        assert_eq!(master_arm.category, "Master Arm");
+        assert_eq!(master_arm.description, "Master Arm Switch, ARM/SAFE");
+        assert_eq!(
+            master_arm
+                .input(InputInterface::SetState)
+                .unwrap()
+                .max_value,
+            Some(1)
+        );
+        assert_eq!(
+            master_arm
+                .input(InputInterface::Action)
+                .unwrap()
+                .argument
+                .as_deref(),
+            Some("TOGGLE")
+        );
+        assert_eq!(
+            master_arm.outputs[0].kind,
+            OutputKind::Integer {
+                mask: 4096,
+                shift_by: 12,
+                max_value: 1,
+            }
+        );
+
+        let volume = catalog.control("UFC_COMM1_VOL").unwrap();
+        let step = volume.input(InputInterface::VariableStep).unwrap();
+        assert_eq!(step.max_value, Some(65535));
+        assert_eq!(step.suggested_step, Some(3200));
+
+        let display = catalog.control("UFC_COMM1_DISPLAY").unwrap();
+        assert_eq!(
+            display.outputs[0].kind,
+            OutputKind::String { max_length: 2 }
+        );
+    }
+
+    #[test]
+    fn accessor_reads_values_from_memory_map() {
+        let catalog = ControlCatalog::from_json(CATALOG).unwrap();
+        let mut memory = VecMemoryMap::new();
+        memory.write(29708, &[0x00, 0x10]).unwrap();
+        memory.write(29788, b"12").unwrap();
+
+        let master_arm = catalog.accessor("MASTER_ARM_SW").unwrap();
+        assert_eq!(master_arm.address_range(), Some(29708..=29709));
+        assert_eq!(master_arm.read(&memory), Some(OutputValue::Integer(1)));
+
+        let display = catalog.accessor("UFC_COMM1_DISPLAY").unwrap();
+        assert_eq!(display.address_range(), Some(29788..=29789));
+        assert_eq!(display.read(&memory), Some(OutputValue::String("12")));
+
+        assert!(catalog.accessor("UNKNOWN").is_none());
+    }
+
+    #[test]
+    fn accessor_outside_address_space_is_rejected() {
+        let mut memory = VecMemoryMap::new();
+        memory.write(0, &[0x01]).unwrap();
+
+        let last_word = OutputAccessor::Integer {
+            address: 0xFFFF,
+            mask: 0xFFFF,
+            shift_by: 0,
+        };
+        assert_eq!(last_word.address_range(), None);
+        assert_eq!(last_word.read(&memory), None);
+
+        let last_byte = OutputAccessor::String {
+            address: 0xFFFF,
+            max_length: 1,
+        };
+        assert_eq!(last_byte.address_range(), Some(0xFFFF..=0xFFFF));
+        let past_end = OutputAccessor::String {
+            address: 0xFFFF,
+            max_length: 2,
+        };
+        assert_eq!(past_end.address_range(), None);
+        assert_eq!(past_end.read(&memory), None);
+
+        let empty = OutputAccessor::String {
+            address: 0xFFFE,
+            max_length: 0,
+        };
+        assert_eq!(empty.address_range(), None);
+        assert_eq!(empty.read(&memory), None);
+    }
+
+    #[test]
+    fn unknown_input_interfaces_are_kept() {
+        let catalog = ControlCatalog::from_json(
+            r#"{ "Misc": { "NEW_CONTROL": {
+                "category": "Misc",
+                "identifier": "NEW_CONTROL",
+                "inputs": [{ "interface": "set_position" }],
+                "outputs": []
+            } } }"#,
+        )
+        .unwrap();
+        let control = catalog.control("NEW_CONTROL").unwrap();
+        assert_eq!(control.inputs[0].interface, InputInterface::Unknown);
+        assert!(control.accessor().is_none());
+    }
+
+    #[test]
+    fn invalid_json_is_rejected() {
+        assert!(matches!(
+            ControlCatalog::from_json("{ \"Misc\": [] }"),
+            Err(Error::CatalogError(_))
+        ));
+    }
+}
@@ -7,6 +7,7 @@ pub enum Error {
     CommandError(),
     BufferTooSmall(),
     IoError(io::Error),
+    CatalogError(serde_json::Error),
 }
 
 impl From<io::Error> for Error {
@@ -14,3 +15,9 @@ impl From<io::Error> for Error {
         Self::IoError(value)
     }
 }
+
+impl From<serde_json::Error> for Error {
+    fn from(value: serde_json::Error) -> Self {
+        Self::CatalogError(value)
+    }
+}
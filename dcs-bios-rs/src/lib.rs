@@ -4,6 +4,7 @@ use error::Error;
 use mem::MemoryMap;
 use source::Source;
 
+pub mod catalog;
 pub mod error;
 pub mod import;
 pub mod mem;
@@ -19,16 +20,39 @@ pub trait DcsBios<M: MemoryMap> {
     fn read_packet(&mut self) -> Result<DcsBiosPacket, Error>;
 
     fn get_integer(memory_map: &M, address: u16, mask: u16, shift_by: u16) -> Option<u16> {
-        let data = memory_map.read(address..=(address + 1))?;
-        Some((u16::from_le_bytes([data[0], data[1]]) & mask) >> shift_by)
+        read_integer(memory_map, address, mask, shift_by)
     }
 
     fn get_string(memory_map: &M, address: u16, length: u16) -> Option<&str> {
-        let data = memory_map.read(address..=(address + (length - 1)))?;
-        str::from_utf8(data).ok().or(Some("&E&"))
+        read_string(memory_map, address, length)
     }
 }
 
+pub fn read_integer<M: MemoryMap>(
+    memory_map: &M,
+    address: u16,
+    mask: u16,
+    shift_by: u16,
+) -> Option<u16> {
+    let data = memory_map.read(integer_range(address)?)?;
+    Some((u16::from_le_bytes([data[0], data[1]]) & mask) >> shift_by)
+}
+
+pub fn read_string<M: MemoryMap>(memory_map: &M, address: u16, length: u16) -> Option<&str> {
+    let data = memory_map.read(string_range(address, length)?)?;
+    str::from_utf8(data).ok().or(Some("&E&"))
+}
+
+/// 整数の出力が占める 2 バイト。アドレス空間の外にはみ出す場合は `None`
+pub(crate) fn integer_range(address: u16) -> Option<RangeInclusive<u16>> {
+    Some(address..=address.checked_add(1)?)
+}
+
+/// 文字列の出力が占める範囲。長さが 0 か、アドレス空間の外にはみ出す場合は `None`
+pub(crate) fn string_range(address: u16, length: u16) -> Option<RangeInclusive<u16>> {
+    Some(address..=address.checked_add(length.checked_sub(1)?)?)
+}
+
 pub struct Listener<'a, M: MemoryMap + 'a, F: Fn(RangeInclusive<u16>, &'a M)> {
     pub _phantom: PhantomData<&'a M>,
     pub address: RangeInclusive<u16>,
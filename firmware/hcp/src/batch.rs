@@ -0,0 +1,111 @@
+//! 複数の操作イベントをまとめた packet
+//!
+//! スイッチを同時に操作したときに、イベントごとに `Set` frame と ACK を往復させないために使う。
+//! 各イベントの seq は `first_seq` からの連番で、展開すると `ControlEvent` と同じ順序になる。
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::{ControlEvent, ControlValue};
+
+/// 1 packet に入れるイベントの最大数
+///
+/// どの `ControlValue` でも `MAX_PAYLOAD_SIZE` に収まる数にしている
+pub const MAX_BATCHED_EVENTS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BatchedControl {
+    pub control_id: u16,
+    pub event: ControlValue,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ControlEventBatch {
+    /// 先頭のイベントの seq
+    pub first_seq: u16,
+    pub events: Vec<BatchedControl, MAX_BATCHED_EVENTS>,
+}
+
+impl ControlEventBatch {
+    /// 個々の `ControlEvent` に展開する
+    pub fn control_events(&self) -> impl Iterator<Item = ControlEvent> + '_ {
+        (0u16..)
+            .zip(&self.events)
+            .map(|(offset, entry)| ControlEvent {
+                seq: self.first_seq.wrapping_add(offset),
+                control_id: entry.control_id,
+                event: entry.event.clone(),
+            })
+    }
+
+    /// 最後のイベントの seq。空の場合は `None`
+    pub fn last_seq(&self) -> Option<u16> {
+        let count = u16::try_from(self.events.len()).ok()?;
+        count
+            .checked_sub(1)
+            .map(|offset| self.first_seq.wrapping_add(offset))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        AppPacketError, AppPacketKind, decode_set_packet, encode_set_packet,
+        encode_set_packet_with_version,
+    };
+
+    #[test]
+    fn batch_expands_to_ordered_events() {
+        let batch = ControlEventBatch {
+            first_seq: u16::MAX,
+            events: Vec::from_slice(&[
+                BatchedControl {
+                    control_id: 3,
+                    event: ControlValue::Button { pressed: true },
+                },
+                BatchedControl {
+                    control_id: 4,
+                    event: ControlValue::Toggle { state: false },
+                },
+            ])
+            .unwrap(),
+        };
+
+        let events: std::vec::Vec<_> = batch.control_events().collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].seq, events[0].control_id), (u16::MAX, 3));
+        assert_eq!((events[1].seq, events[1].control_id), (0, 4));
+        assert_eq!(batch.last_seq(), Some(0));
+        assert_eq!(
+            ControlEventBatch {
+                first_seq: 1,
+                events: Vec::new(),
+            }
+            .last_seq(),
+            None
+        );
+    }
+
+    #[test]
+    fn full_batch_of_largest_events_fits_in_payload() {
+        let entry = BatchedControl {
+            control_id: u16::MAX,
+            event: ControlValue::Absolute { value: i16::MIN },
+        };
+        let packet = AppPacketKind::ControlEventBatch(ControlEventBatch {
+            first_seq: u16::MAX,
+            events: core::iter::repeat_n(entry, MAX_BATCHED_EVENTS).collect(),
+        });
+
+        let encoded = encode_set_packet(&packet).unwrap();
+        assert_eq!(decode_set_packet(&encoded).unwrap(), packet);
+        assert_eq!(
+            encode_set_packet_with_version(1, &packet),
+            Err(AppPacketError::NotAvailableInVersion(1))
+        );
+    }
+}
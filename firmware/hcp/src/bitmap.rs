@@ -0,0 +1,779 @@
+//! 1bpp ビットマップの部分更新
+//!
+//! `DisplayPayload::Bitmap` で画面の一部分だけを送る。
+//! 領域内のピクセルは行ごとに byte 境界へ揃え、MSB から左詰めで並べる。
+//! ホストは `Raw` / `Rle` / `XorRle` のうち最も小さい表現を選び、
+//! デバイスは受け取った更新をそのまま framebuffer に適用する。
+//!
+//! RLE は PackBits 形式:
+//!
+//! - `0x00..=0x7F`: 続く `n + 1` byte をそのまま出力する
+//! - `0x80..=0xFF`: 続く 1 byte を `(n & 0x7F) + 2` 回繰り返す
+//!
+//! `XorRle` は前回送った画面との XOR を RLE したもので、
+//! デバイス側では現在の framebuffer に XOR して適用する。
+
+use core::fmt;
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::display::{Point, Region};
+
+/// 1 つの更新に入れられる圧縮後データ長 (byte)
+///
+/// ヘッダー込みで `MAX_PAYLOAD_SIZE` に収まるようにしている
+pub const MAX_BITMAP_DATA_LEN: usize = 96;
+
+const MAX_LITERAL_LEN: usize = 128;
+const MIN_RUN_LEN: usize = 2;
+const MAX_RUN_LEN: usize = 129;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BitmapError {
+    /// framebuffer の大きさに対してバッファが足りない
+    BufferTooSmall,
+    /// 領域が framebuffer からはみ出している
+    OutOfBounds,
+    /// 比較する 2 つの framebuffer の大きさが異なる
+    SizeMismatch,
+    /// 展開後の長さが領域の大きさと一致しない
+    LengthMismatch,
+    /// RLE データが途中で切れている
+    InvalidRle,
+    /// どの表現でも `MAX_BITMAP_DATA_LEN` に収まらない
+    TooLarge,
+}
+
+impl fmt::Display for BitmapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitmapError::BufferTooSmall => write!(f, "framebuffer buffer is too small"),
+            BitmapError::OutOfBounds => write!(f, "region is outside of the framebuffer"),
+            BitmapError::SizeMismatch => write!(f, "framebuffers have different sizes"),
+            BitmapError::LengthMismatch => {
+                write!(f, "bitmap data length does not match the region")
+            }
+            BitmapError::InvalidRle => write!(f, "RLE data is truncated"),
+            BitmapError::TooLarge => write!(
+                f,
+                "bitmap does not fit in {MAX_BITMAP_DATA_LEN} bytes with any compression"
+            ),
+        }
+    }
+}
+
+impl core::error::Error for BitmapError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub enum BitmapCompression {
+    /// 圧縮なし
+    Raw,
+    /// PackBits 形式の RLE
+    Rle,
+    /// 現在の画面との XOR を RLE したもの
+    XorRle,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub struct BitmapUpdate {
+    pub region: Region,
+    pub compression: BitmapCompression,
+    #[cfg_attr(test, ts(type = "Array<number>"))]
+    pub data: Vec<u8, MAX_BITMAP_DATA_LEN>,
+}
+
+/// 1bpp の framebuffer
+///
+/// 行ごとに byte 境界へ揃え、MSB が左端のピクセル。
+/// `B` には `[u8; N]` や `&mut [u8]`、ホスト側では `std::vec::Vec<u8>` を使う
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MonoFramebuffer<B> {
+    width: u16,
+    height: u16,
+    buffer: B,
+}
+
+impl<B: AsRef<[u8]>> MonoFramebuffer<B> {
+    pub fn new(width: u16, height: u16, buffer: B) -> Result<Self, BitmapError> {
+        if buffer.as_ref().len() < stride(width) * usize::from(height) {
+            return Err(BitmapError::BufferTooSmall);
+        }
+        Ok(Self {
+            width,
+            height,
+            buffer,
+        })
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    /// 1 行あたりの byte 数
+    pub fn stride(&self) -> usize {
+        stride(self.width)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer.as_ref()[..self.stride() * usize::from(self.height)]
+    }
+
+    pub fn into_inner(self) -> B {
+        self.buffer
+    }
+
+    /// 範囲外は消灯扱い
+    pub fn pixel(&self, point: Point) -> bool {
+        self.pixel_at(usize::from(point.x), usize::from(point.y))
+    }
+
+    pub fn bounds(&self) -> Region {
+        Region {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    fn pixel_at(&self, x: usize, y: usize) -> bool {
+        if x >= usize::from(self.width) || y >= usize::from(self.height) {
+            return false;
+        }
+        self.buffer.as_ref()[y * self.stride() + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+
+    fn check_region(&self, region: Region) -> Result<(), BitmapError> {
+        if u32::from(region.x) + u32::from(region.width) > u32::from(self.width)
+            || u32::from(region.y) + u32::from(region.height) > u32::from(self.height)
+        {
+            return Err(BitmapError::OutOfBounds);
+        }
+        Ok(())
+    }
+
+    /// 領域を詰めた表現の `index` byte 目
+    fn region_byte(&self, region: Region, index: usize) -> u8 {
+        let row_len = stride(region.width);
+        let y = usize::from(region.y) + index / row_len;
+        let first = (index % row_len) * 8;
+
+        let mut byte = 0;
+        for bit in 0..8 {
+            let dx = first + bit;
+            if dx < usize::from(region.width) && self.pixel_at(usize::from(region.x) + dx, y) {
+                byte |= 0x80 >> bit;
+            }
+        }
+        byte
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> MonoFramebuffer<B> {
+    /// 範囲外は無視する
+    pub fn set_pixel(&mut self, point: Point, on: bool) {
+        self.set_pixel_at(usize::from(point.x), usize::from(point.y), on);
+    }
+
+    pub fn clear(&mut self) {
+        let len = self.stride() * usize::from(self.height);
+        self.buffer.as_mut()[..len].fill(0);
+    }
+
+    fn set_pixel_at(&mut self, x: usize, y: usize, on: bool) {
+        if x >= usize::from(self.width) || y >= usize::from(self.height) {
+            return;
+        }
+        let index = y * self.stride() + x / 8;
+        let mask = 0x80 >> (x % 8);
+        let byte = &mut self.buffer.as_mut()[index];
+        if on {
+            *byte |= mask;
+        } else {
+            *byte &= !mask;
+        }
+    }
+
+    /// 領域を詰めた表現の `index` byte 目を書き込む。`xor` の場合は反転のみ行う
+    fn write_region_byte(&mut self, region: Region, index: usize, byte: u8, xor: bool) {
+        let row_len = stride(region.width);
+        let y = usize::from(region.y) + index / row_len;
+        let first = (index % row_len) * 8;
+
+        for bit in 0..8 {
+            let dx = first + bit;
+            if dx >= usize::from(region.width) {
+                break;
+            }
+            let x = usize::from(region.x) + dx;
+            let on = byte & (0x80 >> bit) != 0;
+            if xor {
+                if on {
+                    let current = self.pixel_at(x, y);
+                    self.set_pixel_at(x, y, !current);
+                }
+            } else {
+                self.set_pixel_at(x, y, on);
+            }
+        }
+    }
+}
+
+/// 更新を framebuffer に適用する
+///
+/// データを検証してから書き込むので、エラー時は framebuffer を変更しない
+pub fn apply_bitmap_update<B: AsRef<[u8]> + AsMut<[u8]>>(
+    framebuffer: &mut MonoFramebuffer<B>,
+    update: &BitmapUpdate,
+) -> Result<(), BitmapError> {
+    framebuffer.check_region(update.region)?;
+    let expected = region_len(update.region);
+    let region = update.region;
+
+    match update.compression {
+        BitmapCompression::Raw => {
+            if update.data.len() != expected {
+                return Err(BitmapError::LengthMismatch);
+            }
+            for (index, byte) in update.data.iter().enumerate() {
+                framebuffer.write_region_byte(region, index, *byte, false);
+            }
+        }
+        BitmapCompression::Rle | BitmapCompression::XorRle => {
+            let xor = update.compression == BitmapCompression::XorRle;
+            if rle_decode(&update.data, expected, |_, _| {})? != expected {
+                return Err(BitmapError::LengthMismatch);
+            }
+            rle_decode(&update.data, expected, |index, byte| {
+                framebuffer.write_region_byte(region, index, byte, xor);
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// `region` の更新を最も小さい表現で作る
+///
+/// `previous` はデバイスに表示されている (前回送った) 画面。
+/// `None` の場合は `XorRle` を使わない
+pub fn encode_bitmap_update<B: AsRef<[u8]>>(
+    current: &MonoFramebuffer<B>,
+    previous: Option<&MonoFramebuffer<B>>,
+    region: Region,
+) -> Result<BitmapUpdate, BitmapError> {
+    current.check_region(region)?;
+    if let Some(previous) = previous {
+        check_same_size(current, previous)?;
+    }
+    let len = region_len(region);
+
+    let mut best: Option<BitmapUpdate> = None;
+    let mut consider = |compression, data: Option<Vec<u8, MAX_BITMAP_DATA_LEN>>| {
+        if let Some(data) = data
+            && best
+                .as_ref()
+                .is_none_or(|best| data.len() < best.data.len())
+        {
+            best = Some(BitmapUpdate {
+                region,
+                compression,
+                data,
+            });
+        }
+    };
+
+    consider(BitmapCompression::Raw, collect_raw(current, region, len));
+    consider(
+        BitmapCompression::Rle,
+        rle_encode(len, |index| current.region_byte(region, index)),
+    );
+    if let Some(previous) = previous {
+        consider(
+            BitmapCompression::XorRle,
+            rle_encode(len, |index| {
+                current.region_byte(region, index) ^ previous.region_byte(region, index)
+            }),
+        );
+    }
+
+    best.ok_or(BitmapError::TooLarge)
+}
+
+/// `region` を 1 packet に収まる横長の帯に分けて更新を作る
+///
+/// 帯はできるだけ高くなるように選ぶ。1 行でも収まらない場合は `TooLarge` を返して終わる
+pub fn encode_bitmap_bands<'a, B: AsRef<[u8]>>(
+    current: &'a MonoFramebuffer<B>,
+    previous: Option<&'a MonoFramebuffer<B>>,
+    region: Region,
+) -> BitmapBands<'a, B> {
+    BitmapBands {
+        current,
+        previous,
+        region,
+        next_row: 0,
+    }
+}
+
+pub struct BitmapBands<'a, B> {
+    current: &'a MonoFramebuffer<B>,
+    previous: Option<&'a MonoFramebuffer<B>>,
+    region: Region,
+    next_row: u16,
+}
+
+impl<B: AsRef<[u8]>> Iterator for BitmapBands<'_, B> {
+    type Item = Result<BitmapUpdate, BitmapError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_row >= self.region.height {
+            return None;
+        }
+
+        let mut best = None;
+        for height in 1..=self.region.height - self.next_row {
+            let band = Region {
+                x: self.region.x,
+                y: self.region.y + self.next_row,
+                width: self.region.width,
+                height,
+            };
+            match encode_bitmap_update(self.current, self.previous, band) {
+                Ok(update) => best = Some(update),
+                Err(BitmapError::TooLarge) => break,
+                Err(error) => {
+                    self.next_row = self.region.height;
+                    return Some(Err(error));
+                }
+            }
+        }
+
+        match best {
+            Some(update) => {
+                self.next_row += update.region.height;
+                Some(Ok(update))
+            }
+            None => {
+                self.next_row = self.region.height;
+                Some(Err(BitmapError::TooLarge))
+            }
+        }
+    }
+}
+
+/// 2 つの画面で異なるピクセルを囲む最小の領域。差分がなければ `None`
+pub fn changed_region<B: AsRef<[u8]>>(
+    current: &MonoFramebuffer<B>,
+    previous: &MonoFramebuffer<B>,
+) -> Result<Option<Region>, BitmapError> {
+    check_same_size(current, previous)?;
+
+    let mut bounds: Option<(u16, u16, u16, u16)> = None;
+    for y in 0..current.height {
+        for x in 0..current.width {
+            let point = Point { x, y };
+            if current.pixel(point) == previous.pixel(point) {
+                continue;
+            }
+            bounds = Some(match bounds {
+                None => (x, y, x, y),
+                Some((left, top, right, bottom)) => {
+                    (left.min(x), top.min(y), right.max(x), bottom.max(y))
+                }
+            });
+        }
+    }
+
+    Ok(bounds.map(|(left, top, right, bottom)| Region {
+        x: left,
+        y: top,
+        width: right - left + 1,
+        height: bottom - top + 1,
+    }))
+}
+
+fn stride(width: u16) -> usize {
+    usize::from(width).div_ceil(8)
+}
+
+fn region_len(region: Region) -> usize {
+    stride(region.width) * usize::from(region.height)
+}
+
+fn check_same_size<B: AsRef<[u8]>>(
+    a: &MonoFramebuffer<B>,
+    b: &MonoFramebuffer<B>,
+) -> Result<(), BitmapError> {
+    if a.width != b.width || a.height != b.height {
+        return Err(BitmapError::SizeMismatch);
+    }
+    Ok(())
+}
+
+fn collect_raw<B: AsRef<[u8]>>(
+    framebuffer: &MonoFramebuffer<B>,
+    region: Region,
+    len: usize,
+) -> Option<Vec<u8, MAX_BITMAP_DATA_LEN>> {
+    let mut data = Vec::new();
+    for index in 0..len {
+        data.push(framebuffer.region_byte(region, index)).ok()?;
+    }
+    Some(data)
+}
+
+/// `len` byte の列を PackBits で圧縮する。収まらない場合は `None`
+fn rle_encode(len: usize, byte_at: impl Fn(usize) -> u8) -> Option<Vec<u8, MAX_BITMAP_DATA_LEN>> {
+    let mut out = Vec::new();
+    let mut index = 0;
+
+    while index < len {
+        let byte = byte_at(index);
+        let mut run = 1;
+        while index + run < len && run < MAX_RUN_LEN && byte_at(index + run) == byte {
+            run += 1;
+        }
+
+        if run >= MIN_RUN_LEN {
+            out.push(0x80 | u8::try_from(run - MIN_RUN_LEN).ok()?)
+                .ok()?;
+            out.push(byte).ok()?;
+            index += run;
+            continue;
+        }
+
+        let start = index;
+        index += 1;
+        while index < len
+            && index - start < MAX_LITERAL_LEN
+            && !(index + 1 < len && byte_at(index) == byte_at(index + 1))
+        {
+            index += 1;
+        }
+        out.push(u8::try_from(index - start - 1).ok()?).ok()?;
+        for literal in start..index {
+            out.push(byte_at(literal)).ok()?;
+        }
+    }
+
+    Some(out)
+}
+
+/// PackBits を展開して `output(index, byte)` に渡す。展開後の長さを返す
+///
+/// `limit` を超えて展開しようとした場合は `LengthMismatch`
+fn rle_decode(
+    data: &[u8],
+    limit: usize,
+    mut output: impl FnMut(usize, u8),
+) -> Result<usize, BitmapError> {
+    let mut written = 0;
+    let mut bytes = data.iter();
+
+    while let Some(&control) = bytes.next() {
+        if control & 0x80 == 0 {
+            let count = usize::from(control) + 1;
+            for _ in 0..count {
+                let byte = *bytes.next().ok_or(BitmapError::InvalidRle)?;
+                if written >= limit {
+                    return Err(BitmapError::LengthMismatch);
+                }
+                output(written, byte);
+                written += 1;
+            }
+        } else {
+            let count = usize::from(control & 0x7F) + MIN_RUN_LEN;
+            let byte = *bytes.next().ok_or(BitmapError::InvalidRle)?;
+            if written + count > limit {
+                return Err(BitmapError::LengthMismatch);
+            }
+            for _ in 0..count {
+                output(written, byte);
+                written += 1;
+            }
+        }
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::{DisplayData, DisplayPayload, DisplayTarget, MAX_PAYLOAD_SIZE, encode_data_packet};
+
+    use std::vec::Vec as StdVec;
+
+    const DDI_WIDTH: u16 = 480;
+    const DDI_HEIGHT: u16 = 480;
+
+    fn framebuffer(width: u16, height: u16) -> MonoFramebuffer<StdVec<u8>> {
+        let len = stride(width) * usize::from(height);
+        MonoFramebuffer::new(width, height, std::vec![0; len]).unwrap()
+    }
+
+    /// 16px ごとの横線と外枠、左上の "H" のような模様
+    fn ddi_page() -> MonoFramebuffer<StdVec<u8>> {
+        let mut page = framebuffer(DDI_WIDTH, DDI_HEIGHT);
+        for y in 0..DDI_HEIGHT {
+            for x in 0..DDI_WIDTH {
+                let border = x == 0 || y == 0 || x == DDI_WIDTH - 1 || y == DDI_HEIGHT - 1;
+                let rule = y % 16 == 0;
+                let glyph =
+                    (8..24).contains(&y) && (x == 8 || x == 16 || (x > 8 && x < 16 && y == 16));
+                page.set_pixel(Point { x, y }, border || rule || glyph);
+            }
+        }
+        page
+    }
+
+    fn noise(width: u16, height: u16) -> MonoFramebuffer<StdVec<u8>> {
+        let mut state: u32 = 0x1234_5678;
+        let len = stride(width) * usize::from(height);
+        let bytes = (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                state.to_be_bytes()[0]
+            })
+            .collect();
+        MonoFramebuffer::new(width, height, bytes).unwrap()
+    }
+
+    fn region(x: u16, y: u16, width: u16, height: u16) -> Region {
+        Region {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn rle_encode_matches_known_bytes() {
+        let input = [0xFF, 0xFF, 0xFF, 0xFF, 0x01, 0x02, 0x03, 0x00, 0x00];
+        let encoded = rle_encode(input.len(), |index| input[index]).unwrap();
+
+        assert_eq!(
+            encoded.as_slice(),
+            &[0x82, 0xFF, 0x02, 0x01, 0x02, 0x03, 0x80, 0x00]
+        );
+
+        let mut decoded = StdVec::new();
+        let len = rle_decode(&encoded, input.len(), |_, byte| decoded.push(byte)).unwrap();
+        assert_eq!(len, input.len());
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    fn rle_encode_splits_long_runs() {
+        let input: StdVec<u8> = core::iter::repeat_n(0xAA, 300).chain(0..60).collect();
+        let encoded = rle_encode(input.len(), |index| input[index]).unwrap();
+
+        assert_eq!(&encoded[..6], &[0xFF, 0xAA, 0xFF, 0xAA, 0xA8, 0xAA]);
+        assert_eq!(encoded[6], 59);
+
+        let mut decoded = StdVec::new();
+        rle_decode(&encoded, input.len(), |_, byte| decoded.push(byte)).unwrap();
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    fn rle_encode_returns_none_when_output_overflows() {
+        let input: StdVec<u8> = (0..=199).collect();
+
+        assert!(rle_encode(input.len(), |index| input[index]).is_none());
+    }
+
+    #[test]
+    fn blank_region_uses_rle() {
+        let current = framebuffer(DDI_WIDTH, DDI_HEIGHT);
+        let update = encode_bitmap_update(&current, None, region(0, 0, DDI_WIDTH, 8)).unwrap();
+
+        assert_eq!(update.compression, BitmapCompression::Rle);
+        assert_eq!(
+            update.data.as_slice(),
+            &[0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xDB, 0x00]
+        );
+    }
+
+    #[test]
+    fn noisy_region_uses_raw() {
+        let current = noise(64, 8);
+        let update = encode_bitmap_update(&current, None, current.bounds()).unwrap();
+
+        assert_eq!(update.compression, BitmapCompression::Raw);
+        assert_eq!(update.data.as_slice(), current.as_bytes());
+    }
+
+    #[test]
+    fn small_change_uses_xor_delta() {
+        let previous = noise(64, 8);
+        let mut current = previous.clone();
+        let flipped = Point { x: 33, y: 4 };
+        current.set_pixel(flipped, !previous.pixel(flipped));
+
+        let update = encode_bitmap_update(&current, Some(&previous), current.bounds()).unwrap();
+        assert_eq!(update.compression, BitmapCompression::XorRle);
+
+        let mut device = previous.clone();
+        apply_bitmap_update(&mut device, &update).unwrap();
+        assert_eq!(device, current);
+    }
+
+    #[test]
+    fn unaligned_region_roundtrip_works() {
+        let source = noise(40, 12);
+        let target = region(3, 2, 13, 7);
+        let update = encode_bitmap_update(&source, None, target).unwrap();
+
+        let mut device = framebuffer(40, 12);
+        apply_bitmap_update(&mut device, &update).unwrap();
+
+        for y in 0..12 {
+            for x in 0..40 {
+                let point = Point { x, y };
+                let expected = target.contains(point) && source.pixel(point);
+                assert_eq!(device.pixel(point), expected, "{x},{y}");
+            }
+        }
+    }
+
+    #[test]
+    fn bands_redraw_full_ddi_page() {
+        let previous = framebuffer(DDI_WIDTH, DDI_HEIGHT);
+        let current = ddi_page();
+
+        let mut device = previous.clone();
+        let mut seq = 0u16;
+        for update in encode_bitmap_bands(&current, Some(&previous), current.bounds()) {
+            let update = update.unwrap();
+            let packet = DisplayData {
+                seq,
+                target: DisplayTarget::Screen(0),
+                payload: DisplayPayload::Bitmap(update.clone()),
+            };
+            assert!(encode_data_packet(&packet).unwrap().len() <= MAX_PAYLOAD_SIZE);
+
+            apply_bitmap_update(&mut device, &update).unwrap();
+            seq = seq.wrapping_add(1);
+        }
+
+        assert_eq!(device, current);
+        // 1 行ずつ送るよりは十分少ない
+        assert!(seq < DDI_HEIGHT / 4);
+    }
+
+    #[test]
+    fn bands_send_only_changed_region() {
+        let previous = ddi_page();
+        let mut current = previous.clone();
+        for x in 100..140 {
+            current.set_pixel(Point { x, y: 200 }, true);
+        }
+
+        let changed = changed_region(&current, &previous).unwrap().unwrap();
+        assert_eq!(changed, region(100, 200, 40, 1));
+
+        let updates: StdVec<_> = encode_bitmap_bands(&current, Some(&previous), changed)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(updates.len(), 1);
+
+        let mut device = previous.clone();
+        apply_bitmap_update(&mut device, &updates[0]).unwrap();
+        assert_eq!(device, current);
+        assert_eq!(changed_region(&current, &current).unwrap(), None);
+    }
+
+    #[test]
+    fn too_wide_region_is_rejected() {
+        let current = noise(1024, 1);
+        let mut bands = encode_bitmap_bands(&current, None, current.bounds());
+
+        assert_eq!(bands.next(), Some(Err(BitmapError::TooLarge)));
+        assert_eq!(bands.next(), None);
+    }
+
+    #[test]
+    fn largest_update_fits_in_payload() {
+        let packet = DisplayData {
+            seq: u16::MAX,
+            target: DisplayTarget::Screen(u8::MAX),
+            payload: DisplayPayload::Bitmap(BitmapUpdate {
+                region: region(u16::MAX, u16::MAX, u16::MAX, u16::MAX),
+                compression: BitmapCompression::XorRle,
+                data: Vec::from_slice(&[0xAB; MAX_BITMAP_DATA_LEN]).unwrap(),
+            }),
+        };
+
+        assert!(encode_data_packet(&packet).unwrap().len() <= MAX_PAYLOAD_SIZE);
+    }
+
+    #[test]
+    fn invalid_updates_leave_framebuffer_untouched() {
+        let original = noise(16, 4);
+        let mut device = original.clone();
+
+        let out_of_bounds = BitmapUpdate {
+            region: region(8, 0, 16, 1),
+            compression: BitmapCompression::Raw,
+            data: Vec::from_slice(&[0xFF, 0xFF]).unwrap(),
+        };
+        assert_eq!(
+            apply_bitmap_update(&mut device, &out_of_bounds),
+            Err(BitmapError::OutOfBounds)
+        );
+
+        let short_raw = BitmapUpdate {
+            region: region(0, 0, 16, 2),
+            compression: BitmapCompression::Raw,
+            data: Vec::from_slice(&[0xFF, 0xFF]).unwrap(),
+        };
+        assert_eq!(
+            apply_bitmap_update(&mut device, &short_raw),
+            Err(BitmapError::LengthMismatch)
+        );
+
+        let truncated = BitmapUpdate {
+            region: region(0, 0, 16, 2),
+            compression: BitmapCompression::Rle,
+            data: Vec::from_slice(&[0x03, 0xFF, 0xFF]).unwrap(),
+        };
+        assert_eq!(
+            apply_bitmap_update(&mut device, &truncated),
+            Err(BitmapError::InvalidRle)
+        );
+
+        let overflowing = BitmapUpdate {
+            region: region(0, 0, 16, 2),
+            compression: BitmapCompression::XorRle,
+            data: Vec::from_slice(&[0x83, 0xFF]).unwrap(),
+        };
+        assert_eq!(
+            apply_bitmap_update(&mut device, &overflowing),
+            Err(BitmapError::LengthMismatch)
+        );
+
+        assert_eq!(device, original);
+    }
+
+    #[test]
+    fn framebuffer_rejects_short_buffer() {
+        assert_eq!(
+            MonoFramebuffer::new(9, 2, [0u8; 3]),
+            Err(BitmapError::BufferTooSmall)
+        );
+        assert!(MonoFramebuffer::new(9, 2, [0u8; 4]).is_ok());
+    }
+}
@@ -0,0 +1,180 @@
+//! アナログ軸のキャリブレーション
+//!
+//! ADC の生の値を `ControlValue::Absolute` の値に換算する範囲と、ホストがその範囲を記録させるやり取り。
+//! ホストは `CalibrateAxis` を `Set` frame で送って記録の開始や確定を指示し、
+//! デバイスは `AxisCalibrationStatus` で記録中の範囲や確定した範囲を返す。
+
+use serde::{Deserialize, Serialize};
+
+/// `ControlValue::Absolute` の最大値。最小値は `-ABSOLUTE_MAX`
+pub const ABSOLUTE_MAX: i16 = i16::MAX;
+
+/// ADC の生の値での軸の範囲
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub struct AxisCalibration {
+    pub min: u16,
+    /// 0 に換算する位置。`min` にするとスロットルのような片側だけの軸になる
+    pub center: u16,
+    pub max: u16,
+    /// `center` から前後この幅までを 0 にする
+    pub deadzone: u16,
+}
+
+impl AxisCalibration {
+    /// `0..=max_raw` の全体を使い、中央を 0 にする
+    pub const fn full_range(max_raw: u16) -> Self {
+        Self {
+            min: 0,
+            center: max_raw / 2,
+            max: max_raw,
+            deadzone: 0,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.min < self.max && (self.min..=self.max).contains(&self.center)
+    }
+
+    /// 生の値を `-ABSOLUTE_MAX..=ABSOLUTE_MAX` に換算する
+    pub fn apply(&self, raw: u16) -> i16 {
+        let raw = i64::from(raw);
+        let low = i64::from(self.center) - i64::from(self.deadzone);
+        let high = i64::from(self.center) + i64::from(self.deadzone);
+        let value = if raw < low {
+            -scale(low - raw, low - i64::from(self.min))
+        } else if raw > high {
+            scale(raw - high, i64::from(self.max) - high)
+        } else {
+            0
+        };
+        i16::try_from(value).unwrap_or(0)
+    }
+}
+
+/// `distance / span` を `0..=ABSOLUTE_MAX` に丸める。範囲のない側は 0 のままにする
+fn scale(distance: i64, span: i64) -> i64 {
+    let max = i64::from(ABSOLUTE_MAX);
+    if span <= 0 {
+        return 0;
+    }
+    ((distance * max + span / 2) / span).min(max)
+}
+
+/// ホストからデバイスへのキャリブレーションの指示
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub enum CalibrationCommand {
+    /// 今の位置を中心として範囲の記録を始める。記録中は `Absolute` のイベントを送らない
+    Begin,
+    /// 記録中に、今の位置を中心に置き換える
+    CaptureCenter,
+    /// 記録した範囲を確定する
+    Commit,
+    /// 記録をやめて元の範囲に戻す
+    Cancel,
+    /// 現在の範囲の問い合わせ
+    Get,
+    /// 範囲を直接設定する
+    Set(AxisCalibration),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub enum CalibrationError {
+    /// 軸ではない、または存在しない操作系
+    UnknownControl,
+    /// 記録中でないのに `CaptureCenter` / `Commit` を受け取った
+    NotRecording,
+    /// 範囲が狭すぎるか、中心が範囲の外
+    InvalidRange,
+}
+
+/// `CalibrateAxis` への応答。記録中は範囲が広がるたびにも送る
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub struct AxisCalibrationStatus {
+    pub control_id: u16,
+    /// フィルター後の今の生の値
+    pub raw: u16,
+    pub recording: bool,
+    /// 記録中はここまでに記録した範囲、それ以外は使っている範囲
+    pub calibration: AxisCalibration,
+    pub error: Option<CalibrationError>,
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::{AppPacketKind, decode_set_packet, encode_set_packet};
+
+    #[test]
+    fn calibration_maps_both_sides_of_center() {
+        let calibration = AxisCalibration {
+            min: 100,
+            center: 2000,
+            max: 4000,
+            deadzone: 50,
+        };
+
+        assert_eq!(calibration.apply(0), -ABSOLUTE_MAX);
+        assert_eq!(calibration.apply(100), -ABSOLUTE_MAX);
+        assert_eq!(calibration.apply(1950), 0);
+        assert_eq!(calibration.apply(2050), 0);
+        assert_eq!(calibration.apply(3025), ABSOLUTE_MAX / 2 + 1);
+        assert_eq!(calibration.apply(4000), ABSOLUTE_MAX);
+        assert_eq!(calibration.apply(u16::MAX), ABSOLUTE_MAX);
+        assert!(calibration.apply(1000) < 0);
+    }
+
+    #[test]
+    fn one_sided_axis_and_invalid_ranges() {
+        let throttle = AxisCalibration {
+            min: 10,
+            center: 10,
+            max: 1010,
+            deadzone: 0,
+        };
+        assert!(throttle.is_valid());
+        assert_eq!(throttle.apply(0), 0);
+        assert_eq!(throttle.apply(510), ABSOLUTE_MAX / 2 + 1);
+
+        assert!(AxisCalibration::full_range(4095).is_valid());
+        assert!(
+            !AxisCalibration {
+                min: 10,
+                center: 5,
+                max: 20,
+                deadzone: 0,
+            }
+            .is_valid()
+        );
+        assert!(!AxisCalibration::full_range(0).is_valid());
+    }
+
+    #[test]
+    fn calibration_packets_roundtrip() {
+        for packet in [
+            AppPacketKind::CalibrateAxis {
+                control_id: 0x0301,
+                command: CalibrationCommand::Set(AxisCalibration::full_range(4095)),
+            },
+            AppPacketKind::AxisCalibrationStatus(AxisCalibrationStatus {
+                control_id: 0x0301,
+                raw: 2048,
+                recording: true,
+                calibration: AxisCalibration::full_range(4095),
+                error: Some(CalibrationError::InvalidRange),
+            }),
+        ] {
+            let encoded = encode_set_packet(&packet).unwrap();
+            assert_eq!(decode_set_packet(&encoded).unwrap(), packet);
+            assert!(!packet.is_available_in(1));
+        }
+    }
+}
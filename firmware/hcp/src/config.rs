@@ -0,0 +1,138 @@
+//! ホストからデバイスへの設定
+//!
+//! ホストは `GetConfig` / `SetConfig` を `Set` frame で送り、
+//! デバイスは `ConfigResponse` で現在の値かエラーを返す。
+//! 値の型と範囲はキーごとに決まっていて、`ConfigKey::validate` で検証する。
+
+use serde::{Deserialize, Serialize};
+
+pub const MAX_DEBOUNCE_MS: u16 = 1000;
+pub const MAX_SCAN_INTERVAL_MS: u16 = 1000;
+pub const MAX_ENCODER_ACCELERATION: u8 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConfigKey {
+    /// スイッチのチャタリング除去時間 (ms)。`U16`
+    DebounceMs,
+    /// LED / バックライトの明るさ。`U8`
+    LedBrightness,
+    /// マトリクスの行を選択した後と解除した後に待つ時間 (ms)。1 行の走査にはこの 2 倍かかる。`U16`、1 以上
+    ScanIntervalMs,
+    /// エンコーダーの加速度。0 で加速なし。`U8`
+    EncoderAcceleration,
+}
+
+impl ConfigKey {
+    pub const ALL: [ConfigKey; 4] = [
+        ConfigKey::DebounceMs,
+        ConfigKey::LedBrightness,
+        ConfigKey::ScanIntervalMs,
+        ConfigKey::EncoderAcceleration,
+    ];
+
+    /// 値の型と範囲を検証する
+    pub fn validate(self, value: ConfigValue) -> Result<(), ConfigStatus> {
+        match (self, value) {
+            (ConfigKey::DebounceMs, ConfigValue::U16(ms)) if ms <= MAX_DEBOUNCE_MS => Ok(()),
+            (ConfigKey::LedBrightness, ConfigValue::U8(_)) => Ok(()),
+            (ConfigKey::ScanIntervalMs, ConfigValue::U16(ms))
+                if (1..=MAX_SCAN_INTERVAL_MS).contains(&ms) =>
+            {
+                Ok(())
+            }
+            (ConfigKey::EncoderAcceleration, ConfigValue::U8(level))
+                if level <= MAX_ENCODER_ACCELERATION =>
+            {
+                Ok(())
+            }
+            (ConfigKey::DebounceMs | ConfigKey::ScanIntervalMs, ConfigValue::U16(_))
+            | (ConfigKey::EncoderAcceleration, ConfigValue::U8(_)) => Err(ConfigStatus::OutOfRange),
+            _ => Err(ConfigStatus::TypeMismatch),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConfigValue {
+    U8(u8),
+    U16(u16),
+}
+
+/// `ConfigResponse` の結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConfigStatus {
+    /// 現在の値。`SetConfig` の場合は反映後の値
+    Value(ConfigValue),
+    /// デバイスがこのキーに対応していない
+    UnsupportedKey,
+    /// 値の型がキーと一致しない
+    TypeMismatch,
+    /// 値が範囲外
+    OutOfRange,
+    /// 値は反映したが保存に失敗した
+    StorageFailed(ConfigValue),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConfigResponse {
+    pub key: ConfigKey,
+    pub status: ConfigStatus,
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::{AppPacketKind, decode_set_packet, encode_set_packet};
+
+    #[test]
+    fn config_packets_roundtrip() {
+        let packets = [
+            AppPacketKind::GetConfig {
+                key: ConfigKey::DebounceMs,
+            },
+            AppPacketKind::SetConfig {
+                key: ConfigKey::ScanIntervalMs,
+                value: ConfigValue::U16(2),
+            },
+            AppPacketKind::ConfigResponse(ConfigResponse {
+                key: ConfigKey::LedBrightness,
+                status: ConfigStatus::Value(ConfigValue::U8(200)),
+            }),
+            AppPacketKind::ConfigResponse(ConfigResponse {
+                key: ConfigKey::EncoderAcceleration,
+                status: ConfigStatus::OutOfRange,
+            }),
+        ];
+
+        for packet in packets {
+            let encoded = encode_set_packet(&packet).unwrap();
+            assert_eq!(decode_set_packet(&encoded).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn validate_checks_type_and_range() {
+        assert_eq!(ConfigKey::DebounceMs.validate(ConfigValue::U16(20)), Ok(()));
+        assert_eq!(
+            ConfigKey::DebounceMs.validate(ConfigValue::U16(MAX_DEBOUNCE_MS + 1)),
+            Err(ConfigStatus::OutOfRange)
+        );
+        assert_eq!(
+            ConfigKey::ScanIntervalMs.validate(ConfigValue::U16(0)),
+            Err(ConfigStatus::OutOfRange)
+        );
+        assert_eq!(
+            ConfigKey::LedBrightness.validate(ConfigValue::U16(1)),
+            Err(ConfigStatus::TypeMismatch)
+        );
+        assert_eq!(
+            ConfigKey::EncoderAcceleration.validate(ConfigValue::U16(1)),
+            Err(ConfigStatus::TypeMismatch)
+        );
+    }
+}
@@ -0,0 +1,387 @@
+//! デバイスの入出力一覧 (descriptor)
+//!
+//! `DeviceHello` の `capabilities` は個数しか持たないため、
+//! ホストは `RequestDescriptor { page }` で各 control / display の詳細を問い合わせる。
+//! デバイスは `DescriptorPage` を返し、ホストは `next_page` がなくなるまで順に要求する。
+//! 1 ページには `DESCRIPTORS_PER_PAGE` 個までの項目が入り、128 byte に収まる。
+
+use core::fmt;
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::{ControlValue, DisplayTarget, SegmentKind};
+
+/// control のラベル長 (byte)
+pub const MAX_LABEL_LEN: usize = 16;
+/// 1 ページに入れる項目数
+pub const DESCRIPTORS_PER_PAGE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub enum ControlKind {
+    Button,
+    Toggle,
+    Encoder,
+    Axis,
+}
+
+impl ControlKind {
+    /// この種類の control が通常送る `ControlValue`
+    pub const fn default_events(self) -> SupportedEvents {
+        match self {
+            ControlKind::Button => SupportedEvents::BUTTON,
+            ControlKind::Toggle => SupportedEvents::TOGGLE,
+            ControlKind::Encoder => SupportedEvents::ENCODER_DELTA,
+            ControlKind::Axis => SupportedEvents::ABSOLUTE,
+        }
+    }
+}
+
+/// control が送る `ControlValue` の種類 (bit の組み合わせ)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub struct SupportedEvents(pub u8);
+
+impl SupportedEvents {
+    pub const NONE: Self = Self(0);
+    pub const BUTTON: Self = Self(1 << 0);
+    pub const ENCODER_DELTA: Self = Self(1 << 1);
+    pub const ABSOLUTE: Self = Self(1 << 2);
+    pub const TOGGLE: Self = Self(1 << 3);
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// `value` がこの組み合わせに含まれるか。`RequestDeviceHello` は常に含まない
+    pub fn supports(self, value: &ControlValue) -> bool {
+        match value {
+            ControlValue::Button { .. } => self.contains(Self::BUTTON),
+            ControlValue::EncoderDelta { .. } => self.contains(Self::ENCODER_DELTA),
+            ControlValue::Absolute { .. } => self.contains(Self::ABSOLUTE),
+            ControlValue::Toggle { .. } => self.contains(Self::TOGGLE),
+            ControlValue::RequestDeviceHello => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub struct ControlDescriptor {
+    pub id: u16,
+    pub kind: ControlKind,
+    pub events: SupportedEvents,
+    #[cfg_attr(test, ts(type = "string"))]
+    pub label: heapless::String<MAX_LABEL_LEN>,
+}
+
+impl ControlDescriptor {
+    /// `kind` の標準の `events` を持つ descriptor を作る
+    ///
+    /// ラベルが長すぎる場合は `None` を返す
+    pub fn new(id: u16, kind: ControlKind, label: &str) -> Option<Self> {
+        Some(Self {
+            id,
+            kind,
+            events: kind.default_events(),
+            label: heapless::String::try_from(label).ok()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub enum DisplayKind {
+    /// 1bpp のビットマップ画面。大きさはピクセル単位
+    MonoBitmap,
+    /// 文字グリッドのみの画面。大きさは列数と行数
+    CharacterGrid,
+    /// 表示灯。大きさは 1x1
+    Indicator,
+    /// segment 表示器。大きさは桁数と行数
+    Segments(SegmentKind),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub struct DisplayDescriptor {
+    pub target: DisplayTarget,
+    pub kind: DisplayKind,
+    pub width: u16,
+    pub height: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DescriptorEntry {
+    Control(ControlDescriptor),
+    Display(DisplayDescriptor),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DescriptorPage {
+    pub page: u16,
+    pub total_pages: u16,
+    pub entries: Vec<DescriptorEntry, DESCRIPTORS_PER_PAGE>,
+}
+
+impl DescriptorPage {
+    /// `entries` の `page` ページ目を作る。範囲外のページは `None`
+    pub fn from_entries(entries: &[DescriptorEntry], page: u16) -> Option<Self> {
+        Self::from_fn(entries.len(), page, |index| entries.get(index).cloned())
+    }
+
+    /// 項目を 1 つずつ作りながらページを作る
+    ///
+    /// 全項目を RAM に並べたくないファームウェア向け。
+    /// 項目がない場合も空の 0 ページ目を返す
+    pub fn from_fn(
+        entry_count: usize,
+        page: u16,
+        mut entry_at: impl FnMut(usize) -> Option<DescriptorEntry>,
+    ) -> Option<Self> {
+        let total_pages = u16::try_from(entry_count.div_ceil(DESCRIPTORS_PER_PAGE).max(1)).ok()?;
+        if page >= total_pages {
+            return None;
+        }
+
+        let start = usize::from(page) * DESCRIPTORS_PER_PAGE;
+        let end = entry_count.min(start + DESCRIPTORS_PER_PAGE);
+        let mut page_entries = Vec::new();
+        for index in start..end {
+            page_entries.push(entry_at(index)?).ok()?;
+        }
+
+        Some(Self {
+            page,
+            total_pages,
+            entries: page_entries,
+        })
+    }
+
+    /// 次に要求するページ。最後のページなら `None`
+    pub fn next_page(&self) -> Option<u16> {
+        let next = self.page.checked_add(1)?;
+        (next < self.total_pages).then_some(next)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DescriptorError {
+    /// 要求していないページを受け取った
+    UnexpectedPage { expected: u16, actual: u16 },
+    /// 途中で総ページ数が変わった (デバイスが再起動した場合など)
+    TotalPagesChanged { expected: u16, actual: u16 },
+    /// 既に全ページを受け取っている
+    AlreadyComplete,
+}
+
+impl fmt::Display for DescriptorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DescriptorError::UnexpectedPage { expected, actual } => {
+                write!(f, "expected descriptor page {expected} but got {actual}")
+            }
+            DescriptorError::TotalPagesChanged { expected, actual } => write!(
+                f,
+                "descriptor page count changed from {expected} to {actual}"
+            ),
+            DescriptorError::AlreadyComplete => write!(f, "all descriptor pages were received"),
+        }
+    }
+}
+
+impl core::error::Error for DescriptorError {}
+
+/// ホスト側でページを順に受け取る
+///
+/// 項目そのものは呼び出し側で保持する
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DescriptorAssembler {
+    next_page: Option<u16>,
+    total_pages: Option<u16>,
+}
+
+impl DescriptorAssembler {
+    pub const fn new() -> Self {
+        Self {
+            next_page: Some(0),
+            total_pages: None,
+        }
+    }
+
+    /// 次に要求するページ。全ページを受け取った後は `None`
+    pub fn next_page(&self) -> Option<u16> {
+        self.next_page
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.next_page.is_none()
+    }
+
+    /// ページを受け取り、次に要求するページを返す
+    pub fn accept(&mut self, page: &DescriptorPage) -> Result<Option<u16>, DescriptorError> {
+        let expected = self.next_page.ok_or(DescriptorError::AlreadyComplete)?;
+        if page.page != expected {
+            return Err(DescriptorError::UnexpectedPage {
+                expected,
+                actual: page.page,
+            });
+        }
+        if let Some(total_pages) = self.total_pages
+            && total_pages != page.total_pages
+        {
+            return Err(DescriptorError::TotalPagesChanged {
+                expected: total_pages,
+                actual: page.total_pages,
+            });
+        }
+
+        self.total_pages = Some(page.total_pages);
+        self.next_page = page.next_page();
+        Ok(self.next_page)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::{AppPacketKind, MAX_PAYLOAD_SIZE, decode_set_packet, encode_set_packet};
+
+    use std::{format, vec::Vec as StdVec};
+
+    fn entries() -> StdVec<DescriptorEntry> {
+        let mut entries: StdVec<_> = (0..9)
+            .map(|id| {
+                DescriptorEntry::Control(
+                    ControlDescriptor::new(id, ControlKind::Button, &format!("OSB {id}")).unwrap(),
+                )
+            })
+            .collect();
+        entries.push(DescriptorEntry::Display(DisplayDescriptor {
+            target: DisplayTarget::Screen(0),
+            kind: DisplayKind::MonoBitmap,
+            width: 480,
+            height: 480,
+        }));
+        entries
+    }
+
+    #[test]
+    fn pages_cover_all_entries_in_order() {
+        let entries = entries();
+        let mut assembler = DescriptorAssembler::new();
+        let mut received = StdVec::new();
+
+        while let Some(page) = assembler.next_page() {
+            let packet = AppPacketKind::DescriptorPage(
+                DescriptorPage::from_entries(&entries, page).unwrap(),
+            );
+            let AppPacketKind::DescriptorPage(decoded) =
+                decode_set_packet(&encode_set_packet(&packet).unwrap()).unwrap()
+            else {
+                unreachable!();
+            };
+
+            assert_eq!(decoded.total_pages, 3);
+            assembler.accept(&decoded).unwrap();
+            received.extend(decoded.entries);
+        }
+
+        assert!(assembler.is_complete());
+        assert_eq!(received, entries);
+        assert_eq!(DescriptorPage::from_entries(&entries, 3), None);
+    }
+
+    #[test]
+    fn empty_descriptor_has_single_page() {
+        let page = DescriptorPage::from_entries(&[], 0).unwrap();
+
+        assert_eq!(page.total_pages, 1);
+        assert!(page.entries.is_empty());
+        assert_eq!(page.next_page(), None);
+    }
+
+    #[test]
+    fn largest_page_fits_in_payload() {
+        let label = "W".repeat(MAX_LABEL_LEN);
+        let control = DescriptorEntry::Control(ControlDescriptor {
+            id: u16::MAX,
+            kind: ControlKind::Axis,
+            events: SupportedEvents(u8::MAX),
+            label: heapless::String::try_from(label.as_str()).unwrap(),
+        });
+        let display = DescriptorEntry::Display(DisplayDescriptor {
+            target: DisplayTarget::Indicator(u16::MAX),
+            kind: DisplayKind::Segments(SegmentKind::Sixteen),
+            width: u16::MAX,
+            height: u16::MAX,
+        });
+
+        for entry in [control, display] {
+            let page = DescriptorPage {
+                page: u16::MAX,
+                total_pages: u16::MAX,
+                entries: Vec::from_slice(&[entry.clone(), entry.clone(), entry.clone(), entry])
+                    .unwrap(),
+            };
+            let encoded = encode_set_packet(&AppPacketKind::DescriptorPage(page)).unwrap();
+            assert!(encoded.len() <= MAX_PAYLOAD_SIZE);
+        }
+    }
+
+    #[test]
+    fn assembler_rejects_out_of_order_pages() {
+        let entries = entries();
+        let mut assembler = DescriptorAssembler::new();
+
+        let second = DescriptorPage::from_entries(&entries, 1).unwrap();
+        assert_eq!(
+            assembler.accept(&second),
+            Err(DescriptorError::UnexpectedPage {
+                expected: 0,
+                actual: 1
+            })
+        );
+
+        assert_eq!(
+            assembler.accept(&DescriptorPage::from_entries(&entries, 0).unwrap()),
+            Ok(Some(1))
+        );
+        let resized = DescriptorPage::from_entries(&entries[..4], 0)
+            .map(|page| DescriptorPage { page: 1, ..page });
+        assert_eq!(
+            assembler.accept(&resized.unwrap()),
+            Err(DescriptorError::TotalPagesChanged {
+                expected: 3,
+                actual: 1
+            })
+        );
+    }
+
+    #[test]
+    fn supported_events_match_control_values() {
+        let events = ControlKind::Button
+            .default_events()
+            .union(SupportedEvents::TOGGLE);
+
+        assert!(events.supports(&ControlValue::Button { pressed: true }));
+        assert!(events.supports(&ControlValue::Toggle { state: false }));
+        assert!(!events.supports(&ControlValue::EncoderDelta { steps: 1 }));
+        assert!(!events.supports(&ControlValue::RequestDeviceHello));
+    }
+}
@@ -0,0 +1,229 @@
+//! DDI などの画面向けの描画コマンド
+//!
+//! `DisplayPayload::Commands` に複数のコマンドを詰めて送る。
+//! コマンドは先頭から順に適用し、1 packet は 128 byte に収まる必要がある。
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+/// 1 packet に入れられるコマンド数の上限
+pub const MAX_DISPLAY_COMMANDS: usize = 8;
+/// 1 つの `Text` コマンドに入れられる文字列長 (byte)
+pub const MAX_COMMAND_TEXT_LEN: usize = 48;
+
+pub type DisplayCommands = Vec<DisplayCommand, MAX_DISPLAY_COMMANDS>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub enum DisplayCommand {
+    /// 画面全体を消去する
+    Clear,
+    /// 指定領域だけを消去する (部分更新用)
+    ClearRegion(Region),
+    /// 文字グリッド上の行・列に文字列を描画する
+    Text {
+        row: u8,
+        column: u8,
+        font: FontId,
+        attributes: TextAttributes,
+        #[cfg_attr(test, ts(type = "string"))]
+        content: heapless::String<MAX_COMMAND_TEXT_LEN>,
+    },
+    /// 矩形を描画する。`filled` が false の場合は枠線のみ
+    Box { region: Region, filled: bool },
+    /// 2 点間に 1px の線を描画する
+    Line { from: Point, to: Point },
+}
+
+/// ピクセル座標 (左上が原点)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub struct Point {
+    pub x: u16,
+    pub y: u16,
+}
+
+/// ピクセル単位の矩形領域
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub struct Region {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Region {
+    pub fn contains(&self, point: Point) -> bool {
+        point.x >= self.x
+            && point.y >= self.y
+            && u32::from(point.x) < u32::from(self.x) + u32::from(self.width)
+            && u32::from(point.y) < u32::from(self.y) + u32::from(self.height)
+    }
+}
+
+/// デバイス側で定義するフォント番号。0 はデフォルトフォント
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub struct FontId(pub u8);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub struct TextAttributes {
+    /// 背景と文字色を反転する
+    pub inverse: bool,
+    /// 点滅表示する (周期はデバイス側で決める)
+    pub blink: bool,
+}
+
+impl DisplayCommand {
+    /// 属性なし・デフォルトフォントの `Text` コマンドを作る
+    ///
+    /// 文字列が長すぎる場合は `None` を返す
+    pub fn text(row: u8, column: u8, content: &str) -> Option<Self> {
+        Some(DisplayCommand::Text {
+            row,
+            column,
+            font: FontId::default(),
+            attributes: TextAttributes::default(),
+            content: heapless::String::try_from(content).ok()?,
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        AppPacketError, DisplayData, DisplayPayload, DisplayTarget, MAX_PAYLOAD_SIZE,
+        decode_data_packet, encode_data_packet,
+    };
+
+    fn roundtrip(commands: &[DisplayCommand]) -> usize {
+        let packet = DisplayData {
+            seq: 0x1234,
+            target: DisplayTarget::Screen(1),
+            payload: DisplayPayload::Commands(Vec::from_slice(commands).unwrap()),
+        };
+
+        let encoded = encode_data_packet(&packet).unwrap();
+        assert_eq!(decode_data_packet(&encoded).unwrap(), packet);
+        encoded.len()
+    }
+
+    #[test]
+    fn clear_roundtrip_works() {
+        roundtrip(&[DisplayCommand::Clear]);
+    }
+
+    #[test]
+    fn clear_region_roundtrip_works() {
+        roundtrip(&[DisplayCommand::ClearRegion(Region {
+            x: 0,
+            y: 200,
+            width: 480,
+            height: 40,
+        })]);
+    }
+
+    #[test]
+    fn text_roundtrip_works() {
+        roundtrip(&[DisplayCommand::Text {
+            row: 3,
+            column: 12,
+            font: FontId(2),
+            attributes: TextAttributes {
+                inverse: true,
+                blink: true,
+            },
+            content: heapless::String::try_from("NAV MENU").unwrap(),
+        }]);
+    }
+
+    #[test]
+    fn box_roundtrip_works() {
+        roundtrip(&[
+            DisplayCommand::Box {
+                region: Region {
+                    x: 10,
+                    y: 20,
+                    width: 100,
+                    height: 30,
+                },
+                filled: false,
+            },
+            DisplayCommand::Box {
+                region: Region {
+                    x: 300,
+                    y: 300,
+                    width: 1,
+                    height: 1,
+                },
+                filled: true,
+            },
+        ]);
+    }
+
+    #[test]
+    fn line_roundtrip_works() {
+        roundtrip(&[DisplayCommand::Line {
+            from: Point { x: 0, y: 0 },
+            to: Point { x: 639, y: 479 },
+        }]);
+    }
+
+    #[test]
+    fn longest_text_command_fits_in_payload() {
+        let content = [b'W'; MAX_COMMAND_TEXT_LEN];
+        let command = DisplayCommand::Text {
+            row: u8::MAX,
+            column: u8::MAX,
+            font: FontId(u8::MAX),
+            attributes: TextAttributes {
+                inverse: true,
+                blink: true,
+            },
+            content: heapless::String::try_from(core::str::from_utf8(&content).unwrap()).unwrap(),
+        };
+
+        assert!(roundtrip(&[command]) <= MAX_PAYLOAD_SIZE);
+    }
+
+    #[test]
+    fn oversized_command_list_is_rejected() {
+        let command =
+            DisplayCommand::text(0, 0, core::str::from_utf8(&[b'X'; 40]).unwrap()).unwrap();
+        let packet = DisplayData {
+            seq: 1,
+            target: DisplayTarget::Screen(0),
+            payload: DisplayPayload::Commands(
+                Vec::from_slice(&[command.clone(), command.clone(), command]).unwrap(),
+            ),
+        };
+
+        assert_eq!(
+            encode_data_packet(&packet),
+            Err(AppPacketError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn region_contains_checks_bounds() {
+        let region = Region {
+            x: 10,
+            y: 10,
+            width: 5,
+            height: 5,
+        };
+
+        assert!(region.contains(Point { x: 10, y: 14 }));
+        assert!(!region.contains(Point { x: 15, y: 10 }));
+        assert!(!region.contains(Point { x: 9, y: 10 }));
+    }
+}
@@ -0,0 +1,116 @@
+//! 表示灯 (indicator lamp) の状態
+//!
+//! `DisplayPayload::Indicator` で `DisplayTarget::Indicator` に送る。
+//! 点滅の位相はデバイス側の時計で決める。
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub enum LampMode {
+    Off,
+    On,
+    /// `period_ms` 周期で点滅する (点灯と消灯は半分ずつ)。0 の場合は点灯のまま
+    Blink {
+        period_ms: u16,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub struct IndicatorState {
+    pub mode: LampMode,
+    /// 点灯時の明るさ。`u8::MAX` が最大
+    pub brightness: u8,
+}
+
+impl IndicatorState {
+    pub const OFF: Self = Self {
+        mode: LampMode::Off,
+        brightness: 0,
+    };
+
+    pub const fn on(brightness: u8) -> Self {
+        Self {
+            mode: LampMode::On,
+            brightness,
+        }
+    }
+
+    pub const fn blink(period_ms: u16, brightness: u8) -> Self {
+        Self {
+            mode: LampMode::Blink { period_ms },
+            brightness,
+        }
+    }
+
+    /// `now_ms` 時点で点灯しているか
+    pub fn is_lit(&self, now_ms: u32) -> bool {
+        match self.mode {
+            LampMode::Off => false,
+            LampMode::On | LampMode::Blink { period_ms: 0 } => true,
+            LampMode::Blink { period_ms } => {
+                let period = u32::from(period_ms);
+                now_ms % period < period.div_ceil(2)
+            }
+        }
+    }
+
+    /// `now_ms` 時点での出力値 (PWM の duty など)。消灯中は 0
+    pub fn level(&self, now_ms: u32) -> u8 {
+        if self.is_lit(now_ms) {
+            self.brightness
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        DisplayData, DisplayPayload, DisplayTarget, decode_data_packet, encode_data_packet,
+    };
+
+    #[test]
+    fn indicator_roundtrip_works() {
+        for state in [
+            IndicatorState::OFF,
+            IndicatorState::on(200),
+            IndicatorState::blink(500, u8::MAX),
+        ] {
+            let packet = DisplayData {
+                seq: 7,
+                target: DisplayTarget::Indicator(0x0102),
+                payload: DisplayPayload::Indicator(state),
+            };
+
+            let encoded = encode_data_packet(&packet).unwrap();
+            assert_eq!(decode_data_packet(&encoded).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn blink_alternates_every_half_period() {
+        let state = IndicatorState::blink(500, 128);
+
+        assert_eq!(state.level(0), 128);
+        assert_eq!(state.level(249), 128);
+        assert_eq!(state.level(250), 0);
+        assert_eq!(state.level(499), 0);
+        assert_eq!(state.level(500), 128);
+    }
+
+    #[test]
+    fn zero_period_blink_stays_lit() {
+        let state = IndicatorState::blink(0, 10);
+
+        assert!(state.is_lit(0));
+        assert!(state.is_lit(12345));
+        assert!(!IndicatorState::OFF.is_lit(0));
+    }
+}
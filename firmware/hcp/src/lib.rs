@@ -1,9 +1,66 @@
 #![cfg_attr(not(test), no_std)]
 
-use heapless::{String, Vec};
+use core::fmt;
+
+// ts-rs の derive は `String` を std の型として展開するため、heapless の `String` は修飾して書く
+use heapless::Vec;
 use serde::{Deserialize, Serialize};
 
-pub const APP_PROTOCOL_VERSION: u8 = 1;
+pub mod batch;
+pub mod bitmap;
+pub mod calibration;
+pub mod config;
+pub mod descriptor;
+pub mod display;
+pub mod indicator;
+pub mod log;
+pub mod pwm;
+pub mod segment;
+pub mod snapshot;
+pub mod time;
+pub mod update;
+pub mod version;
+
+pub use batch::{BatchedControl, ControlEventBatch, MAX_BATCHED_EVENTS};
+pub use bitmap::{
+    BitmapCompression, BitmapError, BitmapUpdate, MAX_BITMAP_DATA_LEN, MonoFramebuffer,
+    apply_bitmap_update, changed_region, encode_bitmap_bands, encode_bitmap_update,
+};
+pub use calibration::{
+    ABSOLUTE_MAX, AxisCalibration, AxisCalibrationStatus, CalibrationCommand, CalibrationError,
+};
+pub use config::{ConfigKey, ConfigResponse, ConfigStatus, ConfigValue};
+pub use descriptor::{
+    ControlDescriptor, ControlKind, DESCRIPTORS_PER_PAGE, DescriptorAssembler, DescriptorEntry,
+    DescriptorError, DescriptorPage, DisplayDescriptor, DisplayKind, MAX_LABEL_LEN,
+    SupportedEvents,
+};
+pub use display::{
+    DisplayCommand, DisplayCommands, FontId, MAX_COMMAND_TEXT_LEN, MAX_DISPLAY_COMMANDS, Point,
+    Region, TextAttributes,
+};
+pub use indicator::{IndicatorState, LampMode};
+pub use log::{LogEntry, LogLevel, MAX_LOG_TAG_LEN, MAX_LOG_TEXT_LEN};
+pub use pwm::PwmLevel;
+pub use segment::{
+    MAX_SEGMENT_DIGITS, SegmentDigit, SegmentKind, SegmentText, fourteen_segment, seven_segment,
+    sixteen_segment,
+};
+pub use snapshot::{
+    ControlStateSnapshot, ControlValueState, MAX_SNAPSHOT_SWITCHES, MAX_SNAPSHOT_VALUES,
+    SnapshotError,
+};
+pub use time::{ClockOffset, TimeSync, TimestampedControlEvents};
+pub use update::{
+    ImageHash, MAX_UPDATE_CHUNK_LEN, UpdateCommand, UpdateError, UpdatePhase, UpdateSender,
+    UpdateSenderError, UpdateStatus,
+};
+pub use version::{MIN_APP_PROTOCOL_VERSION, ProtocolVersions};
+
+/// 対応する最も新しいバージョン
+///
+/// v2 はまだ固定していないので、リリースまでは `AppPacketKind` の末尾に variant を足しても 2 のままにする
+pub const APP_PROTOCOL_VERSION: u8 = 2;
 pub const MAX_PAYLOAD_SIZE: usize = 128;
 pub const MAX_TEXT_LEN: usize = MAX_PAYLOAD_SIZE;
 pub const MAX_BINARY_LEN: usize = MAX_PAYLOAD_SIZE;
@@ -16,10 +73,40 @@ pub enum AppPacketError {
     Serialize,
     Deserialize,
     UnsupportedVersion(u8),
+    /// 指定したバージョンにはない packet
+    NotAvailableInVersion(u8),
     InvalidDataPacketKind,
     InvalidSetPacketKind,
 }
 
+impl fmt::Display for AppPacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppPacketError::BufferTooSmall => {
+                write!(f, "packet does not fit in {MAX_PAYLOAD_SIZE} bytes")
+            }
+            AppPacketError::Serialize => write!(f, "failed to serialize packet"),
+            AppPacketError::Deserialize => write!(f, "failed to deserialize packet"),
+            AppPacketError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported protocol version {version} \
+                 (supported {MIN_APP_PROTOCOL_VERSION}-{APP_PROTOCOL_VERSION})"
+            ),
+            AppPacketError::NotAvailableInVersion(version) => {
+                write!(f, "packet is not available in protocol version {version}")
+            }
+            AppPacketError::InvalidDataPacketKind => {
+                write!(f, "packet kind is not allowed in a Data frame")
+            }
+            AppPacketError::InvalidSetPacketKind => {
+                write!(f, "packet kind is not allowed in a Set frame")
+            }
+        }
+    }
+}
+
+impl core::error::Error for AppPacketError {}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct AppPacket {
@@ -27,16 +114,74 @@ pub struct AppPacket {
     pub kind: AppPacketKind,
 }
 
+// no_std でヒープを使わないため、描画コマンド列はそのまま持つ
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum AppPacketKind {
     DisplayData(DisplayData),
     DeviceHello(DeviceHello),
     ControlEvent(ControlEvent),
+    /// ホストからデバイスへの descriptor の要求
+    RequestDescriptor {
+        page: u16,
+    },
+    DescriptorPage(DescriptorPage),
+    /// ホストからデバイスへの設定値の問い合わせ
+    GetConfig {
+        key: ConfigKey,
+    },
+    /// ホストからデバイスへの設定変更
+    SetConfig {
+        key: ConfigKey,
+        value: ConfigValue,
+    },
+    /// `GetConfig` / `SetConfig` への応答
+    ConfigResponse(ConfigResponse),
+    /// ホストの対応バージョン。v2 以降の `DeviceHello` への応答
+    HostHello(ProtocolVersions),
+    /// 連番の seq を持つ複数の操作イベント
+    ControlEventBatch(ControlEventBatch),
+    /// ホストからデバイスへの、全ての操作系の現在の状態の要求
+    RequestControlState,
+    /// `RequestControlState` への応答
+    ControlStateSnapshot(ControlStateSnapshot),
+    /// ホストからデバイスへのファームウェア更新のコマンド
+    FirmwareUpdate(UpdateCommand),
+    /// `FirmwareUpdate` への応答
+    FirmwareUpdateStatus(UpdateStatus),
+    /// デバイスから master へのログ
+    Log(LogEntry),
+    /// ホストの時計。`Data` frame で broadcast する
+    TimeSync(TimeSync),
+    /// ホストの時計で検出した時刻を付けた操作イベント
+    TimestampedControlEvents(TimestampedControlEvents),
+    /// ホストからデバイスへの、LED や表示を点滅させて場所を知らせる要求。0 秒で止める
+    Identify {
+        seconds: u8,
+    },
+    /// ホストからデバイスへの、アナログ軸のキャリブレーションの指示
+    CalibrateAxis {
+        control_id: u16,
+        command: CalibrationCommand,
+    },
+    /// `CalibrateAxis` への応答と、記録中の範囲
+    AxisCalibrationStatus(AxisCalibrationStatus),
+}
+
+impl AppPacketKind {
+    /// ACK のない `Data` frame で送る packet か
+    pub fn is_data_kind(&self) -> bool {
+        matches!(
+            self,
+            AppPacketKind::DisplayData(_) | AppPacketKind::TimeSync(_)
+        )
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
 pub struct DisplayData {
     pub seq: u16,
     pub target: DisplayTarget,
@@ -51,51 +196,87 @@ impl DisplayData {
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
 pub enum DisplayTarget {
     Screen(u8),
     Indicator(u16),
+    /// バックライトなど、まとめて明るさを変える照明の系統
+    Backlight(u8),
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
 pub enum DisplayPayload {
     Text {
         format: TextFormat,
-        content: String<MAX_TEXT_LEN>,
+        #[cfg_attr(test, ts(type = "string"))]
+        content: heapless::String<MAX_TEXT_LEN>,
     },
     Bytes {
         encoding: ByteEncoding,
+        #[cfg_attr(test, ts(type = "Array<number>"))]
         data: Vec<u8, MAX_BINARY_LEN>,
     },
+    /// 描画コマンド列。`DisplayTarget::Screen` 向け
+    Commands(#[cfg_attr(test, ts(as = "std::vec::Vec<DisplayCommand>"))] DisplayCommands),
+    /// 1bpp ビットマップの部分更新。`DisplayTarget::Screen` 向け
+    Bitmap(BitmapUpdate),
+    /// 表示灯の状態。`DisplayTarget::Indicator` 向け
+    Indicator(IndicatorState),
+    /// segment 表示器の文字列。`DisplayTarget::Screen` 向け
+    Segments(SegmentText),
+    /// PWM の明るさ。`DisplayTarget::Backlight` と `DisplayTarget::Indicator` 向け
+    Pwm(PwmLevel),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
 pub enum TextFormat {
     Plain,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
 pub enum ByteEncoding {
     MonoBitmap1bpp,
+    /// 桁ごとの点灯パターン (u16 LE)。bit 配置は `segment` モジュールと同じ
+    ///
+    /// 小数点を送れないため、新しいコードは `DisplayPayload::Segments` を使う
     SegmentMap,
     Utf8Text,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
 pub struct DeviceHello {
     pub device_id: u64,
     pub device_kind: DeviceKind,
+    /// 対応する最も新しいバージョン
     pub protocol_version: u8,
+    /// 対応する最も古いバージョン。v1 の layout では送られず 1 になる
+    pub min_protocol_version: u8,
     pub firmware_version: Version,
     pub capabilities: Capabilities,
 }
 
+impl DeviceHello {
+    pub fn protocol_versions(&self) -> ProtocolVersions {
+        ProtocolVersions {
+            min: self.min_protocol_version,
+            max: self.protocol_version,
+        }
+    }
+}
+
 #[repr(u16)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
 pub enum DeviceKind {
     UpperPanelDdi = 0,
     ButtonPanel = 1,
@@ -105,6 +286,7 @@ pub enum DeviceKind {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
 pub struct Version {
     pub major: u8,
     pub minor: u8,
@@ -113,14 +295,32 @@ pub struct Version {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
 pub struct Capabilities {
     pub displays: u8,
     pub controls: u16,
     pub features: u32,
 }
 
+// `Capabilities::features` の bit
+/// `ControlEvent` を送る
+pub const FEATURE_CONTROL_EVENTS: u32 = 1 << 0;
+/// `RequestDescriptor` に応答できる
+pub const FEATURE_DESCRIPTORS: u32 = 1 << 1;
+/// `RequestControlState` に応答できる
+pub const FEATURE_CONTROL_STATE: u32 = 1 << 2;
+/// `FirmwareUpdate` で更新できる
+pub const FEATURE_FIRMWARE_UPDATE: u32 = 1 << 3;
+/// `Identify` で点滅できる
+pub const FEATURE_IDENTIFY: u32 = 1 << 4;
+/// `DisplayPayload::Pwm` で明るさを変えられる
+pub const FEATURE_PWM_OUTPUTS: u32 = 1 << 5;
+/// `CalibrateAxis` でアナログ軸の範囲を記録できる
+pub const FEATURE_AXIS_CALIBRATION: u32 = 1 << 6;
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
 pub struct ControlEvent {
     pub seq: u16,
     pub control_id: u16,
@@ -129,6 +329,7 @@ pub struct ControlEvent {
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
 pub enum ControlValue {
     Button { pressed: bool },
     EncoderDelta { steps: i8 },
@@ -140,31 +341,45 @@ pub enum ControlValue {
 pub fn encode_data_packet(
     display: &DisplayData,
 ) -> Result<Vec<u8, MAX_PAYLOAD_SIZE>, AppPacketError> {
-    encode_packet(&AppPacket {
-        version: APP_PROTOCOL_VERSION,
-        kind: AppPacketKind::DisplayData(display.clone()),
-    })
+    encode_data_packet_with_version(APP_PROTOCOL_VERSION, display)
+}
+
+/// 交渉済みのバージョンの layout で encode する
+pub fn encode_data_packet_with_version(
+    version: u8,
+    display: &DisplayData,
+) -> Result<Vec<u8, MAX_PAYLOAD_SIZE>, AppPacketError> {
+    version::encode_versioned(version, &AppPacketKind::DisplayData(display.clone()))
 }
 
 pub fn encode_set_packet(
     kind: &AppPacketKind,
 ) -> Result<Vec<u8, MAX_PAYLOAD_SIZE>, AppPacketError> {
-    if matches!(kind, AppPacketKind::DisplayData(_)) {
+    encode_set_packet_with_version(APP_PROTOCOL_VERSION, kind)
+}
+
+/// 交渉済みのバージョンの layout で encode する
+pub fn encode_set_packet_with_version(
+    version: u8,
+    kind: &AppPacketKind,
+) -> Result<Vec<u8, MAX_PAYLOAD_SIZE>, AppPacketError> {
+    if kind.is_data_kind() {
         return Err(AppPacketError::InvalidSetPacketKind);
     }
 
-    encode_packet(&AppPacket {
-        version: APP_PROTOCOL_VERSION,
-        kind: kind.clone(),
-    })
+    version::encode_versioned(version, kind)
 }
 
+/// `Data` frame で broadcast する `TimeSync` を encode する
+pub fn encode_time_sync_packet(
+    sync: &TimeSync,
+) -> Result<Vec<u8, MAX_PAYLOAD_SIZE>, AppPacketError> {
+    version::encode_versioned(APP_PROTOCOL_VERSION, &AppPacketKind::TimeSync(*sync))
+}
+
+/// 対応範囲内のどのバージョンの packet も decode する。`version` は受信した値のまま
 pub fn decode_app_packet(bytes: &[u8]) -> Result<AppPacket, AppPacketError> {
-    let packet: AppPacket = postcard::from_bytes(bytes).map_err(map_postcard_decode_error)?;
-    if packet.version != APP_PROTOCOL_VERSION {
-        return Err(AppPacketError::UnsupportedVersion(packet.version));
-    }
-    Ok(packet)
+    version::decode_versioned(bytes)
 }
 
 pub fn decode_data_packet(bytes: &[u8]) -> Result<DisplayData, AppPacketError> {
@@ -174,15 +389,24 @@ pub fn decode_data_packet(bytes: &[u8]) -> Result<DisplayData, AppPacketError> {
     }
 }
 
+pub fn decode_time_sync_packet(bytes: &[u8]) -> Result<TimeSync, AppPacketError> {
+    match decode_app_packet(bytes)?.kind {
+        AppPacketKind::TimeSync(sync) => Ok(sync),
+        _ => Err(AppPacketError::InvalidDataPacketKind),
+    }
+}
+
 pub fn decode_set_packet(bytes: &[u8]) -> Result<AppPacketKind, AppPacketError> {
     let packet = decode_app_packet(bytes)?;
-    if matches!(packet.kind, AppPacketKind::DisplayData(_)) {
+    if packet.kind.is_data_kind() {
         return Err(AppPacketError::InvalidSetPacketKind);
     }
     Ok(packet.kind)
 }
 
-fn encode_packet(packet: &AppPacket) -> Result<Vec<u8, MAX_PAYLOAD_SIZE>, AppPacketError> {
+pub(crate) fn encode_packet<T: Serialize>(
+    packet: &T,
+) -> Result<Vec<u8, MAX_PAYLOAD_SIZE>, AppPacketError> {
     let mut buffer = [0u8; MAX_PAYLOAD_SIZE];
     let encoded = postcard::to_slice(packet, &mut buffer).map_err(map_postcard_encode_error)?;
     Vec::from_slice(encoded).map_err(|_| AppPacketError::BufferTooSmall)
@@ -195,7 +419,7 @@ fn map_postcard_encode_error(error: postcard::Error) -> AppPacketError {
     }
 }
 
-fn map_postcard_decode_error(_error: postcard::Error) -> AppPacketError {
+pub(crate) fn map_postcard_decode_error(_error: postcard::Error) -> AppPacketError {
     AppPacketError::Deserialize
 }
 
@@ -204,6 +428,51 @@ fn map_postcard_decode_error(_error: postcard::Error) -> AppPacketError {
 mod tests {
     use super::*;
 
+    use imcp::capture::WIRESHARK_DISSECTOR;
+    use std::{format, string::String as StdString, vec::Vec as StdVec};
+
+    /// dissector の `local NAME = { [index] = "Variant", ... }` を読み出す
+    fn dissector_name_table(name: &str) -> Option<StdVec<(u8, StdString)>> {
+        let start = WIRESHARK_DISSECTOR.find(&format!("local {name} = {{\n"))?;
+        WIRESHARK_DISSECTOR[start..]
+            .lines()
+            .skip(1)
+            .take_while(|line| *line != "}")
+            .map(|line| {
+                let (index, variant) = line.trim().split_once("] = ")?;
+                let index = index.trim_start_matches('[');
+                let index = match index.strip_prefix("0x") {
+                    Some(hex) => u8::from_str_radix(hex, 16).ok()?,
+                    None => index.parse().ok()?,
+                };
+                Some((
+                    index,
+                    variant.trim_end_matches(',').trim_matches('"').into(),
+                ))
+            })
+            .collect()
+    }
+
+    /// 全 variant の postcard の index と variant 名
+    ///
+    /// index を 0 から順に、中身を 0 で埋めて decode できなくなるまで列挙する。
+    /// variant を足すと期待値にも自動で入るので、dissector の追加漏れはテストで落ちる
+    fn all_variants<T: serde::de::DeserializeOwned + core::fmt::Debug>() -> StdVec<(u8, StdString)>
+    {
+        (0..=u8::MAX)
+            .map_while(|index| {
+                let mut bytes = [0u8; MAX_PAYLOAD_SIZE];
+                bytes[0] = index;
+                let value: T = postcard::from_bytes(&bytes).ok()?;
+                let name = format!("{value:?}")
+                    .chars()
+                    .take_while(char::is_ascii_alphanumeric)
+                    .collect();
+                Some((index, name))
+            })
+            .collect()
+    }
+
     #[test]
     fn display_data_roundtrip_works() {
         let payload = DisplayData {
@@ -227,6 +496,7 @@ mod tests {
             device_id: 0x0123_4567_89AB_CDEF,
             device_kind: DeviceKind::UpperPanelDdi,
             protocol_version: APP_PROTOCOL_VERSION,
+            min_protocol_version: MIN_APP_PROTOCOL_VERSION,
             firmware_version: Version {
                 major: 0,
                 minor: 1,
@@ -251,6 +521,7 @@ mod tests {
             device_id: 0x0BAD_F00D_CAFE_BEEF,
             device_kind: DeviceKind::ImcpHub,
             protocol_version: APP_PROTOCOL_VERSION,
+            min_protocol_version: MIN_APP_PROTOCOL_VERSION,
             firmware_version: Version {
                 major: 1,
                 minor: 2,
@@ -352,6 +623,16 @@ mod tests {
         assert_eq!(result, Err(AppPacketError::InvalidSetPacketKind));
     }
 
+    #[test]
+    fn packet_error_display_includes_version() {
+        let error = AppPacketError::UnsupportedVersion(APP_PROTOCOL_VERSION.saturating_add(1));
+
+        assert_eq!(
+            error.to_string(),
+            "unsupported protocol version 3 (supported 1-2)"
+        );
+    }
+
     #[test]
     fn display_sequence_can_reject_older_packets() {
         let newer = DisplayData {
@@ -373,4 +654,98 @@ mod tests {
             .supersedes(10)
         );
     }
+
+    #[test]
+    fn wireshark_dissector_matches_enum_layout() {
+        let tables = [
+            ("APP_PACKET_KINDS", all_variants::<AppPacketKind>()),
+            ("CONFIG_KEYS", all_variants::<ConfigKey>()),
+            ("CONFIG_VALUES", all_variants::<ConfigValue>()),
+            ("CONFIG_STATUSES", all_variants::<ConfigStatus>()),
+            ("DESCRIPTOR_ENTRIES", all_variants::<DescriptorEntry>()),
+            ("CONTROL_KINDS", all_variants::<ControlKind>()),
+            ("DISPLAY_KINDS", all_variants::<DisplayKind>()),
+            ("DISPLAY_TARGETS", all_variants::<DisplayTarget>()),
+            ("DISPLAY_PAYLOADS", all_variants::<DisplayPayload>()),
+            ("LAMP_MODES", all_variants::<LampMode>()),
+            ("SEGMENT_KINDS", all_variants::<SegmentKind>()),
+            ("BITMAP_COMPRESSIONS", all_variants::<BitmapCompression>()),
+            ("DISPLAY_COMMANDS", all_variants::<DisplayCommand>()),
+            ("TEXT_FORMATS", all_variants::<TextFormat>()),
+            ("BYTE_ENCODINGS", all_variants::<ByteEncoding>()),
+            ("DEVICE_KINDS", all_variants::<DeviceKind>()),
+            ("CONTROL_VALUES", all_variants::<ControlValue>()),
+            ("UPDATE_COMMANDS", all_variants::<UpdateCommand>()),
+            ("UPDATE_PHASES", all_variants::<UpdatePhase>()),
+            ("UPDATE_ERRORS", all_variants::<UpdateError>()),
+            ("LOG_LEVELS", all_variants::<LogLevel>()),
+            ("CALIBRATION_COMMANDS", all_variants::<CalibrationCommand>()),
+            ("CALIBRATION_ERRORS", all_variants::<CalibrationError>()),
+        ];
+
+        for (name, expected) in tables {
+            assert!(!expected.is_empty(), "{name}");
+            assert_eq!(dissector_name_table(name), Some(expected), "{name}");
+        }
+    }
+
+    /// 型の宣言を依存をたどって集める。依存をたどる順番は build ごとに変わるので名前順に並べる
+    #[derive(Default)]
+    struct TypescriptDeclarations {
+        declarations: std::collections::BTreeMap<StdString, StdString>,
+    }
+
+    impl ts_rs::TypeVisitor for TypescriptDeclarations {
+        fn visit<T: ts_rs::TS + 'static + ?Sized>(&mut self) {
+            // `Option` や `Vec` は宣言を持たないので、中の型だけたどる
+            if T::output_path().is_none() {
+                T::visit_generics(self);
+                return;
+            }
+            if !self.declarations.contains_key(&T::ident()) {
+                self.declarations.insert(T::ident(), T::decl());
+                T::visit_dependencies(self);
+            }
+        }
+    }
+
+    /// manager UI が使う型。`manager/src/lib/generated/hcp.ts` と一致させる
+    fn typescript_declarations() -> StdString {
+        use ts_rs::TypeVisitor;
+
+        let mut declarations = TypescriptDeclarations::default();
+        declarations.visit::<DisplayData>();
+        declarations.visit::<DeviceHello>();
+        declarations.visit::<ControlEvent>();
+        declarations.visit::<ControlDescriptor>();
+        declarations.visit::<DisplayDescriptor>();
+        declarations.visit::<LogEntry>();
+        declarations.visit::<CalibrationCommand>();
+        declarations.visit::<CalibrationError>();
+        declarations.visit::<AxisCalibrationStatus>();
+
+        let mut output = StdString::from(
+            "// hcp の型から生成したファイル。編集せず、`typescript_bindings_match_manager_ui` が失敗したときに表示する内容で置き換える\n",
+        );
+        for declaration in declarations.declarations.values() {
+            output.push_str(&format!("\nexport {declaration}\n"));
+        }
+        output
+    }
+
+    #[test]
+    fn typescript_bindings_match_manager_ui() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../../manager/src/lib/generated/hcp.ts");
+        let expected = typescript_declarations();
+        let actual = std::fs::read_to_string(&path).unwrap_or_default();
+        if actual != expected {
+            println!("{expected}");
+        }
+        assert!(
+            actual == expected,
+            "{} is out of date. Replace it with the declarations printed above",
+            path.display()
+        );
+    }
 }
@@ -0,0 +1,114 @@
+//! デバイスから master へ送るログ
+//!
+//! defmt RTT は debug probe が必要なため、組み立て後のパネルでは HCP の `Log` でログを送る。
+
+use core::fmt::{self, Write};
+
+use serde::{Deserialize, Serialize};
+
+/// モジュール名などの短いタグの最大長
+pub const MAX_LOG_TAG_LEN: usize = 16;
+/// 本文の最大長。超えた分は切り捨てる
+pub const MAX_LOG_TEXT_LEN: usize = 96;
+
+/// 重要なものほど小さい
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub struct LogEntry {
+    pub level: LogLevel,
+    /// このログの前に送れずに捨てたログの数
+    pub dropped: u16,
+    #[cfg_attr(test, ts(type = "string"))]
+    pub tag: heapless::String<MAX_LOG_TAG_LEN>,
+    #[cfg_attr(test, ts(type = "string"))]
+    pub text: heapless::String<MAX_LOG_TEXT_LEN>,
+}
+
+impl LogEntry {
+    /// 長すぎる `tag` / `text` は文字の境界で切り捨てる
+    pub fn new(level: LogLevel, tag: &str, text: &str) -> Self {
+        let mut entry = Self::empty(level, tag);
+        push_truncated(&mut entry.text, text);
+        entry
+    }
+
+    /// `format_args!` の結果を本文にする
+    pub fn from_args(level: LogLevel, tag: &str, args: fmt::Arguments<'_>) -> Self {
+        let mut entry = Self::empty(level, tag);
+        let _ = Truncating(&mut entry.text).write_fmt(args);
+        entry
+    }
+
+    fn empty(level: LogLevel, tag: &str) -> Self {
+        let mut entry = Self {
+            level,
+            dropped: 0,
+            tag: heapless::String::new(),
+            text: heapless::String::new(),
+        };
+        push_truncated(&mut entry.tag, tag);
+        entry
+    }
+}
+
+/// 入りきらない文字を捨てる writer
+struct Truncating<'a, const N: usize>(&'a mut heapless::String<N>);
+
+impl<const N: usize> Write for Truncating<'_, N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        push_truncated(self.0, s);
+        Ok(())
+    }
+}
+
+fn push_truncated<const N: usize>(target: &mut heapless::String<N>, text: &str) {
+    for c in text.chars() {
+        if target.push(c).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::{AppPacketKind, decode_set_packet, encode_set_packet};
+
+    #[test]
+    fn long_text_is_truncated_on_char_boundary() {
+        let entry = LogEntry::new(LogLevel::Warn, "matrix-scanner-task", &"あ".repeat(40));
+        assert_eq!(entry.tag.as_str(), "matrix-scanner-t");
+        assert_eq!(entry.text.chars().count(), MAX_LOG_TEXT_LEN / 3);
+
+        let entry = LogEntry::from_args(LogLevel::Info, "imcp", format_args!("addr={:#04x}", 2));
+        assert_eq!(entry.text.as_str(), "addr=0x02");
+        assert!(LogLevel::Error < LogLevel::Trace);
+    }
+
+    #[test]
+    fn full_entry_fits_in_payload() {
+        let mut entry = LogEntry::new(
+            LogLevel::Trace,
+            &"t".repeat(MAX_LOG_TAG_LEN),
+            &"x".repeat(MAX_LOG_TEXT_LEN),
+        );
+        entry.dropped = u16::MAX;
+        let packet = AppPacketKind::Log(entry);
+
+        let encoded = encode_set_packet(&packet).unwrap();
+        assert_eq!(decode_set_packet(&encoded).unwrap(), packet);
+    }
+}
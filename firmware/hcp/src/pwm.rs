@@ -0,0 +1,103 @@
+//! バックライトや表示灯の PWM 出力
+//!
+//! `DisplayPayload::Pwm` で、照明の系統は `DisplayTarget::Backlight`、個々の表示灯は
+//! `DisplayTarget::Indicator` に送る。
+//! 明るさはデバイスが今出している値から `fade_ms` かけて変える。
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub struct PwmLevel {
+    /// `u8::MAX` が最大
+    pub brightness: u8,
+    /// 今の明るさから `brightness` まで変えるのにかける時間。0 ならすぐに変える
+    pub fade_ms: u16,
+}
+
+impl PwmLevel {
+    pub const OFF: Self = Self::set(0);
+
+    pub const fn set(brightness: u8) -> Self {
+        Self::fade(brightness, 0)
+    }
+
+    pub const fn fade(brightness: u8, fade_ms: u16) -> Self {
+        Self {
+            brightness,
+            fade_ms,
+        }
+    }
+
+    /// `0..=max_value` の値 (DCS-BIOS のつまみの位置など) を明るさに換算する
+    pub fn from_ratio(value: u16, max_value: u16, fade_ms: u16) -> Self {
+        let brightness = if max_value == 0 {
+            0
+        } else {
+            let scaled =
+                u32::from(value.min(max_value)) * u32::from(u8::MAX) + u32::from(max_value) / 2;
+            u8::try_from(scaled / u32::from(max_value)).unwrap_or(u8::MAX)
+        };
+        Self::fade(brightness, fade_ms)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        AppPacketError, AppPacketKind, DisplayData, DisplayPayload, DisplayTarget,
+        decode_data_packet, encode_data_packet, encode_data_packet_with_version,
+    };
+
+    #[test]
+    fn pwm_roundtrip_works() {
+        for target in [
+            DisplayTarget::Backlight(3),
+            DisplayTarget::Indicator(0x0102),
+        ] {
+            let packet = DisplayData {
+                seq: 7,
+                target,
+                payload: DisplayPayload::Pwm(PwmLevel::fade(128, 500)),
+            };
+
+            let encoded = encode_data_packet(&packet).unwrap();
+            assert_eq!(decode_data_packet(&encoded).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn backlight_is_not_available_in_v1() {
+        let packet = DisplayData {
+            seq: 0,
+            target: DisplayTarget::Backlight(0),
+            payload: DisplayPayload::Bytes {
+                encoding: crate::ByteEncoding::Utf8Text,
+                data: heapless::Vec::new(),
+            },
+        };
+
+        assert!(!AppPacketKind::DisplayData(packet.clone()).is_available_in(1));
+        assert_eq!(
+            encode_data_packet_with_version(1, &packet),
+            Err(AppPacketError::NotAvailableInVersion(1))
+        );
+    }
+
+    #[test]
+    fn ratio_is_rounded_to_brightness() {
+        assert_eq!(PwmLevel::from_ratio(0, 65535, 0), PwmLevel::OFF);
+        assert_eq!(
+            PwmLevel::from_ratio(65535, 65535, 0),
+            PwmLevel::set(u8::MAX)
+        );
+        assert_eq!(PwmLevel::from_ratio(32768, 65535, 0).brightness, 128);
+        assert_eq!(PwmLevel::from_ratio(2, 4, 200), PwmLevel::fade(128, 200));
+        // 範囲外と 0 除算
+        assert_eq!(PwmLevel::from_ratio(9, 4, 0).brightness, u8::MAX);
+        assert_eq!(PwmLevel::from_ratio(1, 0, 0), PwmLevel::OFF);
+    }
+}
@@ -0,0 +1,375 @@
+//! 7 / 14 / 16 segment 表示器
+//!
+//! `DisplayPayload::Segments` では桁ごとに ASCII 文字と小数点を送り、
+//! 点灯パターンへの変換はこのモジュールの表で行う。
+//! ホストのプレビューとファームウェアが同じ表を使うことで表示を揃える。
+//!
+//! bit 配置 (bit 0 から順に):
+//!
+//! - 7 segment: `a b c d e f g`
+//! - 14 segment: `a b c d e f g1 g2 h j k l m n`
+//! - 16 segment: `a1 a2 b c d1 d2 e f g1 g2 h j k l m n`
+//!
+//! `h j k` は上段の左斜め・中央縦・右斜め、`l m n` は下段の左斜め・中央縦・右斜め。
+//! 小数点は bit に含めず `SegmentDigit::decimal_point` で持つ。
+//! 表示できない文字は空白になる。
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+/// 1 packet で送れる桁数の上限
+pub const MAX_SEGMENT_DIGITS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub enum SegmentKind {
+    Seven,
+    Fourteen,
+    Sixteen,
+}
+
+impl SegmentKind {
+    /// 文字の点灯パターン
+    pub fn mask(self, character: u8) -> u16 {
+        match self {
+            SegmentKind::Seven => u16::from(seven_segment(character)),
+            SegmentKind::Fourteen => fourteen_segment(character),
+            SegmentKind::Sixteen => sixteen_segment(character),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub struct SegmentDigit {
+    /// ASCII 文字
+    pub character: u8,
+    pub decimal_point: bool,
+}
+
+impl SegmentDigit {
+    pub fn mask(&self, kind: SegmentKind) -> u16 {
+        kind.mask(self.character)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(test, derive(ts_rs::TS))]
+pub struct SegmentText {
+    pub kind: SegmentKind,
+    /// 左の桁から順に並べる
+    #[cfg_attr(test, ts(as = "std::vec::Vec<SegmentDigit>"))]
+    pub digits: Vec<SegmentDigit, MAX_SEGMENT_DIGITS>,
+}
+
+impl SegmentText {
+    /// 文字列を桁に分ける
+    ///
+    /// `.` は直前の桁の小数点として扱う (DCS-BIOS の UFC スクラッチパッドなど)。
+    /// 直前の桁がない、または小数点が既にある場合は空白の桁に小数点を付ける。
+    /// ASCII 以外の文字がある場合と、桁数が多すぎる場合は `None` を返す
+    pub fn from_ascii(kind: SegmentKind, text: &str) -> Option<Self> {
+        let mut digits: Vec<SegmentDigit, MAX_SEGMENT_DIGITS> = Vec::new();
+
+        for character in text.chars() {
+            if !character.is_ascii() {
+                return None;
+            }
+            if character == '.' {
+                if let Some(last) = digits.last_mut()
+                    && !last.decimal_point
+                {
+                    last.decimal_point = true;
+                    continue;
+                }
+                digits
+                    .push(SegmentDigit {
+                        character: b' ',
+                        decimal_point: true,
+                    })
+                    .ok()?;
+                continue;
+            }
+
+            digits
+                .push(SegmentDigit {
+                    character: u8::try_from(character).ok()?,
+                    decimal_point: false,
+                })
+                .ok()?;
+        }
+
+        Some(Self { kind, digits })
+    }
+
+    /// 桁ごとの点灯パターンと小数点
+    pub fn masks(&self) -> impl Iterator<Item = (u16, bool)> + '_ {
+        self.digits
+            .iter()
+            .map(|digit| (digit.mask(self.kind), digit.decimal_point))
+    }
+}
+
+mod seven {
+    pub const A: u8 = 1 << 0;
+    pub const B: u8 = 1 << 1;
+    pub const C: u8 = 1 << 2;
+    pub const D: u8 = 1 << 3;
+    pub const E: u8 = 1 << 4;
+    pub const F: u8 = 1 << 5;
+    pub const G: u8 = 1 << 6;
+}
+
+mod fourteen {
+    pub const A: u16 = 1 << 0;
+    pub const B: u16 = 1 << 1;
+    pub const C: u16 = 1 << 2;
+    pub const D: u16 = 1 << 3;
+    pub const E: u16 = 1 << 4;
+    pub const F: u16 = 1 << 5;
+    pub const G1: u16 = 1 << 6;
+    pub const G2: u16 = 1 << 7;
+    pub const H: u16 = 1 << 8;
+    pub const J: u16 = 1 << 9;
+    pub const K: u16 = 1 << 10;
+    pub const L: u16 = 1 << 11;
+    pub const M: u16 = 1 << 12;
+    pub const N: u16 = 1 << 13;
+}
+
+/// 7 segment の点灯パターン。英字は大文字と小文字を区別しない
+pub fn seven_segment(character: u8) -> u8 {
+    use seven::*;
+
+    match character.to_ascii_uppercase() {
+        b'0' | b'O' => A | B | C | D | E | F,
+        b'1' => B | C,
+        b'2' => A | B | D | E | G,
+        b'3' => A | B | C | D | G,
+        b'4' => B | C | F | G,
+        b'5' | b'S' => A | C | D | F | G,
+        b'6' => A | C | D | E | F | G,
+        b'7' => A | B | C,
+        b'8' => A | B | C | D | E | F | G,
+        b'9' => A | B | C | D | F | G,
+        b'A' => A | B | C | E | F | G,
+        b'B' => C | D | E | F | G,
+        b'C' | b'[' | b'(' => A | D | E | F,
+        b'D' => B | C | D | E | G,
+        b'E' => A | D | E | F | G,
+        b'F' => A | E | F | G,
+        b'G' => A | C | D | E | F,
+        b'H' => B | C | E | F | G,
+        b'I' => E | F,
+        b'J' => B | C | D | E,
+        b'L' => D | E | F,
+        b'N' => C | E | G,
+        b'P' => A | B | E | F | G,
+        b'Q' => A | B | C | F | G,
+        b'R' => E | G,
+        b'T' => D | E | F | G,
+        b'U' => B | C | D | E | F,
+        b'Y' => B | C | D | F | G,
+        b']' | b')' => A | B | C | D,
+        b'-' => G,
+        b'_' => D,
+        b'=' => D | G,
+        b'\'' => B,
+        b'"' => B | F,
+        b'?' => A | B | E | G,
+        _ => 0,
+    }
+}
+
+/// 14 segment の点灯パターン。英字は大文字と小文字を区別しない
+pub fn fourteen_segment(character: u8) -> u16 {
+    use fourteen::*;
+
+    match character.to_ascii_uppercase() {
+        b'0' => A | B | C | D | E | F | K | L,
+        b'1' => B | C,
+        b'2' => A | B | D | E | G1 | G2,
+        b'3' => A | B | C | D | G2,
+        b'4' => B | C | F | G1 | G2,
+        b'5' | b'S' => A | C | D | F | G1 | G2,
+        b'6' => A | C | D | E | F | G1 | G2,
+        b'7' => A | B | C,
+        b'8' => A | B | C | D | E | F | G1 | G2,
+        b'9' => A | B | C | D | F | G1 | G2,
+        b'A' => A | B | C | E | F | G1 | G2,
+        b'B' => A | B | C | D | G2 | J | M,
+        b'C' => A | D | E | F,
+        b'D' => A | B | C | D | J | M,
+        b'E' => A | D | E | F | G1 | G2,
+        b'F' => A | E | F | G1,
+        b'G' => A | C | D | E | F | G2,
+        b'H' => B | C | E | F | G1 | G2,
+        b'I' => A | D | J | M,
+        b'J' => B | C | D | E,
+        b'K' => E | F | G1 | K | N,
+        b'L' => D | E | F,
+        b'M' => B | C | E | F | H | K,
+        b'N' => B | C | E | F | H | N,
+        b'O' => A | B | C | D | E | F,
+        b'P' => A | B | E | F | G1 | G2,
+        b'Q' => A | B | C | D | E | F | N,
+        b'R' => A | B | E | F | G1 | G2 | N,
+        b'T' => A | J | M,
+        b'U' => B | C | D | E | F,
+        b'V' => E | F | K | L,
+        b'W' => B | C | E | F | L | N,
+        b'X' => H | K | L | N,
+        b'Y' => H | K | M,
+        b'Z' => A | D | K | L,
+        b'-' => G1 | G2,
+        b'+' => G1 | G2 | J | M,
+        b'*' => G1 | G2 | H | J | K | L | M | N,
+        b'/' => K | L,
+        b'\\' => H | N,
+        b'<' | b'(' => K | N,
+        b'>' | b')' => H | L,
+        b'[' => A | D | E | F,
+        b']' => A | B | C | D,
+        b'=' => D | G1 | G2,
+        b'_' => D,
+        b'\'' => J,
+        b'"' => F | J,
+        b':' => J | M,
+        b',' => L,
+        b'?' => A | B | G2 | M,
+        b'#' => B | C | D | G1 | G2 | J | M,
+        _ => 0,
+    }
+}
+
+/// 16 segment の点灯パターン
+///
+/// 14 segment の `a` と `d` を左右に分けたもので、それ以外の形は同じ
+pub fn sixteen_segment(character: u8) -> u16 {
+    let mask = fourteen_segment(character);
+    // a -> a1 a2, b c -> 1 つずらす, d -> d1 d2, e 以降 -> 2 つずらす
+    let a = if mask & fourteen::A != 0 { 0b11 } else { 0 };
+    let bc = (mask & (fourteen::B | fourteen::C)) << 1;
+    let d = if mask & fourteen::D != 0 {
+        0b11 << 4
+    } else {
+        0
+    };
+    let rest = (mask & !(fourteen::A | fourteen::B | fourteen::C | fourteen::D)) << 2;
+    a | bc | d | rest
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        DisplayData, DisplayPayload, DisplayTarget, decode_data_packet, encode_data_packet,
+    };
+
+    #[test]
+    fn seven_segment_digits_match_common_font() {
+        let digits: [u8; 10] =
+            core::array::from_fn(|index| seven_segment(b'0' + u8::try_from(index).unwrap()));
+
+        assert_eq!(
+            digits,
+            [0x3F, 0x06, 0x5B, 0x4F, 0x66, 0x6D, 0x7D, 0x07, 0x7F, 0x6F]
+        );
+        assert_eq!(seven_segment(b'-'), 0x40);
+        assert_eq!(seven_segment(b'M'), 0);
+    }
+
+    #[test]
+    fn fourteen_segment_letters_are_case_insensitive() {
+        for upper in b'A'..=b'Z' {
+            assert_ne!(fourteen_segment(upper), 0, "{}", char::from(upper));
+            assert_eq!(
+                fourteen_segment(upper),
+                fourteen_segment(upper.to_ascii_lowercase())
+            );
+        }
+        assert_eq!(fourteen_segment(b'T'), 0b0001_0010_0000_0001);
+        assert_eq!(fourteen_segment(b' '), 0);
+        assert_eq!(fourteen_segment(0xB0), 0);
+    }
+
+    #[test]
+    fn sixteen_segment_splits_top_and_bottom_bars() {
+        assert_eq!(sixteen_segment(b'-'), 0b0000_0011_0000_0000);
+        assert_eq!(sixteen_segment(b'_'), 0b0000_0000_0011_0000);
+        assert_eq!(sixteen_segment(b'1'), 0b0000_0000_0000_1100);
+        assert_eq!(sixteen_segment(b'8'), 0b0000_0011_1111_1111);
+        assert_eq!(sixteen_segment(b'*'), 0b1111_1111_0000_0000);
+    }
+
+    #[test]
+    fn from_ascii_folds_decimal_points() {
+        let text = SegmentText::from_ascii(SegmentKind::Fourteen, ".1-2.5..").unwrap();
+        let digits: std::vec::Vec<_> = text
+            .digits
+            .iter()
+            .map(|digit| (char::from(digit.character), digit.decimal_point))
+            .collect();
+
+        assert_eq!(
+            digits,
+            [
+                (' ', true),
+                ('1', false),
+                ('-', false),
+                ('2', true),
+                ('5', true),
+                (' ', true),
+            ]
+        );
+    }
+
+    #[test]
+    fn from_ascii_renders_ufc_scratchpad() {
+        let text = SegmentText::from_ascii(SegmentKind::Seven, "1 25.0").unwrap();
+        let masks: std::vec::Vec<_> = text.masks().collect();
+
+        assert_eq!(
+            masks,
+            [
+                (0x06, false),
+                (0x00, false),
+                (0x5B, false),
+                (0x6D, true),
+                (0x3F, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn from_ascii_rejects_too_many_digits() {
+        assert!(SegmentText::from_ascii(SegmentKind::Seven, "0123456789ABCDEF.").is_some());
+        assert!(SegmentText::from_ascii(SegmentKind::Seven, "0123456789ABCDEFG").is_none());
+    }
+
+    #[test]
+    fn from_ascii_rejects_non_ascii() {
+        // Latin-1 の文字も u8 に収まるが、セグメントのフォントにはない
+        assert!(SegmentText::from_ascii(SegmentKind::Seven, "12\u{00B0}").is_none());
+        assert!(SegmentText::from_ascii(SegmentKind::Sixteen, "\u{00C9}T\u{00C9}").is_none());
+        assert!(SegmentText::from_ascii(SegmentKind::Sixteen, "ETE").is_some());
+    }
+
+    #[test]
+    fn segment_text_roundtrip_works() {
+        let packet = DisplayData {
+            seq: 3,
+            target: DisplayTarget::Screen(2),
+            payload: DisplayPayload::Segments(
+                SegmentText::from_ascii(SegmentKind::Sixteen, "ABCDEFGH.IJKLMNOP").unwrap(),
+            ),
+        };
+
+        let encoded = encode_data_packet(&packet).unwrap();
+        assert_eq!(decode_data_packet(&encoded).unwrap(), packet);
+    }
+}
@@ -0,0 +1,179 @@
+//! 操作系の現在の状態
+//!
+//! `ControlEvent` は変化しか送らないため、ホストが再起動したりイベントを取りこぼしたりすると
+//! トグルや軸の位置が分からなくなる。
+//! ホストは `RequestControlState` を送り、デバイスは `ControlStateSnapshot` で現在の状態を返す。
+
+use core::fmt;
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+/// 1 つの snapshot に入るボタン / トグルの最大数
+pub const MAX_SNAPSHOT_SWITCHES: usize = 128;
+/// 1 つの snapshot に入る軸 / エンコーダーの最大数
+pub const MAX_SNAPSHOT_VALUES: usize = 12;
+
+const SWITCH_BYTES: usize = MAX_SNAPSHOT_SWITCHES / 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SnapshotError {
+    TooManySwitches,
+    TooManyValues,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::TooManySwitches => write!(
+                f,
+                "snapshot cannot hold more than {MAX_SNAPSHOT_SWITCHES} switches"
+            ),
+            SnapshotError::TooManyValues => {
+                write!(
+                    f,
+                    "snapshot cannot hold more than {MAX_SNAPSHOT_VALUES} values"
+                )
+            }
+        }
+    }
+}
+
+impl core::error::Error for SnapshotError {}
+
+/// 軸の位置、またはエンコーダーの積算値
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ControlValueState {
+    pub control_id: u16,
+    pub value: i16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ControlStateSnapshot {
+    /// この snapshot の後にデバイスが送る最初のイベントの seq
+    pub next_seq: u16,
+    /// `switches` の先頭 bit の control ID。以降の bit は連番
+    pub first_switch_id: u16,
+    pub switch_count: u16,
+    /// ボタン / トグルの状態。LSB から順に 1 bit ずつで、1 なら押されている / on
+    pub switches: Vec<u8, SWITCH_BYTES>,
+    pub values: Vec<ControlValueState, MAX_SNAPSHOT_VALUES>,
+}
+
+impl ControlStateSnapshot {
+    pub const fn new(next_seq: u16, first_switch_id: u16) -> Self {
+        Self {
+            next_seq,
+            first_switch_id,
+            switch_count: 0,
+            switches: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    /// 次の control ID のボタン / トグルを追加する
+    pub fn push_switch(&mut self, on: bool) -> Result<(), SnapshotError> {
+        let index = usize::from(self.switch_count);
+        if index >= MAX_SNAPSHOT_SWITCHES {
+            return Err(SnapshotError::TooManySwitches);
+        }
+        if index % 8 == 0 {
+            self.switches
+                .push(0)
+                .map_err(|_| SnapshotError::TooManySwitches)?;
+        }
+        if on && let Some(byte) = self.switches.last_mut() {
+            *byte |= 1 << (index % 8);
+        }
+        self.switch_count += 1;
+        Ok(())
+    }
+
+    pub fn push_value(&mut self, control_id: u16, value: i16) -> Result<(), SnapshotError> {
+        self.values
+            .push(ControlValueState { control_id, value })
+            .map_err(|_| SnapshotError::TooManyValues)
+    }
+
+    /// ボタン / トグルの状態を control ID と一緒に返す
+    pub fn switch_states(&self) -> impl Iterator<Item = (u16, bool)> + '_ {
+        (0..self.switch_count).filter_map(|index| {
+            let byte = self.switches.get(usize::from(index / 8))?;
+            Some((
+                self.first_switch_id.wrapping_add(index),
+                byte & (1 << (index % 8)) != 0,
+            ))
+        })
+    }
+
+    /// 範囲外の control ID は `None`
+    pub fn switch_state(&self, control_id: u16) -> Option<bool> {
+        let index = control_id.checked_sub(self.first_switch_id)?;
+        self.switch_states()
+            .nth(usize::from(index))
+            .map(|(_, on)| on)
+    }
+
+    pub fn value(&self, control_id: u16) -> Option<i16> {
+        self.values
+            .iter()
+            .find(|state| state.control_id == control_id)
+            .map(|state| state.value)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::{AppPacketKind, decode_set_packet, encode_set_packet};
+
+    #[test]
+    fn switches_are_packed_lsb_first() {
+        let mut snapshot = ControlStateSnapshot::new(5, 10);
+        for on in [true, false, false, true, false, false, false, false, true] {
+            snapshot.push_switch(on).unwrap();
+        }
+
+        assert_eq!(snapshot.switches.as_slice(), &[0b0000_1001, 0b0000_0001]);
+        assert_eq!(snapshot.switch_state(10), Some(true));
+        assert_eq!(snapshot.switch_state(11), Some(false));
+        assert_eq!(snapshot.switch_state(18), Some(true));
+        assert_eq!(snapshot.switch_state(19), None);
+        assert_eq!(snapshot.switch_state(9), None);
+        assert_eq!(
+            snapshot
+                .switch_states()
+                .filter(|(_, on)| *on)
+                .map(|(id, _)| id)
+                .collect::<std::vec::Vec<_>>(),
+            [10, 13, 18]
+        );
+    }
+
+    #[test]
+    fn full_snapshot_fits_in_payload() {
+        let mut snapshot = ControlStateSnapshot::new(u16::MAX, u16::MAX - 200);
+        for index in 0..MAX_SNAPSHOT_SWITCHES {
+            snapshot.push_switch(index % 3 == 0).unwrap();
+        }
+        for index in 0..MAX_SNAPSHOT_VALUES {
+            snapshot
+                .push_value(u16::MAX - u16::try_from(index).unwrap(), i16::MIN)
+                .unwrap();
+        }
+        assert_eq!(
+            snapshot.push_switch(true),
+            Err(SnapshotError::TooManySwitches)
+        );
+        assert_eq!(snapshot.push_value(0, 0), Err(SnapshotError::TooManyValues));
+        assert_eq!(snapshot.value(u16::MAX), Some(i16::MIN));
+
+        let packet = AppPacketKind::ControlStateSnapshot(snapshot);
+        let encoded = encode_set_packet(&packet).unwrap();
+        assert_eq!(decode_set_packet(&encoded).unwrap(), packet);
+    }
+}
@@ -0,0 +1,151 @@
+//! ホストとデバイスの時計合わせ
+//!
+//! ホストは自分の時計を `TimeSync` で定期的に broadcast する。
+//! ACK が衝突しないよう、`Set` ではなく `FramePayload::Data` で送る。
+//! デバイスは受信時の自分の時計との差を覚えておき、操作を検出した時刻をホストの時計に換算して
+//! `TimestampedControlEvents` で送る。
+//! ホストは bus の再送に関係なく実際に操作した順序が分かり、操作から受信までの遅延も測れる。
+//!
+//! 差には `TimeSync` の送信時間が含まれないため、換算した時刻は実際より 1 frame 分ほど早くなる。
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ControlEvent, ControlEventBatch};
+
+/// ホストの時計 (µs)。起点はホストが決める
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TimeSync {
+    pub host_time_us: u64,
+}
+
+/// 検出した時刻を付けた操作イベント
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TimestampedControlEvents {
+    /// 検出した時刻をホストの時計に換算した値 (µs)
+    pub timestamp_us: u64,
+    pub batch: ControlEventBatch,
+}
+
+impl TimestampedControlEvents {
+    /// 個々の `ControlEvent` に展開する
+    pub fn control_events(&self) -> impl Iterator<Item = ControlEvent> + '_ {
+        self.batch.control_events()
+    }
+}
+
+/// デバイスの時計とホストの時計の差
+///
+/// 差は u64 の wrapping 演算で持つため、どちらの時計が進んでいても正しく換算できる。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ClockOffset {
+    offset_us: Option<u64>,
+}
+
+impl ClockOffset {
+    pub const fn new() -> Self {
+        Self { offset_us: None }
+    }
+
+    /// `local_us` に受信した `TimeSync` で差を更新する
+    pub fn sync(&mut self, local_us: u64, sync: &TimeSync) {
+        self.offset_us = Some(sync.host_time_us.wrapping_sub(local_us));
+    }
+
+    /// ホストが変わった場合などに差を捨てる
+    pub fn reset(&mut self) {
+        self.offset_us = None;
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.offset_us.is_some()
+    }
+
+    /// デバイスの時刻をホストの時計に換算する。まだ `TimeSync` を受け取っていなければ `None`
+    pub fn host_time_us(&self, local_us: u64) -> Option<u64> {
+        self.offset_us.map(|offset| local_us.wrapping_add(offset))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        AppPacketError, AppPacketKind, BatchedControl, ControlValue, MAX_BATCHED_EVENTS,
+        decode_data_packet, decode_set_packet, decode_time_sync_packet, encode_set_packet,
+        encode_set_packet_with_version, encode_time_sync_packet,
+    };
+
+    #[test]
+    fn offset_converts_local_time_to_host_time() {
+        let mut offset = ClockOffset::new();
+        assert_eq!(offset.host_time_us(100), None);
+
+        // ホストの時計の方が遅れていても換算できる
+        offset.sync(
+            5_000,
+            &TimeSync {
+                host_time_us: 1_000,
+            },
+        );
+        assert_eq!(offset.host_time_us(5_250), Some(1_250));
+
+        offset.sync(
+            10,
+            &TimeSync {
+                host_time_us: 1_000_000,
+            },
+        );
+        assert_eq!(offset.host_time_us(30), Some(1_000_020));
+
+        offset.reset();
+        assert!(!offset.is_synced());
+    }
+
+    #[test]
+    fn time_sync_is_sent_in_data_frames() {
+        let sync = TimeSync {
+            host_time_us: u64::MAX,
+        };
+        let encoded = encode_time_sync_packet(&sync).unwrap();
+        assert_eq!(decode_time_sync_packet(&encoded).unwrap(), sync);
+
+        assert_eq!(
+            decode_set_packet(&encoded),
+            Err(AppPacketError::InvalidSetPacketKind)
+        );
+        assert_eq!(
+            decode_data_packet(&encoded),
+            Err(AppPacketError::InvalidDataPacketKind)
+        );
+        assert_eq!(
+            encode_set_packet(&AppPacketKind::TimeSync(sync)),
+            Err(AppPacketError::InvalidSetPacketKind)
+        );
+    }
+
+    #[test]
+    fn full_timestamped_batch_fits_in_payload() {
+        let event = BatchedControl {
+            control_id: u16::MAX,
+            event: ControlValue::Absolute { value: i16::MIN },
+        };
+        let packet = AppPacketKind::TimestampedControlEvents(TimestampedControlEvents {
+            timestamp_us: u64::MAX,
+            batch: ControlEventBatch {
+                first_seq: u16::MAX,
+                events: core::iter::repeat_n(event, MAX_BATCHED_EVENTS).collect(),
+            },
+        });
+
+        let encoded = encode_set_packet(&packet).unwrap();
+        assert_eq!(decode_set_packet(&encoded).unwrap(), packet);
+        assert_eq!(
+            encode_set_packet_with_version(1, &packet),
+            Err(AppPacketError::NotAvailableInVersion(1))
+        );
+    }
+}
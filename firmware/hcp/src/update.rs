@@ -0,0 +1,354 @@
+//! IMCP 経由のファームウェア更新
+//!
+//! ホストは `UpdateCommand` を 1 つずつ送り、デバイスは毎回 `UpdateStatus` を返す。
+//!
+//! 1. `Begin { image_size, sha256 }` で書き込みを始める。
+//!    同じイメージを送り直す場合、デバイスは受信済みの長さを返すので、その位置から続ける
+//! 2. `Chunk { offset, data }` を先頭から順に送る
+//! 3. `Verify` でデバイスが書き込んだイメージの SHA-256 を確認する
+//! 4. `Commit` で次回起動時に新しいイメージを使うようにする。再起動はデバイスが行う
+//!
+//! ホスト側の手順は `UpdateSender` にまとめてある。
+
+use core::fmt;
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+/// 1 つの `Chunk` に入る最大の長さ
+pub const MAX_UPDATE_CHUNK_LEN: usize = 96;
+
+/// イメージ全体の SHA-256
+pub type ImageHash = [u8; 32];
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UpdateCommand {
+    Begin {
+        image_size: u32,
+        sha256: ImageHash,
+    },
+    Chunk {
+        offset: u32,
+        data: Vec<u8, MAX_UPDATE_CHUNK_LEN>,
+    },
+    Verify,
+    Commit,
+    /// 書き込み中のイメージを破棄する
+    Abort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UpdatePhase {
+    Idle,
+    Receiving,
+    Verified,
+    /// 次回起動時に新しいイメージを使う
+    Committed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UpdateError {
+    /// `Begin` の前にコマンドを受け取った
+    NotStarted,
+    /// イメージが書き込み先に入らない
+    ImageTooLarge,
+    /// 受信済みの長さと違う offset。`received` から送り直す
+    UnexpectedOffset,
+    /// 全て受信する前に `Verify` を受け取った
+    Incomplete,
+    /// 書き込んだイメージの hash が一致しない。最初からやり直す
+    HashMismatch,
+    /// `Verify` の前に `Commit` を受け取った
+    NotVerified,
+    FlashFailed,
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateError::NotStarted => write!(f, "update session is not started"),
+            UpdateError::ImageTooLarge => write!(f, "image does not fit in the update area"),
+            UpdateError::UnexpectedOffset => write!(f, "chunk offset does not match"),
+            UpdateError::Incomplete => write!(f, "image is not fully received"),
+            UpdateError::HashMismatch => write!(f, "image hash does not match"),
+            UpdateError::NotVerified => write!(f, "image is not verified"),
+            UpdateError::FlashFailed => write!(f, "failed to access flash"),
+        }
+    }
+}
+
+impl core::error::Error for UpdateError {}
+
+/// `UpdateCommand` への応答
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UpdateStatus {
+    pub phase: UpdatePhase,
+    /// 先頭から書き込み済みの長さ
+    pub received: u32,
+    /// 直前のコマンドを処理できなかった理由
+    pub error: Option<UpdateError>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UpdateSenderError {
+    /// イメージが `u32` で表せない
+    ImageTooLarge,
+    /// デバイスが続行できないエラーを返した
+    Device(UpdateError),
+    /// 送ったコマンドに合わない状態が返ってきた
+    UnexpectedPhase(UpdatePhase),
+}
+
+impl fmt::Display for UpdateSenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateSenderError::ImageTooLarge => write!(f, "image is too large"),
+            UpdateSenderError::Device(error) => write!(f, "device rejected update: {error}"),
+            UpdateSenderError::UnexpectedPhase(phase) => {
+                write!(f, "unexpected update phase {phase:?}")
+            }
+        }
+    }
+}
+
+impl core::error::Error for UpdateSenderError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SenderStep {
+    Begin,
+    Chunk,
+    Verify,
+    Commit,
+    Done,
+}
+
+/// ホスト側の送信手順
+///
+/// `next_command` のコマンドを送り、返ってきた `UpdateStatus` を `accept` に渡す。
+/// 応答がない場合は同じコマンドをそのまま送り直せばよい。
+#[derive(Debug, Clone)]
+pub struct UpdateSender<'a> {
+    image: &'a [u8],
+    image_size: u32,
+    sha256: ImageHash,
+    chunk_len: usize,
+    step: SenderStep,
+    offset: u32,
+}
+
+impl<'a> UpdateSender<'a> {
+    pub fn new(image: &'a [u8], sha256: ImageHash) -> Result<Self, UpdateSenderError> {
+        let image_size =
+            u32::try_from(image.len()).map_err(|_| UpdateSenderError::ImageTooLarge)?;
+        Ok(Self {
+            image,
+            image_size,
+            sha256,
+            chunk_len: MAX_UPDATE_CHUNK_LEN,
+            step: SenderStep::Begin,
+            offset: 0,
+        })
+    }
+
+    /// 1 つの `Chunk` の長さ。受信バッファが小さいデバイス向け
+    pub fn with_chunk_len(mut self, chunk_len: usize) -> Self {
+        self.chunk_len = chunk_len.clamp(1, MAX_UPDATE_CHUNK_LEN);
+        self
+    }
+
+    /// 次に送るコマンド。完了後は `None`
+    pub fn next_command(&self) -> Option<UpdateCommand> {
+        match self.step {
+            SenderStep::Begin => Some(UpdateCommand::Begin {
+                image_size: self.image_size,
+                sha256: self.sha256,
+            }),
+            SenderStep::Chunk => {
+                let start = usize::try_from(self.offset).ok()?;
+                let end = start.saturating_add(self.chunk_len).min(self.image.len());
+                Some(UpdateCommand::Chunk {
+                    offset: self.offset,
+                    data: Vec::from_slice(self.image.get(start..end)?).ok()?,
+                })
+            }
+            SenderStep::Verify => Some(UpdateCommand::Verify),
+            SenderStep::Commit => Some(UpdateCommand::Commit),
+            SenderStep::Done => None,
+        }
+    }
+
+    /// デバイスの応答で次の手順に進む
+    pub fn accept(&mut self, status: &UpdateStatus) -> Result<(), UpdateSenderError> {
+        match status.error {
+            // 受信済みの位置から送り直す
+            Some(UpdateError::UnexpectedOffset) if status.phase == UpdatePhase::Receiving => {
+                self.resume_from(status.received);
+                return Ok(());
+            }
+            // デバイスが再起動した場合は最初からやり直す
+            Some(UpdateError::NotStarted) if self.step != SenderStep::Begin => {
+                self.step = SenderStep::Begin;
+                self.offset = 0;
+                return Ok(());
+            }
+            Some(error) => return Err(UpdateSenderError::Device(error)),
+            None => {}
+        }
+
+        match (self.step, status.phase) {
+            (SenderStep::Begin | SenderStep::Chunk, UpdatePhase::Receiving) => {
+                self.resume_from(status.received);
+            }
+            (SenderStep::Verify, UpdatePhase::Verified) => self.step = SenderStep::Commit,
+            (SenderStep::Commit, UpdatePhase::Committed) => self.step = SenderStep::Done,
+            (_, phase) => return Err(UpdateSenderError::UnexpectedPhase(phase)),
+        }
+        Ok(())
+    }
+
+    /// デバイスが受信済みの長さとイメージ全体の長さ
+    pub fn progress(&self) -> (u32, u32) {
+        (self.offset, self.image_size)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.step == SenderStep::Done
+    }
+
+    fn resume_from(&mut self, received: u32) {
+        self.offset = received.min(self.image_size);
+        self.step = if self.offset == self.image_size {
+            SenderStep::Verify
+        } else {
+            SenderStep::Chunk
+        };
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::{AppPacketKind, decode_set_packet, encode_set_packet};
+
+    fn receiving(received: u32) -> UpdateStatus {
+        UpdateStatus {
+            phase: UpdatePhase::Receiving,
+            received,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn full_chunk_fits_in_payload() {
+        let packet = AppPacketKind::FirmwareUpdate(UpdateCommand::Chunk {
+            offset: u32::MAX,
+            data: Vec::from_slice(&[0xAA; MAX_UPDATE_CHUNK_LEN]).unwrap(),
+        });
+        let encoded = encode_set_packet(&packet).unwrap();
+        assert_eq!(decode_set_packet(&encoded).unwrap(), packet);
+
+        let status = AppPacketKind::FirmwareUpdateStatus(UpdateStatus {
+            phase: UpdatePhase::Receiving,
+            received: 10,
+            error: Some(UpdateError::UnexpectedOffset),
+        });
+        let encoded = encode_set_packet(&status).unwrap();
+        assert_eq!(decode_set_packet(&encoded).unwrap(), status);
+    }
+
+    #[test]
+    fn sender_walks_through_session() {
+        let image = [1u8; 10];
+        let mut sender = UpdateSender::new(&image, [7; 32])
+            .unwrap()
+            .with_chunk_len(4);
+
+        assert_eq!(
+            sender.next_command(),
+            Some(UpdateCommand::Begin {
+                image_size: 10,
+                sha256: [7; 32],
+            })
+        );
+        sender.accept(&receiving(0)).unwrap();
+
+        let mut offsets = std::vec::Vec::new();
+        while let Some(UpdateCommand::Chunk { offset, data }) = sender.next_command() {
+            offsets.push((offset, data.len()));
+            sender
+                .accept(&receiving(offset + u32::try_from(data.len()).unwrap()))
+                .unwrap();
+        }
+        assert_eq!(offsets, [(0, 4), (4, 4), (8, 2)]);
+        assert_eq!(sender.progress(), (10, 10));
+
+        assert_eq!(sender.next_command(), Some(UpdateCommand::Verify));
+        sender
+            .accept(&UpdateStatus {
+                phase: UpdatePhase::Verified,
+                received: 10,
+                error: None,
+            })
+            .unwrap();
+        assert_eq!(sender.next_command(), Some(UpdateCommand::Commit));
+        sender
+            .accept(&UpdateStatus {
+                phase: UpdatePhase::Committed,
+                received: 10,
+                error: None,
+            })
+            .unwrap();
+        assert!(sender.is_done());
+        assert_eq!(sender.next_command(), None);
+    }
+
+    #[test]
+    fn sender_resumes_from_device_progress() {
+        let image = [0u8; 200];
+        let mut sender = UpdateSender::new(&image, [0; 32]).unwrap();
+
+        // 途中まで受信済みのデバイスに再接続した
+        sender.accept(&receiving(96)).unwrap();
+        let Some(UpdateCommand::Chunk { offset, data }) = sender.next_command() else {
+            unreachable!();
+        };
+        assert_eq!((offset, data.len()), (96, 96));
+
+        sender
+            .accept(&UpdateStatus {
+                phase: UpdatePhase::Receiving,
+                received: 50,
+                error: Some(UpdateError::UnexpectedOffset),
+            })
+            .unwrap();
+        assert_eq!(sender.progress(), (50, 200));
+
+        sender
+            .accept(&UpdateStatus {
+                phase: UpdatePhase::Idle,
+                received: 0,
+                error: Some(UpdateError::NotStarted),
+            })
+            .unwrap();
+        assert!(matches!(
+            sender.next_command(),
+            Some(UpdateCommand::Begin { .. })
+        ));
+
+        assert_eq!(
+            sender.accept(&UpdateStatus {
+                phase: UpdatePhase::Idle,
+                received: 0,
+                error: Some(UpdateError::ImageTooLarge),
+            }),
+            Err(UpdateSenderError::Device(UpdateError::ImageTooLarge))
+        );
+    }
+}
@@ -0,0 +1,341 @@
+//! HCP のバージョン交渉
+//!
+//! デバイスは対応する最小バージョンの layout で `DeviceHello` を送り、
+//! `min_protocol_version` から `protocol_version` までを対応範囲として通知する。
+//! v2 以降に対応するホストは `HostHello` で自分の対応範囲を返し、
+//! 以降は両者とも `ProtocolVersions::negotiate` で決まるバージョンで送る。
+//! 受信側は packet 先頭の `version` を見て、その版の layout で decode する。
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::{APP_PROTOCOL_VERSION, AppPacketError, AppPacketKind, MAX_PAYLOAD_SIZE};
+
+/// decode / encode できる最も古いバージョン
+pub const MIN_APP_PROTOCOL_VERSION: u8 = 1;
+
+/// 対応するバージョンの範囲 (両端を含む)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ProtocolVersions {
+    pub min: u8,
+    pub max: u8,
+}
+
+impl ProtocolVersions {
+    /// この crate が対応する範囲
+    pub const SUPPORTED: Self = Self {
+        min: MIN_APP_PROTOCOL_VERSION,
+        max: APP_PROTOCOL_VERSION,
+    };
+
+    pub fn contains(self, version: u8) -> bool {
+        (self.min..=self.max).contains(&version)
+    }
+
+    /// 両者が対応する最も新しいバージョン。共通のバージョンがない場合は `None`
+    pub fn negotiate(self, other: Self) -> Option<u8> {
+        let version = self.max.min(other.max);
+        (version >= self.min.max(other.min)).then_some(version)
+    }
+}
+
+impl AppPacketKind {
+    /// `version` の layout で送れる packet か
+    pub fn is_available_in(&self, version: u8) -> bool {
+        match version {
+            v1::VERSION => v1::is_available(self),
+            APP_PROTOCOL_VERSION => true,
+            _ => false,
+        }
+    }
+}
+
+pub(crate) fn encode_versioned(
+    version: u8,
+    kind: &AppPacketKind,
+) -> Result<Vec<u8, MAX_PAYLOAD_SIZE>, AppPacketError> {
+    match version {
+        v1::VERSION => v1::encode(kind),
+        APP_PROTOCOL_VERSION => crate::encode_packet(&crate::AppPacket {
+            version,
+            kind: kind.clone(),
+        }),
+        _ => Err(AppPacketError::UnsupportedVersion(version)),
+    }
+}
+
+pub(crate) fn decode_versioned(bytes: &[u8]) -> Result<crate::AppPacket, AppPacketError> {
+    let version = *bytes.first().ok_or(AppPacketError::Deserialize)?;
+    let kind = match version {
+        v1::VERSION => v1::decode(bytes)?,
+        APP_PROTOCOL_VERSION => {
+            postcard::from_bytes::<crate::AppPacket>(bytes)
+                .map_err(crate::map_postcard_decode_error)?
+                .kind
+        }
+        _ => return Err(AppPacketError::UnsupportedVersion(version)),
+    };
+    Ok(crate::AppPacket { version, kind })
+}
+
+/// v1 の wire layout
+///
+/// v1 には `DisplayData` / `DeviceHello` / `ControlEvent` しかなく、
+/// `DisplayTarget` は `Screen` / `Indicator`、`DisplayPayload` は `Text` / `Bytes` だけ。
+/// `DeviceHello` に `min_protocol_version` がない。
+mod v1 {
+    use heapless::Vec;
+    use serde::{Deserialize, Serialize};
+
+    use crate::{
+        AppPacketError, Capabilities, ControlEvent, DeviceKind, DisplayData, DisplayPayload,
+        DisplayTarget, MAX_PAYLOAD_SIZE, Version,
+    };
+
+    pub const VERSION: u8 = 1;
+
+    #[derive(Serialize, Deserialize)]
+    struct AppPacket {
+        version: u8,
+        kind: AppPacketKind,
+    }
+
+    #[allow(clippy::large_enum_variant)]
+    #[derive(Serialize, Deserialize)]
+    enum AppPacketKind {
+        DisplayData(DisplayData),
+        DeviceHello(DeviceHello),
+        ControlEvent(ControlEvent),
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct DeviceHello {
+        device_id: u64,
+        device_kind: DeviceKind,
+        protocol_version: u8,
+        firmware_version: Version,
+        capabilities: Capabilities,
+    }
+
+    fn has_v1_payload(data: &DisplayData) -> bool {
+        matches!(
+            data.target,
+            DisplayTarget::Screen(_) | DisplayTarget::Indicator(_)
+        ) && matches!(
+            data.payload,
+            DisplayPayload::Text { .. } | DisplayPayload::Bytes { .. }
+        )
+    }
+
+    pub fn is_available(kind: &crate::AppPacketKind) -> bool {
+        match kind {
+            crate::AppPacketKind::DisplayData(data) => has_v1_payload(data),
+            crate::AppPacketKind::DeviceHello(_) | crate::AppPacketKind::ControlEvent(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn encode(
+        kind: &crate::AppPacketKind,
+    ) -> Result<Vec<u8, MAX_PAYLOAD_SIZE>, AppPacketError> {
+        let kind = match kind {
+            crate::AppPacketKind::DisplayData(data) if has_v1_payload(data) => {
+                AppPacketKind::DisplayData(data.clone())
+            }
+            crate::AppPacketKind::DeviceHello(hello) => AppPacketKind::DeviceHello(DeviceHello {
+                device_id: hello.device_id,
+                device_kind: hello.device_kind,
+                protocol_version: hello.protocol_version,
+                firmware_version: hello.firmware_version,
+                capabilities: hello.capabilities,
+            }),
+            crate::AppPacketKind::ControlEvent(event) => AppPacketKind::ControlEvent(event.clone()),
+            _ => return Err(AppPacketError::NotAvailableInVersion(VERSION)),
+        };
+        crate::encode_packet(&AppPacket {
+            version: VERSION,
+            kind,
+        })
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<crate::AppPacketKind, AppPacketError> {
+        let packet: AppPacket =
+            postcard::from_bytes(bytes).map_err(crate::map_postcard_decode_error)?;
+        match packet.kind {
+            AppPacketKind::DisplayData(data) if has_v1_payload(&data) => {
+                Ok(crate::AppPacketKind::DisplayData(data))
+            }
+            AppPacketKind::DisplayData(_) => Err(AppPacketError::Deserialize),
+            // v1 のデバイスは v1 の layout で DeviceHello を送るので、最小バージョンは 1
+            AppPacketKind::DeviceHello(hello) => {
+                Ok(crate::AppPacketKind::DeviceHello(crate::DeviceHello {
+                    device_id: hello.device_id,
+                    device_kind: hello.device_kind,
+                    protocol_version: hello.protocol_version,
+                    min_protocol_version: VERSION,
+                    firmware_version: hello.firmware_version,
+                    capabilities: hello.capabilities,
+                }))
+            }
+            AppPacketKind::ControlEvent(event) => Ok(crate::AppPacketKind::ControlEvent(event)),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        ByteEncoding, Capabilities, ControlEvent, ControlValue, DeviceHello, DeviceKind,
+        DisplayData, DisplayPayload, DisplayTarget, IndicatorState, Version, decode_app_packet,
+        decode_data_packet, decode_set_packet, encode_data_packet_with_version,
+        encode_set_packet_with_version,
+    };
+
+    fn hello(min: u8, max: u8) -> DeviceHello {
+        DeviceHello {
+            device_id: 0x0123_4567_89AB_CDEF,
+            device_kind: DeviceKind::UpperPanelDdi,
+            protocol_version: max,
+            min_protocol_version: min,
+            firmware_version: Version {
+                major: 0,
+                minor: 2,
+                patch: 0,
+            },
+            capabilities: Capabilities {
+                displays: 1,
+                controls: 20,
+                features: 0x03,
+            },
+        }
+    }
+
+    #[test]
+    fn negotiate_picks_highest_common_version() {
+        let v1_only = ProtocolVersions { min: 1, max: 1 };
+        let v2_only = ProtocolVersions { min: 2, max: 2 };
+
+        assert_eq!(
+            ProtocolVersions::SUPPORTED.negotiate(ProtocolVersions::SUPPORTED),
+            Some(APP_PROTOCOL_VERSION)
+        );
+        assert_eq!(ProtocolVersions::SUPPORTED.negotiate(v1_only), Some(1));
+        assert_eq!(
+            v1_only.negotiate(ProtocolVersions { min: 1, max: 5 }),
+            Some(1)
+        );
+        assert_eq!(v1_only.negotiate(v2_only), None);
+        assert!(ProtocolVersions::SUPPORTED.contains(MIN_APP_PROTOCOL_VERSION));
+        assert!(!ProtocolVersions::SUPPORTED.contains(APP_PROTOCOL_VERSION + 1));
+    }
+
+    #[test]
+    fn v1_packet_bytes_are_unchanged() {
+        // v1 の crate が encode した ControlEvent
+        let event = AppPacketKind::ControlEvent(ControlEvent {
+            seq: 7,
+            control_id: 12,
+            event: ControlValue::Button { pressed: true },
+        });
+        let encoded = encode_set_packet_with_version(1, &event).unwrap();
+
+        assert_eq!(encoded.as_slice(), &[1, 2, 7, 12, 0, 1]);
+        assert_eq!(decode_set_packet(&encoded).unwrap(), event);
+    }
+
+    #[test]
+    fn device_hello_roundtrips_across_versions() {
+        for version in MIN_APP_PROTOCOL_VERSION..=APP_PROTOCOL_VERSION {
+            let packet = AppPacketKind::DeviceHello(hello(version, APP_PROTOCOL_VERSION));
+            let encoded = encode_set_packet_with_version(version, &packet).unwrap();
+            let decoded = decode_app_packet(&encoded).unwrap();
+
+            assert_eq!(decoded.version, version);
+            assert_eq!(decoded.kind, packet);
+        }
+    }
+
+    #[test]
+    fn v1_device_hello_has_no_min_version() {
+        let encoded =
+            encode_set_packet_with_version(1, &AppPacketKind::DeviceHello(hello(1, 2))).unwrap();
+        let current = crate::encode_set_packet(&AppPacketKind::DeviceHello(hello(1, 2))).unwrap();
+
+        assert_eq!(encoded.len() + 1, current.len());
+        let AppPacketKind::DeviceHello(decoded) = decode_set_packet(&encoded).unwrap() else {
+            unreachable!();
+        };
+        assert_eq!(
+            decoded.protocol_versions(),
+            ProtocolVersions { min: 1, max: 2 }
+        );
+    }
+
+    #[test]
+    fn display_data_roundtrips_across_versions() {
+        let text = DisplayData {
+            seq: 3,
+            target: DisplayTarget::Screen(0),
+            payload: DisplayPayload::Bytes {
+                encoding: ByteEncoding::Utf8Text,
+                data: Vec::from_slice(b"MASTER ARM").unwrap(),
+            },
+        };
+
+        for version in MIN_APP_PROTOCOL_VERSION..=APP_PROTOCOL_VERSION {
+            let encoded = encode_data_packet_with_version(version, &text).unwrap();
+            assert_eq!(encoded[0], version);
+            assert_eq!(decode_data_packet(&encoded).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn newer_packets_cannot_be_sent_as_v1() {
+        let indicator = DisplayData {
+            seq: 4,
+            target: DisplayTarget::Indicator(1),
+            payload: DisplayPayload::Indicator(IndicatorState::on(255)),
+        };
+
+        assert_eq!(
+            encode_data_packet_with_version(1, &indicator),
+            Err(AppPacketError::NotAvailableInVersion(1))
+        );
+        assert_eq!(
+            encode_set_packet_with_version(1, &AppPacketKind::RequestDescriptor { page: 0 }),
+            Err(AppPacketError::NotAvailableInVersion(1))
+        );
+        assert!(!AppPacketKind::HostHello(ProtocolVersions::SUPPORTED).is_available_in(1));
+        assert!(
+            AppPacketKind::HostHello(ProtocolVersions::SUPPORTED)
+                .is_available_in(APP_PROTOCOL_VERSION)
+        );
+        assert_eq!(
+            encode_set_packet_with_version(
+                APP_PROTOCOL_VERSION + 1,
+                &AppPacketKind::RequestDescriptor { page: 0 }
+            ),
+            Err(AppPacketError::UnsupportedVersion(APP_PROTOCOL_VERSION + 1))
+        );
+
+        // v2 の packet に v1 の version を付けても v1 としては読まない
+        let mut encoded = crate::encode_data_packet(&indicator).unwrap();
+        encoded[0] = 1;
+        assert_eq!(
+            decode_app_packet(&encoded),
+            Err(AppPacketError::Deserialize)
+        );
+    }
+
+    #[test]
+    fn host_hello_roundtrip_works() {
+        let packet = AppPacketKind::HostHello(ProtocolVersions::SUPPORTED);
+        let encoded = crate::encode_set_packet(&packet).unwrap();
+
+        assert_eq!(decode_set_packet(&encoded).unwrap(), packet);
+        assert_eq!(decode_app_packet(&[]), Err(AppPacketError::Deserialize));
+    }
+}
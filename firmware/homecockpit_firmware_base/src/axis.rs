@@ -0,0 +1,364 @@
+//! ADC の値からアナログ軸の `Absolute` イベントを作る
+//!
+//! `AxisInput` は 1 本の軸のフィルター、キャリブレーション、送信済みの値を持つ。
+//! メインループから ADC の値を `sample` に渡し、返ってきたイベントをキューに積む。
+//! キャリブレーションは RAM 上だけで持つため、保存するデバイスは `calibration` を読んで保存する。
+
+use hcp::{
+    ABSOLUTE_MAX, AppPacketKind, AxisCalibration, AxisCalibrationStatus, BatchedControl,
+    CalibrationCommand, CalibrationError, ControlValue,
+};
+
+/// 何も指定しないときの平滑化の強さ。1/4 ずつ新しい値に寄せる
+pub const DEFAULT_AXIS_SMOOTHING: u8 = 2;
+/// 何も指定しないときに送る最小の変化量
+pub const DEFAULT_AXIS_THRESHOLD: u16 = 64;
+
+/// 平滑化した値の固定小数点の桁
+const FILTER_FRACTION_BITS: u32 = 8;
+
+/// 1 本のアナログ軸
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AxisInput {
+    control_id: u16,
+    calibration: AxisCalibration,
+    smoothing: u8,
+    threshold: u16,
+    filtered: Option<u32>,
+    sent: Option<i16>,
+    recording: Option<AxisCalibration>,
+    range_changed: bool,
+}
+
+impl AxisInput {
+    pub const fn new(control_id: u16, calibration: AxisCalibration) -> Self {
+        Self {
+            control_id,
+            calibration,
+            smoothing: DEFAULT_AXIS_SMOOTHING,
+            threshold: DEFAULT_AXIS_THRESHOLD,
+            filtered: None,
+            sent: None,
+            recording: None,
+            range_changed: false,
+        }
+    }
+
+    /// 新しい値を `1 / 2^shift` ずつ取り込む。0 なら平滑化しない
+    pub const fn with_smoothing(mut self, shift: u8) -> Self {
+        self.smoothing = if shift > 15 { 15 } else { shift };
+        self
+    }
+
+    /// 前回送った値からこれ以上変わったときだけ送る。0 なら変わるたびに送る
+    pub const fn with_threshold(mut self, threshold: u16) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn control_id(&self) -> u16 {
+        self.control_id
+    }
+
+    pub fn calibration(&self) -> AxisCalibration {
+        self.calibration
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// 平滑化した生の値。まだ値を受け取っていなければ 0
+    pub fn raw(&self) -> u16 {
+        let Some(filtered) = self.filtered else {
+            return 0;
+        };
+        let rounded = (filtered + (1 << (FILTER_FRACTION_BITS - 1))) >> FILTER_FRACTION_BITS;
+        u16::try_from(rounded).unwrap_or(u16::MAX)
+    }
+
+    /// キャリブレーション後の今の値。`ControlStateSnapshot` などに使う
+    pub fn value(&self) -> i16 {
+        self.calibration.apply(self.raw())
+    }
+
+    /// ADC の値を 1 つ取り込み、送るべき変化があればイベントを返す
+    ///
+    /// 0 と両端に着いたときは、変化が `threshold` より小さくても送る。
+    /// 範囲の記録中はイベントを返さない
+    pub fn sample(&mut self, raw: u16) -> Option<BatchedControl> {
+        let raw = self.filter(raw);
+        if let Some(recording) = &mut self.recording {
+            if raw < recording.min {
+                recording.min = raw;
+                self.range_changed = true;
+            }
+            if raw > recording.max {
+                recording.max = raw;
+                self.range_changed = true;
+            }
+            return None;
+        }
+
+        let value = self.calibration.apply(raw);
+        let should_send = match self.sent {
+            None => true,
+            Some(sent) if sent == value => false,
+            Some(sent) => {
+                let delta = (i32::from(value) - i32::from(sent)).unsigned_abs();
+                delta >= u32::from(self.threshold)
+                    || value == 0
+                    || value.unsigned_abs() == ABSOLUTE_MAX.unsigned_abs()
+            }
+        };
+        if !should_send {
+            return None;
+        }
+        self.sent = Some(value);
+        Some(BatchedControl {
+            control_id: self.control_id,
+            event: ControlValue::Absolute { value },
+        })
+    }
+
+    fn filter(&mut self, raw: u16) -> u16 {
+        let target = u32::from(raw) << FILTER_FRACTION_BITS;
+        let filtered = match self.filtered {
+            Some(filtered) => {
+                let delta = (i64::from(target) - i64::from(filtered)) >> self.smoothing;
+                u32::try_from(i64::from(filtered) + delta).unwrap_or(target)
+            }
+            None => target,
+        };
+        self.filtered = Some(filtered);
+        self.raw()
+    }
+
+    /// キャリブレーションの指示を処理し、応答を返す
+    pub fn handle_command(&mut self, command: CalibrationCommand) -> AxisCalibrationStatus {
+        let raw = self.raw();
+        let error = match (command, &mut self.recording) {
+            (CalibrationCommand::Begin, recording) => {
+                *recording = Some(AxisCalibration {
+                    min: raw,
+                    center: raw,
+                    max: raw,
+                    deadzone: self.calibration.deadzone,
+                });
+                self.range_changed = false;
+                None
+            }
+            (CalibrationCommand::CaptureCenter, Some(recording)) => {
+                recording.center = raw;
+                None
+            }
+            (CalibrationCommand::Commit, Some(recording)) if recording.is_valid() => {
+                self.calibration = *recording;
+                self.recording = None;
+                self.sent = None;
+                None
+            }
+            (CalibrationCommand::Commit, Some(_)) => Some(CalibrationError::InvalidRange),
+            (CalibrationCommand::CaptureCenter | CalibrationCommand::Commit, None) => {
+                Some(CalibrationError::NotRecording)
+            }
+            (CalibrationCommand::Cancel, recording) => {
+                *recording = None;
+                self.sent = None;
+                None
+            }
+            (CalibrationCommand::Get, _) => None,
+            (CalibrationCommand::Set(calibration), _) if calibration.is_valid() => {
+                self.calibration = calibration;
+                self.recording = None;
+                self.sent = None;
+                None
+            }
+            (CalibrationCommand::Set(_), _) => Some(CalibrationError::InvalidRange),
+        };
+        self.status(error)
+    }
+
+    /// 記録中に範囲が広がっていれば、その範囲を `AxisCalibrationStatus` で返す
+    ///
+    /// 広がるたびに送ると bus が埋まるため、メインループから一定の間隔で呼ぶ
+    pub fn take_recording_status(&mut self) -> Option<AppPacketKind> {
+        if !self.range_changed || self.recording.is_none() {
+            return None;
+        }
+        self.range_changed = false;
+        Some(AppPacketKind::AxisCalibrationStatus(self.status(None)))
+    }
+
+    fn status(&self, error: Option<CalibrationError>) -> AxisCalibrationStatus {
+        AxisCalibrationStatus {
+            control_id: self.control_id,
+            raw: self.raw(),
+            recording: self.recording.is_some(),
+            calibration: self.recording.unwrap_or(self.calibration),
+            error,
+        }
+    }
+}
+
+/// `CalibrateAxis` を処理して `AxisCalibrationStatus` を返す
+///
+/// それ以外の packet の場合は `None`。
+/// `axes` にない操作系には `UnknownControl` を返す
+pub fn handle_calibration_packet(
+    axes: &mut [AxisInput],
+    packet: &AppPacketKind,
+) -> Option<AppPacketKind> {
+    let AppPacketKind::CalibrateAxis {
+        control_id,
+        command,
+    } = *packet
+    else {
+        return None;
+    };
+    let status = match axes.iter_mut().find(|axis| axis.control_id == control_id) {
+        Some(axis) => axis.handle_command(command),
+        None => AxisCalibrationStatus {
+            control_id,
+            raw: 0,
+            recording: false,
+            calibration: AxisCalibration::full_range(0),
+            error: Some(CalibrationError::UnknownControl),
+        },
+    };
+    Some(AppPacketKind::AxisCalibrationStatus(status))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    fn absolute(event: Option<BatchedControl>) -> Option<i16> {
+        match event?.event {
+            ControlValue::Absolute { value } => Some(value),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn threshold_limits_events_but_reports_rest_positions() {
+        let mut axis = AxisInput::new(0x0301, AxisCalibration::full_range(4000))
+            .with_smoothing(0)
+            .with_threshold(1000);
+
+        assert_eq!(absolute(axis.sample(2000)), Some(0));
+        // 小さな揺れは送らない
+        assert_eq!(absolute(axis.sample(2010)), None);
+        assert_eq!(absolute(axis.sample(2030)), None);
+        assert_eq!(absolute(axis.sample(2100)), Some(1638));
+        // 中心と端は閾値より小さくても送る
+        assert_eq!(absolute(axis.sample(2000)), Some(0));
+        assert_eq!(absolute(axis.sample(3990)), Some(32603));
+        assert_eq!(absolute(axis.sample(4000)), Some(ABSOLUTE_MAX));
+        assert_eq!(absolute(axis.sample(4095)), None);
+    }
+
+    #[test]
+    fn smoothing_filters_spikes() {
+        let mut axis = AxisInput::new(0x0301, AxisCalibration::full_range(4000)).with_threshold(0);
+
+        axis.sample(2000);
+        axis.sample(4000);
+        assert_eq!(axis.raw(), 2500);
+        for _ in 0..40 {
+            axis.sample(4000);
+        }
+        assert_eq!(axis.raw(), 4000);
+        assert_eq!(axis.value(), ABSOLUTE_MAX);
+    }
+
+    #[test]
+    fn wizard_records_range_and_commits() {
+        let mut axes =
+            [AxisInput::new(0x0301, AxisCalibration::full_range(4095)).with_smoothing(0)];
+        axes[0].sample(2100);
+
+        let command = |command| AppPacketKind::CalibrateAxis {
+            control_id: 0x0301,
+            command,
+        };
+        let Some(AppPacketKind::AxisCalibrationStatus(status)) =
+            handle_calibration_packet(&mut axes, &command(CalibrationCommand::Commit))
+        else {
+            unreachable!();
+        };
+        assert_eq!(status.error, Some(CalibrationError::NotRecording));
+
+        handle_calibration_packet(&mut axes, &command(CalibrationCommand::Begin)).unwrap();
+        assert_eq!(axes[0].take_recording_status(), None);
+        for raw in [1500, 300, 3800, 2100] {
+            assert_eq!(axes[0].sample(raw), None);
+        }
+        let Some(AppPacketKind::AxisCalibrationStatus(status)) = axes[0].take_recording_status()
+        else {
+            unreachable!();
+        };
+        assert!(status.recording);
+        assert_eq!(
+            (status.calibration.min, status.calibration.max),
+            (300, 3800)
+        );
+        assert_eq!(axes[0].take_recording_status(), None);
+
+        let Some(AppPacketKind::AxisCalibrationStatus(status)) =
+            handle_calibration_packet(&mut axes, &command(CalibrationCommand::Commit))
+        else {
+            unreachable!();
+        };
+        assert_eq!(status.error, None);
+        assert_eq!(
+            status.calibration,
+            AxisCalibration {
+                min: 300,
+                center: 2100,
+                max: 3800,
+                deadzone: 0,
+            }
+        );
+        // 確定後は今の値を送り直す
+        assert_eq!(absolute(axes[0].sample(2100)), Some(0));
+        assert_eq!(absolute(axes[0].sample(3800)), Some(ABSOLUTE_MAX));
+    }
+
+    #[test]
+    fn invalid_or_unknown_requests_are_rejected() {
+        let mut axes = [AxisInput::new(0x0301, AxisCalibration::full_range(4095))];
+
+        let Some(AppPacketKind::AxisCalibrationStatus(status)) = handle_calibration_packet(
+            &mut axes,
+            &AppPacketKind::CalibrateAxis {
+                control_id: 0x0302,
+                command: CalibrationCommand::Get,
+            },
+        ) else {
+            unreachable!();
+        };
+        assert_eq!(status.error, Some(CalibrationError::UnknownControl));
+
+        // 動かさずに確定すると範囲がない
+        axes[0].sample(2000);
+        axes[0].handle_command(CalibrationCommand::Begin);
+        let status = axes[0].handle_command(CalibrationCommand::Commit);
+        assert_eq!(status.error, Some(CalibrationError::InvalidRange));
+        assert!(status.recording);
+
+        let status =
+            axes[0].handle_command(CalibrationCommand::Set(AxisCalibration::full_range(0)));
+        assert_eq!(status.error, Some(CalibrationError::InvalidRange));
+        let status = axes[0].handle_command(CalibrationCommand::Cancel);
+        assert!(!status.recording);
+        assert_eq!(status.calibration, AxisCalibration::full_range(4095));
+
+        assert_eq!(
+            handle_calibration_packet(&mut axes, &AppPacketKind::RequestControlState),
+            None
+        );
+    }
+}
@@ -0,0 +1,273 @@
+//! デバイス設定の保持と `GetConfig` / `SetConfig` の処理
+//!
+//! 値の保存先は `ConfigStorage` で抽象化する。
+//! flash などに保存しないデバイスは `VolatileConfigStorage` を使う。
+
+use hcp::{AppPacketKind, ConfigKey, ConfigResponse, ConfigStatus, ConfigValue};
+
+/// 設定値の保存先
+pub trait ConfigStorage {
+    type Error;
+
+    /// 保存されていない場合は `Ok(None)`
+    fn load(&mut self, key: ConfigKey) -> Result<Option<ConfigValue>, Self::Error>;
+    fn store(&mut self, key: ConfigKey, value: ConfigValue) -> Result<(), Self::Error>;
+}
+
+/// RAM 上だけで持つ storage。再起動すると既定値に戻る
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VolatileConfigStorage {
+    values: [Option<ConfigValue>; ConfigKey::ALL.len()],
+}
+
+impl VolatileConfigStorage {
+    pub const fn new() -> Self {
+        Self {
+            values: [None; ConfigKey::ALL.len()],
+        }
+    }
+}
+
+impl ConfigStorage for VolatileConfigStorage {
+    type Error = core::convert::Infallible;
+
+    fn load(&mut self, key: ConfigKey) -> Result<Option<ConfigValue>, Self::Error> {
+        Ok(self.values[key_index(key)])
+    }
+
+    fn store(&mut self, key: ConfigKey, value: ConfigValue) -> Result<(), Self::Error> {
+        self.values[key_index(key)] = Some(value);
+        Ok(())
+    }
+}
+
+/// ファームウェアが参照する設定値
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceConfig {
+    pub debounce_ms: u16,
+    pub led_brightness: u8,
+    pub scan_interval_ms: u16,
+    pub encoder_acceleration: u8,
+}
+
+impl DeviceConfig {
+    pub const DEFAULT: Self = Self {
+        debounce_ms: 5,
+        led_brightness: u8::MAX,
+        scan_interval_ms: 1,
+        encoder_acceleration: 0,
+    };
+
+    /// storage から読み込む。読めない値や不正な値は既定値のままにする
+    pub fn load<S: ConfigStorage>(storage: &mut S) -> Self {
+        let mut config = Self::DEFAULT;
+        for key in ConfigKey::ALL {
+            if let Ok(Some(value)) = storage.load(key) {
+                let _ = config.set(key, value);
+            }
+        }
+        config
+    }
+
+    pub fn get(&self, key: ConfigKey) -> ConfigValue {
+        match key {
+            ConfigKey::DebounceMs => ConfigValue::U16(self.debounce_ms),
+            ConfigKey::LedBrightness => ConfigValue::U8(self.led_brightness),
+            ConfigKey::ScanIntervalMs => ConfigValue::U16(self.scan_interval_ms),
+            ConfigKey::EncoderAcceleration => ConfigValue::U8(self.encoder_acceleration),
+        }
+    }
+
+    /// 値を検証してから反映する
+    pub fn set(&mut self, key: ConfigKey, value: ConfigValue) -> Result<(), ConfigStatus> {
+        key.validate(value)?;
+        match (key, value) {
+            (ConfigKey::DebounceMs, ConfigValue::U16(ms)) => self.debounce_ms = ms,
+            (ConfigKey::LedBrightness, ConfigValue::U8(level)) => self.led_brightness = level,
+            (ConfigKey::ScanIntervalMs, ConfigValue::U16(ms)) => self.scan_interval_ms = ms,
+            (ConfigKey::EncoderAcceleration, ConfigValue::U8(level)) => {
+                self.encoder_acceleration = level
+            }
+            _ => return Err(ConfigStatus::TypeMismatch),
+        }
+        Ok(())
+    }
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// `GetConfig` / `SetConfig` を処理して `ConfigResponse` を返す
+///
+/// それ以外の packet の場合は `None`。
+/// `supported` に含まれないキーには `UnsupportedKey` を返す
+pub fn handle_config_packet<S: ConfigStorage>(
+    config: &mut DeviceConfig,
+    storage: &mut S,
+    supported: &[ConfigKey],
+    packet: &AppPacketKind,
+) -> Option<AppPacketKind> {
+    let (key, status) = match *packet {
+        AppPacketKind::GetConfig { key } if !supported.contains(&key) => {
+            (key, ConfigStatus::UnsupportedKey)
+        }
+        AppPacketKind::GetConfig { key } => (key, ConfigStatus::Value(config.get(key))),
+        AppPacketKind::SetConfig { key, .. } if !supported.contains(&key) => {
+            (key, ConfigStatus::UnsupportedKey)
+        }
+        AppPacketKind::SetConfig { key, value } => match config.set(key, value) {
+            Ok(()) if storage.store(key, value).is_err() => {
+                (key, ConfigStatus::StorageFailed(value))
+            }
+            Ok(()) => (key, ConfigStatus::Value(config.get(key))),
+            Err(status) => (key, status),
+        },
+        _ => return None,
+    };
+
+    Some(AppPacketKind::ConfigResponse(ConfigResponse {
+        key,
+        status,
+    }))
+}
+
+fn key_index(key: ConfigKey) -> usize {
+    match key {
+        ConfigKey::DebounceMs => 0,
+        ConfigKey::LedBrightness => 1,
+        ConfigKey::ScanIntervalMs => 2,
+        ConfigKey::EncoderAcceleration => 3,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    const SUPPORTED: [ConfigKey; 3] = [
+        ConfigKey::DebounceMs,
+        ConfigKey::LedBrightness,
+        ConfigKey::ScanIntervalMs,
+    ];
+
+    struct FailingStorage;
+
+    impl ConfigStorage for FailingStorage {
+        type Error = ();
+
+        fn load(&mut self, _key: ConfigKey) -> Result<Option<ConfigValue>, Self::Error> {
+            Err(())
+        }
+
+        fn store(&mut self, _key: ConfigKey, _value: ConfigValue) -> Result<(), Self::Error> {
+            Err(())
+        }
+    }
+
+    fn response(key: ConfigKey, status: ConfigStatus) -> Option<AppPacketKind> {
+        Some(AppPacketKind::ConfigResponse(ConfigResponse {
+            key,
+            status,
+        }))
+    }
+
+    #[test]
+    fn set_config_updates_value_and_storage() {
+        let mut config = DeviceConfig::DEFAULT;
+        let mut storage = VolatileConfigStorage::new();
+
+        let reply = handle_config_packet(
+            &mut config,
+            &mut storage,
+            &SUPPORTED,
+            &AppPacketKind::SetConfig {
+                key: ConfigKey::ScanIntervalMs,
+                value: ConfigValue::U16(4),
+            },
+        );
+
+        assert_eq!(
+            reply,
+            response(
+                ConfigKey::ScanIntervalMs,
+                ConfigStatus::Value(ConfigValue::U16(4))
+            )
+        );
+        assert_eq!(config.scan_interval_ms, 4);
+        assert_eq!(DeviceConfig::load(&mut storage), config);
+    }
+
+    #[test]
+    fn invalid_or_unsupported_keys_are_rejected() {
+        let mut config = DeviceConfig::DEFAULT;
+        let mut storage = VolatileConfigStorage::new();
+
+        let mut send =
+            |packet| handle_config_packet(&mut config, &mut storage, &SUPPORTED, &packet);
+
+        assert_eq!(
+            send(AppPacketKind::SetConfig {
+                key: ConfigKey::DebounceMs,
+                value: ConfigValue::U8(3),
+            }),
+            response(ConfigKey::DebounceMs, ConfigStatus::TypeMismatch)
+        );
+        assert_eq!(
+            send(AppPacketKind::SetConfig {
+                key: ConfigKey::ScanIntervalMs,
+                value: ConfigValue::U16(0),
+            }),
+            response(ConfigKey::ScanIntervalMs, ConfigStatus::OutOfRange)
+        );
+        assert_eq!(
+            send(AppPacketKind::GetConfig {
+                key: ConfigKey::EncoderAcceleration,
+            }),
+            response(ConfigKey::EncoderAcceleration, ConfigStatus::UnsupportedKey)
+        );
+        assert_eq!(
+            send(AppPacketKind::GetConfig {
+                key: ConfigKey::DebounceMs,
+            }),
+            response(
+                ConfigKey::DebounceMs,
+                ConfigStatus::Value(ConfigValue::U16(DeviceConfig::DEFAULT.debounce_ms))
+            )
+        );
+        assert_eq!(send(AppPacketKind::RequestDescriptor { page: 0 }), None);
+        assert_eq!(config, DeviceConfig::DEFAULT);
+    }
+
+    #[test]
+    fn storage_failure_is_reported_after_applying() {
+        let mut config = DeviceConfig::DEFAULT;
+
+        let reply = handle_config_packet(
+            &mut config,
+            &mut FailingStorage,
+            &SUPPORTED,
+            &AppPacketKind::SetConfig {
+                key: ConfigKey::LedBrightness,
+                value: ConfigValue::U8(10),
+            },
+        );
+
+        assert_eq!(
+            reply,
+            response(
+                ConfigKey::LedBrightness,
+                ConfigStatus::StorageFailed(ConfigValue::U8(10))
+            )
+        );
+        assert_eq!(config.led_brightness, 10);
+        assert_eq!(
+            DeviceConfig::load(&mut FailingStorage),
+            DeviceConfig::DEFAULT
+        );
+    }
+}
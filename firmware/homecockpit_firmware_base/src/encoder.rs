@@ -0,0 +1,318 @@
+//! ロータリーエンコーダーの A/B 相から `EncoderDelta` イベントを作る
+//!
+//! `QuadratureDecoder` は gray code の遷移だけを数え、両相が同時に変わった遷移は捨てる。
+//! `EncoderInput` は 1 つのエンコーダーのステップを溜め、速く回したときの加速をかけて、
+//! `poll` で一定の間隔ごとにまとめた `EncoderDelta` を返す。
+
+use hcp::{BatchedControl, ControlValue};
+
+/// 何も指定しないときに `EncoderDelta` を送る最小の間隔
+pub const DEFAULT_ENCODER_INTERVAL_MS: u32 = 20;
+/// これより短い間隔で回したステップに加速をかける
+pub const ENCODER_ACCELERATION_WINDOW_MS: u32 = 100;
+
+/// 1 クリックあたりの遷移の数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EncoderResolution {
+    /// 1 クリックで 4 遷移 (gray code を 1 周する)
+    FullStep,
+    /// 1 クリックで 2 遷移
+    HalfStep,
+}
+
+impl EncoderResolution {
+    const fn transitions(self) -> i8 {
+        match self {
+            EncoderResolution::FullStep => 4,
+            EncoderResolution::HalfStep => 2,
+        }
+    }
+}
+
+/// 前の状態と今の状態 (`A << 1 | B`) から求める遷移の向き。`None` は不正な遷移
+///
+/// A が B より先に変わる向き (00 -> 10 -> 11 -> 01) を正にする
+const TRANSITIONS: [Option<i8>; 16] = [
+    Some(0),  // 00 -> 00
+    Some(-1), // 00 -> 01
+    Some(1),  // 00 -> 10
+    None,     // 00 -> 11
+    Some(1),  // 01 -> 00
+    Some(0),  // 01 -> 01
+    None,     // 01 -> 10
+    Some(-1), // 01 -> 11
+    Some(-1), // 10 -> 00
+    None,     // 10 -> 01
+    Some(0),  // 10 -> 10
+    Some(1),  // 10 -> 11
+    None,     // 11 -> 00
+    Some(1),  // 11 -> 01
+    Some(-1), // 11 -> 10
+    Some(0),  // 11 -> 11
+];
+
+/// A/B 相の gray code をクリック単位のステップに変える
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct QuadratureDecoder {
+    resolution: EncoderResolution,
+    state: Option<u8>,
+    transitions: i8,
+    invalid_transitions: u16,
+}
+
+impl QuadratureDecoder {
+    pub const fn new(resolution: EncoderResolution) -> Self {
+        Self {
+            resolution,
+            state: None,
+            transitions: 0,
+            invalid_transitions: 0,
+        }
+    }
+
+    /// 両相が同時に変わったために捨てた遷移の数。配線やサンプリング間隔の確認に使う
+    pub fn invalid_transitions(&self) -> u16 {
+        self.invalid_transitions
+    }
+
+    /// 今の A/B の値を取り込み、1 クリック分進んだら向き (`1` / `-1`) を返す
+    ///
+    /// 最初の呼び出しは状態を覚えるだけ。不正な遷移では途中まで数えた遷移を捨てる
+    pub fn update(&mut self, a: bool, b: bool) -> Option<i8> {
+        let state = (u8::from(a) << 1) | u8::from(b);
+        let previous = self.state.replace(state)?;
+        let Some(direction) = TRANSITIONS[usize::from(previous << 2 | state)] else {
+            self.invalid_transitions = self.invalid_transitions.saturating_add(1);
+            self.transitions = 0;
+            return None;
+        };
+        // 逆向きに変わったら途中まで数えた遷移を打ち消す
+        self.transitions += direction;
+        let per_step = self.resolution.transitions();
+        if self.transitions.abs() < per_step {
+            return None;
+        }
+        let step = self.transitions.signum();
+        self.transitions = 0;
+        Some(step)
+    }
+}
+
+/// 1 つのエンコーダーのステップの蓄積と加速
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EncoderInput {
+    control_id: u16,
+    decoder: QuadratureDecoder,
+    acceleration: u8,
+    interval_ms: u32,
+    pending: i32,
+    last_step: Option<(u64, i8)>,
+    last_sent_ms: Option<u64>,
+}
+
+impl EncoderInput {
+    pub const fn new(control_id: u16, resolution: EncoderResolution) -> Self {
+        Self {
+            control_id,
+            decoder: QuadratureDecoder::new(resolution),
+            acceleration: 0,
+            interval_ms: DEFAULT_ENCODER_INTERVAL_MS,
+            pending: 0,
+            last_step: None,
+            last_sent_ms: None,
+        }
+    }
+
+    /// `EncoderDelta` を送る最小の間隔
+    pub const fn with_interval(mut self, interval_ms: u32) -> Self {
+        self.interval_ms = interval_ms;
+        self
+    }
+
+    /// 加速度。`DeviceConfig::encoder_acceleration` の値を渡す。0 で加速なし
+    pub fn set_acceleration(&mut self, acceleration: u8) {
+        self.acceleration = acceleration;
+    }
+
+    pub fn control_id(&self) -> u16 {
+        self.control_id
+    }
+
+    pub fn decoder(&self) -> &QuadratureDecoder {
+        &self.decoder
+    }
+
+    /// まだ送っていないステップ数
+    pub fn pending_steps(&self) -> i32 {
+        self.pending
+    }
+
+    /// 今の A/B の値を取り込む
+    ///
+    /// 前のステップから `ENCODER_ACCELERATION_WINDOW_MS` 以内に同じ向きに回ったときは、
+    /// 間隔が短いほど大きく加速する
+    pub fn update(&mut self, now_ms: u64, a: bool, b: bool) {
+        let Some(direction) = self.decoder.update(a, b) else {
+            return;
+        };
+        let multiplier = match self.last_step {
+            Some((last_ms, last_direction)) if last_direction == direction => {
+                let window = u64::from(ENCODER_ACCELERATION_WINDOW_MS);
+                let elapsed = now_ms.saturating_sub(last_ms).min(window);
+                let boost = u64::from(self.acceleration) * (window - elapsed) / window;
+                i32::try_from(boost).unwrap_or(0) + 1
+            }
+            _ => 1,
+        };
+        self.last_step = Some((now_ms, direction));
+        self.pending = self
+            .pending
+            .saturating_add(i32::from(direction) * multiplier);
+    }
+
+    /// 溜まったステップを `EncoderDelta` にまとめて返す
+    ///
+    /// 前に送ってから `interval_ms` 経っていなければ `None`。
+    /// `i8` に収まらない分は次の呼び出しに残す
+    pub fn poll(&mut self, now_ms: u64) -> Option<BatchedControl> {
+        if self.pending == 0 {
+            return None;
+        }
+        if let Some(last_sent_ms) = self.last_sent_ms
+            && now_ms.saturating_sub(last_sent_ms) < u64::from(self.interval_ms)
+        {
+            return None;
+        }
+        let steps = self.pending.clamp(i32::from(i8::MIN), i32::from(i8::MAX));
+        self.pending -= steps;
+        self.last_sent_ms = Some(now_ms);
+        Some(BatchedControl {
+            control_id: self.control_id,
+            event: ControlValue::EncoderDelta {
+                steps: i8::try_from(steps).unwrap_or(0),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    /// 正の向きの gray code。`(A, B)`
+    const CLOCKWISE: [(bool, bool); 4] =
+        [(false, false), (true, false), (true, true), (false, true)];
+
+    /// gray code を `cycles` 周する A/B の列。負なら逆向き
+    fn sequence(cycles: i32) -> std::vec::Vec<(bool, bool)> {
+        let count = usize::try_from(cycles.unsigned_abs()).unwrap() * CLOCKWISE.len();
+        (1..=count)
+            .map(|index| {
+                let index = if cycles < 0 { 4 - index % 4 } else { index };
+                CLOCKWISE[index % 4]
+            })
+            .collect()
+    }
+
+    fn decode(decoder: &mut QuadratureDecoder, samples: &[(bool, bool)]) -> i32 {
+        samples
+            .iter()
+            .filter_map(|&(a, b)| decoder.update(a, b))
+            .map(i32::from)
+            .sum()
+    }
+
+    fn delta(event: Option<BatchedControl>) -> Option<i8> {
+        match event?.event {
+            ControlValue::EncoderDelta { steps } => Some(steps),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn full_and_half_step_count_clicks() {
+        let mut full = QuadratureDecoder::new(EncoderResolution::FullStep);
+        full.update(false, false);
+        assert_eq!(decode(&mut full, &sequence(3)), 3);
+        assert_eq!(decode(&mut full, &sequence(-2)), -2);
+
+        let mut half = QuadratureDecoder::new(EncoderResolution::HalfStep);
+        half.update(false, false);
+        assert_eq!(decode(&mut half, &sequence(3)), 6);
+        assert_eq!(half.invalid_transitions(), 0);
+    }
+
+    #[test]
+    fn bounce_and_invalid_transitions_do_not_count() {
+        let mut decoder = QuadratureDecoder::new(EncoderResolution::FullStep);
+        decoder.update(false, false);
+
+        // チャタリングで行き来しても 1 クリック
+        let bouncing = [
+            (true, false),
+            (false, false),
+            (true, false),
+            (true, true),
+            (false, true),
+            (true, true),
+            (false, true),
+            (false, false),
+        ];
+        assert_eq!(decode(&mut decoder, &bouncing), 1);
+
+        // 両相が同時に変わった遷移は捨てる
+        let skipped = [(true, false), (false, true), (false, false)];
+        assert_eq!(decode(&mut decoder, &skipped), 0);
+        assert_eq!(decoder.invalid_transitions(), 1);
+    }
+
+    #[test]
+    fn steps_are_coalesced_at_bounded_rate() {
+        let mut encoder = EncoderInput::new(0x0201, EncoderResolution::HalfStep).with_interval(20);
+        encoder.update(0, false, false);
+
+        let mut now_ms = 0;
+        for (a, b) in sequence(2) {
+            now_ms += 200;
+            encoder.update(now_ms, a, b);
+        }
+        assert_eq!(delta(encoder.poll(now_ms)), Some(4));
+        assert_eq!(encoder.poll(now_ms), None);
+
+        for (a, b) in sequence(-1) {
+            now_ms += 1;
+            encoder.update(now_ms, a, b);
+        }
+        // 送ってから 20ms 経つまで待つ
+        assert_eq!(encoder.poll(now_ms), None);
+        assert_eq!(delta(encoder.poll(now_ms + 20)), Some(-2));
+    }
+
+    #[test]
+    fn fast_turns_are_accelerated_and_split_into_i8() {
+        let mut encoder = EncoderInput::new(0x0201, EncoderResolution::FullStep);
+        encoder.set_acceleration(10);
+        encoder.update(0, false, false);
+
+        // ゆっくり回すと加速しない
+        let mut now_ms = 0;
+        for (a, b) in sequence(2) {
+            now_ms += 50;
+            encoder.update(now_ms, a, b);
+        }
+        assert_eq!(encoder.pending_steps(), 2);
+
+        // 2ms ごとの遷移 (1 クリック 8ms) は 1 + 10 * 92 / 100 = 10 倍
+        for (a, b) in sequence(20) {
+            now_ms += 2;
+            encoder.update(now_ms, a, b);
+        }
+        assert_eq!(encoder.pending_steps(), 2 + 20 * 10);
+        assert_eq!(delta(encoder.poll(now_ms)), Some(i8::MAX));
+        assert_eq!(delta(encoder.poll(now_ms + 20)), Some(75));
+    }
+}
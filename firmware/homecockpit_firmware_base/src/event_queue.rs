@@ -0,0 +1,396 @@
+//! 送信を待つ操作系のイベント
+//!
+//! アドレスが割り当てられる前やバスが詰まっている間に検出したイベントを `ControlEventQueue` に溜め、
+//! 送れるようになったら `next_packet` で検出した順に packet にする。
+//! packet を入れた frame の番号を `packet_queued` で記録し、master が受け取るまでイベントはキューに残す。
+//! キューが一杯のときの扱いは `OverflowPolicy` で選び、失った数は `overflows` で数える。
+
+use hcp::{AppPacketKind, BatchedControl, ControlValue, MAX_BATCHED_EVENTS};
+use heapless::Vec;
+
+use crate::{DeviceRuntimeState, FirmwareBaseError, build_control_event_packet};
+
+/// キューが一杯のときに新しいイベントをどう扱うか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OverflowPolicy {
+    /// 一番古いイベントを捨てて新しいイベントを入れる
+    DropOldest,
+    /// 同じ操作系のイベントが溜まっていれば最新の値に置き換える (エンコーダーはステップを足す)。
+    /// 溜まっていなければ一番古いイベントを捨てる
+    CoalesceLatest,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct QueuedControl {
+    detected_at_us: u64,
+    control: BatchedControl,
+}
+
+/// 送信を待つイベント
+///
+/// 受信確認を待っているイベントは先頭にあり、捨てたりまとめたりしない
+#[derive(Debug, Clone)]
+pub struct ControlEventQueue<const N: usize> {
+    events: Vec<QueuedControl, N>,
+    policy: OverflowPolicy,
+    overflows: u32,
+    /// 最後に `next_packet` で packet にしたイベントの数
+    built: usize,
+    /// 受信確認を待っている frame の番号と、その frame に入れたイベントの数
+    in_flight: Option<(u64, usize)>,
+}
+
+impl<const N: usize> ControlEventQueue<N> {
+    pub const fn new(policy: OverflowPolicy) -> Self {
+        Self {
+            events: Vec::new(),
+            policy,
+            overflows: 0,
+            built: 0,
+            in_flight: None,
+        }
+    }
+
+    /// 溜めたイベントの数 (受信確認を待っているものを含む)
+    pub fn pending(&self) -> usize {
+        self.events.len()
+    }
+
+    /// キューが一杯で、捨てたか最新の値にまとめたイベントの数 (起動してからの合計)
+    pub fn overflows(&self) -> u32 {
+        self.overflows
+    }
+
+    /// 受信確認を待っているイベントの数
+    fn in_flight_len(&self) -> usize {
+        self.in_flight.map_or(0, |(_, count)| count)
+    }
+
+    /// `detected_at_us` に検出したイベントを溜める
+    pub fn push(&mut self, detected_at_us: u64, control: BatchedControl) {
+        if self.events.is_full() {
+            self.overflows = self.overflows.saturating_add(1);
+            if self.policy == OverflowPolicy::CoalesceLatest && self.coalesce(&control) {
+                return;
+            }
+            // 全部が受信確認待ちなら新しいイベントを捨てる
+            let oldest = self.in_flight_len();
+            if oldest >= self.events.len() {
+                return;
+            }
+            self.events.remove(oldest);
+        }
+        // 一杯なら上で 1 つ空けている
+        let _ = self.events.push(QueuedControl {
+            detected_at_us,
+            control,
+        });
+    }
+
+    /// 送っていない同じ操作系の最後のイベントを `control` の値にする。まとめられなければ `false`
+    ///
+    /// 検出した時刻は最初のイベントのままにして、検出した順を崩さない
+    fn coalesce(&mut self, control: &BatchedControl) -> bool {
+        let in_flight = self.in_flight_len();
+        let Some(queued) = self
+            .events
+            .iter_mut()
+            .skip(in_flight)
+            .rev()
+            .find(|queued| queued.control.control_id == control.control_id)
+        else {
+            return false;
+        };
+        let event = match (&queued.control.event, &control.event) {
+            (
+                ControlValue::EncoderDelta {
+                    steps: queued_steps,
+                },
+                ControlValue::EncoderDelta { steps },
+            ) => ControlValue::EncoderDelta {
+                steps: queued_steps.saturating_add(*steps),
+            },
+            (ControlValue::RequestDeviceHello, _) | (_, ControlValue::RequestDeviceHello) => {
+                return false;
+            }
+            (_, event) => event.clone(),
+        };
+        queued.control.event = event;
+        true
+    }
+
+    /// ホストが `DeviceHello` を受け取っていれば、先頭から同じ時刻に検出したイベントをまとめて packet にする
+    ///
+    /// `Ready` になるまでと、前の packet の受信確認を待っている間は `Ok(None)` を返してイベントを残す。
+    /// packet を frame にして queue に入れたら、続けて `packet_queued` を呼ぶ。
+    /// 呼ばなければ次の呼び出しで同じイベントをもう一度 packet にする
+    pub fn next_packet(
+        &mut self,
+        state: &mut DeviceRuntimeState,
+    ) -> Result<Option<AppPacketKind>, FirmwareBaseError> {
+        self.release_acked(state);
+        self.built = 0;
+        if !state.is_ready() || self.in_flight.is_some() {
+            return Ok(None);
+        }
+        let Some(detected_at_us) = self.events.first().map(|queued| queued.detected_at_us) else {
+            return Ok(None);
+        };
+        let batch: Vec<BatchedControl, MAX_BATCHED_EVENTS> = self
+            .events
+            .iter()
+            .take_while(|queued| queued.detected_at_us == detected_at_us)
+            .take(MAX_BATCHED_EVENTS)
+            .map(|queued| queued.control.clone())
+            .collect();
+        let Some((packet, used)) = build_control_event_packet(state, &batch, Some(detected_at_us))?
+        else {
+            return Ok(None);
+        };
+        self.built = used;
+        Ok(Some(packet))
+    }
+
+    /// 最後に `next_packet` で作った packet を `frame` 番目の frame として queue に入れた
+    ///
+    /// `frame` は `DeviceRuntimeState::frame_queued` が返した番号
+    pub fn packet_queued(&mut self, frame: u64) {
+        if self.built > 0 {
+            self.in_flight = Some((frame, self.built));
+            self.built = 0;
+        }
+    }
+
+    /// master が受け取ったイベントをキューから消す
+    fn release_acked(&mut self, state: &DeviceRuntimeState) {
+        let Some((frame, count)) = self.in_flight else {
+            return;
+        };
+        if !state.is_frame_acked(frame) {
+            return;
+        }
+        self.events.drain(..count.min(self.events.len()));
+        self.in_flight = None;
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::{DeviceDescriptor, IMCP_MASTER_ADDRESS, lifecycle::DeviceAction};
+    use hcp::{Capabilities, DeviceKind, ProtocolVersions, Version};
+    use imcp::frame::{Address, Frame, FramePayload};
+
+    const DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
+        device_id: 0x1234,
+        device_kind: DeviceKind::ButtonPanel,
+        firmware_version: Version {
+            major: 0,
+            minor: 1,
+            patch: 0,
+        },
+        capabilities: Capabilities {
+            displays: 0,
+            controls: 4,
+            features: hcp::FEATURE_CONTROL_EVENTS,
+        },
+    };
+
+    fn button(control_id: u16, pressed: bool) -> BatchedControl {
+        BatchedControl {
+            control_id,
+            event: ControlValue::Button { pressed },
+        }
+    }
+
+    fn master_ack() -> Frame {
+        Frame::new(
+            Address::Unicast(0x02),
+            IMCP_MASTER_ADDRESS,
+            FramePayload::Ack(IMCP_MASTER_ADDRESS),
+        )
+    }
+
+    /// アドレスを割り当てた `state` を `DeviceHello` の受信確認まで進める
+    fn acknowledge_hello(state: &mut DeviceRuntimeState) {
+        let Ok(Some(DeviceAction::Send(hello))) = state.poll(0, &DESCRIPTOR) else {
+            unreachable!();
+        };
+        state.frame_queued(&hello);
+        state.handle_frame(0, &master_ack(), &DESCRIPTOR).unwrap();
+        assert!(state.is_ready());
+    }
+
+    /// 次の packet を frame にして queue に入れ、受信確認は受け取らない
+    fn queue_next_packet(
+        queue: &mut ControlEventQueue<4>,
+        state: &mut DeviceRuntimeState,
+    ) -> Option<AppPacketKind> {
+        let packet = queue.next_packet(state).unwrap()?;
+        let frame = crate::encode_set_frame(state, &packet).unwrap();
+        queue.packet_queued(state.frame_queued(&frame).unwrap());
+        Some(packet)
+    }
+
+    fn events_of(packet: AppPacketKind) -> std::vec::Vec<BatchedControl> {
+        match packet {
+            AppPacketKind::ControlEvent(event) => std::vec![BatchedControl {
+                control_id: event.control_id,
+                event: event.event,
+            }],
+            AppPacketKind::ControlEventBatch(batch) => batch.events.into_iter().collect(),
+            _ => unreachable!(),
+        }
+    }
+
+    /// 受信確認を返しながら全部の packet を取り出す
+    fn popped_events(
+        queue: &mut ControlEventQueue<4>,
+        state: &mut DeviceRuntimeState,
+    ) -> std::vec::Vec<BatchedControl> {
+        core::iter::from_fn(|| {
+            let packet = queue_next_packet(queue, state)?;
+            state.handle_frame(0, &master_ack(), &DESCRIPTOR).unwrap();
+            Some(packet)
+        })
+        .flat_map(events_of)
+        .collect()
+    }
+
+    #[test]
+    fn events_wait_for_ready_and_replay_in_order() {
+        let mut queue = ControlEventQueue::<4>::new(OverflowPolicy::DropOldest);
+        queue.push(10, button(1, true));
+        queue.push(10, button(2, true));
+        queue.push(20, button(1, false));
+
+        let mut state = DeviceRuntimeState::new();
+        assert_eq!(queue.next_packet(&mut state), Ok(None));
+        assert_eq!(queue.pending(), 3);
+
+        state.assign_address(0x02);
+        assert_eq!(queue.next_packet(&mut state), Ok(None));
+        state.accept_host_hello(ProtocolVersions::SUPPORTED);
+        acknowledge_hello(&mut state);
+        // 同じ時刻のイベントは 1 つの packet にまとめる
+        let Some(AppPacketKind::ControlEventBatch(batch)) =
+            queue_next_packet(&mut queue, &mut state)
+        else {
+            unreachable!();
+        };
+        assert_eq!(batch.first_seq, 0);
+        assert_eq!(batch.events.len(), 2);
+        // master が受け取るまでイベントを残し、次の packet も作らない
+        assert_eq!(queue.next_packet(&mut state), Ok(None));
+        assert_eq!(queue.pending(), 3);
+        state.handle_frame(0, &master_ack(), &DESCRIPTOR).unwrap();
+        assert_eq!(popped_events(&mut queue, &mut state), [button(1, false)]);
+        assert_eq!(state.next_control_seq(), 3);
+        assert_eq!(queue.overflows(), 0);
+    }
+
+    #[test]
+    fn drop_oldest_counts_overflows() {
+        let mut queue = ControlEventQueue::<4>::new(OverflowPolicy::DropOldest);
+        for control_id in 0..6 {
+            queue.push(u64::from(control_id), button(control_id, true));
+        }
+        assert_eq!(queue.overflows(), 2);
+
+        let mut state = DeviceRuntimeState::new();
+        state.assign_address(0x02);
+        acknowledge_hello(&mut state);
+        let ids: std::vec::Vec<u16> = popped_events(&mut queue, &mut state)
+            .iter()
+            .map(|event| event.control_id)
+            .collect();
+        assert_eq!(ids, [2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn coalesce_keeps_latest_value_per_control() {
+        let mut queue = ControlEventQueue::<4>::new(OverflowPolicy::CoalesceLatest);
+        let encoder = |steps| BatchedControl {
+            control_id: 9,
+            event: ControlValue::EncoderDelta { steps },
+        };
+        queue.push(0, button(1, true));
+        queue.push(1, encoder(3));
+        queue.push(2, button(2, true));
+        queue.push(3, button(1, false));
+
+        // 一杯になったら同じ操作系のイベントをまとめる
+        queue.push(4, button(2, false));
+        queue.push(5, encoder(-1));
+        queue.push(6, encoder(126));
+        assert_eq!(queue.pending(), 4);
+        assert_eq!(queue.overflows(), 3);
+
+        // 溜まっていない操作系は一番古いイベントを押し出す
+        queue.push(7, button(3, true));
+        assert_eq!(queue.overflows(), 4);
+
+        let mut state = DeviceRuntimeState::new();
+        state.assign_address(0x02);
+        acknowledge_hello(&mut state);
+        assert_eq!(
+            popped_events(&mut queue, &mut state),
+            [
+                encoder(i8::MAX),
+                button(2, false),
+                button(1, false),
+                button(3, true)
+            ]
+        );
+    }
+
+    #[test]
+    fn coalesce_keeps_first_detected_time() {
+        let mut queue = ControlEventQueue::<4>::new(OverflowPolicy::CoalesceLatest);
+        let encoder = |steps| BatchedControl {
+            control_id: 9,
+            event: ControlValue::EncoderDelta { steps },
+        };
+        queue.push(0, button(1, true));
+        queue.push(0, encoder(1));
+        queue.push(1, button(2, true));
+        queue.push(2, button(3, true));
+        queue.push(3, encoder(2));
+
+        let mut state = DeviceRuntimeState::new();
+        state.assign_address(0x02);
+        state.accept_host_hello(ProtocolVersions::SUPPORTED);
+        acknowledge_hello(&mut state);
+        // まとめたイベントは最初に検出した時刻の packet に入る
+        let packet = queue_next_packet(&mut queue, &mut state).unwrap();
+        assert_eq!(events_of(packet), [button(1, true), encoder(3)]);
+    }
+
+    #[test]
+    fn overflow_keeps_events_waiting_for_ack() {
+        let mut queue = ControlEventQueue::<4>::new(OverflowPolicy::CoalesceLatest);
+        let mut state = DeviceRuntimeState::new();
+        state.assign_address(0x02);
+        acknowledge_hello(&mut state);
+
+        queue.push(0, button(1, true));
+        queue_next_packet(&mut queue, &mut state).unwrap();
+        queue.push(1, button(2, true));
+        queue.push(2, button(3, true));
+        queue.push(3, button(4, true));
+
+        // 受信確認待ちのイベントにはまとめず、送っていない一番古いイベントを捨てる
+        queue.push(4, button(1, false));
+        assert_eq!(queue.overflows(), 1);
+        assert_eq!(queue.pending(), 4);
+
+        state.handle_frame(0, &master_ack(), &DESCRIPTOR).unwrap();
+        assert_eq!(
+            popped_events(&mut queue, &mut state),
+            [button(3, true), button(4, true), button(1, false)]
+        );
+        assert_eq!(queue.pending(), 0);
+    }
+}
@@ -0,0 +1,139 @@
+//! `Identify` で LED や表示を点滅させて、同じ種類のボードのどれかを知らせる
+//!
+//! 点滅のさせ方はボードごとに違うため `IdentifyOutput` で抽象化する。
+//! `Identifier` は残り時間と点滅の周期だけを持ち、メインループから `poll` で呼ぶ。
+
+use hcp::AppPacketKind;
+use imcp::frame::{Frame, FramePayload};
+
+/// 点滅させる LED や表示
+pub trait IdentifyOutput {
+    /// `on` の間だけ点灯させる。止めたときは `false` で呼ぶ
+    fn set_identify(&mut self, on: bool);
+}
+
+/// 点滅の残り時間と周期
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Identifier {
+    half_period_ms: u32,
+    started_ms: u64,
+    until_ms: Option<u64>,
+    lit: bool,
+}
+
+impl Identifier {
+    /// `half_period_ms` ごとに点灯と消灯を切り替える
+    pub const fn new(half_period_ms: u32) -> Self {
+        Self {
+            half_period_ms,
+            started_ms: 0,
+            until_ms: None,
+            lit: false,
+        }
+    }
+
+    /// `seconds` 秒間点滅させる。0 なら止める
+    pub fn start(&mut self, now_ms: u64, seconds: u8) {
+        if seconds == 0 {
+            self.stop();
+            return;
+        }
+        self.started_ms = now_ms;
+        self.until_ms = Some(now_ms.saturating_add(u64::from(seconds) * 1_000));
+    }
+
+    pub fn stop(&mut self) {
+        self.until_ms = None;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.until_ms.is_some()
+    }
+
+    /// 時間切れを確認し、点灯状態が変わったときだけ `output` に反映する
+    pub fn poll(&mut self, now_ms: u64, output: &mut impl IdentifyOutput) {
+        let lit = match self.until_ms {
+            Some(until_ms) if now_ms < until_ms => {
+                let half_period_ms = u64::from(self.half_period_ms.max(1));
+                (now_ms.saturating_sub(self.started_ms) / half_period_ms).is_multiple_of(2)
+            }
+            Some(_) => {
+                self.until_ms = None;
+                false
+            }
+            None => false,
+        };
+        if lit != self.lit {
+            self.lit = lit;
+            output.set_identify(lit);
+        }
+    }
+}
+
+/// `Identify` を含む frame なら点滅させる秒数を返す
+pub fn identify_request_from_frame(frame: &Frame) -> Option<u8> {
+    let FramePayload::Set(payload) = frame.payload() else {
+        return None;
+    };
+    match hcp::decode_set_packet(payload) {
+        Ok(AppPacketKind::Identify { seconds }) => Some(seconds),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::IMCP_MASTER_ADDRESS;
+    use imcp::frame::Address;
+
+    #[derive(Default)]
+    struct RecordingOutput {
+        changes: std::vec::Vec<bool>,
+    }
+
+    impl IdentifyOutput for RecordingOutput {
+        fn set_identify(&mut self, on: bool) {
+            self.changes.push(on);
+        }
+    }
+
+    #[test]
+    fn blinks_until_timeout() {
+        let mut identifier = Identifier::new(250);
+        let mut output = RecordingOutput::default();
+
+        identifier.poll(0, &mut output);
+        assert!(output.changes.is_empty());
+
+        identifier.start(1_000, 1);
+        for now_ms in (1_000..=2_100).step_by(50) {
+            identifier.poll(now_ms, &mut output);
+        }
+        assert_eq!(output.changes, [true, false, true, false]);
+        assert!(!identifier.is_active());
+    }
+
+    #[test]
+    fn zero_seconds_stops_blinking() {
+        let mut identifier = Identifier::new(250);
+        let mut output = RecordingOutput::default();
+
+        let frame = Frame::new(
+            Address::Unicast(0x22),
+            IMCP_MASTER_ADDRESS,
+            FramePayload::Set(
+                hcp::encode_set_packet(&AppPacketKind::Identify { seconds: 10 }).unwrap(),
+            ),
+        );
+        identifier.start(0, identify_request_from_frame(&frame).unwrap());
+        identifier.poll(0, &mut output);
+        identifier.start(100, 0);
+        identifier.poll(100, &mut output);
+
+        assert_eq!(output.changes, [true, false]);
+        assert!(!identifier.is_active());
+    }
+}
@@ -1,19 +1,70 @@
 #![cfg_attr(not(test), no_std)]
 
+use core::fmt::{self, Write};
+
 use hcp::{
-    APP_PROTOCOL_VERSION, AppPacketError, AppPacketKind, Capabilities, ControlEvent, ControlValue,
-    DeviceHello, DeviceKind, Version, encode_set_packet,
+    APP_PROTOCOL_VERSION, AppPacketError, AppPacketKind, BatchedControl, Capabilities, ClockOffset,
+    ControlDescriptor, ControlEvent, ControlEventBatch, ControlKind, ControlStateSnapshot,
+    ControlValue, DescriptorEntry, DescriptorPage, DeviceHello, DeviceKind, MAX_BATCHED_EVENTS,
+    MIN_APP_PROTOCOL_VERSION, ProtocolVersions, SnapshotError, TimeSync, TimestampedControlEvents,
+    Version, decode_set_packet, decode_time_sync_packet, encode_set_packet_with_version,
 };
 use imcp::frame::{Address, Frame, FramePayload};
 
+pub mod axis;
+pub mod config;
+pub mod encoder;
+pub mod event_queue;
+pub mod identify;
+pub mod lifecycle;
+pub mod logger;
+pub mod matrix;
+pub mod panel;
+pub mod pwm;
+pub mod update;
+
+pub use axis::{AxisInput, handle_calibration_packet};
+pub use config::{ConfigStorage, DeviceConfig, VolatileConfigStorage, handle_config_packet};
+pub use encoder::{EncoderInput, EncoderResolution, QuadratureDecoder};
+pub use event_queue::{ControlEventQueue, OverflowPolicy};
+pub use identify::{Identifier, IdentifyOutput, identify_request_from_frame};
+pub use lifecycle::{DeviceAction, DevicePhase, is_device_hello_request};
+pub use logger::DeviceLogger;
+pub use matrix::{MatrixPins, MatrixScanner, debounce_samples};
+pub use panel::{Panel, PanelControl};
+pub use pwm::{PwmChannel, PwmOutput, pwm_level_from_frame};
+pub use update::{FirmwareUpdater, MemoryUpdateFlash, UpdateFlash, handle_update_packet};
+
 pub const IMCP_MASTER_ADDRESS: u8 = 0x01;
-pub const FEATURE_CONTROL_EVENTS: u32 = 1 << 0;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum FirmwareBaseError {
     Packet(AppPacketError),
     DeviceAddressUnassigned,
+    Snapshot(SnapshotError),
+}
+
+impl fmt::Display for FirmwareBaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FirmwareBaseError::Packet(error) => write!(f, "packet error: {error}"),
+            FirmwareBaseError::DeviceAddressUnassigned => {
+                write!(f, "device address is not assigned yet")
+            }
+            FirmwareBaseError::Snapshot(error) => write!(f, "control state error: {error}"),
+        }
+    }
+}
+
+impl core::error::Error for FirmwareBaseError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            FirmwareBaseError::Packet(error) => Some(error),
+            FirmwareBaseError::DeviceAddressUnassigned => None,
+            FirmwareBaseError::Snapshot(error) => Some(error),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -21,6 +72,13 @@ pub enum FirmwareBaseError {
 pub struct DeviceRuntimeState {
     address: Option<u8>,
     next_control_seq: u16,
+    protocol_version: u8,
+    clock: ClockOffset,
+    phase: DevicePhase,
+    /// master 宛てに queue に入れた `Set` frame の数
+    queued_frames: u64,
+    /// master から受け取った `Ack` の数。IMCP は 1 つずつ送るので、`Ack` は queue に入れた順に届く
+    acked_frames: u64,
 }
 
 impl DeviceRuntimeState {
@@ -28,6 +86,11 @@ impl DeviceRuntimeState {
         Self {
             address: None,
             next_control_seq: 0,
+            protocol_version: MIN_APP_PROTOCOL_VERSION,
+            clock: ClockOffset::new(),
+            phase: DevicePhase::Idle,
+            queued_frames: 0,
+            acked_frames: 0,
         }
     }
 
@@ -35,9 +98,43 @@ impl DeviceRuntimeState {
         self.address
     }
 
+    /// 送信に使う HCP のバージョン。`HostHello` を受け取るまでは最も古いバージョン
+    pub fn protocol_version(&self) -> u8 {
+        self.protocol_version
+    }
+
+    /// アドレスが変わるとホストも変わりうるので、バージョン交渉と時計合わせと `DeviceHello` もやり直す
     pub fn assign_address(&mut self, address: u8) {
         self.address = Some(address);
         self.next_control_seq = 0;
+        self.protocol_version = MIN_APP_PROTOCOL_VERSION;
+        self.clock.reset();
+        self.phase = DevicePhase::Announcing {
+            last_hello_us: None,
+            hello_frame: None,
+        };
+    }
+
+    /// `local_us` に受信した `TimeSync` でホストの時計との差を更新する
+    pub fn accept_time_sync(&mut self, local_us: u64, sync: &TimeSync) {
+        self.clock.sync(local_us, sync);
+    }
+
+    /// デバイスの時刻をホストの時計に換算する。`TimeSync` を受け取るまでは `None`
+    pub fn host_time_us(&self, local_us: u64) -> Option<u64> {
+        self.clock.host_time_us(local_us)
+    }
+
+    /// ホストの対応範囲と交渉する。共通のバージョンがない場合は変更せず `None`
+    pub fn accept_host_hello(&mut self, host: ProtocolVersions) -> Option<u8> {
+        let version = ProtocolVersions::SUPPORTED.negotiate(host)?;
+        self.protocol_version = version;
+        Some(version)
+    }
+
+    /// 次に送るイベントの seq
+    pub fn next_control_seq(&self) -> u16 {
+        self.next_control_seq
     }
 
     pub fn take_next_control_seq(&mut self) -> Result<u16, FirmwareBaseError> {
@@ -70,16 +167,104 @@ impl DeviceDescriptor {
     pub fn protocol_version(&self) -> u8 {
         APP_PROTOCOL_VERSION
     }
+
+    pub fn min_protocol_version(&self) -> u8 {
+        MIN_APP_PROTOCOL_VERSION
+    }
 }
 
 pub fn control_id_from_matrix_position(row: u8, column: u8, columns: u8) -> u16 {
     u16::from(row) * u16::from(columns) + u16::from(column)
 }
 
-pub fn try_assign_address_from_frame(
-    state: &mut DeviceRuntimeState,
-    frame: &Frame,
-) -> Option<u8> {
+/// ボタンマトリクス上のスイッチの descriptor。ラベルは `R{row}C{column}`
+pub fn matrix_button_descriptor(row: u8, column: u8, columns: u8) -> ControlDescriptor {
+    let mut descriptor = ControlDescriptor {
+        id: control_id_from_matrix_position(row, column, columns),
+        kind: ControlKind::Button,
+        events: ControlKind::Button.default_events(),
+        label: Default::default(),
+    };
+    // "R255C255" でも MAX_LABEL_LEN に収まる
+    let _ = write!(descriptor.label, "R{row}C{column}");
+    descriptor
+}
+
+/// `RequestDescriptor` を含む frame なら要求されたページを返す
+pub fn descriptor_request_from_frame(frame: &Frame) -> Option<u16> {
+    let FramePayload::Set(payload) = frame.payload() else {
+        return None;
+    };
+    match decode_set_packet(payload) {
+        Ok(AppPacketKind::RequestDescriptor { page }) => Some(page),
+        _ => None,
+    }
+}
+
+/// `HostHello` を含む frame ならホストの対応範囲を返す
+pub fn host_hello_from_frame(frame: &Frame) -> Option<ProtocolVersions> {
+    let FramePayload::Set(payload) = frame.payload() else {
+        return None;
+    };
+    match decode_set_packet(payload) {
+        Ok(AppPacketKind::HostHello(versions)) => Some(versions),
+        _ => None,
+    }
+}
+
+/// broadcast の `TimeSync` を含む frame ならホストの時計を返す
+pub fn time_sync_from_frame(frame: &Frame) -> Option<TimeSync> {
+    let FramePayload::Data(payload) = frame.payload() else {
+        return None;
+    };
+    decode_time_sync_packet(payload).ok()
+}
+
+/// `RequestControlState` を含む frame か
+pub fn is_control_state_request(frame: &Frame) -> bool {
+    let FramePayload::Set(payload) = frame.payload() else {
+        return false;
+    };
+    matches!(
+        decode_set_packet(payload),
+        Ok(AppPacketKind::RequestControlState)
+    )
+}
+
+/// 現在の状態から `ControlStateSnapshot` を作る
+///
+/// `switches` は `first_switch_id` から連番の control ID のボタン / トグル、
+/// `values` は軸 / エンコーダーの `(control_id, value)`
+pub fn build_control_state_packet(
+    state: &DeviceRuntimeState,
+    first_switch_id: u16,
+    switches: impl IntoIterator<Item = bool>,
+    values: impl IntoIterator<Item = (u16, i16)>,
+) -> Result<AppPacketKind, FirmwareBaseError> {
+    let mut snapshot = ControlStateSnapshot::new(state.next_control_seq(), first_switch_id);
+    for on in switches {
+        snapshot
+            .push_switch(on)
+            .map_err(FirmwareBaseError::Snapshot)?;
+    }
+    for (control_id, value) in values {
+        snapshot
+            .push_value(control_id, value)
+            .map_err(FirmwareBaseError::Snapshot)?;
+    }
+    Ok(AppPacketKind::ControlStateSnapshot(snapshot))
+}
+
+/// 要求されたページの `DescriptorPage` を作る。範囲外のページは `None`
+pub fn build_descriptor_page_packet(
+    entry_count: usize,
+    page: u16,
+    entry_at: impl FnMut(usize) -> Option<DescriptorEntry>,
+) -> Option<AppPacketKind> {
+    DescriptorPage::from_fn(entry_count, page, entry_at).map(AppPacketKind::DescriptorPage)
+}
+
+pub fn try_assign_address_from_frame(state: &mut DeviceRuntimeState, frame: &Frame) -> Option<u8> {
     match frame.payload() {
         FramePayload::SetAddress { address, .. } => {
             state.assign_address(*address);
@@ -94,6 +279,7 @@ pub fn build_device_hello_packet(descriptor: DeviceDescriptor) -> AppPacketKind
         device_id: descriptor.device_id,
         device_kind: descriptor.device_kind,
         protocol_version: descriptor.protocol_version(),
+        min_protocol_version: descriptor.min_protocol_version(),
         firmware_version: descriptor.firmware_version,
         capabilities: descriptor.capabilities,
     })
@@ -112,11 +298,79 @@ pub fn build_button_control_event(
     }))
 }
 
+/// 溜まった操作イベントを先頭から 1 つの packet にまとめ、使った件数と一緒に返す
+///
+/// `detected_at_us` があり、`TimeSync` を受け取っていて交渉したバージョンに `TimestampedControlEvents` があれば、
+/// ホストの時計での検出時刻を付ける。
+/// 時刻を付けない場合、1 件だけか交渉したバージョンに `ControlEventBatch` がなければ `ControlEvent` にする。
+/// `events` が空なら `None`
+pub fn build_control_event_packet(
+    state: &mut DeviceRuntimeState,
+    events: &[BatchedControl],
+    detected_at_us: Option<u64>,
+) -> Result<Option<(AppPacketKind, usize)>, FirmwareBaseError> {
+    let Some(first) = events.first() else {
+        return Ok(None);
+    };
+    let empty_batch = ControlEventBatch {
+        first_seq: 0,
+        events: Default::default(),
+    };
+    let timestamp_us = detected_at_us
+        .and_then(|detected_at_us| state.host_time_us(detected_at_us))
+        .filter(|&timestamp_us| {
+            AppPacketKind::TimestampedControlEvents(TimestampedControlEvents {
+                timestamp_us,
+                batch: empty_batch.clone(),
+            })
+            .is_available_in(state.protocol_version())
+        });
+    let batch_available = timestamp_us.is_some()
+        || AppPacketKind::ControlEventBatch(empty_batch).is_available_in(state.protocol_version());
+    let count = if batch_available {
+        events.len().min(MAX_BATCHED_EVENTS)
+    } else {
+        1
+    };
+
+    let first_seq = state.take_next_control_seq()?;
+    if count == 1 && timestamp_us.is_none() {
+        let packet = AppPacketKind::ControlEvent(ControlEvent {
+            seq: first_seq,
+            control_id: first.control_id,
+            event: first.event.clone(),
+        });
+        return Ok(Some((packet, 1)));
+    }
+
+    // 残りのイベントの seq も確保する
+    for _ in 1..count {
+        state.take_next_control_seq()?;
+    }
+    let batch = ControlEventBatch {
+        first_seq,
+        events: events[..count].iter().cloned().collect(),
+    };
+    let packet = match timestamp_us {
+        Some(timestamp_us) => AppPacketKind::TimestampedControlEvents(TimestampedControlEvents {
+            timestamp_us,
+            batch,
+        }),
+        None => AppPacketKind::ControlEventBatch(batch),
+    };
+    Ok(Some((packet, count)))
+}
+
+/// 割り当て済みのアドレスから、交渉済みのバージョンで master 宛ての frame を作る
 pub fn encode_set_frame(
-    from_address: u8,
+    state: &DeviceRuntimeState,
     kind: &AppPacketKind,
 ) -> Result<Frame, FirmwareBaseError> {
-    let payload = encode_set_packet(kind).map_err(FirmwareBaseError::Packet)?;
+    let from_address = state
+        .address()
+        .ok_or(FirmwareBaseError::DeviceAddressUnassigned)?;
+    let payload = encode_set_packet_with_version(state.protocol_version(), kind)
+        .map_err(FirmwareBaseError::Packet)?;
     Ok(Frame::new(
         Address::Unicast(IMCP_MASTER_ADDRESS),
         from_address,
@@ -157,10 +411,59 @@ mod tests {
         );
     }
 
+    fn host_frame(kind: &AppPacketKind) -> Frame {
+        Frame::new(
+            Address::Unicast(0x22),
+            IMCP_MASTER_ADDRESS,
+            FramePayload::Set(hcp::encode_set_packet(kind).unwrap()),
+        )
+    }
+
+    #[test]
+    fn descriptor_request_is_answered_with_matrix_buttons() {
+        let request = host_frame(&AppPacketKind::RequestDescriptor { page: 1 });
+        let page = descriptor_request_from_frame(&request).unwrap();
+
+        let packet = build_descriptor_page_packet(40, page, |index| {
+            let index = u8::try_from(index).ok()?;
+            Some(DescriptorEntry::Control(matrix_button_descriptor(
+                index / 5,
+                index % 5,
+                5,
+            )))
+        })
+        .unwrap();
+        let AppPacketKind::DescriptorPage(page) = packet else {
+            unreachable!();
+        };
+
+        assert_eq!(page.page, 1);
+        assert_eq!(page.total_pages, 10);
+        let DescriptorEntry::Control(first) = &page.entries[0] else {
+            unreachable!();
+        };
+        assert_eq!(first.id, 4);
+        assert_eq!(first.label.as_str(), "R0C4");
+        let mut state = DeviceRuntimeState::new();
+        state.assign_address(0x22);
+        state.accept_host_hello(ProtocolVersions::SUPPORTED);
+        assert!(encode_set_frame(&state, &AppPacketKind::DescriptorPage(page)).is_ok());
+    }
+
     #[test]
     fn encode_set_frame_targets_master() {
+        let mut state = DeviceRuntimeState::new();
+        assert_eq!(
+            encode_set_frame(
+                &state,
+                &AppPacketKind::HostHello(ProtocolVersions::SUPPORTED)
+            ),
+            Err(FirmwareBaseError::DeviceAddressUnassigned)
+        );
+
+        state.assign_address(0x22);
         let frame = encode_set_frame(
-            0x22,
+            &state,
             &build_device_hello_packet(DeviceDescriptor {
                 device_id: 0x0123_4567_89AB_CDEF,
                 device_kind: DeviceKind::ButtonPanel,
@@ -172,7 +475,7 @@ mod tests {
                 capabilities: Capabilities {
                     displays: 1,
                     controls: 8,
-                    features: FEATURE_CONTROL_EVENTS,
+                    features: hcp::FEATURE_CONTROL_EVENTS,
                 },
             }),
         )
@@ -182,4 +485,178 @@ mod tests {
         assert_eq!(frame.from_address(), 0x22);
         assert!(matches!(frame.payload(), FramePayload::Set(_)));
     }
+
+    #[test]
+    fn protocol_version_is_negotiated_by_host_hello() {
+        let mut state = DeviceRuntimeState::new();
+        state.assign_address(0x22);
+        assert_eq!(state.protocol_version(), MIN_APP_PROTOCOL_VERSION);
+
+        // v1 のホストでも読めるよう、最初の DeviceHello は最も古い layout で送る
+        let hello = build_device_hello_packet(DeviceDescriptor {
+            device_id: 1,
+            device_kind: DeviceKind::UpperPanelDdi,
+            firmware_version: Version {
+                major: 0,
+                minor: 1,
+                patch: 0,
+            },
+            capabilities: Capabilities {
+                displays: 0,
+                controls: 0,
+                features: 0,
+            },
+        });
+        let FramePayload::Set(payload) =
+            encode_set_frame(&state, &hello).unwrap().payload().clone()
+        else {
+            unreachable!();
+        };
+        assert_eq!(payload[0], MIN_APP_PROTOCOL_VERSION);
+
+        let host = host_hello_from_frame(&host_frame(&AppPacketKind::HostHello(
+            ProtocolVersions::SUPPORTED,
+        )))
+        .unwrap();
+        assert_eq!(state.accept_host_hello(host), Some(APP_PROTOCOL_VERSION));
+        assert_eq!(
+            state.accept_host_hello(ProtocolVersions { min: 9, max: 9 }),
+            None
+        );
+        assert_eq!(state.protocol_version(), APP_PROTOCOL_VERSION);
+
+        state.assign_address(0x23);
+        assert_eq!(state.protocol_version(), MIN_APP_PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn control_events_are_coalesced_after_negotiation() {
+        let events: [BatchedControl; 20] = core::array::from_fn(|index| BatchedControl {
+            control_id: u16::try_from(index).unwrap(),
+            event: ControlValue::Button { pressed: true },
+        });
+        let mut state = DeviceRuntimeState::new();
+        state.assign_address(0x22);
+
+        // v1 のホストには 1 件ずつ送る
+        let (packet, used) = build_control_event_packet(&mut state, &events, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(used, 1);
+        assert!(matches!(
+            packet,
+            AppPacketKind::ControlEvent(ControlEvent { seq: 0, .. })
+        ));
+
+        state.accept_host_hello(ProtocolVersions::SUPPORTED);
+        let (packet, used) = build_control_event_packet(&mut state, &events[1..], None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(used, MAX_BATCHED_EVENTS);
+        let AppPacketKind::ControlEventBatch(batch) = &packet else {
+            unreachable!();
+        };
+        let expanded: Vec<ControlEvent> = batch.control_events().collect();
+        assert_eq!(expanded.len(), MAX_BATCHED_EVENTS);
+        assert_eq!((expanded[0].seq, expanded[0].control_id), (1, 1));
+        assert_eq!((expanded[15].seq, expanded[15].control_id), (16, 16));
+        assert!(encode_set_frame(&state, &packet).is_ok());
+
+        let (packet, used) = build_control_event_packet(&mut state, &events[17..19], None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(used, 2);
+        let AppPacketKind::ControlEventBatch(batch) = packet else {
+            unreachable!();
+        };
+        assert_eq!(batch.first_seq, 17);
+        assert_eq!(state.take_next_control_seq().unwrap(), 19);
+        assert_eq!(
+            build_control_event_packet(&mut state, &[], None).unwrap(),
+            None
+        );
+    }
+
+    fn data_frame(sync: &TimeSync) -> Frame {
+        Frame::new(
+            Address::Broadcast,
+            IMCP_MASTER_ADDRESS,
+            FramePayload::Data(hcp::encode_time_sync_packet(sync).unwrap()),
+        )
+    }
+
+    #[test]
+    fn control_events_carry_host_time_after_time_sync() {
+        let events = [BatchedControl {
+            control_id: 3,
+            event: ControlValue::Button { pressed: true },
+        }];
+        let mut state = DeviceRuntimeState::new();
+        state.assign_address(0x22);
+        state.accept_host_hello(ProtocolVersions::SUPPORTED);
+
+        // 時計を合わせるまでは時刻を付けない
+        let (packet, _) = build_control_event_packet(&mut state, &events, Some(1_000))
+            .unwrap()
+            .unwrap();
+        assert!(matches!(packet, AppPacketKind::ControlEvent(_)));
+
+        let sync = time_sync_from_frame(&data_frame(&TimeSync {
+            host_time_us: 50_000,
+        }))
+        .unwrap();
+        assert_eq!(
+            time_sync_from_frame(&host_frame(&AppPacketKind::RequestControlState)),
+            None
+        );
+        state.accept_time_sync(2_000, &sync);
+
+        let (packet, used) = build_control_event_packet(&mut state, &events, Some(2_500))
+            .unwrap()
+            .unwrap();
+        assert_eq!(used, 1);
+        let AppPacketKind::TimestampedControlEvents(timestamped) = &packet else {
+            unreachable!();
+        };
+        assert_eq!(timestamped.timestamp_us, 50_500);
+        let expanded: Vec<ControlEvent> = timestamped.control_events().collect();
+        assert_eq!((expanded[0].seq, expanded[0].control_id), (1, 3));
+        assert!(encode_set_frame(&state, &packet).is_ok());
+
+        // 新しいアドレスではホストが変わりうるので、時計合わせもやり直す
+        state.assign_address(0x23);
+        assert_eq!(state.host_time_us(2_500), None);
+    }
+
+    #[test]
+    fn control_state_request_is_answered_with_snapshot() {
+        let request = host_frame(&AppPacketKind::RequestControlState);
+        assert!(is_control_state_request(&request));
+        assert!(!is_control_state_request(&host_frame(
+            &AppPacketKind::RequestDescriptor { page: 0 }
+        )));
+
+        let mut state = DeviceRuntimeState::new();
+        state.assign_address(0x22);
+        state.take_next_control_seq().unwrap();
+
+        let packet =
+            build_control_state_packet(&state, 0, (0..40).map(|index| index == 7), [(100, -12)])
+                .unwrap();
+        let AppPacketKind::ControlStateSnapshot(snapshot) = &packet else {
+            unreachable!();
+        };
+        assert_eq!(snapshot.next_seq, 1);
+        assert_eq!(snapshot.switch_count, 40);
+        assert_eq!(snapshot.switch_state(7), Some(true));
+        assert_eq!(snapshot.switch_state(8), Some(false));
+        assert_eq!(snapshot.value(100), Some(-12));
+
+        state.accept_host_hello(ProtocolVersions::SUPPORTED);
+        assert!(encode_set_frame(&state, &packet).is_ok());
+        assert_eq!(
+            build_control_state_packet(&state, 0, [false; 200], []),
+            Err(FirmwareBaseError::Snapshot(SnapshotError::TooManySwitches))
+        );
+    }
 }
@@ -0,0 +1,436 @@
+//! 参加から `DeviceHello` の受信確認までの流れ
+//!
+//! `Join` → `SetAddress` → `DeviceHello` → master の `Ack` の順に進み、`Ready` になってからイベントを送る。
+//! 受け取った frame を `DeviceRuntimeState::handle_frame` に、定期的に `DeviceRuntimeState::poll` を呼び、
+//! 返ってきた `DeviceAction` をファームウェアが実行する。
+//! master 宛ての `Set` frame は queue に入れるたびに `DeviceRuntimeState::frame_queued` で記録し、
+//! `DeviceHello` の frame への `Ack` だけを受信確認として扱う。
+//! ホストの `RequestDeviceHello` を受け取ったときは `DeviceHello` を送り直す。
+
+use hcp::{
+    AppPacketKind, CONTROL_ID_REQUEST_DEVICE_HELLO, ControlValue, MIN_APP_PROTOCOL_VERSION,
+    ProtocolVersions, decode_set_packet, encode_set_packet_with_version,
+};
+use imcp::frame::{Address, Frame, FramePayload};
+
+use crate::{
+    DeviceDescriptor, DeviceRuntimeState, FirmwareBaseError, IMCP_MASTER_ADDRESS,
+    build_device_hello_packet, host_hello_from_frame, time_sync_from_frame,
+};
+
+/// 受信確認がないときに `DeviceHello` を送り直す間隔
+pub const HELLO_RETRY_INTERVAL_US: u64 = 2_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DevicePhase {
+    /// まだ `Join` を送っていない
+    Idle,
+    /// `SetAddress` を待っている
+    Joining,
+    /// `DeviceHello` の受信確認を待っている。`last_hello_us` はまだ送っていなければ `None`、
+    /// `hello_frame` はまだ queue に入れていなければ `None`
+    Announcing {
+        last_hello_us: Option<u64>,
+        hello_frame: Option<u64>,
+    },
+    /// ホストが `DeviceHello` を受け取った。イベントを送ってよい
+    Ready,
+}
+
+/// ファームウェアが実行すること
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DeviceAction {
+    /// IMCP の `Join` を送る
+    Join,
+    /// frame を master に送る。queue に入れたら `DeviceRuntimeState::frame_queued` で記録する
+    Send(Frame),
+    /// `HostHello` で交渉した。共通のバージョンがなければ `version` は `None`
+    Negotiated {
+        host: ProtocolVersions,
+        version: Option<u8>,
+    },
+    /// `DeviceHello` の受信確認を受け取った
+    Ready,
+}
+
+impl DeviceRuntimeState {
+    pub fn phase(&self) -> DevicePhase {
+        self.phase
+    }
+
+    /// `DeviceHello` の受信確認を受け取っていて、イベントを送ってよいか
+    pub fn is_ready(&self) -> bool {
+        self.phase == DevicePhase::Ready
+    }
+
+    /// master 宛ての frame を queue に入れたことを記録する
+    ///
+    /// `Set` frame なら何番目に `Ack` されるかを返す。`DeviceHello` ならその番号の `Ack` を受信確認として待つ
+    pub fn frame_queued(&mut self, frame: &Frame) -> Option<u64> {
+        let FramePayload::Set(payload) = frame.payload() else {
+            return None;
+        };
+        let index = self.queued_frames;
+        self.queued_frames = self.queued_frames.saturating_add(1);
+        if let DevicePhase::Announcing {
+            hello_frame: hello_frame @ None,
+            ..
+        } = &mut self.phase
+            && matches!(
+                decode_set_packet(payload),
+                Ok(AppPacketKind::DeviceHello(_))
+            )
+        {
+            *hello_frame = Some(index);
+        }
+        Some(index)
+    }
+
+    /// `frame_queued` が返した番号の frame を master が受け取ったか
+    pub fn is_frame_acked(&self, index: u64) -> bool {
+        index < self.acked_frames
+    }
+
+    /// 待っている `DeviceHello` の frame が受信確認されていれば `Ready` にする。変わったら `true`
+    fn acknowledge_hello(&mut self) -> bool {
+        let DevicePhase::Announcing {
+            hello_frame: Some(index),
+            ..
+        } = self.phase
+        else {
+            return false;
+        };
+        if !self.is_frame_acked(index) {
+            return false;
+        }
+        self.phase = DevicePhase::Ready;
+        true
+    }
+
+    /// 起動直後の `Join` と、受信確認のない `DeviceHello` の再送
+    pub fn poll(
+        &mut self,
+        now_us: u64,
+        descriptor: &DeviceDescriptor,
+    ) -> Result<Option<DeviceAction>, FirmwareBaseError> {
+        match self.phase {
+            DevicePhase::Idle => {
+                self.phase = DevicePhase::Joining;
+                Ok(Some(DeviceAction::Join))
+            }
+            DevicePhase::Announcing { last_hello_us, .. }
+                if last_hello_us.is_none_or(|sent_us| {
+                    now_us.saturating_sub(sent_us) >= HELLO_RETRY_INTERVAL_US
+                }) =>
+            {
+                self.send_hello(now_us, descriptor).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// `now_us` に受信した frame で状態を進める
+    ///
+    /// 参加と hello に関係しない frame は `Ok(None)` を返すので、ファームウェアがそのまま処理する
+    pub fn handle_frame(
+        &mut self,
+        now_us: u64,
+        frame: &Frame,
+        descriptor: &DeviceDescriptor,
+    ) -> Result<Option<DeviceAction>, FirmwareBaseError> {
+        match frame.payload() {
+            FramePayload::SetAddress { address, .. } => {
+                self.assign_address(*address);
+                return self.send_hello(now_us, descriptor).map(Some);
+            }
+            FramePayload::Ack(_) if frame.from_address() == IMCP_MASTER_ADDRESS => {
+                // queue に入れた frame がなければ数えない
+                if self.acked_frames < self.queued_frames {
+                    self.acked_frames = self.acked_frames.saturating_add(1);
+                }
+                return Ok(self.acknowledge_hello().then_some(DeviceAction::Ready));
+            }
+            _ => {}
+        }
+
+        if let Some(sync) = time_sync_from_frame(frame) {
+            self.accept_time_sync(now_us, &sync);
+            return Ok(None);
+        }
+
+        if let Some(host) = host_hello_from_frame(frame) {
+            let version = self.accept_host_hello(host);
+            return Ok(Some(DeviceAction::Negotiated { host, version }));
+        }
+
+        if is_device_hello_request(frame) && self.address.is_some() {
+            return self.send_hello(now_us, descriptor).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    /// `DeviceHello` を送って受信確認を待つ。ホストがバージョンを知らなくても読めるよう最も古いバージョンで送る
+    ///
+    /// 送り直しても、先に queue に入れた `DeviceHello` の受信確認を待ち続ける
+    fn send_hello(
+        &mut self,
+        now_us: u64,
+        descriptor: &DeviceDescriptor,
+    ) -> Result<DeviceAction, FirmwareBaseError> {
+        let from_address = self
+            .address
+            .ok_or(FirmwareBaseError::DeviceAddressUnassigned)?;
+        let payload = encode_set_packet_with_version(
+            MIN_APP_PROTOCOL_VERSION,
+            &build_device_hello_packet(*descriptor),
+        )
+        .map_err(FirmwareBaseError::Packet)?;
+        let hello_frame = match self.phase {
+            DevicePhase::Announcing { hello_frame, .. } => hello_frame,
+            _ => None,
+        };
+        self.phase = DevicePhase::Announcing {
+            last_hello_us: Some(now_us),
+            hello_frame,
+        };
+        Ok(DeviceAction::Send(Frame::new(
+            Address::Unicast(IMCP_MASTER_ADDRESS),
+            from_address,
+            FramePayload::Set(payload),
+        )))
+    }
+}
+
+/// ホストからの `RequestDeviceHello` を含む frame か
+pub fn is_device_hello_request(frame: &Frame) -> bool {
+    let FramePayload::Set(payload) = frame.payload() else {
+        return false;
+    };
+    matches!(
+        decode_set_packet(payload),
+        Ok(AppPacketKind::ControlEvent(event))
+            if event.control_id == CONTROL_ID_REQUEST_DEVICE_HELLO
+                && event.event == ControlValue::RequestDeviceHello
+    )
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use hcp::{Capabilities, ControlEvent, DeviceKind, TimeSync, Version, encode_set_packet};
+
+    const DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
+        device_id: 0x1234,
+        device_kind: DeviceKind::ButtonPanel,
+        firmware_version: Version {
+            major: 0,
+            minor: 1,
+            patch: 0,
+        },
+        capabilities: Capabilities {
+            displays: 0,
+            controls: 4,
+            features: hcp::FEATURE_CONTROL_EVENTS,
+        },
+    };
+
+    fn master_frame(payload: FramePayload) -> Frame {
+        Frame::new(Address::Unicast(0x22), IMCP_MASTER_ADDRESS, payload)
+    }
+
+    fn host_frame(kind: &AppPacketKind) -> Frame {
+        master_frame(FramePayload::Set(encode_set_packet(kind).unwrap()))
+    }
+
+    fn set_address() -> Frame {
+        master_frame(FramePayload::SetAddress {
+            address: 0x22,
+            id: 7,
+        })
+    }
+
+    fn master_ack() -> Frame {
+        master_frame(FramePayload::Ack(IMCP_MASTER_ADDRESS))
+    }
+
+    /// `DeviceHello` を送る action なら frame を返す
+    fn hello_frame(action: Option<DeviceAction>) -> Frame {
+        let Some(DeviceAction::Send(frame)) = action else {
+            unreachable!();
+        };
+        assert_eq!(frame.from_address(), 0x22);
+        let FramePayload::Set(payload) = frame.payload() else {
+            unreachable!();
+        };
+        let Ok(AppPacketKind::DeviceHello(hello)) = decode_set_packet(payload) else {
+            unreachable!();
+        };
+        assert_eq!(hello.device_id, 0x1234);
+        frame
+    }
+
+    #[test]
+    fn device_joins_announces_and_becomes_ready() {
+        let mut state = DeviceRuntimeState::new();
+        assert_eq!(state.poll(0, &DESCRIPTOR), Ok(Some(DeviceAction::Join)));
+        assert_eq!(state.poll(10, &DESCRIPTOR), Ok(None));
+        assert_eq!(state.phase(), DevicePhase::Joining);
+
+        let hello = hello_frame(
+            state
+                .handle_frame(100, &set_address(), &DESCRIPTOR)
+                .unwrap(),
+        );
+        assert_eq!(state.address(), Some(0x22));
+        assert_eq!(state.frame_queued(&hello), Some(0));
+        assert!(!state.is_ready());
+
+        assert_eq!(
+            state.handle_frame(200, &master_ack(), &DESCRIPTOR),
+            Ok(Some(DeviceAction::Ready))
+        );
+        assert!(state.is_ready());
+        assert!(state.is_frame_acked(0));
+        // Ready の後の Ack は他の frame の受信確認
+        assert_eq!(
+            state.handle_frame(300, &master_ack(), &DESCRIPTOR),
+            Ok(None)
+        );
+
+        let host = host_frame(&AppPacketKind::HostHello(ProtocolVersions::SUPPORTED));
+        assert_eq!(
+            state.handle_frame(400, &host, &DESCRIPTOR),
+            Ok(Some(DeviceAction::Negotiated {
+                host: ProtocolVersions::SUPPORTED,
+                version: Some(hcp::APP_PROTOCOL_VERSION),
+            }))
+        );
+    }
+
+    #[test]
+    fn hello_is_retried_until_acknowledged() {
+        let mut state = DeviceRuntimeState::new();
+        state.poll(0, &DESCRIPTOR).unwrap();
+        let hello = hello_frame(
+            state
+                .handle_frame(100, &set_address(), &DESCRIPTOR)
+                .unwrap(),
+        );
+        state.frame_queued(&hello);
+
+        assert_eq!(
+            state.poll(100 + HELLO_RETRY_INTERVAL_US - 1, &DESCRIPTOR),
+            Ok(None)
+        );
+        let retry = hello_frame(
+            state
+                .poll(100 + HELLO_RETRY_INTERVAL_US, &DESCRIPTOR)
+                .unwrap(),
+        );
+        state.frame_queued(&retry);
+        // 先に queue に入れた DeviceHello の受信確認を待つ
+        assert_eq!(
+            state.phase(),
+            DevicePhase::Announcing {
+                last_hello_us: Some(100 + HELLO_RETRY_INTERVAL_US),
+                hello_frame: Some(0),
+            }
+        );
+
+        // HostHello は受信確認ではない
+        let host = host_frame(&AppPacketKind::HostHello(ProtocolVersions::SUPPORTED));
+        state.handle_frame(5_000_000, &host, &DESCRIPTOR).unwrap();
+        assert!(!state.is_ready());
+
+        state
+            .handle_frame(5_000_100, &master_ack(), &DESCRIPTOR)
+            .unwrap();
+        assert!(state.is_ready());
+        assert_eq!(state.poll(10_000_000, &DESCRIPTOR), Ok(None));
+    }
+
+    #[test]
+    fn ack_for_other_frame_is_not_hello_ack() {
+        let mut state = DeviceRuntimeState::new();
+        state.poll(0, &DESCRIPTOR).unwrap();
+        let hello = hello_frame(
+            state
+                .handle_frame(100, &set_address(), &DESCRIPTOR)
+                .unwrap(),
+        );
+        // DeviceHello を queue に入れる前の Ack は待っていない
+        assert_eq!(
+            state.handle_frame(150, &master_ack(), &DESCRIPTOR),
+            Ok(None)
+        );
+
+        // DeviceHello より先に queue に入れた frame
+        let event = encode_set_packet(&AppPacketKind::ControlEvent(ControlEvent {
+            seq: 0,
+            control_id: 1,
+            event: ControlValue::Button { pressed: true },
+        }))
+        .unwrap();
+        let log = Frame::new(
+            Address::Unicast(IMCP_MASTER_ADDRESS),
+            0x22,
+            FramePayload::Set(event),
+        );
+        assert_eq!(state.frame_queued(&log), Some(0));
+        assert_eq!(state.frame_queued(&hello), Some(1));
+        assert_eq!(state.frame_queued(&master_ack()), None);
+
+        // 1 つ目の Ack は先に送った frame の受信確認
+        assert_eq!(
+            state.handle_frame(200, &master_ack(), &DESCRIPTOR),
+            Ok(None)
+        );
+        assert!(!state.is_ready());
+        assert_eq!(
+            state.handle_frame(300, &master_ack(), &DESCRIPTOR),
+            Ok(Some(DeviceAction::Ready))
+        );
+    }
+
+    #[test]
+    fn device_hello_request_is_answered() {
+        let request = host_frame(&AppPacketKind::ControlEvent(ControlEvent {
+            seq: 0,
+            control_id: CONTROL_ID_REQUEST_DEVICE_HELLO,
+            event: ControlValue::RequestDeviceHello,
+        }));
+        assert!(is_device_hello_request(&request));
+
+        let mut state = DeviceRuntimeState::new();
+        // アドレスがなければ送れない
+        assert_eq!(state.handle_frame(0, &request, &DESCRIPTOR), Ok(None));
+
+        let hello = hello_frame(state.handle_frame(0, &set_address(), &DESCRIPTOR).unwrap());
+        state.frame_queued(&hello);
+        state.handle_frame(0, &master_ack(), &DESCRIPTOR).unwrap();
+        assert!(state.is_ready());
+
+        hello_frame(state.handle_frame(100, &request, &DESCRIPTOR).unwrap());
+        assert!(!state.is_ready());
+    }
+
+    #[test]
+    fn time_sync_is_accepted() {
+        let mut state = DeviceRuntimeState::new();
+        state.handle_frame(0, &set_address(), &DESCRIPTOR).unwrap();
+        let sync = TimeSync {
+            host_time_us: 1_000_000,
+        };
+        let frame = Frame::new(
+            Address::Broadcast,
+            IMCP_MASTER_ADDRESS,
+            FramePayload::Data(hcp::encode_time_sync_packet(&sync).unwrap()),
+        );
+        assert_eq!(state.handle_frame(500, &frame, &DESCRIPTOR), Ok(None));
+        assert_eq!(state.host_time_us(600), Some(1_000_100));
+    }
+}
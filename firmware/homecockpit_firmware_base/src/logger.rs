@@ -0,0 +1,184 @@
+//! ログを溜めて HCP の `Log` で master に送る
+//!
+//! ログを書いた時点では送らず、`DeviceLogger` に溜めておく。
+//! バスが空いているときに `pop_packet` で取り出して送る。
+//! 連続したログでバスを埋めないよう、token bucket で件数を制限する。
+
+use core::fmt;
+
+use hcp::{AppPacketKind, LogEntry, LogLevel};
+use heapless::Deque;
+
+use crate::DeviceRuntimeState;
+
+/// 送信を待つログと送信の制限
+#[derive(Debug, Clone)]
+pub struct DeviceLogger<const N: usize> {
+    entries: Deque<LogEntry, N>,
+    min_level: LogLevel,
+    burst: u8,
+    refill_ms: u32,
+    tokens: u8,
+    last_refill_ms: u64,
+    dropped: u16,
+}
+
+impl<const N: usize> DeviceLogger<N> {
+    /// `burst` 件まで続けて受け付け、以降は `refill_ms` ごとに 1 件ずつ受け付ける
+    pub const fn new(min_level: LogLevel, burst: u8, refill_ms: u32) -> Self {
+        Self {
+            entries: Deque::new(),
+            min_level,
+            burst,
+            refill_ms,
+            tokens: burst,
+            last_refill_ms: 0,
+            dropped: 0,
+        }
+    }
+
+    /// これより詳細なレベルのログは捨てる
+    pub fn set_min_level(&mut self, level: LogLevel) {
+        self.min_level = level;
+    }
+
+    /// 溜めたログの数
+    pub fn pending(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// 制限やキューの溢れで捨てて、まだ報告していないログの数
+    pub fn dropped(&self) -> u16 {
+        self.dropped
+    }
+
+    /// ログを溜める。レベルで除外した場合と捨てた場合は `false`
+    pub fn log(&mut self, now_ms: u64, level: LogLevel, tag: &str, text: &str) -> bool {
+        if level > self.min_level {
+            return false;
+        }
+        self.push(now_ms, || LogEntry::new(level, tag, text))
+    }
+
+    /// `format_args!` で組み立てたログを溜める
+    pub fn log_fmt(
+        &mut self,
+        now_ms: u64,
+        level: LogLevel,
+        tag: &str,
+        args: fmt::Arguments<'_>,
+    ) -> bool {
+        if level > self.min_level {
+            return false;
+        }
+        self.push(now_ms, || LogEntry::from_args(level, tag, args))
+    }
+
+    /// アドレスが割り当てられ、`Log` を送れるバージョンで交渉済みなら、先頭のログを取り出す
+    pub fn pop_packet(&mut self, state: &DeviceRuntimeState) -> Option<AppPacketKind> {
+        state.address()?;
+        let packet = AppPacketKind::Log(self.entries.front()?.clone());
+        if !packet.is_available_in(state.protocol_version()) {
+            return None;
+        }
+        self.entries.pop_front();
+        Some(packet)
+    }
+
+    fn push(&mut self, now_ms: u64, entry: impl FnOnce() -> LogEntry) -> bool {
+        self.refill(now_ms);
+        if self.tokens == 0 || self.entries.is_full() {
+            self.dropped = self.dropped.saturating_add(1);
+            return false;
+        }
+
+        let mut entry = entry();
+        entry.dropped = self.dropped;
+        if self.entries.push_back(entry).is_err() {
+            return false;
+        }
+        self.tokens -= 1;
+        self.dropped = 0;
+        true
+    }
+
+    fn refill(&mut self, now_ms: u64) {
+        if self.refill_ms == 0 {
+            self.tokens = self.burst;
+            return;
+        }
+        let refill_ms = u64::from(self.refill_ms);
+        let elapsed = now_ms.saturating_sub(self.last_refill_ms);
+        let added = elapsed / refill_ms;
+        if added == 0 {
+            return;
+        }
+        self.tokens = u8::try_from(added)
+            .map_or(self.burst, |added| self.tokens.saturating_add(added))
+            .min(self.burst);
+        self.last_refill_ms = now_ms - elapsed % refill_ms;
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use hcp::{APP_PROTOCOL_VERSION, ProtocolVersions};
+
+    fn negotiated_state() -> DeviceRuntimeState {
+        let mut state = DeviceRuntimeState::new();
+        state.assign_address(0x02);
+        state.accept_host_hello(ProtocolVersions::SUPPORTED);
+        assert_eq!(state.protocol_version(), APP_PROTOCOL_VERSION);
+        state
+    }
+
+    fn pop_entry(logger: &mut DeviceLogger<4>, state: &DeviceRuntimeState) -> LogEntry {
+        let Some(AppPacketKind::Log(entry)) = logger.pop_packet(state) else {
+            unreachable!();
+        };
+        entry
+    }
+
+    #[test]
+    fn entries_wait_for_negotiation() {
+        let mut logger = DeviceLogger::<4>::new(LogLevel::Info, 4, 100);
+        assert!(logger.log(0, LogLevel::Info, "boot", "started"));
+        assert!(!logger.log(0, LogLevel::Debug, "boot", "ignored"));
+
+        let mut state = DeviceRuntimeState::new();
+        assert_eq!(logger.pop_packet(&state), None);
+        state.assign_address(0x02);
+        assert_eq!(logger.pop_packet(&state), None);
+        assert_eq!(logger.pending(), 1);
+
+        let state = negotiated_state();
+        let entry = pop_entry(&mut logger, &state);
+        assert_eq!(
+            (entry.level, entry.tag.as_str(), entry.text.as_str()),
+            (LogLevel::Info, "boot", "started")
+        );
+        assert_eq!(logger.pop_packet(&state), None);
+    }
+
+    #[test]
+    fn rate_limit_reports_dropped_entries() {
+        let mut logger = DeviceLogger::<4>::new(LogLevel::Trace, 2, 100);
+        let state = negotiated_state();
+
+        assert!(logger.log(0, LogLevel::Warn, "scan", "1"));
+        assert!(logger.log(10, LogLevel::Warn, "scan", "2"));
+        assert!(!logger.log(20, LogLevel::Warn, "scan", "3"));
+        assert!(!logger.log(99, LogLevel::Warn, "scan", "4"));
+        assert_eq!(logger.dropped(), 2);
+
+        assert!(logger.log_fmt(100, LogLevel::Error, "scan", format_args!("{}", 5)));
+        assert_eq!(logger.dropped(), 0);
+
+        assert_eq!(pop_entry(&mut logger, &state).dropped, 0);
+        assert_eq!(pop_entry(&mut logger, &state).dropped, 0);
+        let entry = pop_entry(&mut logger, &state);
+        assert_eq!((entry.text.as_str(), entry.dropped), ("5", 2));
+    }
+}
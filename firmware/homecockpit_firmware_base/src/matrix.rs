@@ -0,0 +1,365 @@
+//! ボタンマトリクスの走査、チャタリング除去、ゴースト検出
+//!
+//! ピンの操作は `MatrixPins` で抽象化する。
+//! 行を選んでから値が落ち着くまで待つデバイスは、`select_row` の後に待ってから `read_row` を呼ぶ。
+//! 待たなくてよいデバイスは `scan` で全行を続けて読む。
+//!
+//! チャタリングはスイッチごとの積分カウンターで除去する。
+//! ダイオードのないマトリクスで 2 行 2 列の角が全て押されて見えるときは、どれが本当に押されたのか
+//! 分からないため、その角のスイッチの状態を変えずに保つ。
+
+use hcp::{BatchedControl, ControlValue};
+
+use crate::control_id_from_matrix_position;
+
+/// 何も指定しないときに、状態を変えるまでに続けて同じ値を読む回数
+pub const DEFAULT_DEBOUNCE_SAMPLES: u8 = 3;
+
+/// マトリクスの行と列のピン
+pub trait MatrixPins {
+    /// 行を選ぶ。選んだ行のスイッチだけが列に現れる
+    fn select_row(&mut self, row: usize);
+    fn release_row(&mut self, row: usize);
+    /// 選んだ行の `column` 列のスイッチが押されているか
+    fn is_column_active(&mut self, column: usize) -> bool;
+}
+
+/// `debounce_ms` の間同じ値が続いたら状態を変えるのに必要な読み取り回数
+///
+/// `scan_period_ms` は同じ行を読む間隔 (全行を 1 回走査する時間)
+pub fn debounce_samples(debounce_ms: u16, scan_period_ms: u16) -> u8 {
+    let samples = debounce_ms.div_ceil(scan_period_ms.max(1)).max(1);
+    u8::try_from(samples).unwrap_or(u8::MAX)
+}
+
+/// `ROWS` x `COLUMNS` のマトリクスの走査結果。行と列はそれぞれ 32 まで
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MatrixScanner<const ROWS: usize, const COLUMNS: usize> {
+    debounce_samples: u8,
+    ghost_detection: bool,
+    /// 行ごとの最後に読んだ値。bit が列
+    raw: [u32; ROWS],
+    counters: [[u8; COLUMNS]; ROWS],
+    debounced: [u32; ROWS],
+    reported: [u32; ROWS],
+    ghost_rows: u32,
+}
+
+impl<const ROWS: usize, const COLUMNS: usize> MatrixScanner<ROWS, COLUMNS> {
+    const SIZE_CHECK: () = assert!(ROWS <= 32 && COLUMNS <= 32);
+
+    pub const fn new() -> Self {
+        let () = Self::SIZE_CHECK;
+        Self {
+            debounce_samples: DEFAULT_DEBOUNCE_SAMPLES,
+            ghost_detection: true,
+            raw: [0; ROWS],
+            counters: [[0; COLUMNS]; ROWS],
+            debounced: [0; ROWS],
+            reported: [0; ROWS],
+            ghost_rows: 0,
+        }
+    }
+
+    /// 各スイッチにダイオードがあり、ゴーストが起きないマトリクスではゴースト検出を止める
+    pub const fn without_ghost_detection(mut self) -> Self {
+        self.ghost_detection = false;
+        self
+    }
+
+    /// 状態を変えるまでに続けて同じ値を読む回数。`debounce_samples` で求める
+    pub fn set_debounce_samples(&mut self, samples: u8) {
+        self.debounce_samples = samples.max(1);
+        for counters in &mut self.counters {
+            for counter in counters {
+                *counter = (*counter).min(self.debounce_samples);
+            }
+        }
+    }
+
+    /// 選択済みの `row` 行を読む
+    pub fn read_row(&mut self, pins: &mut impl MatrixPins, row: usize) {
+        if row >= ROWS {
+            return;
+        }
+        let mut raw = 0;
+        for column in 0..COLUMNS {
+            if pins.is_column_active(column) {
+                raw |= 1 << column;
+            }
+        }
+        self.raw[row] = raw;
+
+        let ambiguous = self.ambiguous_columns(row);
+        if ambiguous == 0 {
+            self.ghost_rows &= !(1 << row);
+        } else {
+            self.ghost_rows |= 1 << row;
+        }
+
+        for column in 0..COLUMNS {
+            let bit = 1 << column;
+            if ambiguous & bit != 0 {
+                continue;
+            }
+            let counter = &mut self.counters[row][column];
+            if raw & bit != 0 {
+                *counter = counter.saturating_add(1).min(self.debounce_samples);
+                if *counter == self.debounce_samples {
+                    self.debounced[row] |= bit;
+                }
+            } else {
+                *counter = counter.saturating_sub(1);
+                if *counter == 0 {
+                    self.debounced[row] &= !bit;
+                }
+            }
+        }
+    }
+
+    /// 全行を順に選んで読む
+    pub fn scan(&mut self, pins: &mut impl MatrixPins) {
+        for row in 0..ROWS {
+            pins.select_row(row);
+            self.read_row(pins, row);
+            pins.release_row(row);
+        }
+    }
+
+    /// `row` 行で、他の行と 2 列以上重なって押されている列
+    fn ambiguous_columns(&self, row: usize) -> u32 {
+        if !self.ghost_detection {
+            return 0;
+        }
+        let raw = self.raw[row];
+        self.raw
+            .iter()
+            .enumerate()
+            .filter(|&(other, _)| other != row)
+            .map(|(_, &other)| raw & other)
+            .filter(|shared| shared.count_ones() >= 2)
+            .fold(0, |ambiguous, shared| ambiguous | shared)
+    }
+
+    /// ゴーストの疑いがあり、状態を保っているスイッチがあるか
+    pub fn is_ghosting(&self) -> bool {
+        self.ghost_rows != 0
+    }
+
+    pub fn is_pressed(&self, row: usize, column: usize) -> bool {
+        column < COLUMNS
+            && self
+                .debounced
+                .get(row)
+                .is_some_and(|bits| bits & (1 << column) != 0)
+    }
+
+    /// チャタリング除去後の状態。`control_id_from_matrix_position` の順 (行優先)
+    pub fn switch_states(&self) -> impl Iterator<Item = bool> + '_ {
+        self.debounced
+            .iter()
+            .flat_map(|&bits| (0..COLUMNS).map(move |column| bits & (1 << column) != 0))
+    }
+
+    /// まだ返していない状態の変化を 1 つ返す。control ID は `control_id_from_matrix_position`
+    pub fn next_change(&mut self) -> Option<BatchedControl> {
+        let columns = u8::try_from(COLUMNS).unwrap_or(u8::MAX);
+        self.next_change_with(|row, column| {
+            Some(control_id_from_matrix_position(row, column, columns))
+        })
+    }
+
+    /// まだ返していない状態の変化を 1 つ返す。control ID は `control_id(row, column)`
+    ///
+    /// `Panel::matrix_control_id` を渡す。`None` の位置の変化は読み捨てる
+    pub fn next_change_with(
+        &mut self,
+        control_id: impl Fn(u8, u8) -> Option<u16>,
+    ) -> Option<BatchedControl> {
+        loop {
+            let (row, changed) = self
+                .debounced
+                .iter()
+                .zip(&self.reported)
+                .map(|(debounced, reported)| debounced ^ reported)
+                .enumerate()
+                .find(|&(_, changed)| changed != 0)?;
+            let bit = changed & changed.wrapping_neg();
+            self.reported[row] ^= bit;
+            let column = u8::try_from(bit.trailing_zeros()).unwrap_or(u8::MAX);
+            let Some(control_id) = control_id(u8::try_from(row).unwrap_or(u8::MAX), column) else {
+                continue;
+            };
+            return Some(BatchedControl {
+                control_id,
+                event: ControlValue::Button {
+                    pressed: self.debounced[row] & bit != 0,
+                },
+            });
+        }
+    }
+}
+
+impl<const ROWS: usize, const COLUMNS: usize> Default for MatrixScanner<ROWS, COLUMNS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    /// ダイオードのないマトリクス。押されたスイッチを通して行の信号が他の行にも回り込む
+    struct SimulatedMatrix<const ROWS: usize, const COLUMNS: usize> {
+        pressed: [[bool; COLUMNS]; ROWS],
+        selected: Option<usize>,
+    }
+
+    impl<const ROWS: usize, const COLUMNS: usize> SimulatedMatrix<ROWS, COLUMNS> {
+        fn new() -> Self {
+            Self {
+                pressed: [[false; COLUMNS]; ROWS],
+                selected: None,
+            }
+        }
+
+        /// 選んだ行から押されたスイッチをたどって届く列
+        fn reachable_columns(&self, row: usize) -> [bool; COLUMNS] {
+            let mut rows = [false; ROWS];
+            let mut columns = [false; COLUMNS];
+            rows[row] = true;
+            loop {
+                let mut changed = false;
+                for (row, pressed) in self.pressed.iter().enumerate() {
+                    for (column, &pressed) in pressed.iter().enumerate() {
+                        if pressed && rows[row] != columns[column] {
+                            rows[row] = true;
+                            columns[column] = true;
+                            changed = true;
+                        }
+                    }
+                }
+                if !changed {
+                    return columns;
+                }
+            }
+        }
+    }
+
+    impl<const ROWS: usize, const COLUMNS: usize> MatrixPins for SimulatedMatrix<ROWS, COLUMNS> {
+        fn select_row(&mut self, row: usize) {
+            self.selected = Some(row);
+        }
+
+        fn release_row(&mut self, _row: usize) {
+            self.selected = None;
+        }
+
+        fn is_column_active(&mut self, column: usize) -> bool {
+            self.selected
+                .is_some_and(|row| self.reachable_columns(row)[column])
+        }
+    }
+
+    fn changes<const ROWS: usize, const COLUMNS: usize>(
+        scanner: &mut MatrixScanner<ROWS, COLUMNS>,
+    ) -> std::vec::Vec<(u16, bool)> {
+        core::iter::from_fn(|| scanner.next_change())
+            .map(|change| match change.event {
+                ControlValue::Button { pressed } => (change.control_id, pressed),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bouncing_contact_is_reported_once() {
+        let mut matrix = SimulatedMatrix::<2, 3>::new();
+        let mut scanner = MatrixScanner::<2, 3>::new();
+
+        for bounce in [true, false, true, false, true, true, true] {
+            matrix.pressed[1][2] = bounce;
+            scanner.scan(&mut matrix);
+        }
+        assert_eq!(changes(&mut scanner), [(5, true)]);
+        assert!(scanner.is_pressed(1, 2));
+
+        for bounce in [false, true, false, false] {
+            matrix.pressed[1][2] = bounce;
+            scanner.scan(&mut matrix);
+        }
+        assert_eq!(changes(&mut scanner), []);
+        scanner.scan(&mut matrix);
+        assert_eq!(changes(&mut scanner), [(5, false)]);
+    }
+
+    #[test]
+    fn ghost_key_is_not_reported() {
+        let mut matrix = SimulatedMatrix::<3, 3>::new();
+        let mut scanner = MatrixScanner::<3, 3>::new();
+        scanner.set_debounce_samples(1);
+
+        matrix.pressed[0][0] = true;
+        matrix.pressed[0][1] = true;
+        scanner.scan(&mut matrix);
+        assert_eq!(changes(&mut scanner), [(0, true), (1, true)]);
+
+        // 3 つ目の角を押すと 4 つ目 (1, 1) も押されて見える
+        matrix.pressed[1][0] = true;
+        scanner.scan(&mut matrix);
+        scanner.scan(&mut matrix);
+        assert!(scanner.is_ghosting());
+        assert_eq!(changes(&mut scanner), []);
+        assert!(!scanner.is_pressed(1, 1));
+
+        matrix.pressed[0][1] = false;
+        scanner.scan(&mut matrix);
+        assert!(!scanner.is_ghosting());
+        assert_eq!(changes(&mut scanner), [(1, false), (3, true)]);
+        assert_eq!(
+            scanner.switch_states().collect::<std::vec::Vec<_>>(),
+            [true, false, false, true, false, false, false, false, false]
+        );
+    }
+
+    #[test]
+    fn disabled_ghost_detection_reports_all_corners() {
+        let mut matrix = SimulatedMatrix::<2, 2>::new();
+        let mut scanner = MatrixScanner::<2, 2>::new().without_ghost_detection();
+        scanner.set_debounce_samples(1);
+
+        matrix.pressed = [[true, true], [true, false]];
+        scanner.scan(&mut matrix);
+        // 回り込みも押されたスイッチとして扱う
+        assert_eq!(
+            changes(&mut scanner),
+            [(0, true), (1, true), (2, true), (3, true)]
+        );
+    }
+
+    #[test]
+    fn changes_are_mapped_to_panel_ids() {
+        let mut matrix = SimulatedMatrix::<2, 2>::new();
+        let mut scanner = MatrixScanner::<2, 2>::new();
+        scanner.set_debounce_samples(1);
+
+        matrix.pressed = [[true, false], [false, true]];
+        scanner.scan(&mut matrix);
+        // (0, 0) には操作系がない
+        let mapped =
+            scanner.next_change_with(|row, column| (row == 1).then_some(100 + u16::from(column)));
+        assert_eq!(mapped.map(|change| change.control_id), Some(101));
+        assert!(scanner.next_change().is_none());
+    }
+
+    #[test]
+    fn debounce_samples_follow_scan_period() {
+        assert_eq!(debounce_samples(5, 16), 1);
+        assert_eq!(debounce_samples(20, 16), 2);
+        assert_eq!(debounce_samples(0, 16), 1);
+        assert_eq!(debounce_samples(1000, 0), u8::MAX);
+    }
+}
@@ -0,0 +1,189 @@
+//! パネルの操作系の配置
+//!
+//! `Panel` はパネル定義ファイル (`panel.toml`) から build script が生成する。
+//! 生成した `Panel` から `DeviceDescriptor`、descriptor のページ、マトリクスの位置と control ID の対応を作る。
+//! 定義ファイルの読み込みとコード生成は `homecockpit_panel_definition` crate にある。
+
+use hcp::{
+    AppPacketKind, Capabilities, ControlDescriptor, ControlKind, DescriptorEntry, DeviceKind,
+    Version,
+};
+
+use crate::{DeviceDescriptor, build_descriptor_page_packet};
+
+/// 1 つの操作系
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PanelControl {
+    pub id: u16,
+    pub kind: ControlKind,
+    pub label: &'static str,
+    /// ボタンマトリクス上の `(row, column)`。マトリクスにない操作系は `None`
+    pub matrix: Option<(u8, u8)>,
+}
+
+impl PanelControl {
+    /// ラベルが `MAX_LABEL_LEN` を超える場合は `None`。生成したコードでは起きない
+    pub fn descriptor(&self) -> Option<ControlDescriptor> {
+        ControlDescriptor::new(self.id, self.kind, self.label)
+    }
+}
+
+/// パネル全体の操作系
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Panel {
+    pub device_kind: DeviceKind,
+    /// descriptor で返す順
+    pub controls: &'static [PanelControl],
+}
+
+impl Panel {
+    pub fn control_count(&self) -> u16 {
+        u16::try_from(self.controls.len()).unwrap_or(u16::MAX)
+    }
+
+    pub fn control(&self, id: u16) -> Option<&PanelControl> {
+        self.controls.iter().find(|control| control.id == id)
+    }
+
+    /// マトリクスの `(row, column)` にある操作系の control ID
+    pub fn matrix_control_id(&self, row: u8, column: u8) -> Option<u16> {
+        self.controls
+            .iter()
+            .find(|control| control.matrix == Some((row, column)))
+            .map(|control| control.id)
+    }
+
+    /// `features` を持つ `DeviceDescriptor`。`device_id` は後から埋める
+    pub fn device_descriptor(&self, firmware_version: Version, features: u32) -> DeviceDescriptor {
+        DeviceDescriptor {
+            device_id: 0,
+            device_kind: self.device_kind,
+            firmware_version,
+            capabilities: Capabilities {
+                displays: 0,
+                controls: self.control_count(),
+                features,
+            },
+        }
+    }
+
+    pub fn descriptor_entry(&self, index: usize) -> Option<DescriptorEntry> {
+        self.controls
+            .get(index)?
+            .descriptor()
+            .map(DescriptorEntry::Control)
+    }
+
+    /// 要求されたページの `DescriptorPage`。範囲外のページは `None`
+    pub fn descriptor_page_packet(&self, page: u16) -> Option<AppPacketKind> {
+        build_descriptor_page_packet(self.controls.len(), page, |index| {
+            self.descriptor_entry(index)
+        })
+    }
+
+    /// マトリクス上の操作系で最小の control ID
+    pub fn first_matrix_id(&self) -> u16 {
+        self.matrix_controls()
+            .map(|control| control.id)
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// `first_matrix_id` から連番で、マトリクス上の操作系が押されているか
+    ///
+    /// `build_control_state_packet` の `switches` に渡す。間の ID の操作系がマトリクスにない場合は `false`
+    pub fn matrix_switch_states(
+        &self,
+        is_pressed: impl Fn(u8, u8) -> bool,
+    ) -> impl Iterator<Item = bool> {
+        let ids = self.matrix_controls().map(|control| control.id);
+        #[allow(clippy::reversed_empty_ranges)]
+        let range = match (ids.clone().min(), ids.max()) {
+            (Some(first), Some(last)) => first..=last,
+            _ => 1..=0,
+        };
+        range.map(move |id| {
+            self.matrix_controls()
+                .find(|control| control.id == id)
+                .and_then(|control| control.matrix)
+                .is_some_and(|(row, column)| is_pressed(row, column))
+        })
+    }
+
+    fn matrix_controls(&self) -> impl Iterator<Item = &PanelControl> + Clone {
+        self.controls
+            .iter()
+            .filter(|control| control.matrix.is_some())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    static PANEL: Panel = Panel {
+        device_kind: DeviceKind::ButtonPanel,
+        controls: &[
+            PanelControl {
+                id: 10,
+                kind: ControlKind::Button,
+                label: "MASTER ARM",
+                matrix: Some((0, 1)),
+            },
+            PanelControl {
+                id: 12,
+                kind: ControlKind::Toggle,
+                label: "GEAR",
+                matrix: Some((1, 0)),
+            },
+            PanelControl {
+                id: 20,
+                kind: ControlKind::Encoder,
+                label: "HDG",
+                matrix: None,
+            },
+        ],
+    };
+
+    #[test]
+    fn panel_builds_descriptor_and_matrix_mapping() {
+        let descriptor = PANEL.device_descriptor(
+            Version {
+                major: 0,
+                minor: 1,
+                patch: 0,
+            },
+            hcp::FEATURE_DESCRIPTORS,
+        );
+        assert_eq!(descriptor.device_kind, DeviceKind::ButtonPanel);
+        assert_eq!(descriptor.capabilities.controls, 3);
+
+        assert_eq!(PANEL.matrix_control_id(0, 1), Some(10));
+        assert_eq!(PANEL.matrix_control_id(0, 0), None);
+        assert_eq!(PANEL.control(20).unwrap().kind, ControlKind::Encoder);
+
+        let Some(AppPacketKind::DescriptorPage(page)) = PANEL.descriptor_page_packet(0) else {
+            unreachable!();
+        };
+        assert_eq!(page.entries.len(), 3);
+        assert_eq!(
+            page.entries[1],
+            DescriptorEntry::Control(
+                ControlDescriptor::new(12, ControlKind::Toggle, "GEAR").unwrap()
+            )
+        );
+        assert!(PANEL.descriptor_page_packet(1).is_none());
+    }
+
+    #[test]
+    fn matrix_switch_states_fill_gaps() {
+        assert_eq!(PANEL.first_matrix_id(), 10);
+        let states: std::vec::Vec<bool> = PANEL
+            .matrix_switch_states(|row, column| (row, column) == (1, 0))
+            .collect();
+        assert_eq!(states, [false, false, true]);
+    }
+}
@@ -0,0 +1,167 @@
+//! `DisplayPayload::Pwm` で受け取った明るさを PWM に出す
+//!
+//! 出力先は `PwmOutput` で抽象化する。
+//! `PwmChannel` は 1 つの照明の系統や表示灯のフェードを持ち、メインループから `poll` で呼ぶ。
+//! 明るさは duty に線形に換算する。
+
+use hcp::{DisplayPayload, DisplayTarget, PwmLevel, decode_data_packet};
+use imcp::frame::{Frame, FramePayload};
+
+/// 明るさを出力する PWM のチャンネル
+pub trait PwmOutput {
+    /// 最大の明るさに対応する duty
+    fn max_duty(&self) -> u16;
+    fn set_duty(&mut self, duty: u16);
+}
+
+/// 1 つのチャンネルの明るさとフェード
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PwmChannel {
+    from: u8,
+    to: u8,
+    started_ms: u64,
+    fade_ms: u16,
+    applied_duty: Option<u16>,
+}
+
+impl PwmChannel {
+    pub const fn new() -> Self {
+        Self {
+            from: 0,
+            to: 0,
+            started_ms: 0,
+            fade_ms: 0,
+            applied_duty: None,
+        }
+    }
+
+    /// `now_ms` 時点の明るさから `level` へのフェードを始める
+    pub fn set(&mut self, now_ms: u64, level: PwmLevel) {
+        self.from = self.brightness(now_ms);
+        self.to = level.brightness;
+        self.started_ms = now_ms;
+        self.fade_ms = level.fade_ms;
+    }
+
+    /// `now_ms` 時点の明るさ
+    pub fn brightness(&self, now_ms: u64) -> u8 {
+        let elapsed = now_ms.saturating_sub(self.started_ms);
+        let fade_ms = u64::from(self.fade_ms);
+        if elapsed >= fade_ms {
+            return self.to;
+        }
+        let from = i64::from(self.from);
+        let delta = i64::from(self.to) - from;
+        // elapsed < fade_ms <= u16::MAX なので i64 に収まる
+        let elapsed = i64::try_from(elapsed).unwrap_or(i64::MAX);
+        let brightness = from + delta * elapsed / i64::from(self.fade_ms);
+        u8::try_from(brightness).unwrap_or(self.to)
+    }
+
+    pub fn is_fading(&self, now_ms: u64) -> bool {
+        now_ms.saturating_sub(self.started_ms) < u64::from(self.fade_ms)
+    }
+
+    /// `now_ms` 時点の明るさを duty に換算し、変わったときだけ `output` に反映する
+    pub fn poll(&mut self, now_ms: u64, output: &mut impl PwmOutput) {
+        let max_duty = u32::from(output.max_duty());
+        let duty = u32::from(self.brightness(now_ms)) * max_duty / u32::from(u8::MAX);
+        let duty = u16::try_from(duty).unwrap_or(u16::MAX);
+        if self.applied_duty != Some(duty) {
+            self.applied_duty = Some(duty);
+            output.set_duty(duty);
+        }
+    }
+}
+
+impl Default for PwmChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `DisplayPayload::Pwm` を含む frame なら出力先と明るさを返す
+pub fn pwm_level_from_frame(frame: &Frame) -> Option<(DisplayTarget, PwmLevel)> {
+    let FramePayload::Data(payload) = frame.payload() else {
+        return None;
+    };
+    let display = decode_data_packet(payload).ok()?;
+    match display.payload {
+        DisplayPayload::Pwm(level) => Some((display.target, level)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::IMCP_MASTER_ADDRESS;
+    use hcp::{DisplayData, encode_data_packet};
+    use imcp::frame::Address;
+
+    struct RecordingOutput {
+        duties: std::vec::Vec<u16>,
+    }
+
+    impl PwmOutput for RecordingOutput {
+        fn max_duty(&self) -> u16 {
+            1000
+        }
+
+        fn set_duty(&mut self, duty: u16) {
+            self.duties.push(duty);
+        }
+    }
+
+    #[test]
+    fn fade_starts_from_current_brightness() {
+        let mut channel = PwmChannel::new();
+        channel.set(0, PwmLevel::fade(200, 100));
+        assert_eq!(channel.brightness(0), 0);
+        assert_eq!(channel.brightness(50), 100);
+        assert_eq!(channel.brightness(100), 200);
+
+        // フェードの途中で暗くする
+        channel.set(50, PwmLevel::fade(0, 100));
+        assert_eq!(channel.brightness(50), 100);
+        assert_eq!(channel.brightness(100), 50);
+        assert!(channel.is_fading(100));
+        assert_eq!(channel.brightness(150), 0);
+        assert!(!channel.is_fading(150));
+
+        channel.set(200, PwmLevel::set(7));
+        assert_eq!(channel.brightness(200), 7);
+    }
+
+    #[test]
+    fn poll_writes_duty_only_on_change() {
+        let mut channel = PwmChannel::new();
+        let mut output = RecordingOutput {
+            duties: std::vec::Vec::new(),
+        };
+
+        let frame = Frame::new(
+            Address::Unicast(0x22),
+            IMCP_MASTER_ADDRESS,
+            FramePayload::Data(
+                encode_data_packet(&DisplayData {
+                    seq: 0,
+                    target: DisplayTarget::Backlight(1),
+                    payload: DisplayPayload::Pwm(PwmLevel::set(u8::MAX)),
+                })
+                .unwrap(),
+            ),
+        );
+        let (target, level) = pwm_level_from_frame(&frame).unwrap();
+        assert_eq!(target, DisplayTarget::Backlight(1));
+
+        channel.poll(0, &mut output);
+        channel.set(10, level);
+        channel.poll(10, &mut output);
+        channel.poll(20, &mut output);
+
+        assert_eq!(output.duties, [0, 1000]);
+    }
+}
@@ -0,0 +1,394 @@
+//! `FirmwareUpdate` の受信と書き込み
+//!
+//! 書き込み先は `UpdateFlash` で抽象化する。
+//! 実機では bootloader の更新領域、テストでは `MemoryUpdateFlash` を使う。
+//! 受信済みの長さは RAM 上だけで持つため、再送による再開はデバイスが起動している間に限られる。
+
+use hcp::{AppPacketKind, ImageHash, UpdateCommand, UpdateError, UpdatePhase, UpdateStatus};
+use sha2::{Digest, Sha256};
+
+/// 検証時に一度に読み出す長さ
+const VERIFY_READ_LEN: u32 = 64;
+
+/// 新しいイメージの書き込み先
+pub trait UpdateFlash {
+    type Error;
+
+    /// 書き込めるイメージの最大の長さ
+    fn capacity(&self) -> u32;
+    /// 先頭から `len` byte を書き込める状態にする
+    fn erase(&mut self, len: u32) -> Result<(), Self::Error>;
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
+    fn read(&mut self, offset: u32, buffer: &mut [u8]) -> Result<(), Self::Error>;
+    /// 次回起動時に書き込んだイメージを使うようにする
+    fn mark_updated(&mut self, image_size: u32) -> Result<(), Self::Error>;
+}
+
+/// RAM 上の書き込み先
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryUpdateFlash<const N: usize> {
+    data: [u8; N],
+    updated: Option<u32>,
+}
+
+impl<const N: usize> MemoryUpdateFlash<N> {
+    pub const fn new() -> Self {
+        Self {
+            data: [0xFF; N],
+            updated: None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// `mark_updated` されたイメージの長さ
+    pub fn updated_image_size(&self) -> Option<u32> {
+        self.updated
+    }
+
+    fn range(&self, offset: u32, len: usize) -> Option<core::ops::Range<usize>> {
+        let start = usize::try_from(offset).ok()?;
+        let end = start.checked_add(len)?;
+        (end <= N).then_some(start..end)
+    }
+}
+
+impl<const N: usize> Default for MemoryUpdateFlash<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> UpdateFlash for MemoryUpdateFlash<N> {
+    type Error = ();
+
+    fn capacity(&self) -> u32 {
+        u32::try_from(N).unwrap_or(u32::MAX)
+    }
+
+    fn erase(&mut self, len: u32) -> Result<(), Self::Error> {
+        let range = self
+            .range(0, usize::try_from(len).map_err(|_| ())?)
+            .ok_or(())?;
+        self.data[range].fill(0xFF);
+        self.updated = None;
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+        let range = self.range(offset, data.len()).ok_or(())?;
+        self.data[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&mut self, offset: u32, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let range = self.range(offset, buffer.len()).ok_or(())?;
+        buffer.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn mark_updated(&mut self, image_size: u32) -> Result<(), Self::Error> {
+        self.updated = Some(image_size);
+        Ok(())
+    }
+}
+
+/// デバイス側の更新セッション
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmwareUpdater {
+    phase: UpdatePhase,
+    image_size: u32,
+    sha256: ImageHash,
+    received: u32,
+}
+
+impl FirmwareUpdater {
+    pub const fn new() -> Self {
+        Self {
+            phase: UpdatePhase::Idle,
+            image_size: 0,
+            sha256: [0; 32],
+            received: 0,
+        }
+    }
+
+    pub fn status(&self) -> UpdateStatus {
+        self.status_with(None)
+    }
+
+    /// `Commit` 済みで、再起動すれば新しいイメージで起動する
+    pub fn is_committed(&self) -> bool {
+        self.phase == UpdatePhase::Committed
+    }
+
+    /// コマンドを処理して応答を返す
+    pub fn handle<F: UpdateFlash>(
+        &mut self,
+        flash: &mut F,
+        command: &UpdateCommand,
+    ) -> UpdateStatus {
+        let result = match command {
+            UpdateCommand::Begin { image_size, sha256 } => self.begin(flash, *image_size, sha256),
+            UpdateCommand::Chunk { offset, data } => self.write_chunk(flash, *offset, data),
+            UpdateCommand::Verify => self.verify(flash),
+            UpdateCommand::Commit => self.commit(flash),
+            UpdateCommand::Abort => {
+                *self = Self::new();
+                Ok(())
+            }
+        };
+        self.status_with(result.err())
+    }
+
+    fn begin<F: UpdateFlash>(
+        &mut self,
+        flash: &mut F,
+        image_size: u32,
+        sha256: &ImageHash,
+    ) -> Result<(), UpdateError> {
+        // 同じイメージなら受信済みの位置から続ける
+        if matches!(self.phase, UpdatePhase::Receiving | UpdatePhase::Verified)
+            && self.image_size == image_size
+            && self.sha256 == *sha256
+        {
+            self.phase = UpdatePhase::Receiving;
+            return Ok(());
+        }
+
+        *self = Self::new();
+        if image_size > flash.capacity() {
+            return Err(UpdateError::ImageTooLarge);
+        }
+        flash
+            .erase(image_size)
+            .map_err(|_| UpdateError::FlashFailed)?;
+        self.phase = UpdatePhase::Receiving;
+        self.image_size = image_size;
+        self.sha256 = *sha256;
+        Ok(())
+    }
+
+    fn write_chunk<F: UpdateFlash>(
+        &mut self,
+        flash: &mut F,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), UpdateError> {
+        if !matches!(self.phase, UpdatePhase::Receiving | UpdatePhase::Verified) {
+            return Err(UpdateError::NotStarted);
+        }
+        let len = u32::try_from(data.len()).map_err(|_| UpdateError::ImageTooLarge)?;
+        let end = offset.checked_add(len).ok_or(UpdateError::ImageTooLarge)?;
+
+        // 再送された書き込み済みの chunk は無視する
+        if end <= self.received {
+            return Ok(());
+        }
+        if self.phase != UpdatePhase::Receiving || offset != self.received {
+            return Err(UpdateError::UnexpectedOffset);
+        }
+        if end > self.image_size {
+            return Err(UpdateError::ImageTooLarge);
+        }
+
+        flash
+            .write(offset, data)
+            .map_err(|_| UpdateError::FlashFailed)?;
+        self.received = end;
+        Ok(())
+    }
+
+    fn verify<F: UpdateFlash>(&mut self, flash: &mut F) -> Result<(), UpdateError> {
+        match self.phase {
+            UpdatePhase::Idle => return Err(UpdateError::NotStarted),
+            UpdatePhase::Verified | UpdatePhase::Committed => return Ok(()),
+            UpdatePhase::Receiving if self.received < self.image_size => {
+                return Err(UpdateError::Incomplete);
+            }
+            UpdatePhase::Receiving => {}
+        }
+
+        let mut hasher = Sha256::new();
+        let mut buffer = [0u8; VERIFY_READ_LEN as usize];
+        let mut offset = 0;
+        while offset < self.image_size {
+            let len = (self.image_size - offset).min(VERIFY_READ_LEN);
+            let chunk = buffer
+                .get_mut(..usize::try_from(len).map_err(|_| UpdateError::FlashFailed)?)
+                .ok_or(UpdateError::FlashFailed)?;
+            flash
+                .read(offset, chunk)
+                .map_err(|_| UpdateError::FlashFailed)?;
+            hasher.update(&*chunk);
+            offset += len;
+        }
+
+        if hasher.finalize().as_slice() != self.sha256 {
+            *self = Self::new();
+            return Err(UpdateError::HashMismatch);
+        }
+        self.phase = UpdatePhase::Verified;
+        Ok(())
+    }
+
+    fn commit<F: UpdateFlash>(&mut self, flash: &mut F) -> Result<(), UpdateError> {
+        match self.phase {
+            UpdatePhase::Idle => Err(UpdateError::NotStarted),
+            UpdatePhase::Receiving => Err(UpdateError::NotVerified),
+            UpdatePhase::Committed => Ok(()),
+            UpdatePhase::Verified => {
+                flash
+                    .mark_updated(self.image_size)
+                    .map_err(|_| UpdateError::FlashFailed)?;
+                self.phase = UpdatePhase::Committed;
+                Ok(())
+            }
+        }
+    }
+
+    fn status_with(&self, error: Option<UpdateError>) -> UpdateStatus {
+        UpdateStatus {
+            phase: self.phase,
+            received: self.received,
+            error,
+        }
+    }
+}
+
+impl Default for FirmwareUpdater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `FirmwareUpdate` を処理して `FirmwareUpdateStatus` を返す
+///
+/// それ以外の packet の場合は `None`
+pub fn handle_update_packet<F: UpdateFlash>(
+    updater: &mut FirmwareUpdater,
+    flash: &mut F,
+    packet: &AppPacketKind,
+) -> Option<AppPacketKind> {
+    let AppPacketKind::FirmwareUpdate(command) = packet else {
+        return None;
+    };
+    Some(AppPacketKind::FirmwareUpdateStatus(
+        updater.handle(flash, command),
+    ))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use hcp::UpdateSender;
+
+    fn image(len: usize) -> std::vec::Vec<u8> {
+        (0..len)
+            .map(|i| u8::try_from(i * 7 % 251).unwrap())
+            .collect()
+    }
+
+    fn hash(image: &[u8]) -> ImageHash {
+        Sha256::digest(image).into()
+    }
+
+    /// `drop` 番目の応答を届かなかったことにして、最後まで送る
+    fn run(
+        sender: &mut UpdateSender<'_>,
+        updater: &mut FirmwareUpdater,
+        flash: &mut MemoryUpdateFlash<1024>,
+        drop: Option<usize>,
+    ) -> Result<(), hcp::UpdateSenderError> {
+        let mut count = 0;
+        while let Some(command) = sender.next_command() {
+            let packet = AppPacketKind::FirmwareUpdate(command);
+            let Some(AppPacketKind::FirmwareUpdateStatus(status)) =
+                handle_update_packet(updater, flash, &packet)
+            else {
+                unreachable!();
+            };
+            count += 1;
+            if drop == Some(count) {
+                continue;
+            }
+            sender.accept(&status)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sender_updates_memory_flash() {
+        let image = image(300);
+        let mut sender = UpdateSender::new(&image, hash(&image))
+            .unwrap()
+            .with_chunk_len(64);
+        let mut updater = FirmwareUpdater::new();
+        let mut flash = MemoryUpdateFlash::<1024>::new();
+
+        run(&mut sender, &mut updater, &mut flash, Some(3)).unwrap();
+
+        assert!(updater.is_committed());
+        assert_eq!(flash.updated_image_size(), Some(300));
+        assert_eq!(&flash.as_bytes()[..300], image.as_slice());
+        assert_eq!(sender.progress(), (300, 300));
+    }
+
+    #[test]
+    fn transfer_resumes_with_new_sender() {
+        let image = image(500);
+        let mut updater = FirmwareUpdater::new();
+        let mut flash = MemoryUpdateFlash::<1024>::new();
+
+        // 途中でホストが切断した
+        let mut first = UpdateSender::new(&image, hash(&image)).unwrap();
+        for _ in 0..3 {
+            let command = first.next_command().unwrap();
+            first.accept(&updater.handle(&mut flash, &command)).unwrap();
+        }
+        assert_eq!(updater.status().received, 192);
+
+        let mut second = UpdateSender::new(&image, hash(&image)).unwrap();
+        let status = updater.handle(&mut flash, &second.next_command().unwrap());
+        assert_eq!(status.received, 192);
+        second.accept(&status).unwrap();
+        run(&mut second, &mut updater, &mut flash, None).unwrap();
+
+        assert_eq!(&flash.as_bytes()[..500], image.as_slice());
+        assert_eq!(flash.updated_image_size(), Some(500));
+    }
+
+    #[test]
+    fn corrupted_image_is_rejected() {
+        let image = image(100);
+        let mut updater = FirmwareUpdater::new();
+        let mut flash = MemoryUpdateFlash::<1024>::new();
+
+        let mut sender = UpdateSender::new(&image, [0; 32]).unwrap();
+        assert_eq!(
+            run(&mut sender, &mut updater, &mut flash, None),
+            Err(hcp::UpdateSenderError::Device(UpdateError::HashMismatch))
+        );
+        assert_eq!(updater.status().phase, UpdatePhase::Idle);
+        assert_eq!(flash.updated_image_size(), None);
+
+        assert_eq!(
+            updater.handle(&mut flash, &UpdateCommand::Commit).error,
+            Some(UpdateError::NotStarted)
+        );
+        assert_eq!(
+            updater
+                .handle(
+                    &mut flash,
+                    &UpdateCommand::Begin {
+                        image_size: 2048,
+                        sha256: [0; 32],
+                    },
+                )
+                .error,
+            Some(UpdateError::ImageTooLarge)
+        );
+    }
+}
@@ -0,0 +1,397 @@
+//! パネル定義ファイル (TOML) の読み込みと `Panel` のコード生成
+//!
+//! 定義ファイルは `panels/` に置き、firmware の build script と manager がこの crate から読む。
+//! firmware の build script は `PanelDefinition::to_rust` の出力を `OUT_DIR` に書き、`include!` で取り込む。
+//! manager は `panel_definitions` で全部を読み、操作系の一覧に使う。
+//!
+//! ```toml
+//! device_kind = "UpperPanelDdi"
+//!
+//! [matrix]
+//! rows = 8
+//! columns = 5
+//! row_pins = [2, 3, 4, 5, 6, 7, 8, 9]
+//! column_pins = [10, 11, 12, 13, 14]
+//!
+//! [[controls]]
+//! id = 0
+//! kind = "Button"
+//! label = "Button 1"
+//! row = 0
+//! column = 0
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
+
+use hcp::{ControlKind, DeviceKind, MAX_LABEL_LEN, SupportedEvents};
+use serde::Deserialize;
+
+/// 上部パネル (DDI) の定義ファイル
+pub const UPPER_PANEL_DDI: &str = include_str!("../panels/upper_panel_ddi.toml");
+
+/// `panels/` にあるすべての定義ファイル
+pub const PANEL_DEFINITION_SOURCES: [&str; 1] = [UPPER_PANEL_DDI];
+
+/// `PANEL_DEFINITION_SOURCES` をすべて読む。1 つでも読めなければエラーにする
+pub fn panel_definitions() -> Result<Vec<PanelDefinition>, PanelDefinitionError> {
+    PANEL_DEFINITION_SOURCES
+        .iter()
+        .map(|source| PanelDefinition::from_toml(source))
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PanelDefinition {
+    pub device_kind: DeviceKind,
+    pub matrix: Option<MatrixDefinition>,
+    #[serde(default)]
+    pub controls: Vec<ControlDefinition>,
+}
+
+/// ボタンマトリクスの大きさと GPIO
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatrixDefinition {
+    pub rows: u8,
+    pub columns: u8,
+    /// 行ごとの GPIO 番号。空なら pin の割り当てを生成しない
+    #[serde(default)]
+    pub row_pins: Vec<u8>,
+    #[serde(default)]
+    pub column_pins: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ControlDefinition {
+    pub id: u16,
+    pub kind: ControlKind,
+    pub label: String,
+    pub row: Option<u8>,
+    pub column: Option<u8>,
+}
+
+impl ControlDefinition {
+    pub fn events(&self) -> SupportedEvents {
+        self.kind.default_events()
+    }
+
+    pub fn matrix_position(&self) -> Option<(u8, u8)> {
+        self.row.zip(self.column)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PanelDefinitionError {
+    Parse(String),
+    DuplicateControlId(u16),
+    LabelTooLong(u16),
+    /// `row` と `column` の片方だけがある
+    IncompletePosition(u16),
+    /// `[matrix]` がないのにマトリクス上の位置がある
+    MissingMatrix(u16),
+    PositionOutOfRange {
+        id: u16,
+        row: u8,
+        column: u8,
+    },
+    DuplicatePosition {
+        row: u8,
+        column: u8,
+    },
+    /// 32 を超える行や列は `MatrixScanner` で扱えない
+    MatrixTooLarge,
+    /// pin の数が行数・列数と合わないか、同じ pin を使っている
+    InvalidPins,
+}
+
+impl fmt::Display for PanelDefinitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PanelDefinitionError::Parse(message) => {
+                write!(f, "invalid panel definition: {message}")
+            }
+            PanelDefinitionError::DuplicateControlId(id) => {
+                write!(f, "control {id} is defined twice")
+            }
+            PanelDefinitionError::LabelTooLong(id) => {
+                write!(
+                    f,
+                    "label of control {id} is longer than {MAX_LABEL_LEN} bytes"
+                )
+            }
+            PanelDefinitionError::IncompletePosition(id) => {
+                write!(f, "control {id} needs both row and column")
+            }
+            PanelDefinitionError::MissingMatrix(id) => {
+                write!(
+                    f,
+                    "control {id} has a matrix position but [matrix] is missing"
+                )
+            }
+            PanelDefinitionError::PositionOutOfRange { id, row, column } => {
+                write!(f, "control {id} at R{row}C{column} is outside the matrix")
+            }
+            PanelDefinitionError::DuplicatePosition { row, column } => {
+                write!(f, "R{row}C{column} is used by more than one control")
+            }
+            PanelDefinitionError::MatrixTooLarge => {
+                write!(f, "matrix is limited to 32 rows and columns")
+            }
+            PanelDefinitionError::InvalidPins => {
+                write!(f, "matrix pins must be unique and match rows and columns")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PanelDefinitionError {}
+
+impl PanelDefinition {
+    /// TOML を読み、内容を検証する
+    pub fn from_toml(source: &str) -> Result<Self, PanelDefinitionError> {
+        let definition: Self = toml::from_str(source)
+            .map_err(|error| PanelDefinitionError::Parse(error.to_string()))?;
+        definition.validate()?;
+        Ok(definition)
+    }
+
+    pub fn validate(&self) -> Result<(), PanelDefinitionError> {
+        if let Some(matrix) = &self.matrix {
+            if matrix.rows > 32 || matrix.columns > 32 {
+                return Err(PanelDefinitionError::MatrixTooLarge);
+            }
+            let pins_given = !matrix.row_pins.is_empty() || !matrix.column_pins.is_empty();
+            let unique: BTreeSet<_> = matrix.row_pins.iter().chain(&matrix.column_pins).collect();
+            if pins_given
+                && (matrix.row_pins.len() != usize::from(matrix.rows)
+                    || matrix.column_pins.len() != usize::from(matrix.columns)
+                    || unique.len() != matrix.row_pins.len() + matrix.column_pins.len())
+            {
+                return Err(PanelDefinitionError::InvalidPins);
+            }
+        }
+
+        let mut ids = BTreeSet::new();
+        let mut positions = BTreeSet::new();
+        for control in &self.controls {
+            if !ids.insert(control.id) {
+                return Err(PanelDefinitionError::DuplicateControlId(control.id));
+            }
+            if control.label.len() > MAX_LABEL_LEN {
+                return Err(PanelDefinitionError::LabelTooLong(control.id));
+            }
+            if control.row.is_some() != control.column.is_some() {
+                return Err(PanelDefinitionError::IncompletePosition(control.id));
+            }
+            let Some((row, column)) = control.matrix_position() else {
+                continue;
+            };
+            let Some(matrix) = &self.matrix else {
+                return Err(PanelDefinitionError::MissingMatrix(control.id));
+            };
+            if row >= matrix.rows || column >= matrix.columns {
+                return Err(PanelDefinitionError::PositionOutOfRange {
+                    id: control.id,
+                    row,
+                    column,
+                });
+            }
+            if !positions.insert((row, column)) {
+                return Err(PanelDefinitionError::DuplicatePosition { row, column });
+            }
+        }
+        Ok(())
+    }
+
+    /// control ID ごとの送るイベントの種類
+    pub fn supported_events(&self) -> BTreeMap<u16, SupportedEvents> {
+        self.controls
+            .iter()
+            .map(|control| (control.id, control.events()))
+            .collect()
+    }
+
+    /// firmware に `include!` する Rust のコード
+    ///
+    /// `PANEL` (`Panel`) と、`[matrix]` があれば `PANEL_MATRIX_ROWS` / `PANEL_MATRIX_COLUMNS`、
+    /// pin があれば `Peripherals` から行と列の pin の配列を取り出す `panel_matrix_pins!` を定義する
+    pub fn to_rust(&self) -> String {
+        let mut code = String::new();
+        // String への書き込みは失敗しない
+        let _ = self.write_rust(&mut code);
+        code
+    }
+
+    fn write_rust(&self, code: &mut String) -> fmt::Result {
+        writeln!(
+            code,
+            "// パネル定義ファイルから生成したコード。直接編集しない"
+        )?;
+        writeln!(code)?;
+        if let Some(matrix) = &self.matrix {
+            writeln!(
+                code,
+                "pub const PANEL_MATRIX_ROWS: usize = {};",
+                matrix.rows
+            )?;
+            writeln!(
+                code,
+                "pub const PANEL_MATRIX_COLUMNS: usize = {};",
+                matrix.columns
+            )?;
+            writeln!(code)?;
+        }
+        writeln!(
+            code,
+            "pub static PANEL: ::homecockpit_firmware_base::Panel = ::homecockpit_firmware_base::Panel {{"
+        )?;
+        writeln!(
+            code,
+            "    device_kind: ::hcp::DeviceKind::{:?},",
+            self.device_kind
+        )?;
+        writeln!(code, "    controls: &[")?;
+        for control in &self.controls {
+            writeln!(code, "        ::homecockpit_firmware_base::PanelControl {{")?;
+            writeln!(code, "            id: {},", control.id)?;
+            writeln!(
+                code,
+                "            kind: ::hcp::ControlKind::{:?},",
+                control.kind
+            )?;
+            writeln!(code, "            label: {:?},", control.label)?;
+            match control.matrix_position() {
+                Some((row, column)) => {
+                    writeln!(code, "            matrix: Some(({row}, {column})),")?
+                }
+                None => writeln!(code, "            matrix: None,")?,
+            }
+            writeln!(code, "        }},")?;
+        }
+        writeln!(code, "    ],")?;
+        writeln!(code, "}};")?;
+
+        if let Some(matrix) = &self.matrix
+            && !matrix.row_pins.is_empty()
+        {
+            let pins = |pins: &[u8]| {
+                pins.iter()
+                    .map(|pin| std::format!("$p.PIN_{pin}.into()"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            writeln!(code)?;
+            writeln!(code, "/// `(行の pin の配列, 列の pin の配列)`")?;
+            writeln!(code, "#[allow(unused_macros)]")?;
+            writeln!(code, "macro_rules! panel_matrix_pins {{")?;
+            writeln!(code, "    ($p:ident) => {{")?;
+            writeln!(
+                code,
+                "        ([{}], [{}])",
+                pins(&matrix.row_pins),
+                pins(&matrix.column_pins)
+            )?;
+            writeln!(code, "    }};")?;
+            writeln!(code, "}}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    const DEFINITION: &str = r#"
+device_kind = "ButtonPanel"
+
+[matrix]
+rows = 2
+columns = 2
+row_pins = [2, 3]
+column_pins = [10, 11]
+
+[[controls]]
+id = 0
+kind = "Button"
+label = "MASTER ARM"
+row = 0
+column = 1
+
+[[controls]]
+id = 5
+kind = "Encoder"
+label = "HDG"
+"#;
+
+    #[test]
+    fn definition_is_parsed_and_generated() {
+        let definition = PanelDefinition::from_toml(DEFINITION).unwrap();
+        assert_eq!(definition.device_kind, DeviceKind::ButtonPanel);
+        assert_eq!(definition.controls[0].matrix_position(), Some((0, 1)));
+        assert_eq!(
+            definition.supported_events().get(&5),
+            Some(&SupportedEvents::ENCODER_DELTA)
+        );
+
+        let code = definition.to_rust();
+        assert!(code.contains("pub const PANEL_MATRIX_ROWS: usize = 2;"));
+        assert!(code.contains("device_kind: ::hcp::DeviceKind::ButtonPanel,"));
+        assert!(code.contains("label: \"MASTER ARM\","));
+        assert!(code.contains("matrix: Some((0, 1)),"));
+        assert!(code.contains(
+            "([$p.PIN_2.into(), $p.PIN_3.into()], [$p.PIN_10.into(), $p.PIN_11.into()])"
+        ));
+    }
+
+    #[test]
+    fn panel_files_are_valid() {
+        let definitions = panel_definitions().unwrap();
+        assert_eq!(definitions.len(), PANEL_DEFINITION_SOURCES.len());
+        assert_eq!(definitions[0].device_kind, DeviceKind::UpperPanelDdi);
+    }
+
+    #[test]
+    fn invalid_definitions_are_rejected() {
+        let with_control = |control: &str| std::format!("{DEFINITION}\n[[controls]]\n{control}");
+
+        assert_eq!(
+            PanelDefinition::from_toml(&with_control("id = 5\nkind = \"Button\"\nlabel = \"X\"")),
+            Err(PanelDefinitionError::DuplicateControlId(5))
+        );
+        assert_eq!(
+            PanelDefinition::from_toml(&with_control(
+                "id = 6\nkind = \"Button\"\nlabel = \"X\"\nrow = 2\ncolumn = 0"
+            )),
+            Err(PanelDefinitionError::PositionOutOfRange {
+                id: 6,
+                row: 2,
+                column: 0
+            })
+        );
+        assert_eq!(
+            PanelDefinition::from_toml(&with_control(
+                "id = 6\nkind = \"Button\"\nlabel = \"X\"\nrow = 0\ncolumn = 1"
+            )),
+            Err(PanelDefinitionError::DuplicatePosition { row: 0, column: 1 })
+        );
+        assert_eq!(
+            PanelDefinition::from_toml(&with_control(
+                "id = 6\nkind = \"Button\"\nlabel = \"A VERY LONG LABEL\""
+            )),
+            Err(PanelDefinitionError::LabelTooLong(6))
+        );
+        assert!(matches!(
+            PanelDefinition::from_toml(&with_control("id = 6\nkind = \"Lever\"\nlabel = \"X\"")),
+            Err(PanelDefinitionError::Parse(_))
+        ));
+        assert_eq!(
+            PanelDefinition::from_toml(&DEFINITION.replace("[10, 11]", "[10, 3]")),
+            Err(PanelDefinitionError::InvalidPins)
+        );
+    }
+}
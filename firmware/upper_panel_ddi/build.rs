@@ -13,6 +13,8 @@ use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
 
+use homecockpit_panel_definition::{PanelDefinition, UPPER_PANEL_DDI};
+
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
@@ -29,6 +31,14 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
+    // 操作系の配置はパネル定義ファイルから生成して main.rs に include! する
+    let definition = PanelDefinition::from_toml(UPPER_PANEL_DDI)
+        .unwrap_or_else(|error| panic!("upper_panel_ddi.toml: {error}"));
+    File::create(out.join("panel.rs"))
+        .unwrap()
+        .write_all(definition.to_rust().as_bytes())
+        .unwrap();
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
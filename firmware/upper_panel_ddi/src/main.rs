@@ -18,39 +18,73 @@ use embassy_rp::{
 };
 use embassy_rp::{
     bind_interrupts,
-    gpio::{Input, Level, Output},
+    gpio::{AnyPin, Input, Level, Output},
     pac::UART0,
     peripherals::UART0,
     uart::{BufferedUart, Config},
     Peri,
 };
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex};
-use embassy_time::Timer;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    channel::{Channel, TrySendError},
+    mutex::Mutex,
+};
+use embassy_time::{Instant, Timer};
 use embedded_io_async::{Read, Write};
-use hcp::{Capabilities, DeviceKind, Version};
+use hcp::{
+    ConfigKey, FEATURE_CONTROL_EVENTS, FEATURE_CONTROL_STATE, FEATURE_DESCRIPTORS,
+    FEATURE_IDENTIFY, LogLevel, Version, decode_set_packet,
+};
 use homecockpit_firmware_base::{
-    DeviceDescriptor, DeviceRuntimeState, FEATURE_CONTROL_EVENTS, build_button_control_event,
-    build_device_hello_packet, control_id_from_matrix_position, encode_set_frame,
-    try_assign_address_from_frame,
+    ControlEventQueue, DeviceAction, DeviceConfig, DeviceDescriptor, DeviceLogger,
+    DeviceRuntimeState, FirmwareBaseError, Identifier, IdentifyOutput, MatrixPins, MatrixScanner,
+    OverflowPolicy, VolatileConfigStorage, build_control_state_packet, debounce_samples,
+    descriptor_request_from_frame, encode_set_frame, handle_config_packet,
+    identify_request_from_frame, is_control_state_request,
 };
 use imcp::{
     Imcp,
-    frame::Frame,
+    frame::{Frame, FramePayload},
 };
 use imcp_embassy::{EmbassyReceiver, EmbassySender, new};
 use imcp_embedded::{ImcpEmbedded, RpUartCarrierSense};
 use static_cell::StaticCell;
 use {defmt_rtt as _, panic_probe as _};
 
+// PANEL と PANEL_MATRIX_*、panel_matrix_pins! は build.rs がパネル定義ファイルから生成する
+include!(concat!(env!("OUT_DIR"), "/panel.rs"));
+
 const BAUD_RATE: u32 = 115200;
-const CONTROL_MATRIX_COLUMNS: u8 = 5;
-const CONTROL_MATRIX_ROWS: u8 = 8;
 #[cfg(feature = "rp2040")]
 const FLASH_SIZE: usize = 2 * 1024 * 1024;
 
-static RESULT: Mutex<CriticalSectionRawMutex, [[Level; 5]; 8]> = Mutex::new([[Level::Low; 5]; 8]);
+static MATRIX: Mutex<
+    CriticalSectionRawMutex,
+    MatrixScanner<PANEL_MATRIX_ROWS, PANEL_MATRIX_COLUMNS>,
+> = Mutex::new(MatrixScanner::new());
 static DEVICE_STATE: Mutex<CriticalSectionRawMutex, DeviceRuntimeState> =
     Mutex::new(DeviceRuntimeState::new());
+static DEVICE_CONFIG: Mutex<CriticalSectionRawMutex, DeviceConfig> =
+    Mutex::new(DeviceConfig::DEFAULT);
+// flash への保存は未実装なので、再起動すると既定値に戻る
+static CONFIG_STORAGE: Mutex<CriticalSectionRawMutex, VolatileConfigStorage> =
+    Mutex::new(VolatileConfigStorage::new());
+
+/// 送信を待つ操作系のイベントの数
+const CONTROL_EVENT_QUEUE_SIZE: usize = 64;
+// 溢れたら同じスイッチのイベントを最新の状態にまとめる
+static CONTROL_EVENTS: Mutex<CriticalSectionRawMutex, ControlEventQueue<CONTROL_EVENT_QUEUE_SIZE>> =
+    Mutex::new(ControlEventQueue::new(OverflowPolicy::CoalesceLatest));
+
+// 最大 4 件まで続けて受け付け、以降は 1 秒に 1 件
+static DEVICE_LOGGER: Mutex<CriticalSectionRawMutex, DeviceLogger<8>> =
+    Mutex::new(DeviceLogger::new(LogLevel::Info, 4, 1000));
+
+// 250ms ごとに点灯と消灯を切り替える
+static IDENTIFIER: Mutex<CriticalSectionRawMutex, Identifier> = Mutex::new(Identifier::new(250));
+
+/// このパネルが対応する設定 (LED・エンコーダーはまだない)
+const SUPPORTED_CONFIG_KEYS: [ConfigKey; 2] = [ConfigKey::DebounceMs, ConfigKey::ScanIntervalMs];
 
 static FRAME_CHANNEL: Channel<CriticalSectionRawMutex, Frame, 5> = Channel::new();
 
@@ -65,7 +99,8 @@ bind_interrupts!(struct Irqs {
 
 #[derive(Clone, Copy)]
 struct DeviceIdentity {
-    device_id: u64,
+    /// 読めなかった場合は `None`。同じ ID を名乗って他のボードとアドレスが重ならないよう、通常の Join を使う
+    device_id: Option<u64>,
     join_id: u32,
 }
 
@@ -80,28 +115,25 @@ async fn main(spawner: Spawner) {
     #[cfg(feature = "rp235x")]
     let device_identity = initialize_device_identity(p.FLASH, p.TRNG);
 
-    let outputs: [Output<'static>; 8] = [
-        Output::new(p.PIN_2, Level::Low),
-        Output::new(p.PIN_3, Level::Low),
-        Output::new(p.PIN_4, Level::Low),
-        Output::new(p.PIN_5, Level::Low),
-        Output::new(p.PIN_6, Level::Low),
-        Output::new(p.PIN_7, Level::Low),
-        Output::new(p.PIN_8, Level::Low),
-        Output::new(p.PIN_9, Level::Low),
-    ];
-
-    let inputs: [Input<'static>; 5] = [
-        Input::new(p.PIN_10, embassy_rp::gpio::Pull::Down),
-        Input::new(p.PIN_11, embassy_rp::gpio::Pull::Down),
-        Input::new(p.PIN_12, embassy_rp::gpio::Pull::Down),
-        Input::new(p.PIN_13, embassy_rp::gpio::Pull::Down),
-        Input::new(p.PIN_14, embassy_rp::gpio::Pull::Down),
-    ];
-
-    spawner.spawn(scan_matrix(inputs, outputs).expect("failed spawn scan_matrix"));
-
-    let mut old: [[Level; 5]; 8] = [[Level::Low; 5]; 8];
+    let (row_pins, column_pins): (
+        [Peri<'static, AnyPin>; PANEL_MATRIX_ROWS],
+        [Peri<'static, AnyPin>; PANEL_MATRIX_COLUMNS],
+    ) = panel_matrix_pins!(p);
+    let outputs = row_pins.map(|pin| Output::new(pin, Level::Low));
+    let inputs = column_pins.map(|pin| Input::new(pin, embassy_rp::gpio::Pull::Down));
+
+    spawner.spawn(
+        scan_matrix(MatrixGpio {
+            rows: outputs,
+            columns: inputs,
+        })
+        .expect("failed spawn scan_matrix"),
+    );
+    // Pico の基板上の LED
+    spawner.spawn(
+        blink_identify(StatusLed(Output::new(p.PIN_25, Level::Low)))
+            .expect("failed spawn blink_identify"),
+    );
 
     let mut config = Config::default();
     config.baudrate = BAUD_RATE;
@@ -136,49 +168,113 @@ async fn main(spawner: Spawner) {
     spawner
         .spawn(imcp_task(imcp, imcp_embedded, device_identity).expect("failed spawn imcp_task"));
 
+    let mut reported_overflows = 0;
     loop {
-        if let Ok(g) = RESULT.try_lock() {
-            for (r_index, (g_row, o_row)) in g.iter().zip(old.iter()).enumerate() {
-                for (c_index, (g_col, o_col)) in g_row.iter().zip(o_row.iter()).enumerate() {
-                    if g_col != o_col {
-                        info!("r:{} c{} {} → {}", r_index, c_index, o_col, g_col);
-                        enqueue_control_event(
-                            &sender2,
-                            r_index as u8,
-                            c_index as u8,
-                            bool::from(*g_col),
-                        );
-                    }
-                }
+        if let Ok(mut matrix) = MATRIX.try_lock() {
+            // 前回から変化したスイッチは、DeviceHello の受信確認前やバスが詰まっている間もキューに溜める
+            let detected_at_us = Instant::now().as_micros();
+            let mut events = CONTROL_EVENTS.lock().await;
+            while let Some(change) =
+                matrix.next_change_with(|row, column| PANEL.matrix_control_id(row, column))
+            {
+                info!("control {} {}", change.control_id, change.event);
+                events.push(detected_at_us, change);
+            }
+            drop(matrix);
+
+            let overflows = events.overflows();
+            if overflows != reported_overflows {
+                warn!("control event queue overflowed {} times", overflows);
+                forward_log(
+                    LogLevel::Warn,
+                    "matrix",
+                    format_args!("control event queue overflowed {} times", overflows),
+                );
+                reported_overflows = overflows;
+            }
+
+            if events.pending() == 0 {
+                send_pending_log(&sender2);
+            } else {
+                send_pending_control_events(&sender2, &mut events);
             }
-            old = *g;
         }
         Timer::after_millis(5).await;
     }
 }
 
+/// 行を High にして、押されたスイッチの列を High で読む
+struct MatrixGpio {
+    rows: [Output<'static>; PANEL_MATRIX_ROWS],
+    columns: [Input<'static>; PANEL_MATRIX_COLUMNS],
+}
+
+impl MatrixPins for MatrixGpio {
+    fn select_row(&mut self, row: usize) {
+        if let Some(output) = self.rows.get_mut(row) {
+            output.set_high();
+        }
+    }
+
+    fn release_row(&mut self, row: usize) {
+        if let Some(output) = self.rows.get_mut(row) {
+            output.set_low();
+        }
+    }
+
+    fn is_column_active(&mut self, column: usize) -> bool {
+        self.columns
+            .get(column)
+            .is_some_and(|input| input.is_high())
+    }
+}
+
 #[embassy_executor::task]
-async fn scan_matrix(inputs: [Input<'static>; 5], mut outputs: [Output<'static>; 8]) {
+async fn scan_matrix(mut pins: MatrixGpio) {
     loop {
-        for (index, ele) in outputs.iter_mut().enumerate() {
-            ele.set_high();
+        let config = *DEVICE_CONFIG.lock().await;
+        let scan_interval_ms = config.scan_interval_ms;
+        // 1 行ごとに選択と解除の後で待つので、同じ行を読む間隔は 2 x 行数 倍
+        let rows = u16::try_from(PANEL_MATRIX_ROWS).unwrap_or(u16::MAX);
+        let scan_period_ms = scan_interval_ms.saturating_mul(rows.saturating_mul(2));
+        MATRIX
+            .lock()
+            .await
+            .set_debounce_samples(debounce_samples(config.debounce_ms, scan_period_ms));
+
+        for row in 0..PANEL_MATRIX_ROWS {
+            pins.select_row(row);
+            Timer::after_millis(u64::from(scan_interval_ms)).await;
+            MATRIX.lock().await.read_row(&mut pins, row);
+            pins.release_row(row);
+            Timer::after_millis(u64::from(scan_interval_ms)).await;
+        }
+    }
+}
 
-            Timer::after_millis(1).await;
+struct StatusLed(Output<'static>);
 
-            for (index2, ele) in inputs.iter().enumerate() {
-                if let Ok(mut g) = RESULT.try_lock() {
-                    g[index][index2] = ele.get_level();
-                }
-            }
+impl IdentifyOutput for StatusLed {
+    fn set_identify(&mut self, on: bool) {
+        self.0.set_level(Level::from(on));
+    }
+}
 
-            ele.set_low();
-            Timer::after_millis(1).await;
-        }
+#[embassy_executor::task]
+async fn blink_identify(mut led: StatusLed) {
+    loop {
+        IDENTIFIER
+            .lock()
+            .await
+            .poll(Instant::now().as_millis(), &mut led);
+        Timer::after_millis(50).await;
     }
 }
+
 #[embassy_executor::task]
 async fn imcp_task(
     mut imcp: Imcp<
+        'static,
         'static,
         'static,
         EmbassyReceiver<'static, CriticalSectionRawMutex, 5>,
@@ -190,11 +286,26 @@ async fn imcp_task(
     let mut read_buffer = [0u8; 16];
     let tx_sender = FRAME_CHANNEL.sender();
 
-    imcp.send_join(device_identity.join_id)
-        .await
-        .unwrap_or_else(|e| warn!("join error {:?}", e));
+    let descriptor = device_descriptor(device_identity.device_id.unwrap_or_default());
 
     loop {
+        // 起動直後の Join と、受信確認のない DeviceHello の再送
+        let action = DEVICE_STATE
+            .lock()
+            .await
+            .poll(Instant::now().as_micros(), &descriptor);
+        match action {
+            Ok(Some(DeviceAction::Join)) => match device_identity.device_id {
+                Some(device_id) => {
+                    imcp.send_join_with_device_id(device_identity.join_id, device_id)
+                        .await
+                }
+                None => imcp.send_join(device_identity.join_id).await,
+            }
+            .unwrap_or_else(|e| warn!("join error {:?}", e)),
+            action => perform_device_action(&tx_sender, &mut *DEVICE_STATE.lock().await, action),
+        }
+
         match select(imcp_embedded.read(&mut read_buffer), imcp.write_tick()).await {
             embassy_futures::select::Either::First(Ok(s)) => {
                 let frame = imcp.read_tick(&read_buffer[..s]).await.unwrap_or_else(|e| {
@@ -202,7 +313,13 @@ async fn imcp_task(
                     None
                 });
                 if let Some(frame) = frame {
-                    handle_incoming_frame(&tx_sender, &frame, device_identity.device_id);
+                    // 参加と hello の frame は取りこぼすと先に進めないので、lock を待って処理する
+                    let mut state = DEVICE_STATE.lock().await;
+                    let action =
+                        state.handle_frame(Instant::now().as_micros(), &frame, &descriptor);
+                    perform_device_action(&tx_sender, &mut state, action);
+                    drop(state);
+                    handle_incoming_frame(&tx_sender, &frame).await;
                 }
                 info!("read: {}", s)
             }
@@ -227,77 +344,194 @@ async fn imcp_task(
     }
 }
 
-fn enqueue_control_event(
+/// 溜まったイベントを packet にして送る
+///
+/// `DeviceHello` の受信確認前と、前の packet を master が受け取るまでは何もしない
+fn send_pending_control_events(
     sender: &embassy_sync::channel::Sender<'static, CriticalSectionRawMutex, Frame, 5>,
-    row: u8,
-    column: u8,
-    pressed: bool,
+    events: &mut ControlEventQueue<CONTROL_EVENT_QUEUE_SIZE>,
 ) {
-    let frame = if let Ok(mut state) = DEVICE_STATE.try_lock() {
-        let Some(address) = state.address() else {
-            return;
-        };
-        let control_id = control_id_from_matrix_position(row, column, CONTROL_MATRIX_COLUMNS);
-        match build_button_control_event(&mut state, control_id, pressed)
-            .and_then(|packet| encode_set_frame(address, &packet))
-        {
-            Ok(frame) => Some(frame),
-            Err(e) => {
-                warn!("failed build control event {:?}", e);
-                None
-            }
-        }
-    } else {
-        None
+    if FRAME_CHANNEL.is_full() {
+        return;
+    }
+    let Ok(mut state) = DEVICE_STATE.try_lock() else {
+        return;
+    };
+    let frame = match events.next_packet(&mut state) {
+        Ok(Some(packet)) => encode_set_frame(&state, &packet),
+        Ok(None) => return,
+        Err(e) => Err(e),
     };
 
-    if let Some(frame) = frame
-        && let Err(e) = sender.try_send(frame)
-    {
-        warn!("failed queue control event {:?}", e);
+    match frame {
+        Ok(frame) => match queue_frame(sender, &mut state, frame) {
+            Ok(Some(index)) => events.packet_queued(index),
+            Ok(None) => {}
+            Err(e) => warn!("failed queue control event {:?}", e),
+        },
+        Err(e) => warn!("failed build control event {:?}", e),
+    }
+}
+
+/// master にログを送る。送信は `send_pending_log` がバスの空いているときに行う
+fn forward_log(level: LogLevel, tag: &str, args: core::fmt::Arguments<'_>) {
+    if let Ok(mut logger) = DEVICE_LOGGER.try_lock() {
+        logger.log_fmt(Instant::now().as_millis(), level, tag, args);
+    }
+}
+
+fn send_pending_log(
+    sender: &embassy_sync::channel::Sender<'static, CriticalSectionRawMutex, Frame, 5>,
+) {
+    if !FRAME_CHANNEL.is_empty() {
+        return;
+    }
+    let (Ok(mut state), Ok(mut logger)) = (DEVICE_STATE.try_lock(), DEVICE_LOGGER.try_lock())
+    else {
+        return;
+    };
+    let Some(packet) = logger.pop_packet(&state) else {
+        return;
+    };
+    match encode_set_frame(&state, &packet) {
+        Ok(frame) => {
+            if let Err(e) = queue_frame(sender, &mut state, frame) {
+                warn!("failed queue log {:?}", e);
+            }
+        }
+        Err(e) => warn!("failed encode log {:?}", e),
     }
 }
 
-fn device_descriptor() -> DeviceDescriptor {
+fn device_descriptor(device_id: u64) -> DeviceDescriptor {
     DeviceDescriptor {
-        device_id: 0,
-        device_kind: DeviceKind::UpperPanelDdi,
-        firmware_version: Version {
-            major: 0,
-            minor: 1,
-            patch: 0,
-        },
-        capabilities: Capabilities {
-            displays: 0,
-            controls: u16::from(CONTROL_MATRIX_ROWS) * u16::from(CONTROL_MATRIX_COLUMNS),
-            features: FEATURE_CONTROL_EVENTS,
+        device_id,
+        ..PANEL.device_descriptor(
+            Version {
+                major: 0,
+                minor: 1,
+                patch: 0,
+            },
+            FEATURE_CONTROL_EVENTS | FEATURE_DESCRIPTORS | FEATURE_CONTROL_STATE | FEATURE_IDENTIFY,
+        )
+    }
+}
+
+/// master 宛ての frame を queue に入れる
+///
+/// `Ack` は queue に入れた順に届くので、`state` に記録してどの frame の受信確認か分かるようにする。
+/// 記録と queue の順番がずれないよう、`DEVICE_STATE` の lock を持ったまま呼ぶ
+fn queue_frame(
+    sender: &embassy_sync::channel::Sender<'static, CriticalSectionRawMutex, Frame, 5>,
+    state: &mut DeviceRuntimeState,
+    frame: Frame,
+) -> Result<Option<u64>, TrySendError<Frame>> {
+    sender.try_send(frame.clone())?;
+    Ok(state.frame_queued(&frame))
+}
+
+/// `DeviceRuntimeState` が返したことを実行する。`Join` は `imcp_task` が送る
+fn perform_device_action(
+    sender: &embassy_sync::channel::Sender<'static, CriticalSectionRawMutex, Frame, 5>,
+    state: &mut DeviceRuntimeState,
+    action: Result<Option<DeviceAction>, FirmwareBaseError>,
+) {
+    match action {
+        Ok(Some(DeviceAction::Send(frame))) => {
+            if let Err(e) = queue_frame(sender, state, frame) {
+                warn!("failed queue device hello {:?}", e);
+            }
+        }
+        Ok(Some(DeviceAction::Negotiated {
+            version: Some(version),
+            ..
+        })) => {
+            info!("negotiated protocol version {}", version);
+            forward_log(
+                LogLevel::Info,
+                "hcp",
+                format_args!("negotiated protocol version {}", version),
+            );
         }
+        Ok(Some(DeviceAction::Negotiated {
+            host,
+            version: None,
+        })) => warn!("no common protocol version with host {:?}", host),
+        Ok(Some(DeviceAction::Ready)) => info!("device hello acknowledged"),
+        Ok(Some(DeviceAction::Join) | None) => {}
+        Err(e) => warn!("failed device lifecycle {:?}", e),
     }
 }
 
-fn handle_incoming_frame(
+async fn handle_incoming_frame(
     sender: &embassy_sync::channel::Sender<'static, CriticalSectionRawMutex, Frame, 5>,
     frame: &Frame,
-    device_id: u64,
 ) {
-    let address = if let Ok(mut state) = DEVICE_STATE.try_lock() {
-        try_assign_address_from_frame(&mut state, frame)
-    } else {
-        None
-    };
+    if let Some(seconds) = identify_request_from_frame(frame)
+        && let Ok(mut identifier) = IDENTIFIER.try_lock()
+    {
+        info!("identify for {}s", seconds);
+        identifier.start(Instant::now().as_millis(), seconds);
+    }
 
-    if let Some(address) = address {
-        let hello = build_device_hello_packet(DeviceDescriptor {
-            device_id,
-            ..device_descriptor()
-        });
-        match encode_set_frame(address, &hello) {
+    if let FramePayload::Set(payload) = frame.payload()
+        && let Ok(packet) = decode_set_packet(payload)
+    {
+        // 設定の要求は再送されないので、lock を待って必ず応答する
+        let reply = handle_config_packet(
+            &mut *DEVICE_CONFIG.lock().await,
+            &mut *CONFIG_STORAGE.lock().await,
+            &SUPPORTED_CONFIG_KEYS,
+            &packet,
+        );
+        if let Some(reply) = reply {
+            let mut state = DEVICE_STATE.lock().await;
+            match encode_set_frame(&state, &reply) {
+                Ok(frame) => {
+                    if let Err(e) = queue_frame(sender, &mut state, frame) {
+                        warn!("failed queue config response {:?}", e);
+                    }
+                }
+                Err(e) => warn!("failed encode config response {:?}", e),
+            }
+        }
+    }
+
+    if is_control_state_request(frame) {
+        let state = DEVICE_STATE.try_lock().ok();
+        let matrix = MATRIX.try_lock().ok();
+        if let (Some(mut state), Some(matrix)) = (state, matrix) {
+            let switches = PANEL.matrix_switch_states(|row, column| {
+                matrix.is_pressed(usize::from(row), usize::from(column))
+            });
+            match build_control_state_packet(&state, PANEL.first_matrix_id(), switches, [])
+                .and_then(|packet| encode_set_frame(&state, &packet))
+            {
+                Ok(frame) => {
+                    if let Err(e) = queue_frame(sender, &mut state, frame) {
+                        warn!("failed queue control state {:?}", e);
+                    }
+                }
+                Err(e) => warn!("failed encode control state {:?}", e),
+            }
+        }
+    }
+
+    if let Some(page) = descriptor_request_from_frame(frame) {
+        let Ok(mut state) = DEVICE_STATE.try_lock() else {
+            return;
+        };
+        let Some(packet) = PANEL.descriptor_page_packet(page) else {
+            warn!("descriptor page {} is out of range", page);
+            return;
+        };
+        match encode_set_frame(&state, &packet) {
             Ok(frame) => {
-                if let Err(e) = sender.try_send(frame) {
-                    warn!("failed queue device hello {:?}", e);
+                if let Err(e) = queue_frame(sender, &mut state, frame) {
+                    warn!("failed queue descriptor page {:?}", e);
                 }
             }
-            Err(e) => warn!("failed encode device hello {:?}", e),
+            Err(e) => warn!("failed encode descriptor page {:?}", e),
         }
     }
 }
@@ -317,25 +551,25 @@ fn initialize_device_identity(_flash: FLASH, trng: TRNG) -> DeviceIdentity {
 }
 
 #[cfg(feature = "rp2040")]
-fn read_rp2040_device_id(flash: Peri<'static, FLASH>) -> u64 {
+fn read_rp2040_device_id(flash: Peri<'static, FLASH>) -> Option<u64> {
     let mut flash = Flash::<_, Blocking, FLASH_SIZE>::new_blocking(flash);
     let mut unique_id = [0u8; 8];
     match flash.blocking_unique_id(&mut unique_id) {
-        Ok(()) => u64::from_be_bytes(unique_id),
+        Ok(()) => Some(u64::from_be_bytes(unique_id)),
         Err(error) => {
             warn!("failed read flash unique id {:?}", error);
-            0
+            None
         }
     }
 }
 
 #[cfg(feature = "rp235x")]
-fn read_rp235x_device_id() -> u64 {
+fn read_rp235x_device_id() -> Option<u64> {
     match otp::get_chipid() {
-        Ok(chip_id) => chip_id,
+        Ok(chip_id) => Some(chip_id),
         Err(error) => {
             warn!("failed read chip id {:?}", error);
-            0
+            None
         }
     }
 }
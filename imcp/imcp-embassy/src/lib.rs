@@ -143,7 +143,10 @@ mod tests {
             let result = imcp.read_tick(data);
 
             assert_eq!(
-                Err(ImcpError::ProtocolError(ProtocolError::UnexpectedAck)),
+                Err(ImcpError::ProtocolError(ProtocolError::UnexpectedAck {
+                    from: 0x01,
+                    acked: 0x01,
+                })),
                 result.await
             )
         });
@@ -1,6 +1,7 @@
 use core::fmt;
+use std::io;
 
-use imcp::frame::Frame;
+use imcp::{error::ImcpError, frame::Frame};
 use tokio::sync::mpsc::{Receiver, Sender};
 
 pub struct TokioSender {
@@ -24,6 +25,37 @@ impl fmt::Display for TokioChannelError {
     }
 }
 
+impl std::error::Error for TokioChannelError {}
+
+impl From<TokioChannelError> for io::Error {
+    fn from(error: TokioChannelError) -> Self {
+        match error {
+            TokioChannelError::Closed => io::Error::new(io::ErrorKind::BrokenPipe, error),
+        }
+    }
+}
+
+/// tokio チャネルを使う `Imcp` が返すエラー
+pub type TokioImcpError = ImcpError<TokioChannelError, TokioChannelError>;
+
+/// IMCP のエラーを `std::io::Error` に変換する
+pub trait IntoIoError {
+    fn into_io_error(self) -> io::Error;
+}
+
+impl IntoIoError for TokioImcpError {
+    fn into_io_error(self) -> io::Error {
+        let kind = match self {
+            ImcpError::DecodeError(_) => io::ErrorKind::InvalidData,
+            ImcpError::EncodeError(_) => io::ErrorKind::InvalidInput,
+            ImcpError::ProtocolError(_) => io::ErrorKind::Other,
+            ImcpError::ReceiveError(TokioChannelError::Closed)
+            | ImcpError::SendError(TokioChannelError::Closed) => io::ErrorKind::BrokenPipe,
+        };
+        io::Error::new(kind, self)
+    }
+}
+
 impl imcp::channel::Sender for TokioSender {
     type Error = TokioChannelError;
 
@@ -57,3 +89,47 @@ impl TokioReceiver {
         self.receiver.recv().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use imcp::error::{DecodeError, ImcpError, ProtocolError};
+
+    use crate::{IntoIoError, TokioChannelError};
+
+    #[test]
+    fn decode_error_maps_to_invalid_data() {
+        let error = ImcpError::<TokioChannelError, TokioChannelError>::DecodeError(
+            DecodeError::InvalidChecksum {
+                received: 0x12,
+                expected: 0x34,
+            },
+        )
+        .into_io_error();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            "decode error: invalid checksum (received 0x12, expected 0x34)"
+        );
+    }
+
+    #[test]
+    fn closed_channel_maps_to_broken_pipe() {
+        let error =
+            ImcpError::<TokioChannelError, TokioChannelError>::SendError(TokioChannelError::Closed)
+                .into_io_error();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+
+        let error = ImcpError::<TokioChannelError, TokioChannelError>::ProtocolError(
+            ProtocolError::UnexpectedAck {
+                from: 0x04,
+                acked: 0x02,
+            },
+        )
+        .into_io_error();
+        assert_eq!(error.kind(), io::ErrorKind::Other);
+        assert!(error.to_string().contains("from address 0x04"));
+    }
+}
@@ -0,0 +1,725 @@
+//! IMCP 通信のキャプチャ/リプレイ
+//!
+//! キャプチャファイルは pcapng 形式 (リトルエンディアン) で保存します。
+//! Interface は 2 つ定義し、link type で記録の中身を区別します。
+//!
+//! * [`LINKTYPE_IMCP_RAW`] (`LINKTYPE_USER0`): UART から読み書きした生バイト列。
+//!   フレームの途中で区切られていたり、複数フレームを含んでいることがある
+//! * [`LINKTYPE_IMCP_FRAME`] (`LINKTYPE_USER1`): 1 レコードにつき 1 フレーム。
+//!   SOF から EOF までのスタッフィング済みバイト列
+//!
+//! タイムスタンプはマイクロ秒単位 (pcapng の既定値) のみ扱います。
+//! 送受信方向は Enhanced Packet Block の `epb_flags` に記録します。
+//!
+//! Wireshark で開く場合は `wireshark/homecockpit.lua` の dissector を読み込んでください。
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    vec::Vec,
+};
+
+use crate::{
+    Imcp,
+    channel::{Receiver, Sender},
+    error::{DecodeError, EncodeError, ImcpError},
+    frame::{Frame, MAX_ENCODED_FRAME_SIZE},
+    parser::FrameParser,
+};
+
+/// 生バイト列を記録する link type (`LINKTYPE_USER0`)
+pub const LINKTYPE_IMCP_RAW: u16 = 147;
+/// 1 フレームずつ記録する link type (`LINKTYPE_USER1`)
+pub const LINKTYPE_IMCP_FRAME: u16 = 148;
+
+/// Wireshark 用の dissector。HCP のテストも enum の並びをこれと照合する
+pub const WIRESHARK_DISSECTOR: &str = include_str!("../wireshark/homecockpit.lua");
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPTION_END: u16 = 0;
+const OPTION_EPB_FLAGS: u16 = 2;
+
+const INTERFACE_RAW: u32 = 0;
+const INTERFACE_FRAME: u32 = 1;
+
+/// ブロック長の上限 (壊れたファイルで巨大な確保をしないため)
+const MAX_BLOCK_LEN: usize = 1 << 20;
+
+/// 記録されたデータの送受信方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+    Unknown,
+}
+
+impl Direction {
+    fn to_epb_flags(self) -> u32 {
+        match self {
+            Direction::Inbound => 0b01,
+            Direction::Outbound => 0b10,
+            Direction::Unknown => 0b00,
+        }
+    }
+
+    fn from_epb_flags(flags: u32) -> Self {
+        match flags & 0b11 {
+            0b01 => Direction::Inbound,
+            0b10 => Direction::Outbound,
+            _ => Direction::Unknown,
+        }
+    }
+}
+
+/// レコードの中身の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureLink {
+    /// UART 上の生バイト列
+    Raw,
+    /// スタッフィング済みの 1 フレーム
+    Frame,
+}
+
+impl CaptureLink {
+    fn from_link_type(link_type: u16) -> Result<Self, CaptureError> {
+        match link_type {
+            LINKTYPE_IMCP_RAW => Ok(CaptureLink::Raw),
+            LINKTYPE_IMCP_FRAME => Ok(CaptureLink::Frame),
+            _ => Err(CaptureError::UnsupportedLinkType(link_type)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    /// UNIX エポックからのマイクロ秒
+    pub timestamp_micros: u64,
+    pub direction: Direction,
+    pub link: CaptureLink,
+    /// 記録されたバイト列 (どちらの link でも UART 上のバイト列そのもの)
+    pub data: Vec<u8>,
+}
+
+impl CaptureRecord {
+    /// `Frame` レコードをデコードする。`Raw` レコードの場合は `None` を返す
+    pub fn decode_frame(&self) -> Option<Result<Frame, DecodeError>> {
+        if self.link != CaptureLink::Frame {
+            return None;
+        }
+
+        let mut rx_buffer = [0u8; MAX_ENCODED_FRAME_SIZE];
+        let mut frame_buffer = [0u8; MAX_ENCODED_FRAME_SIZE];
+        let mut parser = FrameParser::new(&mut rx_buffer, &mut frame_buffer);
+        if let Err(error) = parser.write_data(&self.data) {
+            return Some(Err(error));
+        }
+        parser.next_frame()
+    }
+}
+
+#[derive(Debug)]
+pub enum CaptureError {
+    Io(io::Error),
+    /// 先頭が Section Header Block ではない
+    NotPcapng,
+    /// ビッグエンディアンなど未対応のバイトオーダー
+    UnsupportedByteOrder(u32),
+    /// ブロック長が不正
+    InvalidBlockLength(u32),
+    /// 未定義の interface を参照している
+    UnknownInterface(u32),
+    UnsupportedLinkType(u16),
+    /// 1 レコードに書き込めるサイズを超えている
+    RecordTooLarge(usize),
+    Encode(EncodeError),
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::Io(error) => write!(f, "capture i/o error: {error}"),
+            CaptureError::NotPcapng => write!(f, "not a pcapng capture"),
+            CaptureError::UnsupportedByteOrder(magic) => {
+                write!(f, "unsupported byte order magic 0x{magic:08X}")
+            }
+            CaptureError::InvalidBlockLength(length) => {
+                write!(f, "invalid block length {length}")
+            }
+            CaptureError::UnknownInterface(id) => write!(f, "unknown interface id {id}"),
+            CaptureError::UnsupportedLinkType(link_type) => {
+                write!(f, "unsupported link type {link_type}")
+            }
+            CaptureError::RecordTooLarge(length) => {
+                write!(f, "record of {length} bytes is too large")
+            }
+            CaptureError::Encode(error) => write!(f, "failed to encode frame: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CaptureError::Io(error) => Some(error),
+            CaptureError::Encode(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CaptureError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// pcapng 形式でキャプチャを書き出す
+pub struct CaptureWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Section Header と 2 つの Interface Description を書き込んで開始する
+    pub fn new(mut writer: W) -> Result<Self, CaptureError> {
+        let mut section = Vec::new();
+        section.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        section.extend_from_slice(&1u16.to_le_bytes());
+        section.extend_from_slice(&0u16.to_le_bytes());
+        // section length は不明 (-1)
+        section.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut writer, BLOCK_SECTION_HEADER, &section)?;
+
+        for link_type in [LINKTYPE_IMCP_RAW, LINKTYPE_IMCP_FRAME] {
+            let mut interface = Vec::new();
+            interface.extend_from_slice(&link_type.to_le_bytes());
+            interface.extend_from_slice(&0u16.to_le_bytes());
+            // snaplen 0 = 無制限
+            interface.extend_from_slice(&0u32.to_le_bytes());
+            write_block(&mut writer, BLOCK_INTERFACE_DESCRIPTION, &interface)?;
+        }
+
+        Ok(Self { writer })
+    }
+
+    /// UART 上の生バイト列を記録する
+    pub fn write_raw(
+        &mut self,
+        timestamp_micros: u64,
+        direction: Direction,
+        bytes: &[u8],
+    ) -> Result<(), CaptureError> {
+        self.write_packet(INTERFACE_RAW, timestamp_micros, direction, bytes)
+    }
+
+    /// フレームをエンコードして 1 レコードとして記録する
+    pub fn write_frame(
+        &mut self,
+        timestamp_micros: u64,
+        direction: Direction,
+        frame: &Frame,
+    ) -> Result<(), CaptureError> {
+        let mut encoded = [0u8; MAX_ENCODED_FRAME_SIZE];
+        let encoded_len = frame.encode(&mut encoded).map_err(CaptureError::Encode)?;
+        self.write_packet(
+            INTERFACE_FRAME,
+            timestamp_micros,
+            direction,
+            &encoded[..encoded_len],
+        )
+    }
+
+    pub fn flush(&mut self) -> Result<(), CaptureError> {
+        self.writer.flush().map_err(CaptureError::Io)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_packet(
+        &mut self,
+        interface_id: u32,
+        timestamp_micros: u64,
+        direction: Direction,
+        data: &[u8],
+    ) -> Result<(), CaptureError> {
+        if data.len() > MAX_BLOCK_LEN {
+            return Err(CaptureError::RecordTooLarge(data.len()));
+        }
+        let data_len =
+            u32::try_from(data.len()).map_err(|_| CaptureError::RecordTooLarge(data.len()))?;
+        let timestamp_high = u32::try_from(timestamp_micros >> 32).unwrap_or(u32::MAX);
+        let timestamp_low = u32::try_from(timestamp_micros & 0xFFFF_FFFF).unwrap_or(0);
+
+        let mut packet = Vec::with_capacity(data.len() + 32);
+        packet.extend_from_slice(&interface_id.to_le_bytes());
+        // タイムスタンプは上位 32bit, 下位 32bit の順
+        packet.extend_from_slice(&timestamp_high.to_le_bytes());
+        packet.extend_from_slice(&timestamp_low.to_le_bytes());
+        packet.extend_from_slice(&data_len.to_le_bytes());
+        packet.extend_from_slice(&data_len.to_le_bytes());
+        packet.extend_from_slice(data);
+        pad_to_word(&mut packet);
+
+        packet.extend_from_slice(&OPTION_EPB_FLAGS.to_le_bytes());
+        packet.extend_from_slice(&4u16.to_le_bytes());
+        packet.extend_from_slice(&direction.to_epb_flags().to_le_bytes());
+        packet.extend_from_slice(&OPTION_END.to_le_bytes());
+        packet.extend_from_slice(&0u16.to_le_bytes());
+
+        write_block(&mut self.writer, BLOCK_ENHANCED_PACKET, &packet)
+    }
+}
+
+/// pcapng 形式のキャプチャを先頭から順に読み出す
+pub struct CaptureReader<R: Read> {
+    reader: R,
+    /// interface id ごとの link type
+    interfaces: Vec<u16>,
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// 先頭の Section Header Block を検証して読み出しを開始する
+    pub fn new(mut reader: R) -> Result<Self, CaptureError> {
+        let Some((BLOCK_SECTION_HEADER, total_len)) = read_block_header(&mut reader)? else {
+            return Err(CaptureError::NotPcapng);
+        };
+        let body = read_block_body(&mut reader, total_len)?;
+        check_byte_order(&body)?;
+
+        Ok(Self {
+            reader,
+            interfaces: Vec::new(),
+        })
+    }
+
+    /// 次のパケットレコードを返す。未知のブロックは読み飛ばす
+    pub fn next_record(&mut self) -> Result<Option<CaptureRecord>, CaptureError> {
+        loop {
+            let Some((block_type, body)) = read_block(&mut self.reader)? else {
+                return Ok(None);
+            };
+
+            match block_type {
+                BLOCK_SECTION_HEADER => {
+                    // 新しい section では interface 番号が振り直される
+                    check_byte_order(&body)?;
+                    self.interfaces.clear();
+                }
+                BLOCK_INTERFACE_DESCRIPTION => {
+                    let link_type = read_u16(&body, 0)?;
+                    self.interfaces.push(link_type);
+                }
+                BLOCK_ENHANCED_PACKET => return self.parse_enhanced_packet(&body).map(Some),
+                _ => {}
+            }
+        }
+    }
+
+    fn parse_enhanced_packet(&self, body: &[u8]) -> Result<CaptureRecord, CaptureError> {
+        let interface_id = read_u32(body, 0)?;
+        let timestamp_high = read_u32(body, 4)?;
+        let timestamp_low = read_u32(body, 8)?;
+        let captured_len = read_u32(body, 12)?;
+
+        let link_type = usize::try_from(interface_id)
+            .ok()
+            .and_then(|index| self.interfaces.get(index))
+            .ok_or(CaptureError::UnknownInterface(interface_id))?;
+        let link = CaptureLink::from_link_type(*link_type)?;
+
+        let data_start: usize = 20;
+        let data_end = usize::try_from(captured_len)
+            .ok()
+            .and_then(|len| data_start.checked_add(len))
+            .filter(|end| *end <= body.len())
+            .ok_or(CaptureError::InvalidBlockLength(captured_len))?;
+        let data = body[data_start..data_end].to_vec();
+
+        let mut direction = Direction::Unknown;
+        let mut option_offset = data_end.next_multiple_of(4);
+        while option_offset + 4 <= body.len() {
+            let code = read_u16(body, option_offset)?;
+            let length = usize::from(read_u16(body, option_offset + 2)?);
+            if code == OPTION_END {
+                break;
+            }
+            if code == OPTION_EPB_FLAGS && length == 4 {
+                direction = Direction::from_epb_flags(read_u32(body, option_offset + 4)?);
+            }
+            option_offset += 4 + length.next_multiple_of(4);
+        }
+
+        Ok(CaptureRecord {
+            timestamp_micros: (u64::from(timestamp_high) << 32) | u64::from(timestamp_low),
+            direction,
+            link,
+            data,
+        })
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<CaptureRecord, CaptureError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+/// 記録を順番に `FrameParser` へ流し込み、得られたフレームを順番に返す
+///
+/// `write_data` の失敗もデコードエラーとして結果に含める
+pub fn replay_into_parser<'a>(
+    records: impl IntoIterator<Item = &'a CaptureRecord>,
+    parser: &mut FrameParser<'_, '_>,
+) -> Vec<Result<Frame, DecodeError>> {
+    let mut frames = Vec::new();
+    for record in records {
+        if let Err(error) = parser.write_data(&record.data) {
+            frames.push(Err(error));
+            continue;
+        }
+        while let Some(frame) = parser.next_frame() {
+            frames.push(frame);
+        }
+    }
+    frames
+}
+
+/// 記録を順番に `Imcp::read_tick` へ流し込み、得られたフレームを順番に返す
+///
+/// 1 レコードに複数フレームが含まれる場合は、パーサーに未読のデータがなくなるまで
+/// `read_tick(&[])` で続きを読み出す。自ノード宛てでないフレームは結果に含めない
+pub async fn replay_into_imcp<'a, R: Receiver, S: Sender>(
+    records: impl IntoIterator<Item = &'a CaptureRecord>,
+    imcp: &mut Imcp<'_, '_, '_, R, S>,
+) -> Vec<Result<Frame, ImcpError<R::Error, S::Error>>> {
+    let mut results = Vec::new();
+    for record in records {
+        let mut data = record.data.as_slice();
+        loop {
+            match imcp.read_tick(data).await {
+                Ok(Some(frame)) => results.push(Ok(frame)),
+                Ok(None) => {}
+                Err(error) => results.push(Err(error)),
+            }
+            data = &[];
+            if !imcp.frame_parser.has_unread_data() {
+                break;
+            }
+        }
+    }
+    results
+}
+
+fn write_block<W: Write>(writer: &mut W, block_type: u32, body: &[u8]) -> Result<(), CaptureError> {
+    let total_len = body.len() + 12;
+    let total_len =
+        u32::try_from(total_len).map_err(|_| CaptureError::RecordTooLarge(total_len))?;
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&total_len.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&total_len.to_le_bytes())?;
+    Ok(())
+}
+
+/// 1 ブロックを読み出す。ブロック境界でファイルが終わった場合は `None`
+fn read_block<R: Read>(reader: &mut R) -> Result<Option<(u32, Vec<u8>)>, CaptureError> {
+    let Some((block_type, total_len)) = read_block_header(reader)? else {
+        return Ok(None);
+    };
+    let body = read_block_body(reader, total_len)?;
+    Ok(Some((block_type, body)))
+}
+
+/// ブロックタイプとブロック長を読み出す
+fn read_block_header<R: Read>(reader: &mut R) -> Result<Option<(u32, u32)>, CaptureError> {
+    let mut header = [0u8; 8];
+    if !read_exact_or_eof(reader, &mut header)? {
+        return Ok(None);
+    }
+    let block_type = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let total_len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    Ok(Some((block_type, total_len)))
+}
+
+/// ヘッダーに続く本体と末尾のブロック長を読み出す
+fn read_block_body<R: Read>(reader: &mut R, total_len: u32) -> Result<Vec<u8>, CaptureError> {
+    let body_len = usize::try_from(total_len)
+        .ok()
+        .filter(|len| *len >= 12 && len.is_multiple_of(4) && *len <= MAX_BLOCK_LEN)
+        .map(|len| len - 12)
+        .ok_or(CaptureError::InvalidBlockLength(total_len))?;
+
+    let mut body = vec![0u8; body_len];
+    reader.read_exact(&mut body)?;
+
+    let mut trailer = [0u8; 4];
+    reader.read_exact(&mut trailer)?;
+    if u32::from_le_bytes(trailer) != total_len {
+        return Err(CaptureError::InvalidBlockLength(total_len));
+    }
+
+    Ok(body)
+}
+
+fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool, io::Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => filled += read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(true)
+}
+
+fn check_byte_order(section_body: &[u8]) -> Result<(), CaptureError> {
+    let magic = read_u32(section_body, 0)?;
+    if magic != BYTE_ORDER_MAGIC {
+        return Err(CaptureError::UnsupportedByteOrder(magic));
+    }
+    Ok(())
+}
+
+fn read_u16(body: &[u8], offset: usize) -> Result<u16, CaptureError> {
+    body.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| invalid_body_length(body))
+}
+
+fn read_u32(body: &[u8], offset: usize) -> Result<u32, CaptureError> {
+    body.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| invalid_body_length(body))
+}
+
+fn invalid_body_length(body: &[u8]) -> CaptureError {
+    CaptureError::InvalidBlockLength(u32::try_from(body.len() + 12).unwrap_or(u32::MAX))
+}
+
+fn pad_to_word(buffer: &mut Vec<u8>) {
+    while !buffer.len().is_multiple_of(4) {
+        buffer.push(0);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use core::convert::Infallible;
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        ESC, SOF,
+        frame::{Address, FramePayload, FrameType},
+    };
+
+    /// dissector の `local NAME = { [index] = "Variant", ... }` を読み出す
+    ///
+    /// 表が見つからないか、行の形式が違う場合は `None`
+    fn dissector_name_table(name: &str) -> Option<Vec<(u8, String)>> {
+        let start = WIRESHARK_DISSECTOR.find(&format!("local {name} = {{\n"))?;
+        WIRESHARK_DISSECTOR[start..]
+            .lines()
+            .skip(1)
+            .take_while(|line| *line != "}")
+            .map(|line| {
+                let (index, variant) = line.trim().split_once("] = ")?;
+                let index = index.trim_start_matches('[');
+                let index = match index.strip_prefix("0x") {
+                    Some(hex) => u8::from_str_radix(hex, 16).ok()?,
+                    None => index.parse().ok()?,
+                };
+                Some((
+                    index,
+                    variant.trim_end_matches(',').trim_matches('"').to_string(),
+                ))
+            })
+            .collect()
+    }
+
+    struct NullSender;
+
+    impl Sender for NullSender {
+        type Error = Infallible;
+
+        async fn send(&mut self, _frame: Frame) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    struct NullReceiver;
+
+    impl Receiver for NullReceiver {
+        type Error = Infallible;
+
+        async fn receive(&mut self) -> Result<Frame, Self::Error> {
+            core::future::pending().await
+        }
+    }
+
+    fn encode(frame: &Frame) -> Vec<u8> {
+        let mut raw = [0u8; MAX_ENCODED_FRAME_SIZE];
+        let len = frame.encode(&mut raw).unwrap();
+        raw[..len].to_vec()
+    }
+
+    #[test]
+    fn capture_roundtrip_keeps_records_in_order() {
+        let frame = Frame::new(
+            Address::Unicast(0x02),
+            0x01,
+            FramePayload::Set(heapless::Vec::from_slice(&[SOF, ESC, 0x10]).unwrap()),
+        );
+
+        let mut writer = CaptureWriter::new(Vec::new()).unwrap();
+        writer
+            .write_raw(
+                1_700_000_000_000_001,
+                Direction::Inbound,
+                &[0xAA, 0xBB, 0xCC],
+            )
+            .unwrap();
+        writer
+            .write_frame(1_700_000_000_000_002, Direction::Outbound, &frame)
+            .unwrap();
+        let bytes = writer.into_inner();
+
+        let records = CaptureReader::new(Cursor::new(bytes))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records[0],
+            CaptureRecord {
+                timestamp_micros: 1_700_000_000_000_001,
+                direction: Direction::Inbound,
+                link: CaptureLink::Raw,
+                data: vec![0xAA, 0xBB, 0xCC],
+            }
+        );
+        assert_eq!(records[1].timestamp_micros, 1_700_000_000_000_002);
+        assert_eq!(records[1].direction, Direction::Outbound);
+        assert_eq!(records[1].link, CaptureLink::Frame);
+        assert_eq!(records[1].data, encode(&frame));
+        assert_eq!(records[1].decode_frame(), Some(Ok(frame)));
+        assert_eq!(records[0].decode_frame(), None);
+    }
+
+    #[test]
+    fn reader_rejects_non_pcapng_input() {
+        let result = CaptureReader::new(Cursor::new(vec![0u8; 32]));
+
+        assert!(matches!(result, Err(CaptureError::NotPcapng)));
+    }
+
+    #[test]
+    fn replay_into_parser_reassembles_split_raw_records() {
+        let first = Frame::new(Address::Unicast(0x01), 0x02, FramePayload::Ping);
+        let second = Frame::new(Address::Broadcast, 0x01, FramePayload::Ack(0x02));
+        let mut stream = encode(&first);
+        stream.extend(encode(&second));
+        let (head, tail) = stream.split_at(5);
+
+        let records = [head, tail].map(|chunk| CaptureRecord {
+            timestamp_micros: 0,
+            direction: Direction::Inbound,
+            link: CaptureLink::Raw,
+            data: chunk.to_vec(),
+        });
+
+        let mut rx_buffer = [0u8; 64];
+        let mut frame_buffer = [0u8; 64];
+        let mut parser = FrameParser::new(&mut rx_buffer, &mut frame_buffer);
+        let frames = replay_into_parser(&records, &mut parser);
+
+        assert_eq!(frames, vec![Ok(first), Ok(second)]);
+    }
+
+    #[test]
+    fn replay_into_imcp_reads_every_frame_of_a_record() {
+        futures::executor::block_on(async {
+            let first = Frame::new(Address::Broadcast, 0x01, FramePayload::Ack(0xFF));
+            let second = Frame::new(Address::Unicast(0x01), 0x03, FramePayload::Ack(0xFF));
+            let mut data = encode(&first);
+            data.extend(encode(&second));
+            let record = CaptureRecord {
+                timestamp_micros: 0,
+                direction: Direction::Inbound,
+                link: CaptureLink::Raw,
+                data,
+            };
+
+            let mut rx_buffer = [0u8; 64];
+            let mut frame_buffer = [0u8; 64];
+            let mut imcp =
+                Imcp::new_master(NullReceiver, NullSender, &mut rx_buffer, &mut frame_buffer);
+
+            let results = replay_into_imcp([&record], &mut imcp).await;
+
+            assert_eq!(results, vec![Ok(first), Ok(second)]);
+        });
+    }
+
+    #[test]
+    fn replay_into_imcp_skips_frames_for_other_nodes() {
+        futures::executor::block_on(async {
+            let first = Frame::new(Address::Broadcast, 0x02, FramePayload::Ack(0xFF));
+            let foreign = Frame::new(Address::Unicast(0x05), 0x03, FramePayload::Ack(0xFF));
+            let second = Frame::new(Address::Unicast(0x01), 0x03, FramePayload::Ack(0xFF));
+            let mut data = encode(&first);
+            data.extend(encode(&foreign));
+            data.extend(encode(&second));
+            let record = CaptureRecord {
+                timestamp_micros: 0,
+                direction: Direction::Inbound,
+                link: CaptureLink::Raw,
+                data,
+            };
+
+            let mut rx_buffer = [0u8; 64];
+            let mut frame_buffer = [0u8; 64];
+            let mut imcp =
+                Imcp::new_master(NullReceiver, NullSender, &mut rx_buffer, &mut frame_buffer);
+
+            let results = replay_into_imcp([&record], &mut imcp).await;
+
+            assert_eq!(results, vec![Ok(first), Ok(second)]);
+        });
+    }
+
+    #[test]
+    fn wireshark_dissector_matches_frame_types() {
+        let frame_types = [
+            FrameType::Ping,
+            FrameType::Pong,
+            FrameType::Ack,
+            FrameType::Join,
+            FrameType::SetAddress,
+            FrameType::Data,
+            FrameType::Set,
+        ];
+        let expected = frame_types
+            .iter()
+            .map(|frame_type| (*frame_type as u8, format!("{frame_type:?}")))
+            .collect::<Vec<_>>();
+
+        assert_eq!(dissector_name_table("FRAME_TYPES"), Some(expected));
+        assert!(WIRESHARK_DISSECTOR.contains(&format!("LINKTYPE_USER0 ({LINKTYPE_IMCP_RAW})")));
+        assert!(WIRESHARK_DISSECTOR.contains(&format!("LINKTYPE_USER1 ({LINKTYPE_IMCP_FRAME})")));
+        assert!(WIRESHARK_DISSECTOR.contains(&format!("local SOF = 0x{SOF:02X}")));
+        assert!(WIRESHARK_DISSECTOR.contains(&format!("local ESC = 0x{ESC:02X}")));
+    }
+}
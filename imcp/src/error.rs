@@ -1,4 +1,5 @@
 use core::convert::Infallible;
+use core::fmt;
 
 use crate::frame::FrameType;
 
@@ -6,29 +7,42 @@ use crate::frame::FrameType;
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum EncodeError {
     /// 書き込み先バッファのサイズが不足している
-    BufferTooSmall,
+    BufferTooSmall {
+        /// 書き込み先バッファの容量
+        capacity: usize,
+    },
 }
 
 /// デコード時に発生する可能性のあるエラー
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum DecodeError {
-    InvalidChecksum,
+    /// 受信したチェックサムと計算したチェックサムが一致しない
+    InvalidChecksum { received: u8, expected: u8 },
     /// 未定義のフレームタイプID
     UnknownFrameType(u8),
     /// ペイロード長がフレームタイプや実際のデータ長と矛盾
-    InvalidPayloadLength,
+    InvalidPayloadLength { expected: usize, actual: usize },
     /// スタッフィング解除後バッファが不足
-    FrameBufferTooSmall,
+    FrameBufferTooSmall {
+        /// 不足したバッファの容量
+        capacity: usize,
+    },
     /// エスケープシーケンスが不正 (例: ESC の直後に EOF/SOF が来た)
     InvalidEscapeSequence,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub enum ProtocolError{
+pub enum ProtocolError {
     InvalidFrameType(FrameType),
-    UnexpectedAck,
+    /// 送信待ちのフレームと対応しない ACK を受信した
+    UnexpectedAck {
+        /// ACK を送ってきたノードのアドレス
+        from: u8,
+        /// ACK に含まれていた宛先アドレス
+        acked: u8,
+    },
     NodeNotReady,
     AddressPoolExhausted,
 }
@@ -42,3 +56,84 @@ pub enum ImcpError<RE = Infallible, SE = Infallible> {
     ReceiveError(RE),
     SendError(SE),
 }
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::BufferTooSmall { capacity } => {
+                write!(f, "encode buffer too small (capacity {capacity} bytes)")
+            }
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::InvalidChecksum { received, expected } => write!(
+                f,
+                "invalid checksum (received 0x{received:02X}, expected 0x{expected:02X})"
+            ),
+            DecodeError::UnknownFrameType(frame_type) => {
+                write!(f, "unknown frame type 0x{frame_type:02X}")
+            }
+            DecodeError::InvalidPayloadLength { expected, actual } => write!(
+                f,
+                "invalid payload length (expected {expected} bytes, got {actual} bytes)"
+            ),
+            DecodeError::FrameBufferTooSmall { capacity } => {
+                write!(f, "frame buffer too small (capacity {capacity} bytes)")
+            }
+            DecodeError::InvalidEscapeSequence => write!(f, "invalid escape sequence"),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::InvalidFrameType(frame_type) => {
+                write!(f, "frame type {frame_type:?} is not valid in this state")
+            }
+            ProtocolError::UnexpectedAck { from, acked } => write!(
+                f,
+                "unexpected ack for 0x{acked:02X} from address 0x{from:02X}"
+            ),
+            ProtocolError::NodeNotReady => write!(f, "node is not ready"),
+            ProtocolError::AddressPoolExhausted => write!(f, "address pool exhausted"),
+        }
+    }
+}
+
+impl<RE: fmt::Display, SE: fmt::Display> fmt::Display for ImcpError<RE, SE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImcpError::ProtocolError(error) => write!(f, "protocol error: {error}"),
+            ImcpError::DecodeError(error) => write!(f, "decode error: {error}"),
+            ImcpError::EncodeError(error) => write!(f, "encode error: {error}"),
+            ImcpError::ReceiveError(error) => write!(f, "receive error: {error}"),
+            ImcpError::SendError(error) => write!(f, "send error: {error}"),
+        }
+    }
+}
+
+impl core::error::Error for EncodeError {}
+
+impl core::error::Error for DecodeError {}
+
+impl core::error::Error for ProtocolError {}
+
+impl<RE, SE> core::error::Error for ImcpError<RE, SE>
+where
+    RE: fmt::Debug + fmt::Display,
+    SE: fmt::Debug + fmt::Display,
+{
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            ImcpError::ProtocolError(error) => Some(error),
+            ImcpError::DecodeError(error) => Some(error),
+            ImcpError::EncodeError(error) => Some(error),
+            ImcpError::ReceiveError(_) | ImcpError::SendError(_) => None,
+        }
+    }
+}
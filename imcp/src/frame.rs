@@ -59,6 +59,8 @@ pub enum FramePayload {
     Pong,
     Ack(u8),
     Join(u32),
+    /// 安定したデバイス ID 付きの Join。マスターは同じデバイスに同じアドレスを割り当てようとする
+    JoinWithDeviceId { id: u32, device_id: u64 },
     SetAddress { address: u8, id: u32 },
     Data(Vec<u8, MAX_PAYLOAD_SIZE>),
     Set(Vec<u8, MAX_PAYLOAD_SIZE>),
@@ -71,6 +73,9 @@ impl Format for FramePayload {
             FramePayload::Ping | FramePayload::Pong => defmt::write!(fmt, "{0}", self),
             FramePayload::Ack(a) => defmt::write!(fmt, "Ack address: {0}", a),
             FramePayload::Join(a) => defmt::write!(fmt, "Join id: {0}", a),
+            FramePayload::JoinWithDeviceId { id, device_id } => {
+                defmt::write!(fmt, "Join id: {0} device_id: {1=u64:x}", id, device_id)
+            }
             FramePayload::SetAddress { address, id } => {
                 defmt::write!(fmt, "SetAddress address: {0} id: {1} ", address, id)
             }
@@ -108,7 +113,7 @@ impl<'a> FramePayload {
             FramePayload::Ping => FrameType::Ping,
             FramePayload::Pong => FrameType::Pong,
             FramePayload::Ack(_) => FrameType::Ack,
-            FramePayload::Join(_) => FrameType::Join,
+            FramePayload::Join(_) | FramePayload::JoinWithDeviceId { .. } => FrameType::Join,
             FramePayload::SetAddress { .. } => FrameType::SetAddress,
             FramePayload::Data(_) => FrameType::Data,
             FramePayload::Set(_) => FrameType::Set,
@@ -119,6 +124,7 @@ impl<'a> FramePayload {
         match self {
             FramePayload::Ping | FramePayload::Pong => 0,
             FramePayload::Join(_) => 4,
+            FramePayload::JoinWithDeviceId { .. } => 12,
             FramePayload::Ack(_) => 1,
             FramePayload::Set(data) => data
                 .len()
@@ -154,37 +160,64 @@ impl<'a> FramePayload {
             // --- ペイロード長が 0 であるべきタイプ ---
             FrameType::Ping => {
                 if payload_len != 0 {
-                    return Err(DecodeError::InvalidPayloadLength);
+                    return Err(DecodeError::InvalidPayloadLength {
+                        expected: 0,
+                        actual: payload_len,
+                    });
                 }
                 Ok(FramePayload::Ping)
             }
             FrameType::Pong => {
                 if payload_len != 0 {
-                    return Err(DecodeError::InvalidPayloadLength);
+                    return Err(DecodeError::InvalidPayloadLength {
+                        expected: 0,
+                        actual: payload_len,
+                    });
                 }
                 Ok(FramePayload::Pong)
             }
             FrameType::Ack => {
                 if payload_len != 1 {
-                    return Err(DecodeError::InvalidPayloadLength);
+                    return Err(DecodeError::InvalidPayloadLength {
+                        expected: 1,
+                        actual: payload_len,
+                    });
                 }
                 Ok(FramePayload::Ack(payload_slice[0]))
             }
             FrameType::Join => {
-                if payload_len != 4 {
-                    return Err(DecodeError::InvalidPayloadLength);
+                // 4 byte: id のみ, 12 byte: id + device_id
+                if payload_len != 4 && payload_len != 12 {
+                    return Err(DecodeError::InvalidPayloadLength {
+                        expected: if payload_len > 4 { 12 } else { 4 },
+                        actual: payload_len,
+                    });
                 }
                 let mut bytes = [0u8; 4];
-                bytes.copy_from_slice(payload_slice);
-                Ok(FramePayload::Join(u32::from_le_bytes(bytes)))
+                bytes.copy_from_slice(&payload_slice[..4]);
+                let id = u32::from_le_bytes(bytes);
+                if payload_len == 4 {
+                    return Ok(FramePayload::Join(id));
+                }
+                let mut device_id = [0u8; 8];
+                device_id.copy_from_slice(&payload_slice[4..12]);
+                Ok(FramePayload::JoinWithDeviceId {
+                    id,
+                    device_id: u64::from_le_bytes(device_id),
+                })
             }
             FrameType::Set => heapless::Vec::from_slice(payload_slice)
-                .map_err(|_| DecodeError::FrameBufferTooSmall)
+                .map_err(|_| DecodeError::FrameBufferTooSmall {
+                    capacity: MAX_PAYLOAD_SIZE,
+                })
                 .map(FramePayload::Set),
 
             FrameType::SetAddress => {
                 if payload_len != 5 {
-                    return Err(DecodeError::InvalidPayloadLength);
+                    return Err(DecodeError::InvalidPayloadLength {
+                        expected: 5,
+                        actual: payload_len,
+                    });
                 }
                 let mut bytes = [0u8; 4];
                 bytes.copy_from_slice(&payload_slice[1..5]);
@@ -196,7 +229,9 @@ impl<'a> FramePayload {
 
             // --- 任意のペイロード長を許可するタイプ ---
             FrameType::Data => heapless::Vec::from_slice(payload_slice)
-                .map_err(|_| DecodeError::FrameBufferTooSmall)
+                .map_err(|_| DecodeError::FrameBufferTooSmall {
+                    capacity: MAX_PAYLOAD_SIZE,
+                })
                 .map(FramePayload::Data),
         }
     }
@@ -272,7 +307,9 @@ impl<'a> Frame {
         // バッファに1バイト書き込む内部関数
         let write_raw_byte = |byte: u8, idx: usize, buf: &mut [u8]| -> Result<usize, EncodeError> {
             if idx >= buf.len() {
-                return Err(EncodeError::BufferTooSmall);
+                return Err(EncodeError::BufferTooSmall {
+                    capacity: buf.len(),
+                });
             }
             buf[idx] = byte;
             Ok(idx + 1)
@@ -314,6 +351,12 @@ impl<'a> Frame {
                     write_idx = write_stuffed_byte(byte, write_idx, buffer)?;
                 }
             }
+            FramePayload::JoinWithDeviceId { id, device_id } => {
+                for &byte in id.to_le_bytes().iter().chain(&device_id.to_le_bytes()) {
+                    checksum ^= byte;
+                    write_idx = write_stuffed_byte(byte, write_idx, buffer)?;
+                }
+            }
             FramePayload::SetAddress { address, id } => {
                 checksum ^= *address;
                 write_idx = write_stuffed_byte(*address, write_idx, buffer)?;
@@ -358,7 +401,10 @@ impl<'a> Frame {
         let min_len = Self::HEADER_LEN + Self::CHECKSUM_LEN;
         if buffer.len() < min_len {
             // EOFを受け取ったのに純粋なフレームが短すぎる = 破損
-            return Err(DecodeError::InvalidPayloadLength);
+            return Err(DecodeError::InvalidPayloadLength {
+                expected: min_len,
+                actual: buffer.len(),
+            });
         }
 
         // 2. チェックサム検証
@@ -369,7 +415,10 @@ impl<'a> Frame {
         let expected_checksum = Self::calculate_xor_checksum(data_slice);
 
         if checksum_byte != expected_checksum {
-            return Err(DecodeError::InvalidChecksum);
+            return Err(DecodeError::InvalidChecksum {
+                received: checksum_byte,
+                expected: expected_checksum,
+            });
         }
 
         // 3. ヘッダーフィールドの抽出
@@ -381,7 +430,10 @@ impl<'a> Frame {
         // 4. ヘッダーのペイロード長と実際のペイロード長が一致するか検証
         let actual_payload_len = data_len - Self::HEADER_LEN;
         if (payload_len as usize) != actual_payload_len {
-            return Err(DecodeError::InvalidPayloadLength);
+            return Err(DecodeError::InvalidPayloadLength {
+                expected: payload_len as usize,
+                actual: actual_payload_len,
+            });
         }
 
         // 5. フレームタイプを解析
@@ -402,7 +454,10 @@ impl<'a> Frame {
 
 #[cfg(test)]
 mod tests {
-    use crate::frame::Frame;
+    use crate::{
+        error::DecodeError,
+        frame::{Frame, FramePayload, FrameType},
+    };
 
     #[test]
     fn test_checksum_calculation() {
@@ -410,4 +465,22 @@ mod tests {
         assert_eq!(Frame::calculate_xor_checksum(&[0xFF, 0x01]), 0xFE);
         assert_eq!(Frame::calculate_xor_checksum(&[]), 0x00);
     }
+
+    #[test]
+    fn test_malformed_join_reports_expected_length() {
+        assert_eq!(
+            FramePayload::decode(FrameType::Join, &[0; 3]),
+            Err(DecodeError::InvalidPayloadLength {
+                expected: 4,
+                actual: 3,
+            })
+        );
+        assert_eq!(
+            FramePayload::decode(FrameType::Join, &[0; 7]),
+            Err(DecodeError::InvalidPayloadLength {
+                expected: 12,
+                actual: 7,
+            })
+        );
+    }
 }
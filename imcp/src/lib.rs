@@ -1,4 +1,4 @@
-#![cfg_attr(all(not(test), not(feature = "test-utils")), no_std)]
+#![cfg_attr(all(not(test), not(feature = "std")), no_std)]
 
 use heapless::Vec;
 
@@ -7,10 +7,14 @@ use crate::channel::Sender;
 use crate::error::*;
 use crate::frame::*;
 use crate::parser::FrameParser;
+use crate::sticky::StickyAddressTable;
+#[cfg(feature = "std")]
+pub mod capture;
 pub mod channel;
 pub mod error;
 pub mod frame;
 pub mod parser;
+pub mod sticky;
 
 pub const SOF: u8 = 0xFE;
 pub const EOF: u8 = 0xFF;
@@ -19,7 +23,7 @@ pub const ESC: u8 = 0xFD;
 pub const ESC_XOR: u8 = 0x20;
 
 #[cfg(feature = "defmt")]
-use defmt::{info, trace}; // Format トレイトもインポート
+use defmt::{info, trace, warn}; // Format トレイトもインポート
 
 // "defmt" フィーチャーが無効な場合 (ログを出力しない)
 #[cfg(not(feature = "defmt"))]
@@ -68,7 +72,8 @@ pub enum ClientState {
 #[derive(PartialEq)]
 pub struct MasterState {
     next_address: u8,
-    pending_assignment: Option<(u32, u8)>,
+    /// 応答待ちの `SetAddress` の (ノード ID, アドレス, デバイス ID)
+    pending_assignment: Option<(u32, u8, Option<u64>)>,
     pending_assignment_retries: u8,
 }
 
@@ -78,7 +83,7 @@ pub enum NodeType {
     Master(MasterState),
 }
 
-pub struct Imcp<'rx_buf, 'parser_frame_buffer, R, S> {
+pub struct Imcp<'rx_buf, 'parser_frame_buffer, 'sticky, R, S> {
     tx_receiver: R,
     tx_sender: S,
     address: u8,
@@ -86,12 +91,42 @@ pub struct Imcp<'rx_buf, 'parser_frame_buffer, R, S> {
     pending_frame: Option<Frame>,
     frame_parser: FrameParser<'rx_buf, 'parser_frame_buffer>,
     node_type: NodeType,
+    join_retries: u8,
+    /// マスターが使うデバイス ID とアドレスの対応表。クライアントの分まで大きくならないよう呼び出し側が持つ
+    sticky_addresses: Option<&'sticky mut StickyAddressTable>,
 }
 
 const MAX_SET_ADDRESS_RETRIES: u8 = 3;
 
+/// `JoinWithDeviceId` を知らないマスターは 12 byte の Join を捨てるので、
+/// この回数だけ送り直しても `SetAddress` が来なければ ID だけの Join に切り替える
+const MAX_JOIN_WITH_DEVICE_ID_RETRIES: u8 = 10;
+
 impl MasterState {
-    fn allocate_address(&self) -> Result<u8, ProtocolError> {
+    /// Join してきたノードに割り当てるアドレスを決める
+    ///
+    /// デバイス ID が対応表にあり、他のノードが使っていなければそのアドレスを再利用し、
+    /// なければ他のデバイスに予約されておらず、他のノードも使っていない次のアドレスを使う
+    fn allocate_address(
+        &mut self,
+        sticky_addresses: Option<&StickyAddressTable>,
+        node_id: u32,
+        device_id: Option<u64>,
+    ) -> Result<u8, ProtocolError> {
+        let Some(table) = sticky_addresses else {
+            return self.check_next_address();
+        };
+        if let Some(address) = device_id.and_then(|id| table.reusable_address(id, node_id)) {
+            return Ok(address);
+        }
+
+        while !table.is_available(self.next_address, device_id, node_id) {
+            self.advance_address()?;
+        }
+        self.check_next_address()
+    }
+
+    fn check_next_address(&self) -> Result<u8, ProtocolError> {
         if self.next_address == 0x00 || self.next_address == 0x01 || self.next_address == 0xFF {
             return Err(ProtocolError::AddressPoolExhausted);
         }
@@ -109,8 +144,8 @@ impl MasterState {
     }
 }
 
-impl<'rx_buf, 'parser_frame_buffer, R: Receiver, S: Sender>
-    Imcp<'rx_buf, 'parser_frame_buffer, R, S>
+impl<'rx_buf, 'parser_frame_buffer, 'sticky, R: Receiver, S: Sender>
+    Imcp<'rx_buf, 'parser_frame_buffer, 'sticky, R, S>
 {
     pub fn new_master(
         tx_receiver: R,
@@ -123,6 +158,7 @@ impl<'rx_buf, 'parser_frame_buffer, R: Receiver, S: Sender>
         Self {
             address: 0x01,
             node_id: None,
+            join_retries: 0,
             pending_frame: None,
             frame_parser,
             node_type: NodeType::Master(MasterState {
@@ -132,6 +168,7 @@ impl<'rx_buf, 'parser_frame_buffer, R: Receiver, S: Sender>
             }),
             tx_receiver,
             tx_sender,
+            sticky_addresses: None,
         }
     }
 
@@ -146,11 +183,13 @@ impl<'rx_buf, 'parser_frame_buffer, R: Receiver, S: Sender>
         Self {
             address: 0x00,
             node_id: None,
+            join_retries: 0,
             pending_frame: None,
             frame_parser,
             node_type: NodeType::Client(ClientState::NotReady),
             tx_receiver,
             tx_sender,
+            sticky_addresses: None,
         }
     }
 
@@ -167,13 +206,50 @@ impl<'rx_buf, 'parser_frame_buffer, R: Receiver, S: Sender>
         Ok(())
     }
 
+    /// 安定したデバイス ID 付きで Join する
+    ///
+    /// マスターが対応表を持っていれば、前回と同じアドレスが割り当てられる
+    pub async fn send_join_with_device_id(
+        &mut self,
+        id: u32,
+        device_id: u64,
+    ) -> Result<(), ImcpError<R::Error, S::Error>> {
+        if let NodeType::Client(_state) = &self.node_type {
+            self.node_id = Some(id);
+            self.node_type = NodeType::Client(ClientState::Joining(id));
+            self.join_retries = 0;
+            let frame = Frame::new(
+                Address::Unicast(0x01),
+                self.address,
+                FramePayload::JoinWithDeviceId { id, device_id },
+            );
+            self.tx_sender
+                .send(frame)
+                .await
+                .map_err(ImcpError::SendError)?;
+        }
+        Ok(())
+    }
+
+    /// マスターが使うデバイス ID とアドレスの対応表を設定する。クライアントでは使わない
+    pub fn set_sticky_addresses(&mut self, table: &'sticky mut StickyAddressTable) {
+        if let NodeType::Master(_) = self.node_type {
+            self.sticky_addresses = Some(table);
+        }
+    }
+
+    /// マスターの対応表。永続化に使う。設定していない場合は `None`
+    pub fn sticky_addresses(&self) -> Option<&StickyAddressTable> {
+        self.sticky_addresses.as_deref()
+    }
+
     pub async fn write_tick(
         &mut self,
     ) -> Result<Vec<u8, MAX_ENCODED_FRAME_SIZE>, ImcpError<R::Error, S::Error>> {
         let next_frame = loop {
             let frame = if let Some(frame) = self.pending_frame.take() {
                 trace!("rewrite pending_frame: {:?}", frame);
-                frame
+                self.fall_back_to_plain_join(frame)
             } else {
                 trace!("wait for write new frame");
                 self.tx_receiver
@@ -201,14 +277,17 @@ impl<'rx_buf, 'parser_frame_buffer, R: Receiver, S: Sender>
             .encode(&mut raw)
             .map_err(ImcpError::EncodeError)?;
         let mut buf = Vec::<u8, MAX_ENCODED_FRAME_SIZE>::new();
-        buf.extend_from_slice(&raw[..size])
-            .map_err(|_| ImcpError::EncodeError(EncodeError::BufferTooSmall))?;
+        buf.extend_from_slice(&raw[..size]).map_err(|_| {
+            ImcpError::EncodeError(EncodeError::BufferTooSmall {
+                capacity: MAX_ENCODED_FRAME_SIZE,
+            })
+        })?;
         match next_frame.payload() {
             FramePayload::SetAddress { address: _, id: _ } => {
                 trace!("set pending_frame to {:?}", next_frame);
                 self.pending_frame = Some(next_frame);
             }
-            FramePayload::Join(_id) => {
+            FramePayload::Join(_) | FramePayload::JoinWithDeviceId { .. } => {
                 trace!("set pending_frame to {:?}", next_frame);
                 self.pending_frame = Some(next_frame);
             }
@@ -221,6 +300,23 @@ impl<'rx_buf, 'parser_frame_buffer, R: Receiver, S: Sender>
         Ok(buf)
     }
 
+    /// 応答のない `JoinWithDeviceId` の再送を数え、上限に達したら ID だけの Join にする
+    fn fall_back_to_plain_join(&mut self, frame: Frame) -> Frame {
+        let FramePayload::JoinWithDeviceId { id, .. } = frame.payload() else {
+            return frame;
+        };
+        if self.join_retries < MAX_JOIN_WITH_DEVICE_ID_RETRIES {
+            self.join_retries = self.join_retries.saturating_add(1);
+            return frame;
+        }
+        warn!("no SetAddress for JoinWithDeviceId, falling back to Join");
+        Frame::new(
+            frame.to_address(),
+            frame.from_address(),
+            FramePayload::Join(*id),
+        )
+    }
+
     pub async fn read_tick<'b>(
         &'b mut self,
         new_data: &[u8],
@@ -246,10 +342,15 @@ impl<'rx_buf, 'parser_frame_buffer, R: Receiver, S: Sender>
             Address::Broadcast => (),
         }
 
+        let from_address = frame.from_address();
         match frame.payload_mut() {
             FramePayload::Ack(data) => {
+                let unexpected_ack = ImcpError::ProtocolError(ProtocolError::UnexpectedAck {
+                    from: from_address,
+                    acked: *data,
+                });
                 if self.pending_frame.is_none() && data != &0xFF {
-                    return Err(ImcpError::ProtocolError(ProtocolError::UnexpectedAck));
+                    return Err(unexpected_ack);
                 }
                 if let Some(pending_frame) = self.pending_frame.as_ref() {
                     let expected_address = pending_frame.to_address().as_byte();
@@ -258,21 +359,30 @@ impl<'rx_buf, 'parser_frame_buffer, R: Receiver, S: Sender>
                         _ => expected_address,
                     };
                     if *data != expected_address {
-                        return Err(ImcpError::ProtocolError(ProtocolError::UnexpectedAck));
+                        return Err(unexpected_ack);
                     }
 
                     if !matches!(pending_frame.to_address(), Address::Broadcast)
-                        && frame.from_address() != expected_sender
+                        && from_address != expected_sender
                     {
-                        return Err(ImcpError::ProtocolError(ProtocolError::UnexpectedAck));
+                        return Err(unexpected_ack);
                     }
 
                     if matches!(pending_frame.payload(), FramePayload::SetAddress { .. })
                         && let NodeType::Master(state) = &mut self.node_type
                     {
-                        state.pending_assignment = None;
+                        let assignment = state.pending_assignment.take();
                         state.pending_assignment_retries = 0;
-                        let _ = state.advance_address();
+                        if let Some((node_id, address, Some(device_id))) = assignment
+                            && let Some(table) = self.sticky_addresses.as_deref_mut()
+                        {
+                            // デバイスが受け取ってから対応表に載せる
+                            table.assign_to_node(device_id, address, node_id);
+                        }
+                        // 対応表から再発行したアドレスでは連番を進めない
+                        if assignment.is_some_and(|(_, address, _)| address == state.next_address) {
+                            let _ = state.advance_address();
+                        }
                     }
                 }
 
@@ -327,27 +437,32 @@ impl<'rx_buf, 'parser_frame_buffer, R: Receiver, S: Sender>
                     }
                 }
             }
-            FramePayload::Join(id) => {
+            FramePayload::Join(_) | FramePayload::JoinWithDeviceId { .. } => {
+                let (id, device_id) = match frame.payload() {
+                    FramePayload::JoinWithDeviceId { id, device_id } => (*id, Some(*device_id)),
+                    FramePayload::Join(id) => (*id, None),
+                    _ => return Ok(None),
+                };
                 if let NodeType::Master(state) = &mut self.node_type {
-                    if let Some((pending_id, _)) = state.pending_assignment {
-                        if pending_id == *id {
+                    if let Some((pending_id, _, _)) = state.pending_assignment {
+                        if pending_id == id {
                             return Ok(Some(frame));
                         }
                         return Ok(None);
                     }
 
                     let assigned_address = state
-                        .allocate_address()
+                        .allocate_address(self.sticky_addresses.as_deref(), id, device_id)
                         .map_err(ImcpError::ProtocolError)?;
                     let frame = Frame::new(
                         Address::Unicast(0x00),
                         self.address,
                         FramePayload::SetAddress {
                             address: assigned_address,
-                            id: *id,
+                            id,
                         },
                     );
-                    state.pending_assignment = Some((*id, assigned_address));
+                    state.pending_assignment = Some((id, assigned_address, device_id));
                     state.pending_assignment_retries = 0;
                     self.tx_sender
                         .send(frame)
@@ -450,8 +565,8 @@ pub mod imcp_test {
             .expect("encoded frame should contain a complete frame")
     }
 
-    impl<'rx_buf, 'parser_frame_buffer, R: Receiver, S: Sender>
-        Imcp<'rx_buf, 'parser_frame_buffer, R, S>
+    impl<'rx_buf, 'parser_frame_buffer, 'sticky, R: Receiver, S: Sender>
+        Imcp<'rx_buf, 'parser_frame_buffer, 'sticky, R, S>
     {
         pub fn new(
             tx_receiver: R,
@@ -460,15 +575,17 @@ pub mod imcp_test {
             pending_frame: Option<Frame>,
             frame_parser: FrameParser<'rx_buf, 'parser_frame_buffer>,
             node_type: NodeType,
-        ) -> Imcp<'rx_buf, 'parser_frame_buffer, R, S> {
+        ) -> Imcp<'rx_buf, 'parser_frame_buffer, 'sticky, R, S> {
             Imcp {
                 tx_receiver,
                 tx_sender,
                 address,
                 node_id: None,
+                join_retries: 0,
                 pending_frame,
                 frame_parser,
                 node_type,
+                sticky_addresses: None,
             }
         }
 
@@ -571,7 +688,7 @@ mod tests {
         let mut buffer = [0u8; 7];
         let result = frame.encode(&mut buffer);
 
-        assert_eq!(result, Err(EncodeError::BufferTooSmall));
+        assert_eq!(result, Err(EncodeError::BufferTooSmall { capacity: 7 }));
     }
 
     #[test]
@@ -646,7 +763,13 @@ mod tests {
     fn test_decode_error_bad_checksum_pure() {
         let buffer: &[u8] = &[0x01, 0x02, 0x00, 0x00, 0x00, 0xFF]; // Bad Checksum
         let res = Frame::decode(buffer);
-        assert_eq!(res, Err(DecodeError::InvalidChecksum));
+        assert_eq!(
+            res,
+            Err(DecodeError::InvalidChecksum {
+                received: 0xFF,
+                expected: 0x03,
+            })
+        );
     }
 
     // --- (FrameParser テスト) ---
@@ -853,6 +976,7 @@ mod tests {
                 tx_sender: sender,
                 address: 0x01,
                 node_id: None,
+                join_retries: 0,
                 pending_frame: Some(pending_frame),
                 frame_parser: parser,
                 node_type: NodeType::Master(MasterState {
@@ -860,13 +984,17 @@ mod tests {
                     pending_assignment: None,
                     pending_assignment_retries: 0,
                 }),
+                sticky_addresses: None,
             };
 
             let result = imcp.read_tick(&encoded[..encoded_len]).await;
 
             assert_eq!(
                 result,
-                Err(ImcpError::ProtocolError(ProtocolError::UnexpectedAck))
+                Err(ImcpError::ProtocolError(ProtocolError::UnexpectedAck {
+                    from: 0x03,
+                    acked: 0x03,
+                }))
             );
             assert!(imcp.pending_frame.is_some());
         });
@@ -935,6 +1063,7 @@ mod tests {
                 tx_sender: sender,
                 address: 0x01,
                 node_id: None,
+                join_retries: 0,
                 pending_frame: Some(set.clone()),
                 frame_parser: FrameParser::new(&mut rx_buf, &mut frame_buf),
                 node_type: NodeType::Master(MasterState {
@@ -942,6 +1071,7 @@ mod tests {
                     pending_assignment: None,
                     pending_assignment_retries: 0,
                 }),
+                sticky_addresses: None,
             };
 
             let encoded = imcp.write_tick().await.unwrap();
@@ -981,13 +1111,15 @@ mod tests {
                 tx_sender: sender,
                 address: 0x01,
                 node_id: None,
+                join_retries: 0,
                 pending_frame: Some(retry_exhausted),
                 frame_parser: parser,
                 node_type: NodeType::Master(MasterState {
                     next_address: 0x02,
-                    pending_assignment: Some((0xAA55_AA55, 0x02)),
+                    pending_assignment: Some((0xAA55_AA55, 0x02, None)),
                     pending_assignment_retries: MAX_SET_ADDRESS_RETRIES,
                 }),
+                sticky_addresses: None,
             };
 
             let encoded = imcp.write_tick().await.unwrap();
@@ -1006,6 +1138,7 @@ mod tests {
                     next_address: 0x02,
                     pending_assignment: None,
                     pending_assignment_retries: 0,
+                    ..
                 })
             ));
         });
@@ -1033,13 +1166,15 @@ mod tests {
                 tx_sender: sender,
                 address: 0x01,
                 node_id: None,
+                join_retries: 0,
                 pending_frame: Some(pending_set_address.clone()),
                 frame_parser: parser,
                 node_type: NodeType::Master(MasterState {
                     next_address: 0x02,
-                    pending_assignment: Some((0xAA55_AA55, 0x02)),
+                    pending_assignment: Some((0xAA55_AA55, 0x02, None)),
                     pending_assignment_retries: MAX_SET_ADDRESS_RETRIES - 1,
                 }),
+                sticky_addresses: None,
             };
 
             let encoded = imcp.write_tick().await.unwrap();
@@ -1056,8 +1191,9 @@ mod tests {
                 imcp.node_type,
                 NodeType::Master(MasterState {
                     next_address: 0x02,
-                    pending_assignment: Some((0xAA55_AA55, 0x02)),
+                    pending_assignment: Some((0xAA55_AA55, 0x02, None)),
                     pending_assignment_retries: MAX_SET_ADDRESS_RETRIES,
+                    ..
                 })
             ));
         });
@@ -1106,13 +1242,15 @@ mod tests {
                 tx_sender: sender,
                 address: 0x01,
                 node_id: None,
+                join_retries: 0,
                 pending_frame: Some(pending_frame),
                 frame_parser: parser,
                 node_type: NodeType::Master(MasterState {
                     next_address: 0x02,
-                    pending_assignment: Some((0x1122_3344, 0x02)),
+                    pending_assignment: Some((0x1122_3344, 0x02, None)),
                     pending_assignment_retries: 2,
                 }),
+                sticky_addresses: None,
             };
 
             let seen = imcp.read_tick(&encoded).await.unwrap().unwrap();
@@ -1125,6 +1263,7 @@ mod tests {
                     next_address: 0x03,
                     pending_assignment: None,
                     pending_assignment_retries: 0,
+                    ..
                 })
             ));
         });
@@ -1227,13 +1366,15 @@ mod tests {
                 tx_sender: sender,
                 address: 0x01,
                 node_id: None,
+                join_retries: 0,
                 pending_frame: None,
                 frame_parser: parser,
                 node_type: NodeType::Master(MasterState {
                     next_address: 0x02,
-                    pending_assignment: Some((0x55AA_55AA, 0x02)),
+                    pending_assignment: Some((0x55AA_55AA, 0x02, None)),
                     pending_assignment_retries: 1,
                 }),
+                sticky_addresses: None,
             };
 
             let seen = imcp.read_tick(&encoded).await.unwrap().unwrap();
@@ -1244,8 +1385,9 @@ mod tests {
                 imcp.node_type,
                 NodeType::Master(MasterState {
                     next_address: 0x02,
-                    pending_assignment: Some((0x55AA_55AA, 0x02)),
+                    pending_assignment: Some((0x55AA_55AA, 0x02, None)),
                     pending_assignment_retries: 1,
+                    ..
                 })
             ));
         });
@@ -1267,6 +1409,7 @@ mod tests {
                 tx_sender: sender,
                 address: 0x01,
                 node_id: None,
+                join_retries: 0,
                 pending_frame: None,
                 frame_parser: parser,
                 node_type: NodeType::Master(MasterState {
@@ -1274,6 +1417,7 @@ mod tests {
                     pending_assignment: None,
                     pending_assignment_retries: 0,
                 }),
+                sticky_addresses: None,
             };
 
             let result = imcp.read_tick(&encoded).await;
@@ -1286,4 +1430,191 @@ mod tests {
             );
         });
     }
+
+    #[test]
+    fn test_join_with_device_id_roundtrip() {
+        let join = Frame::new(
+            Address::Unicast(0x01),
+            0x00,
+            FramePayload::JoinWithDeviceId {
+                id: 0x1234_5678,
+                device_id: 0xE660_FD00_0000_00FE,
+            },
+        );
+
+        let encoded = encode_frame(&join);
+        let mut rx_buf = [0u8; 64];
+        let mut frame_buf = [0u8; 64];
+        let mut parser = FrameParser::new(&mut rx_buf, &mut frame_buf);
+        parser.write_data(&encoded).unwrap();
+        let decoded = parser.next_frame().unwrap().unwrap();
+
+        assert_eq!(decoded, join);
+        assert_eq!(decoded.payload().len(), 12);
+    }
+
+    #[test]
+    fn test_master_reissues_sticky_address_for_known_device() {
+        futures::executor::block_on(async {
+            let join = Frame::new(
+                Address::Unicast(0x01),
+                0x00,
+                FramePayload::JoinWithDeviceId {
+                    id: 0x0000_0042,
+                    device_id: 0xDEAD_BEEF,
+                },
+            );
+            let encoded = encode_frame(&join);
+
+            let mut rx_buf = [0u8; 64];
+            let mut frame_buf = [0u8; 64];
+            let receiver = TestReceiver::new(std::iter::empty());
+            let sender = TestSender::default();
+            let mut imcp = Imcp::new_master(receiver, sender, &mut rx_buf, &mut frame_buf);
+            let mut table = StickyAddressTable::from_entries(&[sticky::StickyAddress {
+                device_id: 0xDEAD_BEEF,
+                address: 0x07,
+            }]);
+            imcp.set_sticky_addresses(&mut table);
+
+            imcp.read_tick(&encoded).await.unwrap();
+
+            assert_eq!(
+                imcp.tx_sender.sent[0].payload(),
+                &FramePayload::SetAddress {
+                    address: 0x07,
+                    id: 0x0000_0042,
+                }
+            );
+            assert!(matches!(
+                &imcp.node_type,
+                NodeType::Master(MasterState {
+                    next_address: 0x02,
+                    pending_assignment: Some((0x0000_0042, 0x07, Some(0xDEAD_BEEF))),
+                    ..
+                })
+            ));
+        });
+    }
+
+    #[test]
+    fn test_master_skips_sticky_addresses_of_other_devices() {
+        futures::executor::block_on(async {
+            let join = Frame::new(
+                Address::Unicast(0x01),
+                0x00,
+                FramePayload::JoinWithDeviceId {
+                    id: 0x0000_0043,
+                    device_id: 0x0000_0001,
+                },
+            );
+            let encoded = encode_frame(&join);
+
+            let mut rx_buf = [0u8; 64];
+            let mut frame_buf = [0u8; 64];
+            let receiver = TestReceiver::new(std::iter::empty());
+            let sender = TestSender::default();
+            let mut imcp = Imcp::new_master(receiver, sender, &mut rx_buf, &mut frame_buf);
+            let mut table = StickyAddressTable::from_entries(&[sticky::StickyAddress {
+                device_id: 0xDEAD_BEEF,
+                address: 0x02,
+            }]);
+            imcp.set_sticky_addresses(&mut table);
+
+            imcp.read_tick(&encoded).await.unwrap();
+
+            assert_eq!(
+                imcp.tx_sender.sent[0].payload(),
+                &FramePayload::SetAddress {
+                    address: 0x03,
+                    id: 0x0000_0043,
+                }
+            );
+            // デバイスが受け取るまでは対応表に載せない
+            assert_eq!(
+                imcp.sticky_addresses().unwrap().address_of(0x0000_0001),
+                None
+            );
+
+            imcp.pending_frame = Some(imcp.tx_sender.sent[0].clone());
+            let ack = Frame::new(Address::Unicast(0x01), 0x03, FramePayload::Ack(0x00));
+            imcp.read_tick(&encode_frame(&ack)).await.unwrap();
+
+            assert_eq!(
+                imcp.sticky_addresses().unwrap().address_of(0x0000_0001),
+                Some(0x03)
+            );
+        });
+    }
+
+    #[test]
+    fn test_master_does_not_reissue_address_held_by_other_node() {
+        futures::executor::block_on(async {
+            let join = |id| {
+                encode_frame(&Frame::new(
+                    Address::Unicast(0x01),
+                    0x00,
+                    FramePayload::JoinWithDeviceId { id, device_id: 0 },
+                ))
+            };
+
+            let mut rx_buf = [0u8; 64];
+            let mut frame_buf = [0u8; 64];
+            let receiver = TestReceiver::new(std::iter::empty());
+            let sender = TestSender::default();
+            let mut imcp = Imcp::new_master(receiver, sender, &mut rx_buf, &mut frame_buf);
+            let mut table = StickyAddressTable::from_entries(&[sticky::StickyAddress {
+                device_id: 0,
+                address: 0x07,
+            }]);
+            imcp.set_sticky_addresses(&mut table);
+
+            imcp.read_tick(&join(0x0000_0044)).await.unwrap();
+            imcp.pending_frame = Some(imcp.tx_sender.sent[0].clone());
+            let ack = Frame::new(Address::Unicast(0x01), 0x07, FramePayload::Ack(0x00));
+            imcp.read_tick(&encode_frame(&ack)).await.unwrap();
+
+            // 同じデバイス ID で別のボードが Join してきても、使用中のアドレスは渡さない
+            imcp.read_tick(&join(0x0000_0045)).await.unwrap();
+            assert_eq!(
+                imcp.tx_sender.sent[1].payload(),
+                &FramePayload::SetAddress {
+                    address: 0x02,
+                    id: 0x0000_0045,
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn test_client_falls_back_to_plain_join() {
+        futures::executor::block_on(async {
+            let shared = Arc::new(Mutex::new(VecDeque::new()));
+            let receiver = QueueReceiver {
+                frames: Arc::clone(&shared),
+            };
+            let sender = QueueSender { frames: shared };
+            let mut rx_buf = [0u8; 64];
+            let mut frame_buf = [0u8; 64];
+            let mut imcp = Imcp::new_client(receiver, sender, &mut rx_buf, &mut frame_buf);
+
+            imcp.send_join_with_device_id(0x1234_5678, 0xDEAD_BEEF)
+                .await
+                .unwrap();
+            // 初回の送信と上限までの再送は JoinWithDeviceId のまま
+            for _ in 0..=MAX_JOIN_WITH_DEVICE_ID_RETRIES {
+                imcp.write_tick().await.unwrap();
+                assert!(matches!(
+                    imcp.pending_frame.as_ref().map(Frame::payload),
+                    Some(FramePayload::JoinWithDeviceId { .. })
+                ));
+            }
+
+            imcp.write_tick().await.unwrap();
+            assert_eq!(
+                imcp.pending_frame.as_ref().map(Frame::payload),
+                Some(&FramePayload::Join(0x1234_5678))
+            );
+        });
+    }
 }
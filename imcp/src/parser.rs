@@ -34,7 +34,9 @@ impl<'rx_buf, 'frame_buf> FrameParser<'rx_buf, 'frame_buf> {
         // 2. 空き容量を計算
         let free_space = self.rx_buffer.len() - self.rx_len;
         if new_data.len() > free_space {
-            return Err(DecodeError::FrameBufferTooSmall);
+            return Err(DecodeError::FrameBufferTooSmall {
+                capacity: self.rx_buffer.len(),
+            });
         }
 
         // 3. データをバッファの末尾にコピー
@@ -101,7 +103,9 @@ impl<'rx_buf, 'frame_buf> FrameParser<'rx_buf, 'frame_buf> {
                                 // アンスタッフィング後バッファが溢れた
                                 // フレームが長すぎる (破損)
                                 self.state = ParserState::WaitingForSof;
-                                return Some(Err(DecodeError::FrameBufferTooSmall));
+                                return Some(Err(DecodeError::FrameBufferTooSmall {
+                                    capacity: self.frame_buffer.len(),
+                                }));
                             }
 
                             if self.is_escaping {
@@ -123,6 +127,11 @@ impl<'rx_buf, 'frame_buf> FrameParser<'rx_buf, 'frame_buf> {
         None
     }
 
+    /// rx_buffer にまだ解析していないデータが残っているか
+    pub fn has_unread_data(&self) -> bool {
+        self.rx_scan_pos < self.rx_len
+    }
+
     /// rx_buffer の消費済み領域 (0..rx_scan_pos) を破棄し、
     /// 有効なデータ (rx_scan_pos..rx_len) をバッファの先頭に移動する
     pub fn consume_rx_buffer(&mut self) {
@@ -0,0 +1,206 @@
+//! デバイス ID とアドレスの対応表
+//!
+//! `JoinWithDeviceId` を受け取ったマスターは、この表を使って
+//! 同じデバイスに前回と同じアドレスを割り当てる。
+//! 表の永続化は呼び出し側 (マネージャーなど) の責任とする。
+//!
+//! デバイス ID を読めなかったボードが同じ ID で Join してくることもあるため、
+//! 今のセッションでアドレスを使っているノード ID も覚え、別のノードには同じアドレスを渡さない。
+
+use heapless::Vec;
+
+/// 対応表に保持できるデバイス数
+pub const MAX_STICKY_ADDRESSES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StickyAddress {
+    pub device_id: u64,
+    pub address: u8,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StickyAddressTable {
+    /// 古い順に並ぶ。あふれた場合は先頭から捨てる
+    entries: Vec<StickyAddress, MAX_STICKY_ADDRESSES>,
+    /// このセッションで対応表のアドレスを使っている (アドレス, ノード ID)。永続化しない
+    holders: Vec<(u8, u32), MAX_STICKY_ADDRESSES>,
+}
+
+impl StickyAddressTable {
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            holders: Vec::new(),
+        }
+    }
+
+    /// 永続化された対応表から復元する
+    ///
+    /// 割り当てられないアドレス (0x00, 0x01, 0xFF) は無視し、
+    /// 同じデバイス ID やアドレスが重複している場合は後ろの項目を優先する
+    pub fn from_entries(entries: &[StickyAddress]) -> Self {
+        let mut table = Self::new();
+        for entry in entries {
+            table.assign(entry.device_id, entry.address);
+        }
+        table
+    }
+
+    pub fn entries(&self) -> &[StickyAddress] {
+        &self.entries
+    }
+
+    /// デバイスに割り当て済みのアドレス
+    pub fn address_of(&self, device_id: u64) -> Option<u8> {
+        self.entries
+            .iter()
+            .find(|entry| entry.device_id == device_id)
+            .map(|entry| entry.address)
+    }
+
+    /// アドレスを予約しているデバイス
+    pub fn owner_of(&self, address: u8) -> Option<u64> {
+        self.entries
+            .iter()
+            .find(|entry| entry.address == address)
+            .map(|entry| entry.device_id)
+    }
+
+    /// `address` が他のデバイスに予約されているか
+    pub fn is_reserved_for_other(&self, address: u8, device_id: Option<u64>) -> bool {
+        self.owner_of(address)
+            .is_some_and(|owner| Some(owner) != device_id)
+    }
+
+    /// このセッションで `address` を使っているノード ID
+    pub fn holder_of(&self, address: u8) -> Option<u32> {
+        self.holders
+            .iter()
+            .find(|(held, _)| *held == address)
+            .map(|(_, node_id)| *node_id)
+    }
+
+    /// `node_id` のノードに割り当ててよいアドレスか
+    ///
+    /// 他のデバイスに予約されているアドレスと、他のノードが使っているアドレスは使えない
+    pub fn is_available(&self, address: u8, device_id: Option<u64>, node_id: u32) -> bool {
+        !self.is_reserved_for_other(address, device_id)
+            && self
+                .holder_of(address)
+                .is_none_or(|holder| holder == node_id)
+    }
+
+    /// デバイスに再発行できるアドレス
+    ///
+    /// 対応表のアドレスを別のノードが使っている場合 (ID を読めなかったボードなど) は `None`
+    pub fn reusable_address(&self, device_id: u64, node_id: u32) -> Option<u8> {
+        self.address_of(device_id)
+            .filter(|address| self.is_available(*address, Some(device_id), node_id))
+    }
+
+    /// 対応を記録し、アドレスを `node_id` のノードが使っているものとする。
+    /// 対応表に変更があった場合は `true` を返す
+    pub fn assign_to_node(&mut self, device_id: u64, address: u8, node_id: u32) -> bool {
+        if !is_assignable(address) {
+            return false;
+        }
+        self.holders.retain(|(held, _)| *held != address);
+        if self.holders.is_full() {
+            self.holders.remove(0);
+        }
+        // 直前に空きを作っているので失敗しない
+        let _ = self.holders.push((address, node_id));
+        self.assign(device_id, address)
+    }
+
+    /// 対応を記録する。変更があった場合は `true` を返す
+    ///
+    /// 同じデバイスや同じアドレスの古い対応は取り除く。
+    /// 表が一杯の場合は最も古い対応を捨てる
+    pub fn assign(&mut self, device_id: u64, address: u8) -> bool {
+        if !is_assignable(address) {
+            return false;
+        }
+        if self.address_of(device_id) == Some(address) {
+            return false;
+        }
+
+        self.entries
+            .retain(|entry| entry.device_id != device_id && entry.address != address);
+        if self.entries.is_full() {
+            self.entries.remove(0);
+        }
+        // 直前に空きを作っているので失敗しない
+        let _ = self.entries.push(StickyAddress { device_id, address });
+        true
+    }
+}
+
+/// クライアントに割り当て可能なアドレスか (未割り当て, マスター, ブロードキャストを除く)
+pub fn is_assignable(address: u8) -> bool {
+    !matches!(address, 0x00 | 0x01 | 0xFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assign_replaces_previous_owner_and_address() {
+        let mut table = StickyAddressTable::new();
+        assert!(table.assign(0xAAAA, 0x02));
+        assert!(table.assign(0xBBBB, 0x03));
+
+        assert!(!table.assign(0xAAAA, 0x02));
+        assert!(table.assign(0xBBBB, 0x02));
+
+        assert_eq!(
+            table.entries(),
+            &[StickyAddress {
+                device_id: 0xBBBB,
+                address: 0x02
+            }]
+        );
+        assert_eq!(table.address_of(0xAAAA), None);
+        assert!(table.is_reserved_for_other(0x02, Some(0xAAAA)));
+        assert!(!table.is_reserved_for_other(0x02, Some(0xBBBB)));
+    }
+
+    #[test]
+    fn address_held_by_other_node_is_not_reused() {
+        let mut table = StickyAddressTable::from_entries(&[StickyAddress {
+            device_id: 0,
+            address: 0x02,
+        }]);
+        // 前回のセッションの対応はどのノードにも使われていない
+        assert_eq!(table.reusable_address(0, 0x1111), Some(0x02));
+
+        assert!(!table.assign_to_node(0, 0x02, 0x1111));
+        assert_eq!(table.holder_of(0x02), Some(0x1111));
+        assert_eq!(table.reusable_address(0, 0x1111), Some(0x02));
+        // 同じデバイス ID で別のノードが Join してきても同じアドレスは渡さない
+        assert_eq!(table.reusable_address(0, 0x2222), None);
+        assert!(!table.is_available(0x02, Some(0), 0x2222));
+        assert!(table.is_available(0x03, Some(0), 0x2222));
+    }
+
+    #[test]
+    fn from_entries_skips_reserved_addresses_and_evicts_oldest() {
+        let mut entries = std::vec::Vec::new();
+        entries.push(StickyAddress {
+            device_id: 1,
+            address: 0x01,
+        });
+        for (device_id, address) in (100u64..).zip(0x10u8..).take(MAX_STICKY_ADDRESSES + 1) {
+            entries.push(StickyAddress { device_id, address });
+        }
+
+        let table = StickyAddressTable::from_entries(&entries);
+
+        assert_eq!(table.entries().len(), MAX_STICKY_ADDRESSES);
+        assert_eq!(table.address_of(1), None);
+        assert_eq!(table.address_of(100), None);
+        assert_eq!(table.address_of(101), Some(0x11));
+    }
+}
@@ -11,12 +11,14 @@ use imcp::{
 
 struct Harness {
     master: Imcp<
+        'static,
         'static,
         'static,
         imcp::imcp_test::MemoryReceiver,
         imcp::imcp_test::MemorySender,
     >,
     client: Imcp<
+        'static,
         'static,
         'static,
         imcp::imcp_test::MemoryReceiver,
@@ -392,7 +394,10 @@ fn unexpected_ack_without_pending_is_rejected_on_os() {
 
         assert_eq!(
             result,
-            Err(ImcpError::ProtocolError(ProtocolError::UnexpectedAck))
+            Err(ImcpError::ProtocolError(ProtocolError::UnexpectedAck {
+                from: 0x03,
+                acked: 0x03,
+            }))
         );
     });
 }
@@ -0,0 +1,1130 @@
+-- Wireshark dissector for IMCP frames and HCP packets.
+--
+-- `imcp::capture` で記録した pcapng を読むための dissector です。
+-- Wireshark の plugins ディレクトリに置くか、`wireshark -X lua_script:homecockpit.lua` で読み込みます。
+--
+-- * LINKTYPE_USER0 (147): UART の生バイト列。レコード内で完結しているフレームだけをデコードする
+-- * LINKTYPE_USER1 (148): 1 レコード 1 フレーム
+--
+-- 下の名前テーブルは Rust 側の型と一致していることを
+-- `imcp` (capture.rs) と `hcp` のテストで検証しています。
+-- フレームタイプや HCP の enum を変更したら、このファイルも更新してください。
+
+local SOF = 0xFE
+local EOF = 0xFF
+local ESC = 0xFD
+local ESC_XOR = 0x20
+
+local HEADER_LEN = 5
+local CHECKSUM_LEN = 1
+
+local FRAME_TYPES = {
+    [0] = "Ping",
+    [1] = "Pong",
+    [2] = "Ack",
+    [3] = "Join",
+    [4] = "SetAddress",
+    [5] = "Data",
+    [6] = "Set",
+}
+
+local ADDRESSES = {
+    [0xFF] = "Broadcast",
+}
+
+-- HCP (postcard) の enum variant index
+local APP_PACKET_KINDS = {
+    [0] = "DisplayData",
+    [1] = "DeviceHello",
+    [2] = "ControlEvent",
+    [3] = "RequestDescriptor",
+    [4] = "DescriptorPage",
+    [5] = "GetConfig",
+    [6] = "SetConfig",
+    [7] = "ConfigResponse",
+    [8] = "HostHello",
+    [9] = "ControlEventBatch",
+    [10] = "RequestControlState",
+    [11] = "ControlStateSnapshot",
+    [12] = "FirmwareUpdate",
+    [13] = "FirmwareUpdateStatus",
+    [14] = "Log",
+    [15] = "TimeSync",
+    [16] = "TimestampedControlEvents",
+    [17] = "Identify",
+    [18] = "CalibrateAxis",
+    [19] = "AxisCalibrationStatus",
+}
+
+local DISPLAY_TARGETS = {
+    [0] = "Screen",
+    [1] = "Indicator",
+    [2] = "Backlight",
+}
+
+local DISPLAY_PAYLOADS = {
+    [0] = "Text",
+    [1] = "Bytes",
+    [2] = "Commands",
+    [3] = "Bitmap",
+    [4] = "Indicator",
+    [5] = "Segments",
+    [6] = "Pwm",
+}
+
+local DESCRIPTOR_ENTRIES = {
+    [0] = "Control",
+    [1] = "Display",
+}
+
+local CONTROL_KINDS = {
+    [0] = "Button",
+    [1] = "Toggle",
+    [2] = "Encoder",
+    [3] = "Axis",
+}
+
+local DISPLAY_KINDS = {
+    [0] = "MonoBitmap",
+    [1] = "CharacterGrid",
+    [2] = "Indicator",
+    [3] = "Segments",
+}
+
+local CONFIG_KEYS = {
+    [0] = "DebounceMs",
+    [1] = "LedBrightness",
+    [2] = "ScanIntervalMs",
+    [3] = "EncoderAcceleration",
+}
+
+local CONFIG_VALUES = {
+    [0] = "U8",
+    [1] = "U16",
+}
+
+local CONFIG_STATUSES = {
+    [0] = "Value",
+    [1] = "UnsupportedKey",
+    [2] = "TypeMismatch",
+    [3] = "OutOfRange",
+    [4] = "StorageFailed",
+}
+
+local LAMP_MODES = {
+    [0] = "Off",
+    [1] = "On",
+    [2] = "Blink",
+}
+
+local SEGMENT_KINDS = {
+    [0] = "Seven",
+    [1] = "Fourteen",
+    [2] = "Sixteen",
+}
+
+local BITMAP_COMPRESSIONS = {
+    [0] = "Raw",
+    [1] = "Rle",
+    [2] = "XorRle",
+}
+
+local DISPLAY_COMMANDS = {
+    [0] = "Clear",
+    [1] = "ClearRegion",
+    [2] = "Text",
+    [3] = "Box",
+    [4] = "Line",
+}
+
+local TEXT_FORMATS = {
+    [0] = "Plain",
+}
+
+local BYTE_ENCODINGS = {
+    [0] = "MonoBitmap1bpp",
+    [1] = "SegmentMap",
+    [2] = "Utf8Text",
+}
+
+local DEVICE_KINDS = {
+    [0] = "UpperPanelDdi",
+    [1] = "ButtonPanel",
+    [2] = "ImcpHub",
+    [3] = "Unknown",
+}
+
+local CONTROL_VALUES = {
+    [0] = "Button",
+    [1] = "EncoderDelta",
+    [2] = "Absolute",
+    [3] = "Toggle",
+    [4] = "RequestDeviceHello",
+}
+
+local UPDATE_COMMANDS = {
+    [0] = "Begin",
+    [1] = "Chunk",
+    [2] = "Verify",
+    [3] = "Commit",
+    [4] = "Abort",
+}
+
+local UPDATE_PHASES = {
+    [0] = "Idle",
+    [1] = "Receiving",
+    [2] = "Verified",
+    [3] = "Committed",
+}
+
+local UPDATE_ERRORS = {
+    [0] = "NotStarted",
+    [1] = "ImageTooLarge",
+    [2] = "UnexpectedOffset",
+    [3] = "Incomplete",
+    [4] = "HashMismatch",
+    [5] = "NotVerified",
+    [6] = "FlashFailed",
+}
+
+local LOG_LEVELS = {
+    [0] = "Error",
+    [1] = "Warn",
+    [2] = "Info",
+    [3] = "Debug",
+    [4] = "Trace",
+}
+
+local CALIBRATION_COMMANDS = {
+    [0] = "Begin",
+    [1] = "CaptureCenter",
+    [2] = "Commit",
+    [3] = "Cancel",
+    [4] = "Get",
+    [5] = "Set",
+}
+
+local CALIBRATION_ERRORS = {
+    [0] = "UnknownControl",
+    [1] = "NotRecording",
+    [2] = "InvalidRange",
+}
+
+---------------------------------------------------------------------------
+-- IMCP
+---------------------------------------------------------------------------
+
+local imcp = Proto("imcp", "Inter-microcontroller communication protocol")
+local imcp_stream = Proto("imcp_stream", "IMCP UART stream")
+
+local imcp_fields = {
+    to = ProtoField.uint8("imcp.to", "To", base.HEX, ADDRESSES),
+    from = ProtoField.uint8("imcp.from", "From", base.HEX, ADDRESSES),
+    frame_type = ProtoField.uint8("imcp.type", "Type", base.DEC, FRAME_TYPES),
+    len = ProtoField.uint16("imcp.len", "Payload length", base.DEC),
+    payload = ProtoField.bytes("imcp.payload", "Payload"),
+    ack = ProtoField.uint8("imcp.ack", "Acked address", base.HEX, ADDRESSES),
+    join_id = ProtoField.uint32("imcp.join_id", "Join id", base.HEX),
+    device_id = ProtoField.uint64("imcp.device_id", "Device id", base.HEX),
+    address = ProtoField.uint8("imcp.address", "Assigned address", base.HEX),
+    checksum = ProtoField.uint8("imcp.checksum", "Checksum", base.HEX),
+    stuffed = ProtoField.bytes("imcp.stuffed", "Stuffed frame"),
+    fragment = ProtoField.bytes("imcp.fragment", "Incomplete frame fragment"),
+}
+
+imcp.fields = {
+    imcp_fields.to,
+    imcp_fields.from,
+    imcp_fields.frame_type,
+    imcp_fields.len,
+    imcp_fields.payload,
+    imcp_fields.ack,
+    imcp_fields.join_id,
+    imcp_fields.device_id,
+    imcp_fields.address,
+    imcp_fields.checksum,
+    imcp_fields.stuffed,
+    imcp_fields.fragment,
+}
+
+local imcp_experts = {
+    bad_checksum = ProtoExpert.new(
+        "imcp.checksum.bad", "Bad checksum", expert.group.CHECKSUM, expert.severity.ERROR),
+    bad_escape = ProtoExpert.new(
+        "imcp.escape.bad", "Invalid escape sequence", expert.group.MALFORMED, expert.severity.ERROR),
+    bad_length = ProtoExpert.new(
+        "imcp.len.bad", "Payload length does not match frame", expert.group.MALFORMED,
+        expert.severity.ERROR),
+    unknown_type = ProtoExpert.new(
+        "imcp.type.unknown", "Unknown frame type", expert.group.MALFORMED, expert.severity.WARN),
+    fragment = ProtoExpert.new(
+        "imcp.fragment", "Frame continues in another record", expert.group.REASSEMBLE,
+        expert.severity.NOTE),
+}
+
+imcp.experts = {
+    imcp_experts.bad_checksum,
+    imcp_experts.bad_escape,
+    imcp_experts.bad_length,
+    imcp_experts.unknown_type,
+    imcp_experts.fragment,
+}
+
+---------------------------------------------------------------------------
+-- HCP
+---------------------------------------------------------------------------
+
+local hcp = Proto("hcp", "HomeCockpit application protocol")
+
+local hcp_fields = {
+    version = ProtoField.uint8("hcp.version", "Version", base.DEC),
+    kind = ProtoField.uint32("hcp.kind", "Kind", base.DEC, APP_PACKET_KINDS),
+    seq = ProtoField.uint16("hcp.seq", "Sequence", base.DEC),
+    target = ProtoField.uint32("hcp.target", "Target", base.DEC, DISPLAY_TARGETS),
+    screen = ProtoField.uint8("hcp.screen", "Screen", base.DEC),
+    indicator = ProtoField.uint16("hcp.indicator", "Indicator", base.DEC),
+    backlight = ProtoField.uint8("hcp.backlight", "Backlight", base.DEC),
+    display_payload = ProtoField.uint32("hcp.display_payload", "Payload", base.DEC, DISPLAY_PAYLOADS),
+    text_format = ProtoField.uint32("hcp.text_format", "Text format", base.DEC, TEXT_FORMATS),
+    text = ProtoField.string("hcp.text", "Text"),
+    byte_encoding = ProtoField.uint32("hcp.byte_encoding", "Encoding", base.DEC, BYTE_ENCODINGS),
+    data = ProtoField.bytes("hcp.data", "Data"),
+    command_count = ProtoField.uint8("hcp.commands", "Command count", base.DEC),
+    command = ProtoField.uint32("hcp.command", "Command", base.DEC, DISPLAY_COMMANDS),
+    x = ProtoField.uint16("hcp.x", "X", base.DEC),
+    y = ProtoField.uint16("hcp.y", "Y", base.DEC),
+    width = ProtoField.uint16("hcp.width", "Width", base.DEC),
+    height = ProtoField.uint16("hcp.height", "Height", base.DEC),
+    row = ProtoField.uint8("hcp.row", "Row", base.DEC),
+    column = ProtoField.uint8("hcp.column", "Column", base.DEC),
+    font = ProtoField.uint8("hcp.font", "Font", base.DEC),
+    inverse = ProtoField.bool("hcp.inverse", "Inverse"),
+    blink = ProtoField.bool("hcp.blink", "Blink"),
+    filled = ProtoField.bool("hcp.filled", "Filled"),
+    compression = ProtoField.uint32("hcp.compression", "Compression", base.DEC, BITMAP_COMPRESSIONS),
+    lamp_mode = ProtoField.uint32("hcp.lamp_mode", "Lamp mode", base.DEC, LAMP_MODES),
+    period_ms = ProtoField.uint16("hcp.period_ms", "Blink period (ms)", base.DEC),
+    brightness = ProtoField.uint8("hcp.brightness", "Brightness", base.DEC),
+    fade_ms = ProtoField.uint16("hcp.fade_ms", "Fade (ms)", base.DEC),
+    segment_kind = ProtoField.uint32("hcp.segment_kind", "Segment kind", base.DEC, SEGMENT_KINDS),
+    digit_count = ProtoField.uint8("hcp.digits", "Digit count", base.DEC),
+    character = ProtoField.uint8("hcp.character", "Character", base.HEX),
+    decimal_point = ProtoField.bool("hcp.decimal_point", "Decimal point"),
+    page = ProtoField.uint16("hcp.page", "Page", base.DEC),
+    total_pages = ProtoField.uint16("hcp.total_pages", "Total pages", base.DEC),
+    entry_count = ProtoField.uint8("hcp.entries", "Entry count", base.DEC),
+    event_count = ProtoField.uint8("hcp.events", "Event count", base.DEC),
+    first_switch_id = ProtoField.uint16("hcp.first_switch_id", "First switch id", base.HEX),
+    switch_count = ProtoField.uint16("hcp.switch_count", "Switch count", base.DEC),
+    switches = ProtoField.bytes("hcp.switches", "Switches"),
+    value_count = ProtoField.uint8("hcp.value_count", "Value count", base.DEC),
+    descriptor_entry = ProtoField.uint32("hcp.descriptor_entry", "Entry", base.DEC, DESCRIPTOR_ENTRIES),
+    control_kind = ProtoField.uint32("hcp.control_kind", "Control kind", base.DEC, CONTROL_KINDS),
+    supported_events = ProtoField.uint8("hcp.supported_events", "Supported events", base.HEX),
+    label = ProtoField.string("hcp.label", "Label"),
+    display_kind = ProtoField.uint32("hcp.display_kind", "Display kind", base.DEC, DISPLAY_KINDS),
+    config_key = ProtoField.uint32("hcp.config_key", "Config key", base.DEC, CONFIG_KEYS),
+    config_type = ProtoField.uint32("hcp.config_type", "Config value type", base.DEC, CONFIG_VALUES),
+    config_u8 = ProtoField.uint8("hcp.config_u8", "Value", base.DEC),
+    config_uint = ProtoField.uint32("hcp.config_uint", "Value", base.DEC),
+    config_status = ProtoField.uint32("hcp.config_status", "Status", base.DEC, CONFIG_STATUSES),
+    device_id = ProtoField.uint64("hcp.device_id", "Device id", base.HEX),
+    device_kind = ProtoField.uint32("hcp.device_kind", "Device kind", base.DEC, DEVICE_KINDS),
+    device_kind_raw = ProtoField.uint16("hcp.device_kind.raw", "Unknown device kind", base.DEC),
+    protocol_version = ProtoField.uint8("hcp.protocol_version", "Protocol version", base.DEC),
+    min_protocol_version = ProtoField.uint8("hcp.min_protocol_version", "Min protocol version", base.DEC),
+    firmware_major = ProtoField.uint8("hcp.firmware.major", "Firmware major", base.DEC),
+    firmware_minor = ProtoField.uint8("hcp.firmware.minor", "Firmware minor", base.DEC),
+    firmware_patch = ProtoField.uint8("hcp.firmware.patch", "Firmware patch", base.DEC),
+    displays = ProtoField.uint8("hcp.capabilities.displays", "Displays", base.DEC),
+    controls = ProtoField.uint16("hcp.capabilities.controls", "Controls", base.DEC),
+    features = ProtoField.uint32("hcp.capabilities.features", "Features", base.HEX),
+    control_id = ProtoField.uint16("hcp.control_id", "Control id", base.HEX),
+    control_value = ProtoField.uint32("hcp.control_value", "Value", base.DEC, CONTROL_VALUES),
+    pressed = ProtoField.bool("hcp.pressed", "Pressed"),
+    steps = ProtoField.int8("hcp.steps", "Steps", base.DEC),
+    absolute = ProtoField.int16("hcp.absolute", "Absolute value", base.DEC),
+    state = ProtoField.bool("hcp.state", "State"),
+    update_command = ProtoField.uint32("hcp.update.command", "Update command", base.DEC, UPDATE_COMMANDS),
+    image_size = ProtoField.uint32("hcp.update.image_size", "Image size", base.DEC),
+    sha256 = ProtoField.bytes("hcp.update.sha256", "SHA-256"),
+    update_offset = ProtoField.uint32("hcp.update.offset", "Offset", base.DEC),
+    update_phase = ProtoField.uint32("hcp.update.phase", "Phase", base.DEC, UPDATE_PHASES),
+    update_received = ProtoField.uint32("hcp.update.received", "Received", base.DEC),
+    update_error = ProtoField.uint32("hcp.update.error", "Error", base.DEC, UPDATE_ERRORS),
+    log_level = ProtoField.uint32("hcp.log.level", "Level", base.DEC, LOG_LEVELS),
+    log_dropped = ProtoField.uint16("hcp.log.dropped", "Dropped", base.DEC),
+    log_tag = ProtoField.string("hcp.log.tag", "Tag"),
+    log_text = ProtoField.string("hcp.log.text", "Text"),
+    host_time_us = ProtoField.uint64("hcp.time.host_time_us", "Host time (us)", base.DEC),
+    timestamp_us = ProtoField.uint64("hcp.time.timestamp_us", "Timestamp (us)", base.DEC),
+    identify_seconds = ProtoField.uint8("hcp.identify.seconds", "Seconds", base.DEC),
+    calibration_command = ProtoField.uint32("hcp.calibration.command", "Command", base.DEC, CALIBRATION_COMMANDS),
+    calibration_raw = ProtoField.uint16("hcp.calibration.raw", "Raw", base.DEC),
+    calibration_recording = ProtoField.bool("hcp.calibration.recording", "Recording"),
+    calibration_min = ProtoField.uint16("hcp.calibration.min", "Min", base.DEC),
+    calibration_center = ProtoField.uint16("hcp.calibration.center", "Center", base.DEC),
+    calibration_max = ProtoField.uint16("hcp.calibration.max", "Max", base.DEC),
+    calibration_deadzone = ProtoField.uint16("hcp.calibration.deadzone", "Deadzone", base.DEC),
+    calibration_error = ProtoField.uint32("hcp.calibration.error", "Error", base.DEC, CALIBRATION_ERRORS),
+    unparsed = ProtoField.bytes("hcp.unparsed", "Undecoded bytes"),
+}
+
+local hcp_field_list = {}
+for _, field in pairs(hcp_fields) do
+    table.insert(hcp_field_list, field)
+end
+hcp.fields = hcp_field_list
+
+local hcp_experts = {
+    malformed = ProtoExpert.new(
+        "hcp.malformed", "Malformed HCP packet", expert.group.MALFORMED, expert.severity.ERROR),
+    unknown_kind = ProtoExpert.new(
+        "hcp.kind.unknown", "Unknown HCP packet kind", expert.group.UNDECODED, expert.severity.WARN),
+}
+
+hcp.experts = {
+    hcp_experts.malformed,
+    hcp_experts.unknown_kind,
+}
+
+-- postcard のデコーダー。読み出すたびに tree へ項目を追加する
+local Reader = {}
+Reader.__index = Reader
+
+function Reader.new(tvb, offset)
+    return setmetatable({ tvb = tvb, offset = offset }, Reader)
+end
+
+function Reader:remaining()
+    return self.tvb:len() - self.offset
+end
+
+function Reader:take(len)
+    if self:remaining() < len then
+        error("truncated", 0)
+    end
+    local range = self.tvb(self.offset, len)
+    self.offset = self.offset + len
+    return range
+end
+
+function Reader:u8(tree, field)
+    local range = self:take(1)
+    tree:add(field, range)
+    return range:uint()
+end
+
+function Reader:bool(tree, field)
+    local range = self:take(1)
+    local value = range:uint()
+    if value > 1 then
+        error("invalid bool", 0)
+    end
+    tree:add(field, range, value == 1)
+    return value == 1
+end
+
+function Reader:i8(tree, field)
+    local range = self:take(1)
+    local value = range:uint()
+    if value >= 0x80 then
+        value = value - 0x100
+    end
+    tree:add(field, range, value)
+    return value
+end
+
+-- varint を UInt64 として読み出す
+function Reader:raw_varint()
+    local start = self.offset
+    local value = UInt64(0)
+    local shift = 0
+    while true do
+        local byte = self:take(1):uint()
+        value = value:bor(UInt64(byte % 0x80):lshift(shift))
+        if byte < 0x80 then
+            return value, self.tvb(start, self.offset - start)
+        end
+        shift = shift + 7
+        if shift > 63 then
+            error("varint too long", 0)
+        end
+    end
+end
+
+function Reader:varint64(tree, field)
+    local value, range = self:raw_varint()
+    tree:add(field, range, value)
+    return value
+end
+
+function Reader:varint(tree, field)
+    local value, range = self:raw_varint()
+    value = value:tonumber()
+    if tree ~= nil then
+        tree:add(field, range, value)
+    end
+    return value
+end
+
+function Reader:zigzag(tree, field)
+    local value, range = self:raw_varint()
+    value = value:tonumber()
+    if value % 2 == 0 then
+        value = value / 2
+    else
+        value = -(value + 1) / 2
+    end
+    tree:add(field, range, value)
+    return value
+end
+
+-- 長さ付きのバイト列。空の場合は項目を追加しない
+function Reader:bytes(tree, field)
+    local len = self:varint(nil)
+    if len == 0 then
+        return nil
+    end
+    local range = self:take(len)
+    tree:add(field, range)
+    return range
+end
+
+function Reader:string(tree, field)
+    local range = self:bytes(tree, field)
+    if range == nil then
+        return ""
+    end
+    return range:string(ENC_UTF_8)
+end
+
+function Reader:enum(tree, field, names)
+    local index = self:varint(tree, field)
+    return index, names[index]
+end
+
+local function subtree(r, tree, label)
+    if r:remaining() == 0 then
+        error("truncated", 0)
+    end
+    return tree:add(hcp, r.tvb(r.offset, r:remaining()), label)
+end
+
+local KIND_DISSECTORS = {}
+
+local function dissect_region(r, tree, label)
+    local region = subtree(r, tree, label)
+    r:varint(region, hcp_fields.x)
+    r:varint(region, hcp_fields.y)
+    r:varint(region, hcp_fields.width)
+    r:varint(region, hcp_fields.height)
+end
+
+local function dissect_point(r, tree, label)
+    local point = subtree(r, tree, label)
+    r:varint(point, hcp_fields.x)
+    r:varint(point, hcp_fields.y)
+end
+
+local function dissect_display_commands(r, tree)
+    local count = r:varint(tree, hcp_fields.command_count)
+    for _ = 1, count do
+        local command_tree = subtree(r, tree, "Display command")
+        local _, command = r:enum(command_tree, hcp_fields.command, DISPLAY_COMMANDS)
+        if command == "Clear" then
+            -- フィールドなし
+        elseif command == "ClearRegion" then
+            dissect_region(r, command_tree, "Region")
+        elseif command == "Text" then
+            r:u8(command_tree, hcp_fields.row)
+            r:u8(command_tree, hcp_fields.column)
+            r:u8(command_tree, hcp_fields.font)
+            r:bool(command_tree, hcp_fields.inverse)
+            r:bool(command_tree, hcp_fields.blink)
+            r:string(command_tree, hcp_fields.text)
+        elseif command == "Box" then
+            dissect_region(r, command_tree, "Region")
+            r:bool(command_tree, hcp_fields.filled)
+        elseif command == "Line" then
+            dissect_point(r, command_tree, "From")
+            dissect_point(r, command_tree, "To")
+        else
+            error("unknown display command", 0)
+        end
+        command_tree:append_text(": " .. command)
+    end
+    return count
+end
+
+local function dissect_display_target(r, tree)
+    local _, target = r:enum(tree, hcp_fields.target, DISPLAY_TARGETS)
+    if target == "Screen" then
+        r:u8(tree, hcp_fields.screen)
+    elseif target == "Indicator" then
+        r:varint(tree, hcp_fields.indicator)
+    elseif target == "Backlight" then
+        r:u8(tree, hcp_fields.backlight)
+    else
+        error("unknown display target", 0)
+    end
+    return target
+end
+
+KIND_DISSECTORS.DisplayData = function(r, tree)
+    local seq = r:varint(tree, hcp_fields.seq)
+    local target = dissect_display_target(r, tree)
+
+    local _, payload = r:enum(tree, hcp_fields.display_payload, DISPLAY_PAYLOADS)
+    if payload == "Text" then
+        r:enum(tree, hcp_fields.text_format, TEXT_FORMATS)
+        r:string(tree, hcp_fields.text)
+    elseif payload == "Bytes" then
+        r:enum(tree, hcp_fields.byte_encoding, BYTE_ENCODINGS)
+        r:bytes(tree, hcp_fields.data)
+    elseif payload == "Commands" then
+        local count = dissect_display_commands(r, tree)
+        return string.format("DisplayData seq=%d %s %d command(s)", seq, target, count)
+    elseif payload == "Bitmap" then
+        dissect_region(r, tree, "Region")
+        local _, compression = r:enum(tree, hcp_fields.compression, BITMAP_COMPRESSIONS)
+        r:bytes(tree, hcp_fields.data)
+        return string.format("DisplayData seq=%d %s Bitmap %s", seq, target, compression)
+    elseif payload == "Indicator" then
+        local _, mode = r:enum(tree, hcp_fields.lamp_mode, LAMP_MODES)
+        if mode == "Blink" then
+            r:varint(tree, hcp_fields.period_ms)
+        elseif mode == nil then
+            error("unknown lamp mode", 0)
+        end
+        r:u8(tree, hcp_fields.brightness)
+        return string.format("DisplayData seq=%d %s %s", seq, target, mode)
+    elseif payload == "Segments" then
+        local _, kind = r:enum(tree, hcp_fields.segment_kind, SEGMENT_KINDS)
+        local count = r:varint(tree, hcp_fields.digit_count)
+        local text = ""
+        for _ = 1, count do
+            local digit = subtree(r, tree, "Digit")
+            local character = r:u8(digit, hcp_fields.character)
+            local decimal_point = r:bool(digit, hcp_fields.decimal_point)
+            text = text .. string.char(character) .. (decimal_point and "." or "")
+            digit:append_text(": " .. string.char(character))
+        end
+        return string.format("DisplayData seq=%d %s %s \"%s\"", seq, target, tostring(kind), text)
+    elseif payload == "Pwm" then
+        local brightness = r:u8(tree, hcp_fields.brightness)
+        local fade_ms = r:varint(tree, hcp_fields.fade_ms)
+        return string.format("DisplayData seq=%d %s Pwm %d fade=%dms", seq, target, brightness, fade_ms)
+    else
+        error("unknown display payload", 0)
+    end
+
+    return string.format("DisplayData seq=%d %s", seq, target)
+end
+
+KIND_DISSECTORS.DeviceHello = function(r, tree, version)
+    local device_id = r:varint64(tree, hcp_fields.device_id)
+
+    local _, device_kind = r:enum(tree, hcp_fields.device_kind, DEVICE_KINDS)
+    if device_kind == "Unknown" then
+        r:varint(tree, hcp_fields.device_kind_raw)
+    end
+
+    r:u8(tree, hcp_fields.protocol_version)
+    -- v1 の layout には最小バージョンがない
+    if version >= 2 then
+        r:u8(tree, hcp_fields.min_protocol_version)
+    end
+
+    local firmware = subtree(r, tree, "Firmware version")
+    r:u8(firmware, hcp_fields.firmware_major)
+    r:u8(firmware, hcp_fields.firmware_minor)
+    r:u8(firmware, hcp_fields.firmware_patch)
+
+    local capabilities = subtree(r, tree, "Capabilities")
+    r:u8(capabilities, hcp_fields.displays)
+    r:varint(capabilities, hcp_fields.controls)
+    r:varint(capabilities, hcp_fields.features)
+
+    return string.format("DeviceHello %s id=0x%s", device_kind or "?", device_id:tohex())
+end
+
+local function dissect_control_value(r, tree)
+    local _, value = r:enum(tree, hcp_fields.control_value, CONTROL_VALUES)
+    if value == "Button" then
+        r:bool(tree, hcp_fields.pressed)
+    elseif value == "EncoderDelta" then
+        r:i8(tree, hcp_fields.steps)
+    elseif value == "Absolute" then
+        r:zigzag(tree, hcp_fields.absolute)
+    elseif value == "Toggle" then
+        r:bool(tree, hcp_fields.state)
+    elseif value == "RequestDeviceHello" then
+        -- フィールドなし
+    else
+        error("unknown control value", 0)
+    end
+    return value
+end
+
+KIND_DISSECTORS.ControlEvent = function(r, tree)
+    local seq = r:varint(tree, hcp_fields.seq)
+    local control_id = r:varint(tree, hcp_fields.control_id)
+    local value = dissect_control_value(r, tree)
+
+    return string.format("ControlEvent seq=%d control=0x%04X %s", seq, control_id, value)
+end
+
+local function dissect_control_event_batch(r, tree)
+    local first_seq = r:varint(tree, hcp_fields.seq)
+    local count = r:varint(tree, hcp_fields.event_count)
+    for i = 1, count do
+        local entry = subtree(r, tree, string.format("Event seq=%d", (first_seq + i - 1) % 0x10000))
+        r:varint(entry, hcp_fields.control_id)
+        dissect_control_value(r, entry)
+    end
+    return first_seq, count
+end
+
+KIND_DISSECTORS.ControlEventBatch = function(r, tree)
+    local first_seq, count = dissect_control_event_batch(r, tree)
+
+    return string.format("ControlEventBatch seq=%d count=%d", first_seq, count)
+end
+
+KIND_DISSECTORS.RequestControlState = function(r, tree)
+    return "RequestControlState"
+end
+
+KIND_DISSECTORS.ControlStateSnapshot = function(r, tree)
+    local next_seq = r:varint(tree, hcp_fields.seq)
+    r:varint(tree, hcp_fields.first_switch_id)
+    local switch_count = r:varint(tree, hcp_fields.switch_count)
+    r:bytes(tree, hcp_fields.switches)
+
+    local value_count = r:varint(tree, hcp_fields.value_count)
+    for _ = 1, value_count do
+        local value = subtree(r, tree, "Value")
+        r:varint(value, hcp_fields.control_id)
+        r:zigzag(value, hcp_fields.absolute)
+    end
+
+    return string.format(
+        "ControlStateSnapshot seq=%d switches=%d values=%d",
+        next_seq,
+        switch_count,
+        value_count
+    )
+end
+
+KIND_DISSECTORS.RequestDescriptor = function(r, tree)
+    local page = r:varint(tree, hcp_fields.page)
+    return string.format("RequestDescriptor page=%d", page)
+end
+
+KIND_DISSECTORS.DescriptorPage = function(r, tree)
+    local page = r:varint(tree, hcp_fields.page)
+    local total_pages = r:varint(tree, hcp_fields.total_pages)
+    local count = r:varint(tree, hcp_fields.entry_count)
+
+    for _ = 1, count do
+        local entry_tree = subtree(r, tree, "Descriptor")
+        local _, entry = r:enum(entry_tree, hcp_fields.descriptor_entry, DESCRIPTOR_ENTRIES)
+        if entry == "Control" then
+            local control_id = r:varint(entry_tree, hcp_fields.control_id)
+            local _, kind = r:enum(entry_tree, hcp_fields.control_kind, CONTROL_KINDS)
+            r:u8(entry_tree, hcp_fields.supported_events)
+            local label = r:string(entry_tree, hcp_fields.label)
+            entry_tree:append_text(string.format(": control 0x%04X %s %s", control_id, tostring(kind), label))
+        elseif entry == "Display" then
+            local target = dissect_display_target(r, entry_tree)
+            local _, kind = r:enum(entry_tree, hcp_fields.display_kind, DISPLAY_KINDS)
+            if kind == "Segments" then
+                r:enum(entry_tree, hcp_fields.segment_kind, SEGMENT_KINDS)
+            elseif kind == nil then
+                error("unknown display kind", 0)
+            end
+            local width = r:varint(entry_tree, hcp_fields.width)
+            local height = r:varint(entry_tree, hcp_fields.height)
+            entry_tree:append_text(string.format(": %s %s %dx%d", target, kind, width, height))
+        else
+            error("unknown descriptor entry", 0)
+        end
+    end
+
+    return string.format("DescriptorPage %d/%d (%d entries)", page + 1, total_pages, count)
+end
+
+local function dissect_config_value(r, tree)
+    local _, value_type = r:enum(tree, hcp_fields.config_type, CONFIG_VALUES)
+    if value_type == "U8" then
+        return tostring(r:u8(tree, hcp_fields.config_u8))
+    elseif value_type == "U16" then
+        return tostring(r:varint(tree, hcp_fields.config_uint))
+    end
+    error("unknown config value", 0)
+end
+
+KIND_DISSECTORS.GetConfig = function(r, tree)
+    local _, key = r:enum(tree, hcp_fields.config_key, CONFIG_KEYS)
+    return string.format("GetConfig %s", tostring(key))
+end
+
+KIND_DISSECTORS.SetConfig = function(r, tree)
+    local _, key = r:enum(tree, hcp_fields.config_key, CONFIG_KEYS)
+    local value = dissect_config_value(r, tree)
+    return string.format("SetConfig %s=%s", tostring(key), value)
+end
+
+KIND_DISSECTORS.ConfigResponse = function(r, tree)
+    local _, key = r:enum(tree, hcp_fields.config_key, CONFIG_KEYS)
+    local _, status = r:enum(tree, hcp_fields.config_status, CONFIG_STATUSES)
+    if status == "Value" or status == "StorageFailed" then
+        local value = dissect_config_value(r, tree)
+        return string.format("ConfigResponse %s %s=%s", tostring(key), status, value)
+    elseif status == nil then
+        error("unknown config status", 0)
+    end
+    return string.format("ConfigResponse %s %s", tostring(key), status)
+end
+
+KIND_DISSECTORS.HostHello = function(r, tree)
+    local min = r:u8(tree, hcp_fields.min_protocol_version)
+    local max = r:u8(tree, hcp_fields.protocol_version)
+    return string.format("HostHello v%d-v%d", min, max)
+end
+
+KIND_DISSECTORS.FirmwareUpdate = function(r, tree)
+    local _, command = r:enum(tree, hcp_fields.update_command, UPDATE_COMMANDS)
+    if command == "Begin" then
+        local image_size = r:varint(tree, hcp_fields.image_size)
+        tree:add(hcp_fields.sha256, r:take(32))
+        return string.format("FirmwareUpdate Begin size=%d", image_size)
+    elseif command == "Chunk" then
+        local offset = r:varint(tree, hcp_fields.update_offset)
+        local data = r:bytes(tree, hcp_fields.data)
+        local len = data and data:len() or 0
+        return string.format("FirmwareUpdate Chunk offset=%d len=%d", offset, len)
+    elseif command == nil then
+        error("unknown update command", 0)
+    end
+    return string.format("FirmwareUpdate %s", command)
+end
+
+KIND_DISSECTORS.FirmwareUpdateStatus = function(r, tree)
+    local _, phase = r:enum(tree, hcp_fields.update_phase, UPDATE_PHASES)
+    local received = r:varint(tree, hcp_fields.update_received)
+    local summary = string.format("FirmwareUpdateStatus %s received=%d", tostring(phase), received)
+    -- Option のタグ
+    if r:take(1):uint() == 1 then
+        local _, error_name = r:enum(tree, hcp_fields.update_error, UPDATE_ERRORS)
+        summary = summary .. " error=" .. tostring(error_name)
+    end
+    return summary
+end
+
+KIND_DISSECTORS.Log = function(r, tree)
+    local _, level = r:enum(tree, hcp_fields.log_level, LOG_LEVELS)
+    local dropped = r:varint(tree, hcp_fields.log_dropped)
+    local tag = r:string(tree, hcp_fields.log_tag)
+    local text = r:string(tree, hcp_fields.log_text)
+    local summary = string.format("Log %s [%s] %s", tostring(level), tag, text)
+    if dropped > 0 then
+        summary = summary .. string.format(" (%d dropped)", dropped)
+    end
+    return summary
+end
+
+KIND_DISSECTORS.TimeSync = function(r, tree)
+    local host_time = r:varint64(tree, hcp_fields.host_time_us)
+    return string.format("TimeSync host=%sus", tostring(host_time))
+end
+
+KIND_DISSECTORS.TimestampedControlEvents = function(r, tree)
+    local timestamp = r:varint64(tree, hcp_fields.timestamp_us)
+    local first_seq, count = dissect_control_event_batch(r, tree)
+    return string.format("TimestampedControlEvents at=%sus seq=%d count=%d", tostring(timestamp), first_seq, count)
+end
+
+KIND_DISSECTORS.Identify = function(r, tree)
+    local seconds = r:u8(tree, hcp_fields.identify_seconds)
+    if seconds == 0 then
+        return "Identify stop"
+    end
+    return string.format("Identify %ds", seconds)
+end
+
+local function dissect_axis_calibration(r, tree)
+    local calibration_tree = subtree(r, tree, "Calibration")
+    local min = r:varint(calibration_tree, hcp_fields.calibration_min)
+    local center = r:varint(calibration_tree, hcp_fields.calibration_center)
+    local max = r:varint(calibration_tree, hcp_fields.calibration_max)
+    local deadzone = r:varint(calibration_tree, hcp_fields.calibration_deadzone)
+    local summary = string.format("%d..%d..%d dz=%d", min, center, max, deadzone)
+    calibration_tree:append_text(": " .. summary)
+    return summary
+end
+
+KIND_DISSECTORS.CalibrateAxis = function(r, tree)
+    local control_id = r:varint(tree, hcp_fields.control_id)
+    local _, command = r:enum(tree, hcp_fields.calibration_command, CALIBRATION_COMMANDS)
+    if command == "Set" then
+        local calibration = dissect_axis_calibration(r, tree)
+        return string.format("CalibrateAxis 0x%04X Set %s", control_id, calibration)
+    elseif command == nil then
+        error("unknown calibration command", 0)
+    end
+    return string.format("CalibrateAxis 0x%04X %s", control_id, command)
+end
+
+KIND_DISSECTORS.AxisCalibrationStatus = function(r, tree)
+    local control_id = r:varint(tree, hcp_fields.control_id)
+    local raw = r:varint(tree, hcp_fields.calibration_raw)
+    local recording = r:bool(tree, hcp_fields.calibration_recording)
+    local calibration = dissect_axis_calibration(r, tree)
+    local summary = string.format("AxisCalibrationStatus 0x%04X raw=%d %s", control_id, raw, calibration)
+    if recording then
+        summary = summary .. " recording"
+    end
+    -- Option のタグ
+    if r:take(1):uint() == 1 then
+        local _, error_name = r:enum(tree, hcp_fields.calibration_error, CALIBRATION_ERRORS)
+        summary = summary .. " error=" .. tostring(error_name)
+    end
+    return summary
+end
+
+local function dissect_hcp(tvb, pinfo, tree)
+    local hcp_tree = tree:add(hcp, tvb())
+    local r = Reader.new(tvb, 0)
+
+    local ok, summary = pcall(function()
+        local version = r:u8(hcp_tree, hcp_fields.version)
+        local index, kind = r:enum(hcp_tree, hcp_fields.kind, APP_PACKET_KINDS)
+        local dissect_kind = kind and KIND_DISSECTORS[kind]
+        if dissect_kind == nil then
+            hcp_tree:add_proto_expert_info(hcp_experts.unknown_kind)
+            return string.format("kind %d", index)
+        end
+        return dissect_kind(r, hcp_tree, version)
+    end)
+
+    if not ok then
+        hcp_tree:add_proto_expert_info(hcp_experts.malformed, "Malformed HCP packet: " .. summary)
+        summary = "malformed"
+    end
+    if r:remaining() > 0 then
+        hcp_tree:add(hcp_fields.unparsed, tvb(r.offset, r:remaining()))
+    end
+
+    hcp_tree:append_text(", " .. summary)
+    return summary
+end
+
+hcp.dissector = function(tvb, pinfo, tree)
+    pinfo.cols.protocol = "HCP"
+    pinfo.cols.info = dissect_hcp(tvb, pinfo, tree)
+    return tvb:len()
+end
+
+---------------------------------------------------------------------------
+-- IMCP frame decoding
+---------------------------------------------------------------------------
+
+-- Lua 5.1 から 5.4 まで同じように動かすため、ビット演算は算術で行う
+local function bxor(a, b)
+    local result = 0
+    local bit = 1
+    while a > 0 or b > 0 do
+        if a % 2 ~= b % 2 then
+            result = result + bit
+        end
+        a = math.floor(a / 2)
+        b = math.floor(b / 2)
+        bit = bit * 2
+    end
+    return result
+end
+
+local function address_name(address)
+    return ADDRESSES[address] or string.format("0x%02X", address)
+end
+
+-- SOF の位置から EOF までを探す。EOF が見つからなければ nil
+local function find_eof(tvb, sof_offset)
+    for offset = sof_offset + 1, tvb:len() - 1 do
+        if tvb(offset, 1):uint() == EOF then
+            return offset
+        end
+    end
+    return nil
+end
+
+-- SOF と EOF の間のスタッフィングを解除する。不正なエスケープがあれば ok = false
+local function unstuff(tvb, first, last)
+    local hex = {}
+    local escaping = false
+    for offset = first, last do
+        local byte = tvb(offset, 1):uint()
+        if escaping then
+            table.insert(hex, string.format("%02x", bxor(byte, ESC_XOR)))
+            escaping = false
+        elseif byte == ESC then
+            escaping = true
+        else
+            table.insert(hex, string.format("%02x", byte))
+        end
+    end
+    return ByteArray.new(table.concat(hex)), not escaping
+end
+
+-- sof_offset から eof_offset までの 1 フレームをデコードして要約を返す
+local function dissect_frame(tvb, pinfo, tree, sof_offset, eof_offset)
+    local stuffed = tvb(sof_offset, eof_offset - sof_offset + 1)
+    local frame_tree = tree:add(imcp, stuffed)
+    frame_tree:add(imcp_fields.stuffed, stuffed)
+
+    local bytes, escape_ok = unstuff(tvb, sof_offset + 1, eof_offset - 1)
+    if not escape_ok then
+        frame_tree:add_proto_expert_info(imcp_experts.bad_escape)
+    end
+    if bytes:len() < HEADER_LEN + CHECKSUM_LEN then
+        frame_tree:add_proto_expert_info(imcp_experts.bad_length)
+        return "malformed IMCP frame"
+    end
+
+    local frame = bytes:tvb("Unstuffed IMCP frame")
+    local to = frame(0, 1):uint()
+    local from = frame(1, 1):uint()
+    local frame_type = frame(2, 1):uint()
+    local payload_len = frame(3, 2):le_uint()
+
+    frame_tree:add(imcp_fields.to, frame(0, 1))
+    frame_tree:add(imcp_fields.from, frame(1, 1))
+    local type_item = frame_tree:add(imcp_fields.frame_type, frame(2, 1))
+    local len_item = frame_tree:add_le(imcp_fields.len, frame(3, 2))
+
+    local type_name = FRAME_TYPES[frame_type]
+    if type_name == nil then
+        type_item:add_proto_expert_info(imcp_experts.unknown_type)
+        type_name = string.format("type %d", frame_type)
+    end
+    local summary = string.format("%s %s -> %s", type_name, address_name(from), address_name(to))
+
+    if frame:len() ~= HEADER_LEN + payload_len + CHECKSUM_LEN then
+        len_item:add_proto_expert_info(imcp_experts.bad_length)
+        return summary
+    end
+
+    local checksum = 0
+    for offset = 0, HEADER_LEN + payload_len - 1 do
+        local byte = frame(offset, 1):uint()
+        checksum = bxor(checksum, byte)
+    end
+    local checksum_range = frame(HEADER_LEN + payload_len, CHECKSUM_LEN)
+    local checksum_item = frame_tree:add(imcp_fields.checksum, checksum_range)
+    if checksum_range:uint() ~= checksum then
+        checksum_item:add_proto_expert_info(
+            imcp_experts.bad_checksum, string.format("Bad checksum (expected 0x%02X)", checksum))
+    end
+
+    if payload_len == 0 then
+        return summary
+    end
+
+    local payload = frame(HEADER_LEN, payload_len)
+    frame_tree:add(imcp_fields.payload, payload)
+
+    if type_name == "Ack" and payload_len == 1 then
+        frame_tree:add(imcp_fields.ack, payload)
+        summary = summary .. " ack=" .. address_name(payload:uint())
+    elseif type_name == "Join" and (payload_len == 4 or payload_len == 12) then
+        frame_tree:add_le(imcp_fields.join_id, frame(HEADER_LEN, 4))
+        summary = summary .. string.format(" id=0x%08X", frame(HEADER_LEN, 4):le_uint())
+        if payload_len == 12 then
+            local device_id = frame(HEADER_LEN + 4, 8)
+            frame_tree:add_le(imcp_fields.device_id, device_id)
+            summary = summary .. " device=0x" .. device_id:le_uint64():tohex()
+        end
+    elseif type_name == "SetAddress" and payload_len == 5 then
+        frame_tree:add(imcp_fields.address, frame(HEADER_LEN, 1))
+        frame_tree:add_le(imcp_fields.join_id, frame(HEADER_LEN + 1, 4))
+        summary = summary .. string.format(
+            " address=0x%02X id=0x%08X", frame(HEADER_LEN, 1):uint(), frame(HEADER_LEN + 1, 4):le_uint())
+    elseif type_name == "Data" or type_name == "Set" then
+        summary = summary .. ": " .. dissect_hcp(payload:tvb(), pinfo, frame_tree)
+    end
+
+    frame_tree:append_text(", " .. summary)
+    return summary
+end
+
+imcp.dissector = function(tvb, pinfo, tree)
+    pinfo.cols.protocol = "IMCP"
+    if tvb:len() == 0 or tvb(0, 1):uint() ~= SOF then
+        return 0
+    end
+
+    local eof_offset = find_eof(tvb, 0)
+    if eof_offset == nil then
+        tree:add(imcp_fields.fragment, tvb()):add_proto_expert_info(imcp_experts.fragment)
+        pinfo.cols.info = "incomplete IMCP frame"
+        return tvb:len()
+    end
+
+    pinfo.cols.info = dissect_frame(tvb, pinfo, tree, 0, eof_offset)
+    return tvb:len()
+end
+
+-- 生バイト列はレコード単位で独立にデコードする (レコードをまたぐフレームは再構成しない)
+imcp_stream.dissector = function(tvb, pinfo, tree)
+    pinfo.cols.protocol = "IMCP"
+    local summaries = {}
+    local offset = 0
+    local len = tvb:len()
+
+    while offset < len do
+        local sof_offset = nil
+        for scan = offset, len - 1 do
+            if tvb(scan, 1):uint() == SOF then
+                sof_offset = scan
+                break
+            end
+        end
+
+        if sof_offset == nil or sof_offset > offset then
+            local fragment_end = sof_offset or len
+            tree:add(imcp_fields.fragment, tvb(offset, fragment_end - offset))
+                :add_proto_expert_info(imcp_experts.fragment)
+            if sof_offset == nil then
+                break
+            end
+        end
+
+        local eof_offset = find_eof(tvb, sof_offset)
+        if eof_offset == nil then
+            tree:add(imcp_fields.fragment, tvb(sof_offset, len - sof_offset))
+                :add_proto_expert_info(imcp_experts.fragment)
+            break
+        end
+
+        table.insert(summaries, dissect_frame(tvb, pinfo, tree, sof_offset, eof_offset))
+        offset = eof_offset + 1
+    end
+
+    if #summaries == 0 then
+        pinfo.cols.info = "IMCP fragment"
+    else
+        pinfo.cols.info = table.concat(summaries, "; ")
+    end
+    return len
+end
+
+---------------------------------------------------------------------------
+-- Registration
+---------------------------------------------------------------------------
+
+local encaps = wtap_encaps or wtap
+local wtap_encap = DissectorTable.get("wtap_encap")
+wtap_encap:add(encaps.USER0, imcp_stream)
+wtap_encap:add(encaps.USER1, imcp)
@@ -1,3 +1,7 @@
 fn main() {
+    // 読めないパネル定義ファイルは実行時に気付けないので build を失敗させる
+    if let Err(error) = homecockpit_panel_definition::panel_definitions() {
+        panic!("invalid panel definition: {error}");
+    }
     tauri_build::build()
 }
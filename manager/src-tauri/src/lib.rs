@@ -6,7 +6,7 @@ use std::{
     path::PathBuf,
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
-        Arc, Mutex,
+        mpsc, Arc, Mutex, OnceLock,
     },
     thread::{self, JoinHandle},
     time::{Duration, Instant},
@@ -20,12 +20,20 @@ use dcs_bios::{
     DcsBios, DcsBiosImpl,
 };
 use hcp::{
-    decode_set_packet, encode_set_packet, AppPacketKind, ControlEvent, ControlValue, DeviceKind,
-    CONTROL_ID_REQUEST_DEVICE_HELLO,
+    decode_set_packet, encode_data_packet_with_version, encode_set_packet_with_version,
+    encode_time_sync_packet, AppPacketKind, AxisCalibration, AxisCalibrationStatus,
+    CalibrationCommand, CalibrationError, ControlEvent, ControlStateSnapshot, ControlValue,
+    DescriptorAssembler, DescriptorEntry, DescriptorPage, DeviceKind, DisplayData, DisplayPayload,
+    DisplayTarget, LogEntry, LogLevel, ProtocolVersions, PwmLevel, SupportedEvents, TimeSync,
+    CONTROL_ID_REQUEST_DEVICE_HELLO, FEATURE_AXIS_CALIBRATION, FEATURE_CONTROL_EVENTS,
+    FEATURE_CONTROL_STATE, FEATURE_DESCRIPTORS, FEATURE_IDENTIFY, FEATURE_PWM_OUTPUTS,
+    MIN_APP_PROTOCOL_VERSION,
 };
+use homecockpit_panel_definition::PanelDefinition;
 use imcp::{
     frame::{Address, Frame, FramePayload, MAX_ENCODED_FRAME_SIZE},
     parser::FrameParser,
+    sticky::{StickyAddress, StickyAddressTable},
 };
 use serde::{Deserialize, Serialize};
 use socket2::{Domain, Protocol, Socket, Type};
@@ -42,9 +50,24 @@ const IMCP_MASTER_ADDRESS: u8 = 0x01;
 const IMCP_ROOT_PROBE_TIMEOUT: Duration = Duration::from_millis(900);
 const IMCP_CHILD_ENUMERATION_TIMEOUT: Duration = Duration::from_millis(600);
 const IMCP_READ_TIMEOUT: Duration = Duration::from_millis(50);
+/// デバイスの時計のずれが積もらないよう、`TimeSync` を送り直す間隔
+const TIME_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+/// DCS-BIOS の値の変化を PWM 出力に反映する間隔
+const PWM_OUTPUT_INTERVAL: Duration = Duration::from_millis(50);
+/// `Data` frame は ACK がないので、取りこぼしても戻るよう変化がなくても送り直す間隔
+const PWM_OUTPUT_RESEND_INTERVAL: Duration = Duration::from_secs(2);
+/// 応答のない `RequestDescriptor` を送り直すまでの時間
+const DESCRIPTOR_PAGE_TIMEOUT: Duration = Duration::from_millis(500);
+/// `RequestDescriptor` を送り直す回数。超えたら descriptor の受信をやめる
+const MAX_DESCRIPTOR_PAGE_RETRIES: u8 = 3;
+/// listener がコマンドの送信結果を返すまでの待ち時間
+const LISTENER_COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+/// 別のパネルが先に検出した操作が後から届くのを待つ時間。時刻付きの操作はこの分だけ遅れて DCS-BIOS に送る
+const CONTROL_REORDER_WINDOW: Duration = Duration::from_millis(30);
 const SETTINGS_FILE_NAME: &str = "manager-state.json";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
 #[serde(rename_all = "lowercase")]
 enum CommandTransport {
     Udp,
@@ -52,6 +75,7 @@ enum CommandTransport {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
 #[serde(rename_all = "camelCase")]
 struct DcsBiosConnectionConfig {
     export_host: String,
@@ -74,12 +98,14 @@ impl Default for DcsBiosConnectionConfig {
 }
 
 #[derive(Debug, Clone, Serialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
 #[serde(rename_all = "camelCase")]
 struct DcsBiosStatus {
     connection_state: String,
     last_seen_at: Option<String>,
     last_packet_at: Option<String>,
     packets_per_second: u32,
+    #[cfg_attr(test, ts(type = "number"))]
     total_packets: u64,
     aircraft_name: Option<String>,
     error: Option<String>,
@@ -102,8 +128,10 @@ impl Default for DcsBiosStatus {
 }
 
 #[derive(Debug, Clone, Serialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
 #[serde(rename_all = "camelCase")]
 struct ManagerLogEntry {
+    #[cfg_attr(test, ts(type = "number"))]
     id: u64,
     at: String,
     level: String,
@@ -112,12 +140,14 @@ struct ManagerLogEntry {
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(ts_rs::TS))]
 #[serde(rename_all = "kebab-case")]
 enum DeviceEndpointTransport {
     Serial,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(ts_rs::TS))]
 #[serde(rename_all = "kebab-case")]
 enum EndpointRoleHint {
     Auto,
@@ -126,6 +156,7 @@ enum EndpointRoleHint {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(ts_rs::TS))]
 #[serde(rename_all = "camelCase")]
 struct DeviceEndpointConfig {
     id: String,
@@ -152,6 +183,7 @@ impl Default for DeviceEndpointConfig {
 }
 
 #[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(ts_rs::TS))]
 #[serde(rename_all = "camelCase")]
 struct ManagedDeviceSummary {
     id: String,
@@ -185,6 +217,7 @@ struct DcsBiosFrameEvent {
 }
 
 #[derive(Debug, Clone, Serialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
 #[serde(rename_all = "camelCase")]
 struct AppSnapshot {
     dcsbios_config: DcsBiosConnectionConfig,
@@ -194,17 +227,42 @@ struct AppSnapshot {
     device_endpoints: Vec<DeviceEndpointConfig>,
     device_role_assignments: Vec<DeviceRoleAssignment>,
     role_mappings: Vec<RoleMappingConfig>,
+    control_catalogs: Vec<DeviceControlCatalog>,
+}
+
+/// パネル定義ファイルから作る、デバイスの種類ごとの操作系の一覧
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[serde(rename_all = "camelCase")]
+struct DeviceControlCatalog {
+    device_kind_id: String,
+    controls: Vec<ControlCatalogEntry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[serde(rename_all = "camelCase")]
+struct ControlCatalogEntry {
+    control_id: u16,
+    label: String,
+    description: String,
+    supported_events: Vec<NormalizedControlEvent>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
 #[serde(rename_all = "camelCase")]
 struct DcsBiosCommandRequest {
+    #[cfg_attr(test, ts(optional = nullable))]
     raw_command: Option<String>,
+    #[cfg_attr(test, ts(optional = nullable))]
     control_id: Option<String>,
+    #[cfg_attr(test, ts(optional = nullable))]
     argument: Option<String>,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(test, derive(ts_rs::TS))]
 #[serde(rename_all = "kebab-case")]
 enum DeviceRole {
     LeftDdi,
@@ -212,6 +270,7 @@ enum DeviceRole {
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(test, derive(ts_rs::TS))]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 enum NormalizedControlEvent {
     ButtonDown,
@@ -224,6 +283,7 @@ enum NormalizedControlEvent {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(ts_rs::TS))]
 #[serde(rename_all = "camelCase")]
 struct DeviceRoleAssignment {
     device_id: String,
@@ -231,6 +291,7 @@ struct DeviceRoleAssignment {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(ts_rs::TS))]
 #[serde(rename_all = "camelCase")]
 struct DcsBiosMappedAction {
     identifier: String,
@@ -238,6 +299,7 @@ struct DcsBiosMappedAction {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(ts_rs::TS))]
 #[serde(rename_all = "camelCase")]
 struct RoleControlMapping {
     id: String,
@@ -246,11 +308,48 @@ struct RoleControlMapping {
     action: DcsBiosMappedAction,
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[serde(rename_all = "kebab-case")]
+enum PwmTargetKind {
+    /// `DisplayTarget::Backlight`
+    Backlight,
+    /// `DisplayTarget::Indicator`
+    Lamp,
+}
+
+/// DCS-BIOS の整数出力 (つまみの位置など) で PWM の明るさを決める
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[serde(rename_all = "camelCase")]
+struct RoleOutputMapping {
+    id: String,
+    target_kind: PwmTargetKind,
+    channel: u16,
+    address: u16,
+    mask: u16,
+    shift_by: u16,
+    max_value: u16,
+    fade_ms: u16,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(ts_rs::TS))]
 #[serde(rename_all = "camelCase")]
 struct RoleMappingConfig {
     role: DeviceRole,
     mappings: Vec<RoleControlMapping>,
+    #[serde(default)]
+    outputs: Vec<RoleOutputMapping>,
+}
+
+/// IMCP アドレスの割り当て履歴 (エンドポイントごと)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct StickyAddressConfig {
+    endpoint_id: String,
+    device_id: u64,
+    address: u8,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -259,17 +358,124 @@ struct PersistedManagerState {
     device_endpoints: Vec<DeviceEndpointConfig>,
     device_role_assignments: Vec<DeviceRoleAssignment>,
     role_mappings: Vec<RoleMappingConfig>,
+    #[serde(default)]
+    sticky_addresses: Vec<StickyAddressConfig>,
+}
+
+/// アナログ軸のキャリブレーションのウィザードの操作。`CalibrateAxis` のコマンドに対応する
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[serde(tag = "step", rename_all = "kebab-case")]
+enum AxisCalibrationStep {
+    Begin,
+    CaptureCenter,
+    Commit,
+    Cancel,
+    Get,
+    Set {
+        min: u16,
+        center: u16,
+        max: u16,
+        deadzone: u16,
+    },
+}
+
+/// デバイスから届いた `AxisCalibrationStatus`。`axis-calibration-changed` で UI に送る
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[serde(rename_all = "camelCase")]
+struct AxisCalibrationUpdate {
+    device_id: String,
+    control_id: u16,
+    raw: u16,
+    recording: bool,
+    min: u16,
+    center: u16,
+    max: u16,
+    deadzone: u16,
+    error: Option<String>,
 }
 
 struct ListenerHandle {
+    endpoint_id: String,
     stop: Arc<AtomicBool>,
     join: Option<JoinHandle<()>>,
+    commands: mpsc::Sender<ListenerCommand>,
+}
+
+/// listener のスレッドが持つシリアルポートを通して送るコマンド
+enum ListenerCommand {
+    Identify {
+        device_id: String,
+        seconds: u8,
+        reply: mpsc::Sender<Result<(), String>>,
+    },
+    CalibrateAxis {
+        device_id: String,
+        control_id: u16,
+        command: CalibrationCommand,
+        reply: mpsc::Sender<Result<(), String>>,
+    },
 }
 
 #[derive(Debug, Clone)]
 struct KnownRuntimeDevice {
     device_id: String,
     device_kind: DeviceKind,
+    /// デバイスと交渉した HCP のバージョン。このデバイスへの送信に使う
+    protocol_version: u8,
+    capability_flags: u32,
+    /// 次に送る `DisplayData` の seq
+    display_seq: u16,
+    /// 出力ごとに最後に送った明るさ
+    sent_outputs: HashMap<String, u8>,
+    /// 受信中の descriptor。要求していない場合は `None`
+    descriptor: Option<DescriptorAssembler>,
+    /// 応答を待っている `RequestDescriptor`
+    descriptor_request: Option<DescriptorRequest>,
+    pending_controls: HashMap<u16, SupportedEvents>,
+    /// descriptor から得た control の一覧。受信が終わるまでは `None`
+    controls: Option<HashMap<u16, SupportedEvents>>,
+    /// 最後に受け取った操作系の状態
+    control_state: Option<ControlStateSnapshot>,
+}
+
+/// 応答を待っている `RequestDescriptor`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DescriptorRequest {
+    page: u16,
+    sent_at: Instant,
+    retries: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DescriptorRetry {
+    Wait,
+    Resend(u16),
+    GiveUp,
+}
+
+impl DescriptorRequest {
+    fn new(page: u16, sent_at: Instant) -> Self {
+        Self {
+            page,
+            sent_at,
+            retries: 0,
+        }
+    }
+
+    /// 応答がないまま `DESCRIPTOR_PAGE_TIMEOUT` が過ぎていれば、同じページを送り直す
+    fn retry(&mut self, now: Instant) -> DescriptorRetry {
+        if now.saturating_duration_since(self.sent_at) < DESCRIPTOR_PAGE_TIMEOUT {
+            return DescriptorRetry::Wait;
+        }
+        if self.retries >= MAX_DESCRIPTOR_PAGE_RETRIES {
+            return DescriptorRetry::GiveUp;
+        }
+        self.retries += 1;
+        self.sent_at = now;
+        DescriptorRetry::Resend(self.page)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -327,6 +533,7 @@ struct RuntimeState {
     device_endpoints: Mutex<Vec<DeviceEndpointConfig>>,
     device_role_assignments: Mutex<Vec<DeviceRoleAssignment>>,
     role_mappings: Mutex<Vec<RoleMappingConfig>>,
+    sticky_addresses: Mutex<Vec<StickyAddressConfig>>,
     log_counter: AtomicU64,
     listener: Mutex<Option<ListenerHandle>>,
     endpoint_listeners: Mutex<Vec<ListenerHandle>>,
@@ -343,6 +550,7 @@ impl RuntimeState {
             device_endpoints: Mutex::new(Vec::new()),
             device_role_assignments: Mutex::new(Vec::new()),
             role_mappings: Mutex::new(Vec::new()),
+            sticky_addresses: Mutex::new(Vec::new()),
             log_counter: AtomicU64::new(0),
             listener: Mutex::new(None),
             endpoint_listeners: Mutex::new(Vec::new()),
@@ -359,6 +567,7 @@ impl RuntimeState {
             device_endpoints: self.device_endpoints.lock().unwrap().clone(),
             device_role_assignments: self.device_role_assignments.lock().unwrap().clone(),
             role_mappings: self.role_mappings.lock().unwrap().clone(),
+            control_catalogs: control_catalogs(),
         }
     }
 
@@ -451,6 +660,49 @@ impl RuntimeState {
         role_mappings
     }
 
+    fn sticky_address_table(&self, endpoint_id: &str) -> StickyAddressTable {
+        let entries = self
+            .sticky_addresses
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry.endpoint_id == endpoint_id)
+            .map(|entry| StickyAddress {
+                device_id: entry.device_id,
+                address: entry.address,
+            })
+            .collect::<Vec<_>>();
+        StickyAddressTable::from_entries(&entries)
+    }
+
+    fn save_sticky_addresses(
+        &self,
+        app: &AppHandle,
+        endpoint_id: &str,
+        table: &StickyAddressTable,
+    ) -> Result<(), String> {
+        let sticky_addresses = {
+            let mut sticky_addresses = self.sticky_addresses.lock().unwrap();
+            sticky_addresses.retain(|entry| entry.endpoint_id != endpoint_id);
+            sticky_addresses.extend(table.entries().iter().map(|entry| StickyAddressConfig {
+                endpoint_id: endpoint_id.to_string(),
+                device_id: entry.device_id,
+                address: entry.address,
+            }));
+            sticky_addresses.clone()
+        };
+
+        persist_manager_state(
+            app,
+            &PersistedManagerState {
+                device_endpoints: self.device_endpoints.lock().unwrap().clone(),
+                device_role_assignments: self.device_role_assignments.lock().unwrap().clone(),
+                role_mappings: self.role_mappings.lock().unwrap().clone(),
+                sticky_addresses,
+            },
+        )
+    }
+
     fn stop_listener(&self, app: &AppHandle) {
         let handle = self.listener.lock().unwrap().take();
         if let Some(mut handle) = handle {
@@ -501,6 +753,8 @@ impl RuntimeState {
 
         let mut listeners = Vec::new();
         for endpoint in endpoints.into_iter().filter(|entry| entry.enabled) {
+            let endpoint_id = endpoint.id.clone();
+            let (commands, commands_for_thread) = mpsc::channel();
             let stop = Arc::new(AtomicBool::new(false));
             let stop_for_thread = stop.clone();
             let app_for_thread = app.clone();
@@ -517,14 +771,17 @@ impl RuntimeState {
                     assignments,
                     role_mappings,
                     stop_for_thread,
+                    commands_for_thread,
                 ) {
                     state.push_log(&app_for_thread, "ERROR", "devices", error);
                 }
             });
 
             listeners.push(ListenerHandle {
+                endpoint_id,
                 stop,
                 join: Some(join),
+                commands,
             });
         }
 
@@ -536,6 +793,23 @@ impl RuntimeState {
         self.start_endpoint_listeners(app.clone())
     }
 
+    /// エンドポイントの listener にコマンドを渡す。listener が動いていなければエラー
+    fn send_listener_command(
+        &self,
+        endpoint_id: &str,
+        command: ListenerCommand,
+    ) -> Result<(), String> {
+        let listeners = self.endpoint_listeners.lock().unwrap();
+        let listener = listeners
+            .iter()
+            .find(|listener| listener.endpoint_id == endpoint_id)
+            .ok_or_else(|| format!("Endpoint {endpoint_id} is not listening."))?;
+        listener
+            .commands
+            .send(command)
+            .map_err(|_| format!("Endpoint listener {endpoint_id} has stopped."))
+    }
+
     fn start_listener(self: &Arc<Self>, app: AppHandle) -> Result<(), String> {
         self.stop_listener(&app);
 
@@ -783,6 +1057,7 @@ fn save_device_endpoints(
             device_endpoints: device_endpoints.clone(),
             device_role_assignments: state.inner.device_role_assignments.lock().unwrap().clone(),
             role_mappings: state.inner.role_mappings.lock().unwrap().clone(),
+            sticky_addresses: state.inner.sticky_addresses.lock().unwrap().clone(),
         },
     )?;
     state.inner.stop_endpoint_listeners(&app);
@@ -852,6 +1127,7 @@ fn save_device_role_assignments(
             device_endpoints: state.inner.device_endpoints.lock().unwrap().clone(),
             device_role_assignments: device_role_assignments.clone(),
             role_mappings: state.inner.role_mappings.lock().unwrap().clone(),
+            sticky_addresses: state.inner.sticky_addresses.lock().unwrap().clone(),
         },
     )?;
     state
@@ -880,6 +1156,7 @@ fn save_role_mappings(
             device_endpoints: state.inner.device_endpoints.lock().unwrap().clone(),
             device_role_assignments: state.inner.device_role_assignments.lock().unwrap().clone(),
             role_mappings: role_mappings.clone(),
+            sticky_addresses: state.inner.sticky_addresses.lock().unwrap().clone(),
         },
     )?;
     state.inner.set_role_mappings(&app, role_mappings);
@@ -898,6 +1175,85 @@ async fn list_devices(
     refresh_devices(app, state.inner.clone()).await
 }
 
+/// 同じ種類のボードを見分けられるよう、デバイスの LED や表示を `seconds` 秒間点滅させる
+///
+/// hub の先のデバイスにも、hub のエンドポイントの listener から送る。0 秒で止める
+#[tauri::command]
+async fn identify_device(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    endpoint_id: String,
+    device_id: String,
+    seconds: u8,
+) -> Result<(), String> {
+    let (reply, replied) = mpsc::channel();
+    state.inner.send_listener_command(
+        &endpoint_id,
+        ListenerCommand::Identify {
+            device_id: device_id.clone(),
+            seconds,
+            reply,
+        },
+    )?;
+    tauri::async_runtime::spawn_blocking(move || replied.recv_timeout(LISTENER_COMMAND_TIMEOUT))
+        .await
+        .map_err(|error| format!("Failed to join identify task: {error}"))?
+        .map_err(|_| format!("Endpoint {endpoint_id} did not answer the identify request."))??;
+
+    state.inner.push_log(
+        &app,
+        "INFO",
+        "devices",
+        if seconds == 0 {
+            format!("Stopped identifying device {device_id}.")
+        } else {
+            format!("Identifying device {device_id} for {seconds}s.")
+        },
+    );
+    Ok(())
+}
+
+/// アナログ軸のキャリブレーションを指示する
+///
+/// 記録した範囲や結果はデバイスから `axis-calibration-changed` で届く
+#[tauri::command]
+async fn calibrate_axis(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    endpoint_id: String,
+    device_id: String,
+    control_id: u16,
+    step: AxisCalibrationStep,
+) -> Result<(), String> {
+    let (reply, replied) = mpsc::channel();
+    state.inner.send_listener_command(
+        &endpoint_id,
+        ListenerCommand::CalibrateAxis {
+            device_id: device_id.clone(),
+            control_id,
+            command: calibration_command(step),
+            reply,
+        },
+    )?;
+    tauri::async_runtime::spawn_blocking(move || replied.recv_timeout(LISTENER_COMMAND_TIMEOUT))
+        .await
+        .map_err(|error| format!("Failed to join calibration task: {error}"))?
+        .map_err(|_| format!("Endpoint {endpoint_id} did not answer the calibration request."))??;
+
+    if matches!(
+        step,
+        AxisCalibrationStep::Commit | AxisCalibrationStep::Set { .. }
+    ) {
+        state.inner.push_log(
+            &app,
+            "INFO",
+            "devices",
+            format!("Sent calibration of axis 0x{control_id:04X} to device {device_id}."),
+        );
+    }
+    Ok(())
+}
+
 fn sanitize_device_endpoints(
     device_endpoints: Vec<DeviceEndpointConfig>,
 ) -> Vec<DeviceEndpointConfig> {
@@ -979,9 +1335,35 @@ fn sanitize_role_mappings(role_mappings: Vec<RoleMappingConfig>) -> Vec<RoleMapp
             })
             .collect();
 
+        let mut seen_outputs = HashSet::new();
+        let outputs = config
+            .outputs
+            .into_iter()
+            .filter(|output| {
+                let channel_fits = match output.target_kind {
+                    PwmTargetKind::Backlight => u8::try_from(output.channel).is_ok(),
+                    PwmTargetKind::Lamp => true,
+                };
+                // DCS-BIOS の整数は 2 byte 読むので、最後のアドレスは使えない
+                channel_fits
+                    && output.mask != 0
+                    && output.address != u16::MAX
+                    && seen_outputs.insert((output.target_kind, output.channel))
+            })
+            .map(|output| RoleOutputMapping {
+                id: if output.id.trim().is_empty() {
+                    Uuid::new_v4().to_string()
+                } else {
+                    output.id
+                },
+                ..output
+            })
+            .collect();
+
         sanitized.push(RoleMappingConfig {
             role: config.role,
             mappings,
+            outputs,
         });
     }
 
@@ -1085,10 +1467,12 @@ struct ProbedImcpDevice {
     assigned_address: Option<u8>,
     device_kind: DeviceKind,
     protocol_version: u8,
+    min_protocol_version: u8,
     device_id: String,
     displays: u8,
     controls: u16,
     features: String,
+    capability_flags: u32,
 }
 
 fn probe_endpoint_root_device(endpoint: &DeviceEndpointConfig) -> Result<EndpointProbe, String> {
@@ -1107,13 +1491,29 @@ fn probe_endpoint_root_device(endpoint: &DeviceEndpointConfig) -> Result<Endpoin
     let mut assigned_address: Option<u8> = None;
 
     while started_at.elapsed() < IMCP_ROOT_PROBE_TIMEOUT {
+        let now_us = host_clock_us();
+        for control in reorder_buffer.drain_ready(now_us) {
+            process_control_event(
+                &state,
+                &app,
+                &config,
+                &known_devices,
+                &mut pressed_buttons,
+                &device_role_assignments,
+                &role_mappings,
+                control.source_address,
+                &control.event,
+                Some(control_latency(control.timestamp_us, now_us)),
+            );
+        }
+
         match port.read(&mut serial_buffer) {
             Ok(bytes_read) if bytes_read > 0 => {
                 parser
                     .write_data(&serial_buffer[..bytes_read])
                     .map_err(|error| {
                         format!(
-                            "Failed to parse IMCP frame on {}: {error:?}",
+                            "Failed to parse IMCP frame on {}: {error}",
                             endpoint.address
                         )
                     })?;
@@ -1125,7 +1525,7 @@ fn probe_endpoint_root_device(endpoint: &DeviceEndpointConfig) -> Result<Endpoin
                     };
 
                     match frame.payload() {
-                        FramePayload::Join(id) => {
+                        FramePayload::Join(id) | FramePayload::JoinWithDeviceId { id, .. } => {
                             let next_address = assigned_address.unwrap_or(0x02);
                             assigned_address = Some(next_address);
                             write_frame(
@@ -1176,12 +1576,16 @@ fn enumerate_children_via_hub(
     endpoint: &DeviceEndpointConfig,
     hub: &ProbedImcpDevice,
 ) -> Result<Vec<ProbedImcpDevice>, String> {
-    let request = encode_set_packet(&AppPacketKind::ControlEvent(ControlEvent {
-        seq: 0,
-        control_id: CONTROL_ID_REQUEST_DEVICE_HELLO,
-        event: ControlValue::RequestDeviceHello,
-    }))
-    .map_err(|error| format!("Failed to encode RequestDeviceHello: {error:?}"))?;
+    // hub のバージョンは分からないので、どのバージョンでも読める layout で送る
+    let request = encode_set_packet_with_version(
+        MIN_APP_PROTOCOL_VERSION,
+        &AppPacketKind::ControlEvent(ControlEvent {
+            seq: 0,
+            control_id: CONTROL_ID_REQUEST_DEVICE_HELLO,
+            event: ControlValue::RequestDeviceHello,
+        }),
+    )
+    .map_err(|error| format!("Failed to encode RequestDeviceHello: {error}"))?;
 
     write_frame(
         port,
@@ -1209,7 +1613,7 @@ fn enumerate_children_via_hub(
                     .write_data(&serial_buffer[..bytes_read])
                     .map_err(|error| {
                         format!(
-                            "Failed to parse IMCP frame on {}: {error:?}",
+                            "Failed to parse IMCP frame on {}: {error}",
                             endpoint.address
                         )
                     })?;
@@ -1344,6 +1748,7 @@ fn load_manager_state(app: &AppHandle) -> Result<PersistedManagerState, String>
                     persisted.device_role_assignments,
                 ),
                 role_mappings: sanitize_role_mappings(persisted.role_mappings),
+                sticky_addresses: persisted.sticky_addresses,
             })
         }
         Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
@@ -1366,7 +1771,7 @@ fn write_frame(port: &mut dyn serialport::SerialPort, frame: &Frame) -> Result<(
     let mut encoded = [0u8; MAX_ENCODED_FRAME_SIZE];
     let encoded_len = frame
         .encode(&mut encoded)
-        .map_err(|error| format!("Failed to encode IMCP frame: {error:?}"))?;
+        .map_err(|error| format!("Failed to encode IMCP frame: {error}"))?;
     port.write_all(&encoded[..encoded_len])
         .map_err(|error| format!("Failed to write IMCP frame: {error}"))?;
     port.flush()
@@ -1401,10 +1806,12 @@ fn decode_device_hello(
         assigned_address: Some(assigned_address),
         device_kind: hello.device_kind,
         protocol_version: hello.protocol_version,
+        min_protocol_version: hello.min_protocol_version,
         device_id: format!("{:016X}", hello.device_id),
         displays: hello.capabilities.displays,
         controls: hello.capabilities.controls,
         features: format_capability_flags(hello.capabilities.features),
+        capability_flags: hello.capabilities.features,
     }))
 }
 
@@ -1438,9 +1845,24 @@ fn format_capability_flags(features: u32) -> String {
     }
 
     let mut flags = Vec::new();
-    if features & (1 << 0) != 0 {
+    if features & FEATURE_CONTROL_EVENTS != 0 {
         flags.push("control-events");
     }
+    if features & FEATURE_DESCRIPTORS != 0 {
+        flags.push("descriptors");
+    }
+    if features & FEATURE_CONTROL_STATE != 0 {
+        flags.push("control-state");
+    }
+    if features & FEATURE_IDENTIFY != 0 {
+        flags.push("identify");
+    }
+    if features & FEATURE_PWM_OUTPUTS != 0 {
+        flags.push("pwm-outputs");
+    }
+    if features & FEATURE_AXIS_CALIBRATION != 0 {
+        flags.push("axis-calibration");
+    }
 
     if flags.is_empty() {
         format!("0x{features:08X}")
@@ -1541,131 +1963,623 @@ fn find_mapping_action(
 }
 
 fn control_supported_events(
-    device_kind: DeviceKind,
+    device: &KnownRuntimeDevice,
     control_id: u16,
-) -> Option<&'static [NormalizedControlEvent]> {
-    match device_kind {
-        DeviceKind::UpperPanelDdi if control_id < 40 => Some(&[
+) -> Option<Vec<NormalizedControlEvent>> {
+    if let Some(controls) = &device.controls {
+        return controls
+            .get(&control_id)
+            .map(|events| normalized_events(*events));
+    }
+
+    // descriptor に対応していない古いファームウェアはパネル定義ファイルの一覧を使う
+    panel_definition(device.device_kind)?
+        .controls
+        .iter()
+        .find(|control| control.id == control_id)
+        .map(|control| normalized_events(control.events()))
+}
+
+/// firmware の build script と同じパネル定義ファイル
+fn panel_definitions() -> &'static [PanelDefinition] {
+    static DEFINITIONS: OnceLock<Vec<PanelDefinition>> = OnceLock::new();
+    DEFINITIONS.get_or_init(|| {
+        // build.rs で読めることを確認している
+        homecockpit_panel_definition::panel_definitions().expect("panel definitions")
+    })
+}
+
+fn panel_definition(device_kind: DeviceKind) -> Option<&'static PanelDefinition> {
+    panel_definitions()
+        .iter()
+        .find(|definition| definition.device_kind == device_kind)
+}
+
+fn control_catalogs() -> Vec<DeviceControlCatalog> {
+    panel_definitions()
+        .iter()
+        .map(|definition| DeviceControlCatalog {
+            device_kind_id: format_device_kind_id(definition.device_kind).to_string(),
+            controls: definition
+                .controls
+                .iter()
+                .map(|control| ControlCatalogEntry {
+                    control_id: control.id,
+                    label: control.label.clone(),
+                    description: match control.matrix_position() {
+                        Some((row, column)) => {
+                            format!("Matrix row {}, column {}", row + 1, column + 1)
+                        }
+                        None => format!("{:?}", control.kind),
+                    },
+                    supported_events: normalized_events(control.events()),
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+fn normalized_events(events: SupportedEvents) -> Vec<NormalizedControlEvent> {
+    let mut normalized = Vec::new();
+    if events.contains(SupportedEvents::BUTTON) {
+        normalized.extend([
             NormalizedControlEvent::ButtonDown,
             NormalizedControlEvent::ButtonUp,
             NormalizedControlEvent::ButtonPushed,
-        ]),
-        _ => None,
+        ]);
+    }
+    if events.contains(SupportedEvents::ENCODER_DELTA) {
+        normalized.push(NormalizedControlEvent::EncoderDelta);
+    }
+    if events.contains(SupportedEvents::ABSOLUTE) {
+        normalized.push(NormalizedControlEvent::AbsoluteChanged);
+    }
+    if events.contains(SupportedEvents::TOGGLE) {
+        normalized.extend([
+            NormalizedControlEvent::ToggleOn,
+            NormalizedControlEvent::ToggleOff,
+        ]);
     }
+    normalized
 }
 
-fn apply_dcsbios_export_packet(
-    memory_map: Arc<Mutex<VecMemoryMap>>,
-    packet: Vec<u8>,
-) -> Result<(), String> {
-    let mut reader = DcsBiosImpl::new(
-        SinglePacketSource::new(packet),
-        SharedMemoryMap { inner: memory_map },
-    );
-    reader
-        .read_packet()
-        .map(|_| ())
-        .map_err(|error| format!("Failed to decode DCS-BIOS export packet: {error:?}"))
+/// デバイスの対応範囲と manager の対応範囲で共通の最も新しいバージョン
+fn negotiate_protocol_version(device: &ProbedImcpDevice) -> Option<u8> {
+    ProtocolVersions::SUPPORTED.negotiate(ProtocolVersions {
+        min: device.min_protocol_version,
+        max: device.protocol_version,
+    })
 }
 
-fn request_child_device_hello(
+fn send_host_hello(
     port: &mut dyn serialport::SerialPort,
-    hub_address: u8,
+    device_address: u8,
+    protocol_version: u8,
 ) -> Result<(), String> {
-    let request = encode_set_packet(&AppPacketKind::ControlEvent(ControlEvent {
-        seq: 0,
-        control_id: CONTROL_ID_REQUEST_DEVICE_HELLO,
-        event: ControlValue::RequestDeviceHello,
-    }))
-    .map_err(|error| format!("Failed to encode RequestDeviceHello: {error:?}"))?;
+    let request = encode_set_packet_with_version(
+        protocol_version,
+        &AppPacketKind::HostHello(ProtocolVersions::SUPPORTED),
+    )
+    .map_err(|error| format!("Failed to encode HostHello: {error}"))?;
 
     write_frame(
         port,
         &Frame::new(
-            Address::Unicast(hub_address),
+            Address::Unicast(device_address),
             IMCP_MASTER_ADDRESS,
             FramePayload::Set(request),
         ),
     )
 }
 
-fn process_control_event(
-    state: &Arc<RuntimeState>,
-    app: &AppHandle,
-    config: &DcsBiosConnectionConfig,
-    known_devices: &HashMap<u8, KnownRuntimeDevice>,
-    pressed_buttons: &mut HashSet<(String, u16)>,
-    device_role_assignments: &[DeviceRoleAssignment],
-    role_mappings: &[RoleMappingConfig],
+/// 全エンドポイントで共通のホストの時計 (µs)。起点は最初に呼んだ時刻
+fn host_clock_us() -> u64 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    let elapsed = EPOCH.get_or_init(Instant::now).elapsed();
+    u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX)
+}
+
+/// ホストの時計を全デバイスに送る。ACK が衝突しないよう `Data` frame で broadcast する
+fn broadcast_time_sync(port: &mut dyn serialport::SerialPort) -> Result<(), String> {
+    let payload = encode_time_sync_packet(&TimeSync {
+        host_time_us: host_clock_us(),
+    })
+    .map_err(|error| format!("Failed to encode TimeSync: {error}"))?;
+
+    write_frame(
+        port,
+        &Frame::new(
+            Address::Broadcast,
+            IMCP_MASTER_ADDRESS,
+            FramePayload::Data(payload),
+        ),
+    )
+}
+
+/// デバイスが操作を検出してから manager が受信するまでの時間
+fn control_latency(timestamp_us: u64, received_us: u64) -> Duration {
+    Duration::from_micros(received_us.saturating_sub(timestamp_us))
+}
+
+/// 時刻付きの操作イベント
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TimestampedControl {
     source_address: u8,
-    control_event: &ControlEvent,
-) {
-    let Some(device) = known_devices.get(&source_address) else {
-        state.push_log(
-            app,
-            "WARN",
-            "devices",
-            format!(
-                "Ignoring control event from unknown IMCP address {} on control {}.",
-                source_address, control_event.control_id
-            ),
-        );
-        return;
-    };
+    timestamp_us: u64,
+    event: ControlEvent,
+}
 
-    if control_supported_events(device.device_kind, control_event.control_id).is_none() {
-        state.push_log(
-            app,
-            "WARN",
-            "devices",
-            format!(
-                "Ignoring control {} from unsupported catalog device {}.",
-                control_event.control_id, device.device_id
-            ),
-        );
-        return;
-    }
+/// 複数のパネルの時刻付きの操作を、デバイスが検出した時刻の順に並べ直す
+///
+/// パネルごとに別の frame で届くため、受信した順は検出した順と限らない。
+/// 検出してから `CONTROL_REORDER_WINDOW` が過ぎるまで溜めてから取り出す
+#[derive(Debug, Default)]
+struct ControlReorderBuffer {
+    pending: Vec<TimestampedControl>,
+}
 
-    let Some(role) = find_role_for_device(device_role_assignments, &device.device_id) else {
-        state.push_log(
-            app,
-            "WARN",
-            "devices",
-            format!(
-                "Ignoring control event for unassigned device {}.",
-                device.device_id
-            ),
-        );
-        return;
-    };
+impl ControlReorderBuffer {
+    fn push(
+        &mut self,
+        source_address: u8,
+        timestamp_us: u64,
+        events: impl IntoIterator<Item = ControlEvent>,
+    ) {
+        self.pending
+            .extend(events.into_iter().map(|event| TimestampedControl {
+                source_address,
+                timestamp_us,
+                event,
+            }));
+    }
 
-    let mut events = Vec::new();
-    match control_event.event {
-        ControlValue::Button { pressed: true } => {
-            pressed_buttons.insert((device.device_id.clone(), control_event.control_id));
-            events.push(NormalizedControlEvent::ButtonDown);
-        }
-        ControlValue::Button { pressed: false } => {
-            events.push(NormalizedControlEvent::ButtonUp);
-            if pressed_buttons.remove(&(device.device_id.clone(), control_event.control_id)) {
-                events.push(NormalizedControlEvent::ButtonPushed);
-            }
-        }
-        ControlValue::EncoderDelta { .. } => events.push(NormalizedControlEvent::EncoderDelta),
-        ControlValue::Absolute { .. } => events.push(NormalizedControlEvent::AbsoluteChanged),
-        ControlValue::Toggle { state: true } => events.push(NormalizedControlEvent::ToggleOn),
-        ControlValue::Toggle { state: false } => events.push(NormalizedControlEvent::ToggleOff),
-        ControlValue::RequestDeviceHello => {}
+    /// `now_us` (ホストの時計) までに待ち時間が過ぎた操作を、検出した時刻の順に取り出す。同じ時刻なら受信した順
+    fn drain_ready(&mut self, now_us: u64) -> Vec<TimestampedControl> {
+        let window_us = u64::try_from(CONTROL_REORDER_WINDOW.as_micros()).unwrap_or(u64::MAX);
+        let (mut ready, pending): (Vec<_>, Vec<_>) = self
+            .pending
+            .drain(..)
+            .partition(|control| control.timestamp_us.saturating_add(window_us) <= now_us);
+        self.pending = pending;
+        ready.sort_by_key(|control| control.timestamp_us);
+        ready
     }
+}
 
-    for input_event in events {
-        let Some(action) =
-            find_mapping_action(role_mappings, role, control_event.control_id, input_event)
-        else {
-            continue;
-        };
+fn request_descriptor_page(
+    port: &mut dyn serialport::SerialPort,
+    device_address: u8,
+    protocol_version: u8,
+    page: u16,
+) -> Result<(), String> {
+    let request = encode_set_packet_with_version(
+        protocol_version,
+        &AppPacketKind::RequestDescriptor { page },
+    )
+    .map_err(|error| format!("Failed to encode RequestDescriptor: {error}"))?;
 
-        match encode_import_command(&action.identifier, &action.argument)
+    write_frame(
+        port,
+        &Frame::new(
+            Address::Unicast(device_address),
+            IMCP_MASTER_ADDRESS,
+            FramePayload::Set(request),
+        ),
+    )
+}
+
+fn request_control_state(
+    port: &mut dyn serialport::SerialPort,
+    device_address: u8,
+    protocol_version: u8,
+) -> Result<(), String> {
+    let request =
+        encode_set_packet_with_version(protocol_version, &AppPacketKind::RequestControlState)
+            .map_err(|error| format!("Failed to encode RequestControlState: {error}"))?;
+
+    write_frame(
+        port,
+        &Frame::new(
+            Address::Unicast(device_address),
+            IMCP_MASTER_ADDRESS,
+            FramePayload::Set(request),
+        ),
+    )
+}
+
+fn pwm_output_target(output: &RoleOutputMapping) -> Option<DisplayTarget> {
+    match output.target_kind {
+        PwmTargetKind::Backlight => u8::try_from(output.channel)
+            .ok()
+            .map(DisplayTarget::Backlight),
+        PwmTargetKind::Lamp => Some(DisplayTarget::Indicator(output.channel)),
+    }
+}
+
+/// DCS-BIOS の整数出力を明るさに換算する。まだ値を受け取っていなければ `None`
+fn pwm_output_level(memory: &VecMemoryMap, output: &RoleOutputMapping) -> Option<PwmLevel> {
+    let value = DcsBiosImpl::<SinglePacketSource, VecMemoryMap>::get_integer(
+        memory,
+        output.address,
+        output.mask,
+        output.shift_by,
+    )?;
+    Some(PwmLevel::from_ratio(
+        value,
+        output.max_value,
+        output.fade_ms,
+    ))
+}
+
+/// ロールに割り当てたデバイスへ、明るさが変わった PWM 出力を送る
+///
+/// `resend` なら変わっていない出力も送り直す
+fn refresh_pwm_outputs(
+    port: &mut dyn serialport::SerialPort,
+    memory: &Mutex<VecMemoryMap>,
+    known_devices: &mut HashMap<u8, KnownRuntimeDevice>,
+    device_role_assignments: &[DeviceRoleAssignment],
+    role_mappings: &[RoleMappingConfig],
+    resend: bool,
+) -> Result<(), String> {
+    let memory = memory.lock().unwrap();
+    for (address, device) in known_devices.iter_mut() {
+        if device.capability_flags & FEATURE_PWM_OUTPUTS == 0 {
+            continue;
+        }
+        let Some(role) = find_role_for_device(device_role_assignments, &device.device_id) else {
+            continue;
+        };
+        let Some(config) = role_mappings.iter().find(|config| config.role == role) else {
+            continue;
+        };
+        if resend {
+            device.sent_outputs.clear();
+        }
+
+        for output in &config.outputs {
+            let (Some(target), Some(level)) =
+                (pwm_output_target(output), pwm_output_level(&memory, output))
+            else {
+                continue;
+            };
+            if device.sent_outputs.get(&output.id) == Some(&level.brightness) {
+                continue;
+            }
+
+            let display = DisplayData {
+                seq: device.display_seq,
+                target,
+                payload: DisplayPayload::Pwm(level),
+            };
+            // v1 のデバイスには送れない
+            if !AppPacketKind::DisplayData(display.clone()).is_available_in(device.protocol_version)
+            {
+                break;
+            }
+            let payload = encode_data_packet_with_version(device.protocol_version, &display)
+                .map_err(|error| format!("Failed to encode PWM output: {error}"))?;
+            write_frame(
+                port,
+                &Frame::new(
+                    Address::Unicast(*address),
+                    IMCP_MASTER_ADDRESS,
+                    FramePayload::Data(payload),
+                ),
+            )?;
+            device.display_seq = device.display_seq.wrapping_add(1);
+            device
+                .sent_outputs
+                .insert(output.id.clone(), level.brightness);
+        }
+    }
+    Ok(())
+}
+
+fn calibration_command(step: AxisCalibrationStep) -> CalibrationCommand {
+    match step {
+        AxisCalibrationStep::Begin => CalibrationCommand::Begin,
+        AxisCalibrationStep::CaptureCenter => CalibrationCommand::CaptureCenter,
+        AxisCalibrationStep::Commit => CalibrationCommand::Commit,
+        AxisCalibrationStep::Cancel => CalibrationCommand::Cancel,
+        AxisCalibrationStep::Get => CalibrationCommand::Get,
+        AxisCalibrationStep::Set {
+            min,
+            center,
+            max,
+            deadzone,
+        } => CalibrationCommand::Set(AxisCalibration {
+            min,
+            center,
+            max,
+            deadzone,
+        }),
+    }
+}
+
+fn axis_calibration_update(
+    device_id: &str,
+    status: &AxisCalibrationStatus,
+) -> AxisCalibrationUpdate {
+    AxisCalibrationUpdate {
+        device_id: device_id.to_string(),
+        control_id: status.control_id,
+        raw: status.raw,
+        recording: status.recording,
+        min: status.calibration.min,
+        center: status.calibration.center,
+        max: status.calibration.max,
+        deadzone: status.calibration.deadzone,
+        error: status.error.map(|error| {
+            match error {
+                CalibrationError::UnknownControl => "unknown-control",
+                CalibrationError::NotRecording => "not-recording",
+                CalibrationError::InvalidRange => "invalid-range",
+            }
+            .to_string()
+        }),
+    }
+}
+
+/// `packet` を送る先のアドレスとバージョンを探す
+///
+/// デバイスが `feature` を持たないか、交渉したバージョンに `packet` がなければエラー
+fn command_target(
+    known_devices: &HashMap<u8, KnownRuntimeDevice>,
+    device_id: &str,
+    feature: u32,
+    packet: &AppPacketKind,
+) -> Result<(u8, u8), String> {
+    let (address, device) = known_devices
+        .iter()
+        .find(|(_, device)| device.device_id == device_id)
+        .ok_or_else(|| format!("Device {device_id} is not connected."))?;
+    if device.capability_flags & feature == 0 || !packet.is_available_in(device.protocol_version) {
+        return Err(format!(
+            "Device {device_id} does not support {}.",
+            format_capability_flags(feature)
+        ));
+    }
+    Ok((*address, device.protocol_version))
+}
+
+fn send_device_command(
+    port: &mut dyn serialport::SerialPort,
+    device_address: u8,
+    protocol_version: u8,
+    packet: &AppPacketKind,
+) -> Result<(), String> {
+    let request = encode_set_packet_with_version(protocol_version, packet)
+        .map_err(|error| format!("Failed to encode {packet:?}: {error}"))?;
+
+    write_frame(
+        port,
+        &Frame::new(
+            Address::Unicast(device_address),
+            IMCP_MASTER_ADDRESS,
+            FramePayload::Set(request),
+        ),
+    )
+}
+
+/// snapshot に合わせて押下中のボタンを作り直す
+///
+/// 取りこぼしたイベントや manager の再起動で `pressed_buttons` がずれていても、ここで揃う
+fn apply_control_state(
+    device: &mut KnownRuntimeDevice,
+    pressed_buttons: &mut HashSet<(String, u16)>,
+    snapshot: &ControlStateSnapshot,
+) {
+    pressed_buttons.retain(|(device_id, _)| *device_id != device.device_id);
+    for (control_id, on) in snapshot.switch_states() {
+        if on {
+            pressed_buttons.insert((device.device_id.clone(), control_id));
+        }
+    }
+    device.control_state = Some(snapshot.clone());
+}
+
+/// デバイスのログを manager のログの level と本文にする
+fn device_log_message(device_id: &str, entry: &LogEntry) -> (&'static str, String) {
+    let level = match entry.level {
+        LogLevel::Error => "ERROR",
+        LogLevel::Warn => "WARN",
+        LogLevel::Info => "INFO",
+        LogLevel::Debug => "DEBUG",
+        LogLevel::Trace => "TRACE",
+    };
+    let mut message = format!("[{device_id}] {}: {}", entry.tag, entry.text);
+    if entry.dropped > 0 {
+        message.push_str(&format!(" ({} earlier entries dropped)", entry.dropped));
+    }
+    (level, message)
+}
+
+/// 応答のない `RequestDescriptor` を送り直し、送り直しきったら descriptor の受信をやめる
+fn retry_descriptor_requests(
+    port: &mut dyn serialport::SerialPort,
+    state: &Arc<RuntimeState>,
+    app: &AppHandle,
+    known_devices: &mut HashMap<u8, KnownRuntimeDevice>,
+) -> Result<(), String> {
+    let now = Instant::now();
+    for (address, device) in known_devices.iter_mut() {
+        let Some(request) = device.descriptor_request.as_mut() else {
+            continue;
+        };
+        match request.retry(now) {
+            DescriptorRetry::Wait => {}
+            DescriptorRetry::Resend(page) => {
+                request_descriptor_page(port, *address, device.protocol_version, page)?
+            }
+            DescriptorRetry::GiveUp => {
+                let page = request.page;
+                device.descriptor = None;
+                device.descriptor_request = None;
+                device.pending_controls.clear();
+                state.push_log(
+                    app,
+                    "WARN",
+                    "devices",
+                    format!(
+                        "Gave up receiving descriptor from device {}: no response to page {page}.",
+                        device.device_id
+                    ),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// descriptor のページを取り込み、次に要求するページを返す
+fn accept_descriptor_page(
+    device: &mut KnownRuntimeDevice,
+    page: &DescriptorPage,
+) -> Result<Option<u16>, String> {
+    let Some(assembler) = device.descriptor.as_mut() else {
+        return Err(format!(
+            "Ignoring unrequested descriptor page from device {}.",
+            device.device_id
+        ));
+    };
+    let next_page = assembler.accept(page).map_err(|error| {
+        format!(
+            "Failed to receive descriptor from device {}: {error}",
+            device.device_id
+        )
+    })?;
+
+    for entry in &page.entries {
+        if let DescriptorEntry::Control(control) = entry {
+            device.pending_controls.insert(control.id, control.events);
+        }
+    }
+
+    if next_page.is_none() {
+        device.descriptor = None;
+        device.controls = Some(std::mem::take(&mut device.pending_controls));
+    }
+    Ok(next_page)
+}
+
+fn apply_dcsbios_export_packet(
+    memory_map: Arc<Mutex<VecMemoryMap>>,
+    packet: Vec<u8>,
+) -> Result<(), String> {
+    let mut reader = DcsBiosImpl::new(
+        SinglePacketSource::new(packet),
+        SharedMemoryMap { inner: memory_map },
+    );
+    reader
+        .read_packet()
+        .map(|_| ())
+        .map_err(|error| format!("Failed to decode DCS-BIOS export packet: {error:?}"))
+}
+
+fn request_child_device_hello(
+    port: &mut dyn serialport::SerialPort,
+    hub_address: u8,
+) -> Result<(), String> {
+    // hub のバージョンは分からないので、どのバージョンでも読める layout で送る
+    let request = encode_set_packet_with_version(
+        MIN_APP_PROTOCOL_VERSION,
+        &AppPacketKind::ControlEvent(ControlEvent {
+            seq: 0,
+            control_id: CONTROL_ID_REQUEST_DEVICE_HELLO,
+            event: ControlValue::RequestDeviceHello,
+        }),
+    )
+    .map_err(|error| format!("Failed to encode RequestDeviceHello: {error}"))?;
+
+    write_frame(
+        port,
+        &Frame::new(
+            Address::Unicast(hub_address),
+            IMCP_MASTER_ADDRESS,
+            FramePayload::Set(request),
+        ),
+    )
+}
+
+fn process_control_event(
+    state: &Arc<RuntimeState>,
+    app: &AppHandle,
+    config: &DcsBiosConnectionConfig,
+    known_devices: &HashMap<u8, KnownRuntimeDevice>,
+    pressed_buttons: &mut HashSet<(String, u16)>,
+    device_role_assignments: &[DeviceRoleAssignment],
+    role_mappings: &[RoleMappingConfig],
+    source_address: u8,
+    control_event: &ControlEvent,
+    latency: Option<Duration>,
+) {
+    let Some(device) = known_devices.get(&source_address) else {
+        state.push_log(
+            app,
+            "WARN",
+            "devices",
+            format!(
+                "Ignoring control event from unknown IMCP address {} on control {}.",
+                source_address, control_event.control_id
+            ),
+        );
+        return;
+    };
+
+    if control_supported_events(device, control_event.control_id).is_none() {
+        state.push_log(
+            app,
+            "WARN",
+            "devices",
+            format!(
+                "Ignoring control {} from unsupported catalog device {}.",
+                control_event.control_id, device.device_id
+            ),
+        );
+        return;
+    }
+
+    let Some(role) = find_role_for_device(device_role_assignments, &device.device_id) else {
+        state.push_log(
+            app,
+            "WARN",
+            "devices",
+            format!(
+                "Ignoring control event for unassigned device {}.",
+                device.device_id
+            ),
+        );
+        return;
+    };
+
+    let mut events = Vec::new();
+    match control_event.event {
+        ControlValue::Button { pressed: true } => {
+            pressed_buttons.insert((device.device_id.clone(), control_event.control_id));
+            events.push(NormalizedControlEvent::ButtonDown);
+        }
+        ControlValue::Button { pressed: false } => {
+            events.push(NormalizedControlEvent::ButtonUp);
+            if pressed_buttons.remove(&(device.device_id.clone(), control_event.control_id)) {
+                events.push(NormalizedControlEvent::ButtonPushed);
+            }
+        }
+        ControlValue::EncoderDelta { .. } => events.push(NormalizedControlEvent::EncoderDelta),
+        ControlValue::Absolute { .. } => events.push(NormalizedControlEvent::AbsoluteChanged),
+        ControlValue::Toggle { state: true } => events.push(NormalizedControlEvent::ToggleOn),
+        ControlValue::Toggle { state: false } => events.push(NormalizedControlEvent::ToggleOff),
+        ControlValue::RequestDeviceHello => {}
+    }
+
+    for input_event in events {
+        let Some(action) =
+            find_mapping_action(role_mappings, role, control_event.control_id, input_event)
+        else {
+            continue;
+        };
+
+        match encode_import_command(&action.identifier, &action.argument)
             .and_then(|payload| send_command_to_dcsbios(config, &payload))
         {
             Ok(()) => state.push_log(
@@ -1673,12 +2587,16 @@ fn process_control_event(
                 "SUCCESS",
                 "mapping",
                 format!(
-                    "Mapped {:?} control {} {:?} -> {} {}",
+                    "Mapped {:?} control {} {:?} -> {} {}{}",
                     role,
                     control_event.control_id,
                     input_event,
                     action.identifier,
-                    action.argument
+                    action.argument,
+                    latency.map_or_else(String::new, |latency| format!(
+                        " ({:.1} ms after input)",
+                        latency.as_secs_f64() * 1000.0
+                    ))
                 ),
             ),
             Err(error) => state.push_log(
@@ -1701,6 +2619,7 @@ fn run_endpoint_listener(
     device_role_assignments: Vec<DeviceRoleAssignment>,
     role_mappings: Vec<RoleMappingConfig>,
     stop: Arc<AtomicBool>,
+    commands: mpsc::Receiver<ListenerCommand>,
 ) -> Result<(), String> {
     let config = state.config.lock().unwrap().clone();
     let mut port = serialport::new(&endpoint.address, endpoint.baud_rate)
@@ -1715,9 +2634,14 @@ fn run_endpoint_listener(
     let mut parser = FrameParser::new(&mut rx_buffer, &mut frame_buffer);
     let mut join_addresses: HashMap<u32, u8> = HashMap::new();
     let mut next_address: u8 = 0x02;
+    let mut sticky_addresses = state.sticky_address_table(&endpoint.id);
     let mut known_devices: HashMap<u8, KnownRuntimeDevice> = HashMap::new();
     let mut requested_children = HashSet::new();
     let mut pressed_buttons: HashSet<(String, u16)> = HashSet::new();
+    let mut reorder_buffer = ControlReorderBuffer::default();
+    let mut last_time_sync: Option<Instant> = None;
+    let mut last_pwm_output: Option<Instant> = None;
+    let mut last_pwm_resend = Instant::now();
 
     state.push_log(
         &app,
@@ -1727,13 +2651,75 @@ fn run_endpoint_listener(
     );
 
     while !stop.load(Ordering::Relaxed) {
+        if last_time_sync.is_none_or(|sent_at| sent_at.elapsed() >= TIME_SYNC_INTERVAL) {
+            broadcast_time_sync(&mut *port)?;
+            last_time_sync = Some(Instant::now());
+        }
+
+        if last_pwm_output.is_none_or(|sent_at| sent_at.elapsed() >= PWM_OUTPUT_INTERVAL) {
+            let resend = last_pwm_resend.elapsed() >= PWM_OUTPUT_RESEND_INTERVAL;
+            refresh_pwm_outputs(
+                &mut *port,
+                &state.dcsbios_memory,
+                &mut known_devices,
+                &device_role_assignments,
+                &role_mappings,
+                resend,
+            )?;
+            last_pwm_output = Some(Instant::now());
+            if resend {
+                last_pwm_resend = Instant::now();
+            }
+        }
+
+        retry_descriptor_requests(&mut *port, &state, &app, &mut known_devices)?;
+
+        while let Ok(command) = commands.try_recv() {
+            match command {
+                ListenerCommand::Identify {
+                    device_id,
+                    seconds,
+                    reply,
+                } => {
+                    let packet = AppPacketKind::Identify { seconds };
+                    let result =
+                        command_target(&known_devices, &device_id, FEATURE_IDENTIFY, &packet)
+                            .and_then(|(address, protocol_version)| {
+                                send_device_command(&mut *port, address, protocol_version, &packet)
+                            });
+                    let _ = reply.send(result);
+                }
+                ListenerCommand::CalibrateAxis {
+                    device_id,
+                    control_id,
+                    command,
+                    reply,
+                } => {
+                    let packet = AppPacketKind::CalibrateAxis {
+                        control_id,
+                        command,
+                    };
+                    let result = command_target(
+                        &known_devices,
+                        &device_id,
+                        FEATURE_AXIS_CALIBRATION,
+                        &packet,
+                    )
+                    .and_then(|(address, protocol_version)| {
+                        send_device_command(&mut *port, address, protocol_version, &packet)
+                    });
+                    let _ = reply.send(result);
+                }
+            }
+        }
+
         match port.read(&mut serial_buffer) {
             Ok(bytes_read) if bytes_read > 0 => {
                 parser
                     .write_data(&serial_buffer[..bytes_read])
                     .map_err(|error| {
                         format!(
-                            "Failed to parse IMCP frame on {}: {error:?}",
+                            "Failed to parse IMCP frame on {}: {error}",
                             endpoint.address
                         )
                     })?;
@@ -1745,12 +2731,42 @@ fn run_endpoint_listener(
                     };
 
                     match frame.payload() {
-                        FramePayload::Join(join_id) => {
+                        FramePayload::Join(_) | FramePayload::JoinWithDeviceId { .. } => {
+                            let (join_id, device_id) = match frame.payload() {
+                                FramePayload::JoinWithDeviceId { id, device_id } => {
+                                    (id, Some(*device_id))
+                                }
+                                FramePayload::Join(id) => (id, None),
+                                _ => continue,
+                            };
                             let address = *join_addresses.entry(*join_id).or_insert_with(|| {
+                                if let Some(address) = device_id
+                                    .and_then(|id| sticky_addresses.reusable_address(id, *join_id))
+                                {
+                                    return address;
+                                }
+                                while !sticky_addresses.is_available(
+                                    next_address,
+                                    device_id,
+                                    *join_id,
+                                ) {
+                                    next_address = next_address.saturating_add(1);
+                                }
                                 let current = next_address;
                                 next_address = next_address.saturating_add(1);
                                 current
                             });
+                            if let Some(device_id) = device_id {
+                                if sticky_addresses.assign_to_node(device_id, address, *join_id) {
+                                    if let Err(error) = state.save_sticky_addresses(
+                                        &app,
+                                        &endpoint.id,
+                                        &sticky_addresses,
+                                    ) {
+                                        state.push_log(&app, "WARN", "devices", error);
+                                    }
+                                }
+                            }
                             write_frame(
                                 &mut *port,
                                 &Frame::new(
@@ -1777,13 +2793,70 @@ fn run_endpoint_listener(
                                 )?;
 
                                 let source_address = frame.from_address();
+                                let Some(protocol_version) = negotiate_protocol_version(&probed)
+                                else {
+                                    state.push_log(
+                                        &app,
+                                        "WARN",
+                                        "devices",
+                                        format!(
+                                            "Device {} supports HCP v{}-v{}, which is not supported by the manager (v{}-v{}).",
+                                            probed.device_id,
+                                            probed.min_protocol_version,
+                                            probed.protocol_version,
+                                            ProtocolVersions::SUPPORTED.min,
+                                            ProtocolVersions::SUPPORTED.max
+                                        ),
+                                    );
+                                    continue;
+                                };
+                                // v1 のデバイスは HostHello を知らないので送らない
+                                let host_hello =
+                                    AppPacketKind::HostHello(ProtocolVersions::SUPPORTED);
+                                if host_hello.is_available_in(protocol_version) {
+                                    send_host_hello(&mut *port, source_address, protocol_version)?;
+                                }
+                                let supports_descriptors =
+                                    probed.capability_flags & FEATURE_DESCRIPTORS != 0
+                                        && AppPacketKind::RequestDescriptor { page: 0 }
+                                            .is_available_in(protocol_version);
                                 known_devices.insert(
                                     source_address,
                                     KnownRuntimeDevice {
                                         device_id: probed.device_id.clone(),
                                         device_kind: probed.device_kind,
+                                        protocol_version,
+                                        capability_flags: probed.capability_flags,
+                                        display_seq: 0,
+                                        sent_outputs: HashMap::new(),
+                                        descriptor: supports_descriptors
+                                            .then(DescriptorAssembler::new),
+                                        descriptor_request: supports_descriptors
+                                            .then(|| DescriptorRequest::new(0, Instant::now())),
+                                        pending_controls: HashMap::new(),
+                                        controls: None,
+                                        control_state: None,
                                     },
                                 );
+                                if supports_descriptors {
+                                    request_descriptor_page(
+                                        &mut *port,
+                                        source_address,
+                                        protocol_version,
+                                        0,
+                                    )?;
+                                }
+                                // 再接続時に、切断中に変わったトグルなどの状態を取り直す
+                                if probed.capability_flags & FEATURE_CONTROL_STATE != 0
+                                    && AppPacketKind::RequestControlState
+                                        .is_available_in(protocol_version)
+                                {
+                                    request_control_state(
+                                        &mut *port,
+                                        source_address,
+                                        protocol_version,
+                                    )?;
+                                }
 
                                 if probed.device_kind == DeviceKind::ImcpHub
                                     && requested_children.insert(source_address)
@@ -1799,6 +2872,166 @@ fn run_endpoint_listener(
                                 Err(_) => continue,
                             };
 
+                            if let AppPacketKind::DescriptorPage(page) = &kind {
+                                write_frame(
+                                    &mut *port,
+                                    &Frame::new(
+                                        Address::Unicast(frame.from_address()),
+                                        IMCP_MASTER_ADDRESS,
+                                        FramePayload::Ack(frame.to_address().as_byte()),
+                                    ),
+                                )?;
+                                let Some(device) = known_devices.get_mut(&frame.from_address())
+                                else {
+                                    continue;
+                                };
+                                // 送り直した要求への応答が遅れて届いたページは受け取り済み
+                                if device
+                                    .descriptor
+                                    .as_ref()
+                                    .and_then(DescriptorAssembler::next_page)
+                                    .is_some_and(|expected| page.page < expected)
+                                {
+                                    continue;
+                                }
+                                device.descriptor_request = None;
+                                match accept_descriptor_page(device, page) {
+                                    Ok(Some(next_page)) => {
+                                        request_descriptor_page(
+                                            &mut *port,
+                                            frame.from_address(),
+                                            device.protocol_version,
+                                            next_page,
+                                        )?;
+                                        device.descriptor_request =
+                                            Some(DescriptorRequest::new(next_page, Instant::now()));
+                                    }
+                                    Ok(None) => state.push_log(
+                                        &app,
+                                        "INFO",
+                                        "devices",
+                                        format!(
+                                            "Received descriptor for device {} ({} controls).",
+                                            device.device_id,
+                                            device.controls.as_ref().map_or(0, HashMap::len)
+                                        ),
+                                    ),
+                                    Err(error) => {
+                                        device.descriptor = None;
+                                        device.pending_controls.clear();
+                                        state.push_log(&app, "WARN", "devices", error);
+                                    }
+                                }
+                                continue;
+                            }
+
+                            if let AppPacketKind::ControlStateSnapshot(snapshot) = &kind {
+                                write_frame(
+                                    &mut *port,
+                                    &Frame::new(
+                                        Address::Unicast(frame.from_address()),
+                                        IMCP_MASTER_ADDRESS,
+                                        FramePayload::Ack(frame.to_address().as_byte()),
+                                    ),
+                                )?;
+                                let Some(device) = known_devices.get_mut(&frame.from_address())
+                                else {
+                                    continue;
+                                };
+                                apply_control_state(device, &mut pressed_buttons, snapshot);
+                                state.push_log(
+                                    &app,
+                                    "INFO",
+                                    "devices",
+                                    format!(
+                                        "Resynchronized control state of device {} ({} switches, {} values).",
+                                        device.device_id,
+                                        snapshot.switch_count,
+                                        snapshot.values.len()
+                                    ),
+                                );
+                                continue;
+                            }
+
+                            if let AppPacketKind::AxisCalibrationStatus(status) = &kind {
+                                write_frame(
+                                    &mut *port,
+                                    &Frame::new(
+                                        Address::Unicast(frame.from_address()),
+                                        IMCP_MASTER_ADDRESS,
+                                        FramePayload::Ack(frame.to_address().as_byte()),
+                                    ),
+                                )?;
+                                if let Some(device) = known_devices.get(&frame.from_address()) {
+                                    let update = axis_calibration_update(&device.device_id, status);
+                                    let _ = app.emit("axis-calibration-changed", update);
+                                }
+                                continue;
+                            }
+
+                            if let AppPacketKind::Log(entry) = &kind {
+                                write_frame(
+                                    &mut *port,
+                                    &Frame::new(
+                                        Address::Unicast(frame.from_address()),
+                                        IMCP_MASTER_ADDRESS,
+                                        FramePayload::Ack(frame.to_address().as_byte()),
+                                    ),
+                                )?;
+                                let device_id =
+                                    known_devices.get(&frame.from_address()).map_or_else(
+                                        || format!("0x{:02X}", frame.from_address()),
+                                        |device| device.device_id.clone(),
+                                    );
+                                let (level, message) = device_log_message(&device_id, entry);
+                                state.push_log(&app, level, "device", message);
+                                continue;
+                            }
+
+                            if let AppPacketKind::ControlEventBatch(batch) = &kind {
+                                write_frame(
+                                    &mut *port,
+                                    &Frame::new(
+                                        Address::Unicast(frame.from_address()),
+                                        IMCP_MASTER_ADDRESS,
+                                        FramePayload::Ack(frame.to_address().as_byte()),
+                                    ),
+                                )?;
+                                for control_event in batch.control_events() {
+                                    process_control_event(
+                                        &state,
+                                        &app,
+                                        &config,
+                                        &known_devices,
+                                        &mut pressed_buttons,
+                                        &device_role_assignments,
+                                        &role_mappings,
+                                        frame.from_address(),
+                                        &control_event,
+                                        None,
+                                    );
+                                }
+                                continue;
+                            }
+
+                            if let AppPacketKind::TimestampedControlEvents(events) = &kind {
+                                write_frame(
+                                    &mut *port,
+                                    &Frame::new(
+                                        Address::Unicast(frame.from_address()),
+                                        IMCP_MASTER_ADDRESS,
+                                        FramePayload::Ack(frame.to_address().as_byte()),
+                                    ),
+                                )?;
+                                // 他のパネルの操作と検出した順に並べてから処理する
+                                reorder_buffer.push(
+                                    frame.from_address(),
+                                    events.timestamp_us,
+                                    events.control_events(),
+                                );
+                                continue;
+                            }
+
                             if let AppPacketKind::ControlEvent(control_event) = kind {
                                 write_frame(
                                     &mut *port,
@@ -1818,6 +3051,7 @@ fn run_endpoint_listener(
                                     &role_mappings,
                                     frame.from_address(),
                                     &control_event,
+                                    None,
                                 );
                             }
                         }
@@ -1954,6 +3188,8 @@ pub fn run() {
                         manager_state.device_role_assignments.clone(),
                     );
                     state.set_role_mappings(&app_handle, manager_state.role_mappings.clone());
+                    *state.sticky_addresses.lock().unwrap() =
+                        manager_state.sticky_addresses.clone();
                     if !manager_state.device_endpoints.is_empty() {
                         tauri::async_runtime::spawn({
                             let app_handle = app_handle.clone();
@@ -1990,7 +3226,9 @@ pub fn run() {
             save_device_role_assignments,
             save_role_mappings,
             list_serial_ports,
-            list_devices
+            list_devices,
+            identify_device,
+            calibrate_axis
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -2074,10 +3312,12 @@ mod tests {
             assigned_address: Some(4),
             device_kind: DeviceKind::ButtonPanel,
             protocol_version: 1,
+            min_protocol_version: 1,
             device_id: "0000000000001234".to_string(),
             displays: 0,
             controls: 20,
             features: "control-events".to_string(),
+            capability_flags: FEATURE_CONTROL_EVENTS,
         };
 
         let summary = probed_device_to_summary(
@@ -2096,6 +3336,33 @@ mod tests {
         assert_eq!(summary.device_kind_id.as_deref(), Some("button-panel"));
     }
 
+    #[test]
+    fn protocol_version_is_negotiated_with_device_range() {
+        let mut device = ProbedImcpDevice {
+            display_name: "Upper Panel DDI".to_string(),
+            firmware_version: "0.1.0".to_string(),
+            assigned_address: Some(2),
+            device_kind: DeviceKind::UpperPanelDdi,
+            protocol_version: 1,
+            min_protocol_version: 1,
+            device_id: "0000000000000001".to_string(),
+            displays: 0,
+            controls: 40,
+            features: "control-events".to_string(),
+            capability_flags: FEATURE_CONTROL_EVENTS,
+        };
+        assert_eq!(negotiate_protocol_version(&device), Some(1));
+
+        device.protocol_version = hcp::APP_PROTOCOL_VERSION + 1;
+        assert_eq!(
+            negotiate_protocol_version(&device),
+            Some(hcp::APP_PROTOCOL_VERSION)
+        );
+
+        device.min_protocol_version = hcp::APP_PROTOCOL_VERSION + 1;
+        assert_eq!(negotiate_protocol_version(&device), None);
+    }
+
     #[test]
     fn unavailable_summary_marks_endpoint_error() {
         let endpoint = DeviceEndpointConfig {
@@ -2156,6 +3423,7 @@ mod tests {
                     },
                 },
             ],
+            outputs: Vec::new(),
         }]);
 
         assert_eq!(mappings.len(), 1);
@@ -2177,6 +3445,7 @@ mod tests {
                         argument: "1".to_string(),
                     },
                 }],
+                outputs: Vec::new(),
             }],
             DeviceRole::RightDdi,
             7,
@@ -2207,6 +3476,49 @@ mod tests {
         assert_eq!(bytes, &[0x34, 0x12]);
     }
 
+    #[test]
+    fn pwm_outputs_follow_dcs_bios_integer() {
+        let output = |target_kind, channel, mask| RoleOutputMapping {
+            id: String::new(),
+            target_kind,
+            channel,
+            address: 0x1000,
+            mask,
+            shift_by: 0,
+            max_value: 65535,
+            fade_ms: 200,
+        };
+        let outputs = sanitize_role_mappings(vec![RoleMappingConfig {
+            role: DeviceRole::LeftDdi,
+            mappings: Vec::new(),
+            outputs: vec![
+                output(PwmTargetKind::Backlight, 0, 0xFFFF),
+                output(PwmTargetKind::Backlight, 0, 0xFFFF),
+                output(PwmTargetKind::Backlight, 256, 0xFFFF),
+                output(PwmTargetKind::Lamp, 256, 0xFFFF),
+                output(PwmTargetKind::Lamp, 1, 0),
+            ],
+        }])
+        .remove(0)
+        .outputs;
+        assert_eq!(outputs.len(), 2);
+        assert!(outputs.iter().all(|output| !output.id.is_empty()));
+        assert_eq!(
+            pwm_output_target(&outputs[1]),
+            Some(DisplayTarget::Indicator(256))
+        );
+
+        let memory = Arc::new(Mutex::new(VecMemoryMap::default()));
+        assert_eq!(pwm_output_level(&memory.lock().unwrap(), &outputs[0]), None);
+        // つまみを半分まで回した
+        let packet = vec![0x55, 0x55, 0x55, 0x55, 0x00, 0x10, 0x02, 0x00, 0x00, 0x80];
+        apply_dcsbios_export_packet(memory.clone(), packet).expect("packet must decode");
+        assert_eq!(
+            pwm_output_level(&memory.lock().unwrap(), &outputs[0]),
+            Some(PwmLevel::fade(128, 200))
+        );
+    }
+
     #[test]
     fn button_release_after_press_generates_pushed_event() {
         let mut pressed_buttons = HashSet::new();
@@ -2227,4 +3539,322 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn descriptor_pages_replace_hardcoded_control_catalog() {
+        let entries: Vec<DescriptorEntry> = (0..6)
+            .map(|id| {
+                let kind = if id == 5 {
+                    hcp::ControlKind::Encoder
+                } else {
+                    hcp::ControlKind::Button
+                };
+                DescriptorEntry::Control(
+                    hcp::ControlDescriptor::new(id, kind, "CTRL").expect("label fits"),
+                )
+            })
+            .collect();
+        let mut device = KnownRuntimeDevice {
+            device_id: "DEVICE-1".to_string(),
+            device_kind: DeviceKind::ButtonPanel,
+            protocol_version: hcp::APP_PROTOCOL_VERSION,
+            capability_flags: FEATURE_CONTROL_EVENTS,
+            display_seq: 0,
+            sent_outputs: HashMap::new(),
+            descriptor: Some(DescriptorAssembler::new()),
+            descriptor_request: None,
+            pending_controls: HashMap::new(),
+            controls: None,
+            control_state: None,
+        };
+
+        assert!(control_supported_events(&device, 0).is_none());
+
+        let first = DescriptorPage::from_entries(&entries, 0).expect("page 0");
+        assert_eq!(accept_descriptor_page(&mut device, &first), Ok(Some(1)));
+        let second = DescriptorPage::from_entries(&entries, 1).expect("page 1");
+        assert_eq!(accept_descriptor_page(&mut device, &second), Ok(None));
+
+        assert_eq!(
+            control_supported_events(&device, 0),
+            Some(vec![
+                NormalizedControlEvent::ButtonDown,
+                NormalizedControlEvent::ButtonUp,
+                NormalizedControlEvent::ButtonPushed,
+            ])
+        );
+        assert_eq!(
+            control_supported_events(&device, 5),
+            Some(vec![NormalizedControlEvent::EncoderDelta])
+        );
+        assert!(control_supported_events(&device, 6).is_none());
+        assert!(accept_descriptor_page(&mut device, &first).is_err());
+    }
+
+    #[test]
+    fn panel_definition_covers_firmware_without_descriptors() {
+        assert_eq!(
+            panel_definitions().len(),
+            homecockpit_panel_definition::PANEL_DEFINITION_SOURCES.len()
+        );
+
+        let device = KnownRuntimeDevice {
+            device_id: "DDI-1".to_string(),
+            device_kind: DeviceKind::UpperPanelDdi,
+            protocol_version: MIN_APP_PROTOCOL_VERSION,
+            capability_flags: FEATURE_CONTROL_EVENTS,
+            display_seq: 0,
+            sent_outputs: HashMap::new(),
+            descriptor: None,
+            descriptor_request: None,
+            pending_controls: HashMap::new(),
+            controls: None,
+            control_state: None,
+        };
+        assert_eq!(
+            control_supported_events(&device, 39),
+            Some(normalized_events(SupportedEvents::BUTTON))
+        );
+        assert!(control_supported_events(&device, 40).is_none());
+
+        let catalogs = control_catalogs();
+        let ddi = catalogs
+            .iter()
+            .find(|catalog| catalog.device_kind_id == "upper-panel-ddi")
+            .expect("DDI catalog");
+        assert_eq!(ddi.controls.len(), 40);
+        assert_eq!(ddi.controls[6].description, "Matrix row 2, column 2");
+    }
+
+    #[test]
+    fn identify_targets_connected_device_that_supports_it() {
+        let device = |device_id: &str, capability_flags, protocol_version| KnownRuntimeDevice {
+            device_id: device_id.to_string(),
+            device_kind: DeviceKind::UpperPanelDdi,
+            protocol_version,
+            capability_flags,
+            display_seq: 0,
+            sent_outputs: HashMap::new(),
+            descriptor: None,
+            descriptor_request: None,
+            pending_controls: HashMap::new(),
+            controls: None,
+            control_state: None,
+        };
+        let (latest, v1) = (hcp::APP_PROTOCOL_VERSION, MIN_APP_PROTOCOL_VERSION);
+        let known_devices = HashMap::from([
+            (0x02, device("LEFT", FEATURE_IDENTIFY, latest)),
+            (0x03, device("RIGHT", FEATURE_IDENTIFY, latest)),
+            (0x04, device("OLD", FEATURE_IDENTIFY, v1)),
+            (0x05, device("PLAIN", 0, latest)),
+        ]);
+
+        let target = |device_id| {
+            let identify = AppPacketKind::Identify { seconds: 0 };
+            command_target(&known_devices, device_id, FEATURE_IDENTIFY, &identify)
+        };
+
+        assert_eq!(target("RIGHT"), Ok((0x03, latest)));
+        assert!(target("OLD").is_err());
+        assert!(target("PLAIN").is_err());
+        assert!(target("MISSING").is_err());
+    }
+
+    #[test]
+    fn axis_calibration_steps_follow_ui_json() {
+        let step: AxisCalibrationStep =
+            serde_json::from_str(r#"{"step":"capture-center"}"#).expect("valid step");
+        assert_eq!(calibration_command(step), CalibrationCommand::CaptureCenter);
+
+        let json = r#"{"step":"set","min":10,"center":500,"max":1000,"deadzone":8}"#;
+        let step: AxisCalibrationStep = serde_json::from_str(json).expect("valid step");
+        let calibration = AxisCalibration {
+            min: 10,
+            center: 500,
+            max: 1000,
+            deadzone: 8,
+        };
+        assert_eq!(
+            calibration_command(step),
+            CalibrationCommand::Set(calibration)
+        );
+
+        let status = AxisCalibrationStatus {
+            control_id: 0x0301,
+            raw: 640,
+            recording: true,
+            calibration,
+            error: Some(CalibrationError::NotRecording),
+        };
+        let update = axis_calibration_update("THROTTLE", &status);
+        assert_eq!((update.min, update.max, update.raw), (10, 1000, 640));
+        assert_eq!(update.error.as_deref(), Some("not-recording"));
+    }
+
+    #[test]
+    fn control_state_snapshot_rebuilds_pressed_buttons() {
+        let mut device = KnownRuntimeDevice {
+            device_id: "DEVICE-1".to_string(),
+            device_kind: DeviceKind::UpperPanelDdi,
+            protocol_version: hcp::APP_PROTOCOL_VERSION,
+            capability_flags: FEATURE_CONTROL_EVENTS,
+            display_seq: 0,
+            sent_outputs: HashMap::new(),
+            descriptor: None,
+            descriptor_request: None,
+            pending_controls: HashMap::new(),
+            controls: None,
+            control_state: None,
+        };
+        let mut pressed_buttons =
+            HashSet::from([("DEVICE-1".to_string(), 1), ("DEVICE-2".to_string(), 1)]);
+        let mut snapshot = ControlStateSnapshot::new(4, 0);
+        for on in [false, false, true, true] {
+            snapshot.push_switch(on).expect("switch fits");
+        }
+
+        apply_control_state(&mut device, &mut pressed_buttons, &snapshot);
+
+        assert_eq!(
+            pressed_buttons,
+            HashSet::from([
+                ("DEVICE-1".to_string(), 2),
+                ("DEVICE-1".to_string(), 3),
+                ("DEVICE-2".to_string(), 1),
+            ])
+        );
+        assert_eq!(device.control_state, Some(snapshot));
+    }
+
+    #[test]
+    fn device_log_includes_device_id_and_dropped_count() {
+        let mut entry = LogEntry::new(LogLevel::Warn, "matrix", "queue full");
+        assert_eq!(
+            device_log_message("DEVICE-1", &entry),
+            ("WARN", "[DEVICE-1] matrix: queue full".to_string())
+        );
+
+        entry.dropped = 3;
+        assert_eq!(
+            device_log_message("DEVICE-1", &entry).1,
+            "[DEVICE-1] matrix: queue full (3 earlier entries dropped)"
+        );
+    }
+
+    #[test]
+    fn control_latency_is_measured_on_host_clock() {
+        assert_eq!(
+            control_latency(1_000, 13_500),
+            Duration::from_micros(12_500)
+        );
+        // 前回の TimeSync からデバイスの時計が速く進むと、受信時刻より後の時刻が付くことがある
+        assert_eq!(control_latency(2_000, 1_900), Duration::ZERO);
+    }
+
+    #[test]
+    fn descriptor_request_is_resent_after_timeout() {
+        let sent_at = Instant::now();
+        let mut request = DescriptorRequest::new(2, sent_at);
+        assert_eq!(
+            request.retry(sent_at + DESCRIPTOR_PAGE_TIMEOUT / 2),
+            DescriptorRetry::Wait
+        );
+
+        let mut now = sent_at;
+        for _ in 0..MAX_DESCRIPTOR_PAGE_RETRIES {
+            now += DESCRIPTOR_PAGE_TIMEOUT;
+            assert_eq!(request.retry(now), DescriptorRetry::Resend(2));
+            // 送り直した時刻から待ち直す
+            assert_eq!(request.retry(now), DescriptorRetry::Wait);
+        }
+        assert_eq!(
+            request.retry(now + DESCRIPTOR_PAGE_TIMEOUT),
+            DescriptorRetry::GiveUp
+        );
+    }
+
+    #[test]
+    fn controls_from_two_panels_are_ordered_by_detection_time() {
+        let event = |control_id| ControlEvent {
+            seq: 0,
+            control_id,
+            event: ControlValue::Button { pressed: true },
+        };
+        let window_us = u64::try_from(CONTROL_REORDER_WINDOW.as_micros()).unwrap();
+        let mut buffer = ControlReorderBuffer::default();
+        // 0x02 のパネルの操作が先に届くが、0x03 のパネルの方が先に検出している
+        buffer.push(0x02, 10_000, [event(1), event(2)]);
+        buffer.push(0x03, 9_000, [event(7)]);
+
+        assert!(buffer.drain_ready(9_000 + window_us - 1).is_empty());
+        let order: Vec<(u8, u16)> = buffer
+            .drain_ready(10_000 + window_us)
+            .into_iter()
+            .map(|control| (control.source_address, control.event.control_id))
+            .collect();
+        assert_eq!(order, [(0x03, 7), (0x02, 1), (0x02, 2)]);
+
+        // 待ち時間の途中なら、過ぎた分だけ取り出す
+        buffer.push(0x02, 20_000, [event(3)]);
+        buffer.push(0x03, 21_000, [event(8)]);
+        let ready = buffer.drain_ready(20_000 + window_us);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].source_address, 0x02);
+        assert_eq!(buffer.drain_ready(21_000 + window_us).len(), 1);
+    }
+
+    /// 型の宣言を依存をたどって集める。依存をたどる順番は build ごとに変わるので名前順に並べる
+    #[derive(Default)]
+    struct TypescriptDeclarations {
+        declarations: std::collections::BTreeMap<String, String>,
+    }
+
+    impl ts_rs::TypeVisitor for TypescriptDeclarations {
+        fn visit<T: ts_rs::TS + 'static + ?Sized>(&mut self) {
+            // `Option` や `Vec` は宣言を持たないので、中の型だけたどる
+            if T::output_path().is_none() {
+                T::visit_generics(self);
+                return;
+            }
+            if !self.declarations.contains_key(&T::ident()) {
+                self.declarations.insert(T::ident(), T::decl());
+                T::visit_dependencies(self);
+            }
+        }
+    }
+
+    /// UI に渡す型。`manager/src/lib/generated/manager.ts` と一致させる
+    fn typescript_declarations() -> String {
+        use ts_rs::TypeVisitor;
+
+        let mut declarations = TypescriptDeclarations::default();
+        declarations.visit::<AppSnapshot>();
+        declarations.visit::<DcsBiosCommandRequest>();
+        declarations.visit::<AxisCalibrationStep>();
+        declarations.visit::<AxisCalibrationUpdate>();
+
+        let mut output = String::from(
+            "// manager の型から生成したファイル。編集せず、`typescript_bindings_match_ui_types` が失敗したときに表示する内容で置き換える\n",
+        );
+        for declaration in declarations.declarations.values() {
+            output.push_str(&format!("\nexport {declaration}\n"));
+        }
+        output
+    }
+
+    #[test]
+    fn typescript_bindings_match_ui_types() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../src/lib/generated/manager.ts");
+        let expected = typescript_declarations();
+        let actual = fs::read_to_string(&path).unwrap_or_default();
+        if actual != expected {
+            println!("{expected}");
+        }
+        assert!(
+            actual == expected,
+            "{} is out of date. Replace it with the declarations printed above",
+            path.display()
+        );
+    }
 }
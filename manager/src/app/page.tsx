@@ -22,10 +22,13 @@ export default function ManagerTabs() {
         runtimeError,
         busyAction,
         serialPorts,
+        axisCalibrations,
         saveConfig,
         startDcsBios,
         stopDcsBios,
         refreshDevices,
+        identifyDevice,
+        calibrateAxis,
         saveDeviceEndpoints,
         saveDeviceRoleAssignments,
         saveRoleMappings,
@@ -60,6 +63,9 @@ export default function ManagerTabs() {
                     serialPorts={serialPorts}
                     busyAction={busyAction}
                     onRefresh={refreshDevices}
+                    axisCalibrations={axisCalibrations}
+                    onIdentify={identifyDevice}
+                    onCalibrateAxis={calibrateAxis}
                     onSaveEndpoints={saveDeviceEndpoints}
                     onSaveDeviceRoleAssignments={saveDeviceRoleAssignments}
                 />
@@ -74,6 +80,7 @@ export default function ManagerTabs() {
                     devices={snapshot.devices}
                     deviceRoleAssignments={snapshot.deviceRoleAssignments}
                     roleMappings={snapshot.roleMappings}
+                    controlCatalogs={snapshot.controlCatalogs}
                     busyAction={busyAction}
                     onSaveRoleMappings={saveRoleMappings}
                 />
@@ -1,10 +1,12 @@
 "use client";
 
 import { useEffect, useMemo, useState } from "react";
-import { Cable, Cpu, Plus, RefreshCw, Trash2, Waypoints } from "lucide-react";
+import { Cable, Cpu, Gauge, Lightbulb, Plus, RefreshCw, Trash2, Waypoints } from "lucide-react";
 
 import { deviceRoleLabels } from "@/lib/control-catalog";
 import type {
+  AxisCalibrationStep,
+  AxisCalibrationUpdate,
   DeviceRole,
   DeviceRoleAssignment,
   DeviceEndpointConfig,
@@ -18,7 +20,14 @@ type DeviceSettingsProps = {
   deviceRoleAssignments: DeviceRoleAssignment[];
   serialPorts: string[];
   busyAction: string | null;
+  axisCalibrations: Record<string, AxisCalibrationUpdate>;
   onRefresh: () => Promise<void>;
+  onIdentify: (device: ManagedDeviceSummary, seconds: number) => Promise<void>;
+  onCalibrateAxis: (
+    device: ManagedDeviceSummary,
+    controlId: number,
+    step: AxisCalibrationStep,
+  ) => Promise<void>;
   onSaveEndpoints: (deviceEndpoints: DeviceEndpointConfig[]) => Promise<void>;
   onSaveDeviceRoleAssignments: (deviceRoleAssignments: DeviceRoleAssignment[]) => Promise<void>;
 };
@@ -43,13 +52,154 @@ const roleHintLabels: Record<EndpointRoleHint, string> = {
 
 const baudRateOptions = [9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600];
 
+// Features に表示される FEATURE_IDENTIFY の名前と、点滅させる秒数
+const identifyFeature = "identify";
+const identifySeconds = 10;
+
+// Features に表示される FEATURE_AXIS_CALIBRATION の名前
+const axisCalibrationFeature = "axis-calibration";
+
+const calibrationErrorLabels: Record<string, string> = {
+  "unknown-control": "この ID の軸はありません",
+  "not-recording": "記録を開始していません",
+  "invalid-range": "範囲が狭すぎるか、中心が範囲の外です",
+};
+
+type AxisCalibrationPanelProps = {
+  device: ManagedDeviceSummary;
+  axisCalibrations: Record<string, AxisCalibrationUpdate>;
+  busyAction: string | null;
+  onCalibrateAxis: DeviceSettingsProps["onCalibrateAxis"];
+};
+
+// 軸を端から端まで動かして範囲を記録するウィザード
+const AxisCalibrationPanel = ({
+  device,
+  axisCalibrations,
+  busyAction,
+  onCalibrateAxis,
+}: AxisCalibrationPanelProps) => {
+  const [controlIdText, setControlIdText] = useState("");
+  const [deadzoneText, setDeadzoneText] = useState("");
+
+  const controlId = /^(0x)?[0-9a-f]{1,4}$/i.test(controlIdText.trim())
+    ? Number.parseInt(controlIdText.trim().replace(/^0x/i, ""), 16)
+    : null;
+  const update =
+    controlId === null ? undefined : axisCalibrations[`${device.deviceId}:${controlId}`];
+  const deadzone = deadzoneText.trim() === "" ? null : Number(deadzoneText);
+  const disabled = busyAction !== null || controlId === null;
+
+  const send = (step: AxisCalibrationStep) => {
+    if (controlId !== null) {
+      void onCalibrateAxis(device, controlId, step);
+    }
+  };
+
+  const applyDeadzone = () => {
+    if (
+      !update ||
+      deadzone === null ||
+      !Number.isInteger(deadzone) ||
+      deadzone < 0 ||
+      deadzone > 0xffff
+    ) {
+      return;
+    }
+    send({ step: "set", min: update.min, center: update.center, max: update.max, deadzone });
+  };
+
+  const buttonClass =
+    "rounded-md border border-gray-300 bg-white px-3 py-1 text-xs text-gray-700 transition hover:bg-gray-50 disabled:cursor-not-allowed disabled:opacity-60";
+
+  return (
+    <div className="rounded-lg border border-gray-200 bg-gray-50 px-4 py-3">
+      <div className="flex items-center gap-2 font-medium">
+        <Gauge size={14} />
+        軸のキャリブレーション
+      </div>
+      <p className="mt-1 text-xs text-gray-500">
+        軸を中央に置いて開始し、端から端まで動かしてから確定します。
+      </p>
+      <div className="mt-3 flex flex-wrap items-center gap-2">
+        <input
+          value={controlIdText}
+          onChange={(event) => setControlIdText(event.target.value)}
+          placeholder="Control ID (0x0301)"
+          className="w-40 rounded-md border border-gray-300 bg-white px-3 py-1 text-xs outline-none transition focus:border-blue-500"
+        />
+        <button type="button" disabled={disabled} onClick={() => send({ step: "get" })} className={buttonClass}>
+          読込
+        </button>
+        <button type="button" disabled={disabled} onClick={() => send({ step: "begin" })} className={buttonClass}>
+          開始
+        </button>
+        <button
+          type="button"
+          disabled={disabled || !update?.recording}
+          onClick={() => send({ step: "capture-center" })}
+          className={buttonClass}
+        >
+          中心を記録
+        </button>
+        <button
+          type="button"
+          disabled={disabled || !update?.recording}
+          onClick={() => send({ step: "commit" })}
+          className={buttonClass}
+        >
+          確定
+        </button>
+        <button
+          type="button"
+          disabled={disabled || !update?.recording}
+          onClick={() => send({ step: "cancel" })}
+          className={buttonClass}
+        >
+          中止
+        </button>
+      </div>
+      {update && (
+        <div className="mt-3 grid gap-1 text-xs text-gray-600">
+          <div className="flex justify-between">
+            <span>{update.recording ? "記録中" : "現在の範囲"}</span>
+            <span>
+              {update.min} / {update.center} / {update.max} (raw {update.raw})
+            </span>
+          </div>
+          {update.error && (
+            <div className="text-red-600">{calibrationErrorLabels[update.error] ?? update.error}</div>
+          )}
+          {!update.recording && (
+            <div className="mt-1 flex items-center gap-2">
+              <span>デッドゾーン</span>
+              <input
+                value={deadzoneText}
+                onChange={(event) => setDeadzoneText(event.target.value)}
+                placeholder={String(update.deadzone)}
+                className="w-24 rounded-md border border-gray-300 bg-white px-2 py-1 outline-none transition focus:border-blue-500"
+              />
+              <button type="button" disabled={disabled} onClick={applyDeadzone} className={buttonClass}>
+                適用
+              </button>
+            </div>
+          )}
+        </div>
+      )}
+    </div>
+  );
+};
+
 const DeviceSettings = ({
   devices,
   deviceEndpoints,
   deviceRoleAssignments,
   serialPorts,
   busyAction,
+  axisCalibrations,
   onRefresh,
+  onIdentify,
+  onCalibrateAxis,
   onSaveEndpoints,
   onSaveDeviceRoleAssignments,
 }: DeviceSettingsProps) => {
@@ -376,9 +526,23 @@ const DeviceSettings = ({
                         )}
                       </div>
                     </div>
-                    <span className="rounded-full border border-gray-200 bg-gray-100 px-3 py-1 text-xs text-gray-700">
-                      {device.state}
-                    </span>
+                    <div className="flex items-center gap-2">
+                      {device.features?.split(", ").includes(identifyFeature) && (
+                        <button
+                          type="button"
+                          onClick={() => void onIdentify(device, identifySeconds)}
+                          disabled={busyAction !== null}
+                          title={`${identifySeconds} 秒間 LED を点滅させる`}
+                          className="inline-flex items-center gap-1 rounded-md border border-gray-300 bg-white px-3 py-1 text-xs text-gray-700 transition hover:bg-gray-50 disabled:cursor-not-allowed disabled:opacity-60"
+                        >
+                          <Lightbulb size={14} />
+                          識別
+                        </button>
+                      )}
+                      <span className="rounded-full border border-gray-200 bg-gray-100 px-3 py-1 text-xs text-gray-700">
+                        {device.state}
+                      </span>
+                    </div>
                   </div>
 
                   <div className="mt-5 grid gap-3 text-sm text-gray-700">
@@ -460,6 +624,14 @@ const DeviceSettings = ({
                       <span>Features</span>
                       <span className="truncate pl-4 text-right">{device.features ?? "Unknown"}</span>
                     </div>
+                    {device.features?.split(", ").includes(axisCalibrationFeature) && (
+                      <AxisCalibrationPanel
+                        device={device}
+                        axisCalibrations={axisCalibrations}
+                        busyAction={busyAction}
+                        onCalibrateAxis={onCalibrateAxis}
+                      />
+                    )}
                   </div>
                 </section>
               ))
@@ -5,18 +5,22 @@ import { ArrowRightLeft, ChevronRight, Plus, Server, Trash2 } from "lucide-react
 
 import { deviceRoleLabels, getControlCatalog } from "@/lib/control-catalog";
 import type {
+  DeviceControlCatalog,
   DeviceRole,
   DeviceRoleAssignment,
   ManagedDeviceSummary,
   NormalizedControlEvent,
+  PwmTargetKind,
   RoleControlMapping,
   RoleMappingConfig,
+  RoleOutputMapping,
 } from "@/lib/manager-types";
 
 type MappingSettingsProps = {
   devices: ManagedDeviceSummary[];
   deviceRoleAssignments: DeviceRoleAssignment[];
   roleMappings: RoleMappingConfig[];
+  controlCatalogs: DeviceControlCatalog[];
   busyAction: string | null;
   onSaveRoleMappings: (roleMappings: RoleMappingConfig[]) => Promise<void>;
 };
@@ -30,15 +34,44 @@ const emptyDraft = () => ({
   argument: "",
 });
 
+const pwmTargetLabels: Record<PwmTargetKind, string> = {
+  backlight: "バックライト",
+  lamp: "表示灯",
+};
+
+// DCS-BIOS の integer 出力の address / mask / shift_by / max_value をそのまま入力する
+const emptyOutputDraft = () => ({
+  targetKind: "backlight" as PwmTargetKind,
+  channel: "0",
+  address: "",
+  mask: "0xFFFF",
+  shiftBy: "0",
+  maxValue: "65535",
+  fadeMs: "200",
+});
+
+// 0x で始まる 16 進数も受け付ける
+const parseU16 = (value: string) => {
+  if (!value.trim()) {
+    return null;
+  }
+  const parsed = Number(value.trim());
+  return Number.isInteger(parsed) && parsed >= 0 && parsed <= 0xffff ? parsed : null;
+};
+
+const formatHex = (value: number) => `0x${value.toString(16).toUpperCase().padStart(4, "0")}`;
+
 export function MappingSettings({
   devices,
   deviceRoleAssignments,
   roleMappings,
+  controlCatalogs,
   busyAction,
   onSaveRoleMappings,
 }: MappingSettingsProps) {
   const [selectedRole, setSelectedRole] = useState<DeviceRole>("left-ddi");
   const [draft, setDraft] = useState(emptyDraft);
+  const [outputDraft, setOutputDraft] = useState(emptyOutputDraft);
 
   const assignedDevice = useMemo(() => {
     const assignment = deviceRoleAssignments.find((entry) => entry.role === selectedRole);
@@ -50,19 +83,72 @@ export function MappingSettings({
   }, [deviceRoleAssignments, devices, selectedRole]);
 
   const availableControls = useMemo(
-    () => getControlCatalog(assignedDevice?.deviceKindId ?? null),
-    [assignedDevice?.deviceKindId],
+    () => getControlCatalog(controlCatalogs, assignedDevice?.deviceKindId ?? null),
+    [controlCatalogs, assignedDevice?.deviceKindId],
   );
 
   const roleMapping = roleMappings.find((entry) => entry.role === selectedRole);
   const mappings = roleMapping?.mappings ?? [];
+  const outputs = roleMapping?.outputs ?? [];
   const selectedControl = availableControls.find(
     (control) => control.controlId === Number(draft.controlId),
   );
 
-  const saveMappingsForRole = async (nextMappings: RoleControlMapping[]) => {
+  const saveRoleMapping = async (
+    nextMappings: RoleControlMapping[],
+    nextOutputs: RoleOutputMapping[],
+  ) => {
     const remaining = roleMappings.filter((entry) => entry.role !== selectedRole);
-    await onSaveRoleMappings([...remaining, { role: selectedRole, mappings: nextMappings }]);
+    await onSaveRoleMappings([
+      ...remaining,
+      { role: selectedRole, mappings: nextMappings, outputs: nextOutputs },
+    ]);
+  };
+
+  const saveMappingsForRole = async (nextMappings: RoleControlMapping[]) => {
+    await saveRoleMapping(nextMappings, outputs);
+  };
+
+  const handleAddOutput = async () => {
+    const channel = parseU16(outputDraft.channel);
+    const address = parseU16(outputDraft.address);
+    const mask = parseU16(outputDraft.mask);
+    const shiftBy = parseU16(outputDraft.shiftBy);
+    const maxValue = parseU16(outputDraft.maxValue);
+    const fadeMs = parseU16(outputDraft.fadeMs);
+    if (
+      channel === null ||
+      address === null ||
+      mask === null ||
+      shiftBy === null ||
+      maxValue === null ||
+      fadeMs === null
+    ) {
+      return;
+    }
+
+    const nextOutputs = [
+      ...outputs.filter(
+        (entry) => !(entry.targetKind === outputDraft.targetKind && entry.channel === channel),
+      ),
+      {
+        id: crypto.randomUUID(),
+        targetKind: outputDraft.targetKind,
+        channel,
+        address,
+        mask,
+        shiftBy,
+        maxValue,
+        fadeMs,
+      },
+    ];
+
+    await saveRoleMapping(mappings, nextOutputs);
+    setOutputDraft(emptyOutputDraft());
+  };
+
+  const handleDeleteOutput = async (outputId: string) => {
+    await saveRoleMapping(mappings, outputs.filter((entry) => entry.id !== outputId));
   };
 
   const handleAddMapping = async () => {
@@ -189,6 +275,9 @@ export function MappingSettings({
                     <div className="rounded-full border border-gray-200 bg-white px-4 py-2 text-sm text-gray-700">
                       {mappings.length} mapping(s)
                     </div>
+                    <div className="rounded-full border border-gray-200 bg-white px-4 py-2 text-sm text-gray-700">
+                      {outputs.length} output(s)
+                    </div>
                   </div>
                 </div>
 
@@ -321,6 +410,151 @@ export function MappingSettings({
                     )}
                   </div>
                 </section>
+
+                <section className="rounded-lg border border-gray-200 bg-white p-6 shadow-sm">
+                  <h3 className="text-xl font-semibold text-gray-900">PWM 出力</h3>
+                  <p className="mt-1 text-sm text-gray-500">
+                    DCS-BIOS の integer 出力の値で、バックライトや表示灯の明るさを変えます。
+                  </p>
+                  <div className="mt-5 grid gap-4 lg:grid-cols-[160px_repeat(6,minmax(0,1fr))_120px]">
+                    <label className="space-y-2 text-sm text-gray-700">
+                      <span>Target</span>
+                      <select
+                        value={outputDraft.targetKind}
+                        onChange={(event) =>
+                          setOutputDraft((current) => ({
+                            ...current,
+                            targetKind: event.target.value as PwmTargetKind,
+                          }))
+                        }
+                        className="w-full rounded-md border border-gray-300 bg-white px-3 py-2 outline-none transition focus:border-blue-500"
+                      >
+                        {Object.entries(pwmTargetLabels).map(([kind, label]) => (
+                          <option key={kind} value={kind}>
+                            {label}
+                          </option>
+                        ))}
+                      </select>
+                    </label>
+
+                    <label className="space-y-2 text-sm text-gray-700">
+                      <span>Channel</span>
+                      <input
+                        value={outputDraft.channel}
+                        onChange={(event) =>
+                          setOutputDraft((current) => ({ ...current, channel: event.target.value }))
+                        }
+                        placeholder="0"
+                        className="w-full rounded-md border border-gray-300 bg-white px-3 py-2 outline-none transition focus:border-blue-500"
+                      />
+                    </label>
+
+                    <label className="space-y-2 text-sm text-gray-700">
+                      <span>Address</span>
+                      <input
+                        value={outputDraft.address}
+                        onChange={(event) =>
+                          setOutputDraft((current) => ({ ...current, address: event.target.value }))
+                        }
+                        placeholder="0x7456"
+                        className="w-full rounded-md border border-gray-300 bg-white px-3 py-2 outline-none transition focus:border-blue-500"
+                      />
+                    </label>
+
+                    <label className="space-y-2 text-sm text-gray-700">
+                      <span>Mask</span>
+                      <input
+                        value={outputDraft.mask}
+                        onChange={(event) =>
+                          setOutputDraft((current) => ({ ...current, mask: event.target.value }))
+                        }
+                        placeholder="0xFFFF"
+                        className="w-full rounded-md border border-gray-300 bg-white px-3 py-2 outline-none transition focus:border-blue-500"
+                      />
+                    </label>
+
+                    <label className="space-y-2 text-sm text-gray-700">
+                      <span>Shift</span>
+                      <input
+                        value={outputDraft.shiftBy}
+                        onChange={(event) =>
+                          setOutputDraft((current) => ({ ...current, shiftBy: event.target.value }))
+                        }
+                        placeholder="0"
+                        className="w-full rounded-md border border-gray-300 bg-white px-3 py-2 outline-none transition focus:border-blue-500"
+                      />
+                    </label>
+
+                    <label className="space-y-2 text-sm text-gray-700">
+                      <span>Max</span>
+                      <input
+                        value={outputDraft.maxValue}
+                        onChange={(event) =>
+                          setOutputDraft((current) => ({ ...current, maxValue: event.target.value }))
+                        }
+                        placeholder="65535"
+                        className="w-full rounded-md border border-gray-300 bg-white px-3 py-2 outline-none transition focus:border-blue-500"
+                      />
+                    </label>
+
+                    <label className="space-y-2 text-sm text-gray-700">
+                      <span>Fade (ms)</span>
+                      <input
+                        value={outputDraft.fadeMs}
+                        onChange={(event) =>
+                          setOutputDraft((current) => ({ ...current, fadeMs: event.target.value }))
+                        }
+                        placeholder="200"
+                        className="w-full rounded-md border border-gray-300 bg-white px-3 py-2 outline-none transition focus:border-blue-500"
+                      />
+                    </label>
+
+                    <button
+                      type="button"
+                      onClick={() => void handleAddOutput()}
+                      disabled={busyAction !== null}
+                      className="inline-flex h-11 items-center justify-center gap-2 self-end rounded-md bg-blue-600 px-4 text-sm font-medium text-white transition hover:bg-blue-700 disabled:cursor-not-allowed disabled:opacity-60"
+                    >
+                      <Plus size={16} />
+                      追加
+                    </button>
+                  </div>
+
+                  <div className="mt-5 space-y-3">
+                    {outputs.length === 0 ? (
+                      <div className="rounded-lg border border-dashed border-gray-300 bg-gray-50 p-6 text-sm text-gray-500">
+                        このロールの PWM 出力はまだありません。
+                      </div>
+                    ) : (
+                      outputs.map((output) => (
+                        <div
+                          key={output.id}
+                          className="grid gap-4 rounded-lg border border-gray-200 bg-gray-50 p-4 lg:grid-cols-[minmax(0,1fr)_minmax(0,1fr)_180px_48px]"
+                        >
+                          <p className="font-medium text-gray-900">
+                            {pwmTargetLabels[output.targetKind]} {output.channel}
+                          </p>
+                          <div className="rounded-md border border-gray-300 bg-white px-3 py-2 text-sm text-gray-700">
+                            {formatHex(output.address)} &amp; {formatHex(output.mask)} &gt;&gt;{" "}
+                            {output.shiftBy} / {output.maxValue}
+                          </div>
+                          <div className="rounded-md border border-gray-300 bg-white px-3 py-2 text-sm text-gray-700">
+                            fade {output.fadeMs} ms
+                          </div>
+                          <button
+                            type="button"
+                            onClick={() => void handleDeleteOutput(output.id)}
+                            disabled={busyAction !== null}
+                            className="inline-flex h-10 items-center justify-center rounded-md border border-red-200 bg-red-50 text-red-700 transition hover:bg-red-100 disabled:cursor-not-allowed disabled:opacity-60"
+                            aria-label="PWM 出力を削除"
+                          >
+                            <Trash2 size={16} />
+                          </button>
+                        </div>
+                      ))
+                    )}
+                  </div>
+                </section>
               </div>
             )}
           </section>
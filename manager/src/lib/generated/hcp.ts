@@ -0,0 +1,127 @@
+// hcp の型から生成したファイル。編集せず、`typescript_bindings_match_manager_ui` が失敗したときに表示する内容で置き換える
+
+export type AxisCalibration = { min: number, 
+/**
+ * 0 に換算する位置。`min` にするとスロットルのような片側だけの軸になる
+ */
+center: number, max: number, 
+/**
+ * `center` から前後この幅までを 0 にする
+ */
+deadzone: number, };
+
+export type AxisCalibrationStatus = { control_id: number, 
+/**
+ * フィルター後の今の生の値
+ */
+raw: number, recording: boolean, 
+/**
+ * 記録中はここまでに記録した範囲、それ以外は使っている範囲
+ */
+calibration: AxisCalibration, error: CalibrationError | null, };
+
+export type BitmapCompression = "Raw" | "Rle" | "XorRle";
+
+export type BitmapUpdate = { region: Region, compression: BitmapCompression, data: Array<number>, };
+
+export type ByteEncoding = "MonoBitmap1bpp" | "SegmentMap" | "Utf8Text";
+
+export type CalibrationCommand = "Begin" | "CaptureCenter" | "Commit" | "Cancel" | "Get" | { "Set": AxisCalibration };
+
+export type CalibrationError = "UnknownControl" | "NotRecording" | "InvalidRange";
+
+export type Capabilities = { displays: number, controls: number, features: number, };
+
+export type ControlDescriptor = { id: number, kind: ControlKind, events: SupportedEvents, label: string, };
+
+export type ControlEvent = { seq: number, control_id: number, event: ControlValue, };
+
+export type ControlKind = "Button" | "Toggle" | "Encoder" | "Axis";
+
+export type ControlValue = { "Button": { pressed: boolean, } } | { "EncoderDelta": { steps: number, } } | { "Absolute": { value: number, } } | { "Toggle": { state: boolean, } } | "RequestDeviceHello";
+
+export type DeviceHello = { device_id: bigint, device_kind: DeviceKind, 
+/**
+ * 対応する最も新しいバージョン
+ */
+protocol_version: number, 
+/**
+ * 対応する最も古いバージョン。v1 の layout では送られず 1 になる
+ */
+min_protocol_version: number, firmware_version: Version, capabilities: Capabilities, };
+
+export type DeviceKind = "UpperPanelDdi" | "ButtonPanel" | "ImcpHub" | { "Unknown": number };
+
+export type DisplayCommand = "Clear" | { "ClearRegion": Region } | { "Text": { row: number, column: number, font: FontId, attributes: TextAttributes, content: string, } } | { "Box": { region: Region, filled: boolean, } } | { "Line": { from: Point, to: Point, } };
+
+export type DisplayData = { seq: number, target: DisplayTarget, payload: DisplayPayload, };
+
+export type DisplayDescriptor = { target: DisplayTarget, kind: DisplayKind, width: number, height: number, };
+
+export type DisplayKind = "MonoBitmap" | "CharacterGrid" | "Indicator" | { "Segments": SegmentKind };
+
+export type DisplayPayload = { "Text": { format: TextFormat, content: string, } } | { "Bytes": { encoding: ByteEncoding, data: Array<number>, } } | { "Commands": Array<DisplayCommand> } | { "Bitmap": BitmapUpdate } | { "Indicator": IndicatorState } | { "Segments": SegmentText } | { "Pwm": PwmLevel };
+
+export type DisplayTarget = { "Screen": number } | { "Indicator": number } | { "Backlight": number };
+
+export type FontId = number;
+
+export type IndicatorState = { mode: LampMode, 
+/**
+ * 点灯時の明るさ。`u8::MAX` が最大
+ */
+brightness: number, };
+
+export type LampMode = "Off" | "On" | { "Blink": { period_ms: number, } };
+
+export type LogEntry = { level: LogLevel, 
+/**
+ * このログの前に送れずに捨てたログの数
+ */
+dropped: number, tag: string, text: string, };
+
+export type LogLevel = "Error" | "Warn" | "Info" | "Debug" | "Trace";
+
+export type Point = { x: number, y: number, };
+
+export type PwmLevel = { 
+/**
+ * `u8::MAX` が最大
+ */
+brightness: number, 
+/**
+ * 今の明るさから `brightness` まで変えるのにかける時間。0 ならすぐに変える
+ */
+fade_ms: number, };
+
+export type Region = { x: number, y: number, width: number, height: number, };
+
+export type SegmentDigit = { 
+/**
+ * ASCII 文字
+ */
+character: number, decimal_point: boolean, };
+
+export type SegmentKind = "Seven" | "Fourteen" | "Sixteen";
+
+export type SegmentText = { kind: SegmentKind, 
+/**
+ * 左の桁から順に並べる
+ */
+digits: Array<SegmentDigit>, };
+
+export type SupportedEvents = number;
+
+export type TextAttributes = { 
+/**
+ * 背景と文字色を反転する
+ */
+inverse: boolean, 
+/**
+ * 点滅表示する (周期はデバイス側で決める)
+ */
+blink: boolean, };
+
+export type TextFormat = "Plain";
+
+export type Version = { major: number, minor: number, patch: number, };
@@ -0,0 +1,45 @@
+// manager の型から生成したファイル。編集せず、`typescript_bindings_match_ui_types` が失敗したときに表示する内容で置き換える
+
+export type AppSnapshot = { dcsbiosConfig: DcsBiosConnectionConfig, dcsbiosStatus: DcsBiosStatus, logs: Array<ManagerLogEntry>, devices: Array<ManagedDeviceSummary>, deviceEndpoints: Array<DeviceEndpointConfig>, deviceRoleAssignments: Array<DeviceRoleAssignment>, roleMappings: Array<RoleMappingConfig>, controlCatalogs: Array<DeviceControlCatalog>, };
+
+export type AxisCalibrationStep = { "step": "begin" } | { "step": "capture-center" } | { "step": "commit" } | { "step": "cancel" } | { "step": "get" } | { "step": "set", min: number, center: number, max: number, deadzone: number, };
+
+export type AxisCalibrationUpdate = { deviceId: string, controlId: number, raw: number, recording: boolean, min: number, center: number, max: number, deadzone: number, error: string | null, };
+
+export type CommandTransport = "udp" | "tcp";
+
+export type ControlCatalogEntry = { controlId: number, label: string, description: string, supportedEvents: Array<NormalizedControlEvent>, };
+
+export type DcsBiosCommandRequest = { rawCommand?: string | null, controlId?: string | null, argument?: string | null, };
+
+export type DcsBiosConnectionConfig = { exportHost: string, exportPort: number, commandHost: string, commandPort: number, commandTransport: CommandTransport, };
+
+export type DcsBiosMappedAction = { identifier: string, argument: string, };
+
+export type DcsBiosStatus = { connectionState: string, lastSeenAt: string | null, lastPacketAt: string | null, packetsPerSecond: number, totalPackets: number, aircraftName: string | null, error: string | null, diagnostics: Array<string>, };
+
+export type DeviceControlCatalog = { deviceKindId: string, controls: Array<ControlCatalogEntry>, };
+
+export type DeviceEndpointConfig = { id: string, name: string, transport: DeviceEndpointTransport, address: string, enabled: boolean, baudRate: number, roleHint: EndpointRoleHint, };
+
+export type DeviceEndpointTransport = "serial";
+
+export type DeviceRole = "left-ddi" | "right-ddi";
+
+export type DeviceRoleAssignment = { deviceId: string, role: DeviceRole, };
+
+export type EndpointRoleHint = "auto" | "direct-device" | "imcp-hub";
+
+export type ManagedDeviceSummary = { id: string, connectionKind: string, gatewayId: string | null, gatewayDisplayName: string | null, endpointId: string, endpointName: string, endpointTransport: string, endpointAddress: string, displayName: string, firmwareVersion: string | null, state: string, protocol: string, assignedAddress: number | null, deviceKind: string | null, deviceKindId: string | null, protocolVersion: number | null, deviceId: string | null, displays: number | null, controls: number | null, features: string | null, };
+
+export type ManagerLogEntry = { id: number, at: string, level: string, source: string, message: string, };
+
+export type NormalizedControlEvent = "BUTTON_DOWN" | "BUTTON_UP" | "BUTTON_PUSHED" | "ENCODER_DELTA" | "ABSOLUTE_CHANGED" | "TOGGLE_ON" | "TOGGLE_OFF";
+
+export type PwmTargetKind = "backlight" | "lamp";
+
+export type RoleControlMapping = { id: string, controlId: number, inputEvent: NormalizedControlEvent, action: DcsBiosMappedAction, };
+
+export type RoleMappingConfig = { role: DeviceRole, mappings: Array<RoleControlMapping>, outputs: Array<RoleOutputMapping>, };
+
+export type RoleOutputMapping = { id: string, targetKind: PwmTargetKind, channel: number, address: number, mask: number, shiftBy: number, maxValue: number, fadeMs: number, };
@@ -8,6 +8,8 @@ import { isTauri } from "@/lib/is-tauri";
 import {
   defaultSnapshot,
   type AppSnapshot,
+  type AxisCalibrationStep,
+  type AxisCalibrationUpdate,
   type DcsBiosCommandRequest,
   type DcsBiosConnectionConfig,
   type DcsBiosStatus,
@@ -23,6 +25,10 @@ export function useManagerState() {
   const [runtimeError, setRuntimeError] = useState<string | null>(null);
   const [busyAction, setBusyAction] = useState<string | null>(null);
   const [serialPorts, setSerialPorts] = useState<string[]>([]);
+  // デバイス ID と操作系 ID ごとの、最後に届いたキャリブレーションの状態
+  const [axisCalibrations, setAxisCalibrations] = useState<
+    Record<string, AxisCalibrationUpdate>
+  >({});
 
   const replaceSnapshot = useCallback((next: AppSnapshot) => {
     setSnapshot(next);
@@ -56,6 +62,13 @@ export function useManagerState() {
     setSnapshot((current) => ({ ...current, roleMappings }));
   }, []);
 
+  const mergeAxisCalibration = useCallback((update: AxisCalibrationUpdate) => {
+    setAxisCalibrations((current) => ({
+      ...current,
+      [`${update.deviceId}:${update.controlId}`]: update,
+    }));
+  }, []);
+
   const refreshSnapshot = useCallback(async () => {
     if (!isTauri()) {
       return;
@@ -145,6 +158,49 @@ export function useManagerState() {
     }
   }, [mergeDevices, runAction]);
 
+  const identifyDevice = useCallback(
+    async (device: ManagedDeviceSummary, seconds: number) => {
+      if (!isTauri() || !device.deviceId) {
+        return;
+      }
+
+      try {
+        await runAction("identify-device", () =>
+          invoke("identify_device", {
+            endpointId: device.endpointId,
+            deviceId: device.deviceId,
+            seconds,
+          }),
+        );
+      } catch (error) {
+        setRuntimeError(String(error));
+      }
+    },
+    [runAction],
+  );
+
+  const calibrateAxis = useCallback(
+    async (device: ManagedDeviceSummary, controlId: number, step: AxisCalibrationStep) => {
+      if (!isTauri() || !device.deviceId) {
+        return;
+      }
+
+      try {
+        await runAction("calibrate-axis", () =>
+          invoke("calibrate_axis", {
+            endpointId: device.endpointId,
+            deviceId: device.deviceId,
+            controlId,
+            step,
+          }),
+        );
+      } catch (error) {
+        setRuntimeError(String(error));
+      }
+    },
+    [runAction],
+  );
+
   const saveDeviceEndpoints = useCallback(
     async (deviceEndpoints: DeviceEndpointConfig[]) => {
       if (!isTauri()) {
@@ -276,6 +332,11 @@ export function useManagerState() {
             mergeRoleMappings(event.payload);
           }
         }),
+        listen<AxisCalibrationUpdate>("axis-calibration-changed", (event) => {
+          if (!disposed) {
+            mergeAxisCalibration(event.payload);
+          }
+        }),
       ]);
 
       unlistenFns = listeners;
@@ -290,6 +351,7 @@ export function useManagerState() {
       }
     };
   }, [
+    mergeAxisCalibration,
     mergeDeviceEndpoints,
     mergeDeviceRoleAssignments,
     mergeDevices,
@@ -305,10 +367,13 @@ export function useManagerState() {
     runtimeError,
     busyAction,
     serialPorts,
+    axisCalibrations,
     saveConfig,
     startDcsBios,
     stopDcsBios,
     refreshDevices,
+    identifyDevice,
+    calibrateAxis,
     saveDeviceEndpoints,
     saveDeviceRoleAssignments,
     saveRoleMappings,
@@ -1,16 +1,21 @@
 use std::{
-    io::{self, BufRead, IsTerminal},
-    process,
-    time::Duration,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, IsTerminal},
+    path::PathBuf,
+    process, thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum, command};
 use imcp::{
+    capture::{CaptureLink, CaptureReader, CaptureWriter, Direction, replay_into_parser},
     frame::{Address, Frame, FramePayload},
     parser::FrameParser,
 };
 use log::LevelFilter;
 
+mod update;
+
 #[derive(Parser, Debug)]
 #[command(version, about = "imcp-cli", long_about = None)]
 struct GlobalOptions {
@@ -29,6 +34,12 @@ enum Commands {
     Unpack(UnpackArgs),
     /// watch: シリアルポートを監視し、受信データをunpackします。
     Watch(WatchArgs),
+    /// record: シリアルポートの受信データを pcapng ファイルに記録します。
+    Record(RecordArgs),
+    /// replay: 記録した pcapng ファイルを再生します。
+    Replay(ReplayArgs),
+    /// update: HCP でデバイスのファームウェアを更新します。
+    Update(UpdateArgs),
 }
 
 #[derive(Args, Debug)]
@@ -47,6 +58,9 @@ struct PackArgs {
 
     #[arg(long)]
     id: Option<u32>,
+    /// join: 指定した場合は安定したデバイス ID 付きの Join を作成します。
+    #[arg(long, value_parser=clap_num::maybe_hex::<u64>)]
+    device_id: Option<u64>,
     #[arg(long)]
     address: Option<u8>,
     #[arg(long)]
@@ -73,6 +87,97 @@ struct WatchArgs {
     list: bool,
 }
 
+#[derive(Args, Debug)]
+struct RecordArgs {
+    /// 記録するシリアルポート
+    #[arg(short, long)]
+    port: String,
+
+    /// ボーレート (デフォルト: 9600)
+    #[arg(short, long, default_value_t = 9600)]
+    baud: u32,
+
+    /// 出力先の pcapng ファイル
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// 生データに加えてデコードしたフレームも記録する
+    #[arg(long)]
+    frames: bool,
+}
+
+#[derive(Args, Debug)]
+struct ReplayArgs {
+    /// 再生する pcapng ファイル
+    file: PathBuf,
+
+    /// 再生するレコードの種類 (デフォルト: raw)
+    #[arg(long, value_enum, default_value_t = ReplayLink::Raw)]
+    link: ReplayLink,
+
+    /// 指定した場合、記録したバイト列をこのシリアルポートへ送信する
+    #[arg(short, long)]
+    port: Option<String>,
+
+    /// ボーレート (デフォルト: 9600)
+    #[arg(short, long, default_value_t = 9600)]
+    baud: u32,
+
+    /// 記録時のタイムスタンプ間隔に合わせて送信する
+    #[arg(long)]
+    realtime: bool,
+}
+
+#[derive(Args, Debug)]
+struct UpdateArgs {
+    /// デバイスを接続したシリアルポート
+    #[arg(short, long)]
+    port: String,
+
+    /// ボーレート (デフォルト: 9600)
+    #[arg(short, long, default_value_t = 9600)]
+    baud: u32,
+
+    /// 書き込むファームウェアイメージ
+    image: PathBuf,
+
+    /// 既にアドレスが割り当てられているデバイスのアドレス。省略時は Join を待つ
+    #[arg(short, long, value_parser=clap_num::maybe_hex::<u8>)]
+    address: Option<u8>,
+
+    /// Join したデバイスに割り当てるアドレス
+    #[arg(long, value_parser=clap_num::maybe_hex::<u8>, default_value_t = 0x02)]
+    assign: u8,
+
+    /// 1 回に送るイメージの長さ
+    #[arg(long, default_value_t = hcp::MAX_UPDATE_CHUNK_LEN)]
+    chunk_len: usize,
+
+    /// 応答を待つ時間 (ms)
+    #[arg(long, default_value_t = 1000)]
+    timeout_ms: u64,
+
+    /// 応答がない場合に送り直す回数
+    #[arg(long, default_value_t = 5)]
+    retries: u32,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[value(rename_all = "lower")]
+enum ReplayLink {
+    Raw,
+    Frame,
+}
+
+impl From<ReplayLink> for CaptureLink {
+    fn from(value: ReplayLink) -> Self {
+        match value {
+            ReplayLink::Raw => CaptureLink::Raw,
+            ReplayLink::Frame => CaptureLink::Frame,
+        }
+    }
+}
+
 #[derive(ValueEnum, Clone, Debug)]
 #[value(rename_all = "lower")]
 enum PacketType {
@@ -104,6 +209,14 @@ fn main() {
         }
         Commands::Unpack(unpack_args) => unpack(unpack_args),
         Commands::Watch(watch_args) => watch(watch_args),
+        Commands::Record(record_args) => record(record_args),
+        Commands::Replay(replay_args) => replay(replay_args),
+        Commands::Update(update_args) => {
+            if let Err(e) = update::update(&update_args) {
+                log::error!("{}", e);
+                process::exit(1);
+            }
+        }
     }
 }
 
@@ -122,7 +235,7 @@ fn handle_line(bytes: &[u8], frame_parser: &mut FrameParser) {
                 println!("{:?}", a);
             }
             Err(e) => {
-                log::warn!("{:?}", e)
+                log::warn!("{}", e)
             }
         }
     }
@@ -182,6 +295,172 @@ fn watch(watch_args: WatchArgs) {
     }
 }
 
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX))
+        .unwrap_or(0)
+}
+
+fn record(record_args: RecordArgs) {
+    let port = serialport::new(&record_args.port, record_args.baud)
+        .timeout(Duration::from_secs(1))
+        .dtr_on_open(true)
+        .open();
+    let mut port = match port {
+        Ok(port) => port,
+        Err(e) => {
+            log::error!("Error opening port {}: {}", record_args.port, e);
+            process::exit(1);
+        }
+    };
+
+    let file = match File::create(&record_args.output) {
+        Ok(file) => file,
+        Err(e) => {
+            log::error!("Error creating {}: {}", record_args.output.display(), e);
+            process::exit(1);
+        }
+    };
+    let mut writer = match CaptureWriter::new(BufWriter::new(file)) {
+        Ok(writer) => writer,
+        Err(e) => {
+            log::error!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut rx_buffer = vec![0; 1024];
+    let mut frame_buffer = vec![0; 1024];
+    let mut frame_parser = FrameParser::new(&mut rx_buffer, &mut frame_buffer);
+
+    log::info!(
+        "Recording port {} at {} baud to {}...",
+        record_args.port,
+        record_args.baud,
+        record_args.output.display()
+    );
+    let mut serial_buf: Vec<u8> = vec![0; 1024];
+
+    loop {
+        match port.read(serial_buf.as_mut_slice()) {
+            Ok(0) => continue,
+            Ok(bytes_read) => {
+                let timestamp = now_micros();
+                let bytes = &serial_buf[..bytes_read];
+                if let Err(e) = writer.write_raw(timestamp, Direction::Inbound, bytes) {
+                    log::error!("{}", e);
+                    break;
+                }
+
+                if record_args.frames {
+                    if let Err(e) = frame_parser.write_data(bytes) {
+                        log::warn!("{}", e);
+                    }
+                    while let Some(frame) = frame_parser.next_frame() {
+                        match frame {
+                            Ok(frame) => {
+                                println!("{:?}", frame);
+                                if let Err(e) =
+                                    writer.write_frame(timestamp, Direction::Inbound, &frame)
+                                {
+                                    log::warn!("{}", e);
+                                }
+                            }
+                            Err(e) => log::warn!("{}", e),
+                        }
+                    }
+                }
+
+                if let Err(e) = writer.flush() {
+                    log::error!("{}", e);
+                    break;
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                continue;
+            }
+            Err(e) => {
+                log::warn!("Port reading error: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+fn replay(replay_args: ReplayArgs) {
+    let file = match File::open(&replay_args.file) {
+        Ok(file) => file,
+        Err(e) => {
+            log::error!("Error opening {}: {}", replay_args.file.display(), e);
+            process::exit(1);
+        }
+    };
+    let reader = match CaptureReader::new(BufReader::new(file)) {
+        Ok(reader) => reader,
+        Err(e) => {
+            log::error!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let link = CaptureLink::from(replay_args.link);
+    let mut records = Vec::new();
+    for record in reader {
+        match record {
+            Ok(record) if record.link == link => records.push(record),
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+    log::info!("{} records loaded", records.len());
+
+    if let Some(port_name) = &replay_args.port {
+        let port = serialport::new(port_name, replay_args.baud)
+            .timeout(Duration::from_secs(1))
+            .dtr_on_open(true)
+            .open();
+        let mut port = match port {
+            Ok(port) => port,
+            Err(e) => {
+                log::error!("Error opening port {}: {}", port_name, e);
+                process::exit(1);
+            }
+        };
+
+        let mut previous_timestamp = None;
+        for record in &records {
+            if replay_args.realtime
+                && let Some(previous) = previous_timestamp
+            {
+                thread::sleep(Duration::from_micros(
+                    record.timestamp_micros.saturating_sub(previous),
+                ));
+            }
+            previous_timestamp = Some(record.timestamp_micros);
+
+            if let Err(e) = port.write_all(&record.data) {
+                log::error!("Port writing error: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let mut rx_buffer = vec![0; 1024];
+    let mut frame_buffer = vec![0; 1024];
+    let mut frame_parser = FrameParser::new(&mut rx_buffer, &mut frame_buffer);
+
+    for frame in replay_into_parser(&records, &mut frame_parser) {
+        match frame {
+            Ok(frame) => println!("{:?}", frame),
+            Err(e) => log::warn!("{}", e),
+        }
+    }
+}
+
 fn unpack(unpack_args: UnpackArgs) {
     // reader は Hex文字列 のイテレータ (Result<String, ...>)
     let reader: Box<dyn Iterator<Item = Result<String, std::io::Error>>>;
@@ -245,7 +524,13 @@ fn pack(pack_args: PackArgs) {
         PacketType::Ping => FramePayload::Ping,
         PacketType::Pong => FramePayload::Pong,
         PacketType::Ack => FramePayload::Ack(pack_args.address.expect("--address is required.")),
-        PacketType::Join => FramePayload::Join(pack_args.id.expect("--id is required.")),
+        PacketType::Join => {
+            let id = pack_args.id.expect("--id is required.");
+            match pack_args.device_id {
+                Some(device_id) => FramePayload::JoinWithDeviceId { id, device_id },
+                None => FramePayload::Join(id),
+            }
+        }
         PacketType::SetAddress => FramePayload::SetAddress {
             address: pack_args.address.expect("--address is required."),
             id: pack_args.id.expect("--id is required."),
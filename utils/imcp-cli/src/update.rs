@@ -0,0 +1,246 @@
+//! update: HCP の `FirmwareUpdate` でデバイスのファームウェアを書き換える
+//!
+//! IMCP のマスターとして動作するため、manager を止めてから使う。
+//! 途中で止めた場合も、同じイメージでもう一度実行すれば受信済みの位置から再開する。
+
+use std::{
+    fs,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use hcp::{
+    APP_PROTOCOL_VERSION, AppPacketKind, FEATURE_FIRMWARE_UPDATE, ImageHash, ProtocolVersions,
+    UpdateCommand, UpdateSender, UpdateStatus, decode_set_packet, encode_set_packet_with_version,
+};
+use imcp::{
+    frame::{Address, Frame, FramePayload, MAX_ENCODED_FRAME_SIZE},
+    parser::FrameParser,
+};
+use serialport::SerialPort;
+use sha2::{Digest, Sha256};
+
+use crate::UpdateArgs;
+
+const IMCP_MASTER_ADDRESS: u8 = 0x01;
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+/// Join と DeviceHello を待つ時間
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Link<'a> {
+    port: Box<dyn SerialPort>,
+    parser: FrameParser<'a, 'a>,
+}
+
+pub fn update(args: &UpdateArgs) -> Result<(), String> {
+    let image = fs::read(&args.image)
+        .map_err(|error| format!("Failed to read {}: {error}", args.image.display()))?;
+    let sha256: ImageHash = Sha256::digest(&image).into();
+    let mut sender = UpdateSender::new(&image, sha256)
+        .map_err(|error| error.to_string())?
+        .with_chunk_len(args.chunk_len);
+
+    let port = serialport::new(&args.port, args.baud)
+        .timeout(READ_TIMEOUT)
+        .dtr_on_open(true)
+        .open()
+        .map_err(|error| format!("Failed to open {}: {error}", args.port))?;
+    let mut rx_buffer = vec![0; 1024];
+    let mut frame_buffer = vec![0; 1024];
+    let mut link = Link {
+        port,
+        parser: FrameParser::new(&mut rx_buffer, &mut frame_buffer),
+    };
+
+    let (address, version) = match args.address {
+        Some(address) => (address, APP_PROTOCOL_VERSION),
+        None => connect(&mut link, args.assign)?,
+    };
+    link.send_set(
+        address,
+        version,
+        &AppPacketKind::HostHello(ProtocolVersions::SUPPORTED),
+    )?;
+    log::info!(
+        "Updating device 0x{address:02X} (protocol v{version}) with {} bytes",
+        image.len()
+    );
+
+    let timeout = Duration::from_millis(args.timeout_ms);
+    while let Some(command) = sender.next_command() {
+        let packet = AppPacketKind::FirmwareUpdate(command);
+        let mut attempts = 0;
+        let status = loop {
+            link.send_set(address, version, &packet)?;
+            if let Some(status) =
+                link.wait_for(timeout, |frame, payload| match decode_set_packet(payload) {
+                    Ok(AppPacketKind::FirmwareUpdateStatus(status))
+                        if frame.from_address() == address =>
+                    {
+                        Some(status)
+                    }
+                    _ => None,
+                })?
+            {
+                break status;
+            }
+            attempts += 1;
+            if attempts > args.retries {
+                return Err(format!("No response from device 0x{address:02X}"));
+            }
+            log::warn!("No response, resending ({attempts}/{})", args.retries);
+        };
+
+        log_status(&packet, &status);
+        sender.accept(&status).map_err(|error| error.to_string())?;
+        let (sent, total) = sender.progress();
+        print!("\r{sent}/{total} bytes");
+        let _ = io::stdout().flush();
+    }
+
+    println!();
+    println!("Update committed. The device restarts with the new firmware.");
+    Ok(())
+}
+
+/// Join したデバイスにアドレスを割り当て、DeviceHello で更新に対応しているか確認する
+fn connect(link: &mut Link<'_>, assign: u8) -> Result<(u8, u8), String> {
+    log::info!("Waiting for a device to join...");
+    let join_id = link
+        .wait_for_frame(CONNECT_TIMEOUT, |frame| match frame.payload() {
+            FramePayload::Join(id) | FramePayload::JoinWithDeviceId { id, .. } => Some(*id),
+            _ => None,
+        })?
+        .ok_or("No device joined")?;
+    link.write_frame(&Frame::new(
+        Address::Unicast(0x00),
+        IMCP_MASTER_ADDRESS,
+        FramePayload::SetAddress {
+            address: assign,
+            id: join_id,
+        },
+    ))?;
+
+    let hello = link
+        .wait_for(CONNECT_TIMEOUT, |_, payload| {
+            match decode_set_packet(payload) {
+                Ok(AppPacketKind::DeviceHello(hello)) => Some(hello),
+                _ => None,
+            }
+        })?
+        .ok_or("Device did not send DeviceHello")?;
+
+    let version = ProtocolVersions::SUPPORTED
+        .negotiate(hello.protocol_versions())
+        .filter(|version| {
+            AppPacketKind::FirmwareUpdate(UpdateCommand::Abort).is_available_in(*version)
+        })
+        .ok_or_else(|| {
+            format!(
+                "Device protocol v{}-v{} does not support firmware update",
+                hello.min_protocol_version, hello.protocol_version
+            )
+        })?;
+    if hello.capabilities.features & FEATURE_FIRMWARE_UPDATE == 0 {
+        return Err(format!(
+            "Device 0x{:016X} does not advertise firmware update",
+            hello.device_id
+        ));
+    }
+
+    log::info!(
+        "Device 0x{:016X} ({:?}) firmware {}.{}.{}",
+        hello.device_id,
+        hello.device_kind,
+        hello.firmware_version.major,
+        hello.firmware_version.minor,
+        hello.firmware_version.patch
+    );
+    Ok((assign, version))
+}
+
+fn log_status(packet: &AppPacketKind, status: &UpdateStatus) {
+    match status.error {
+        Some(error) => log::warn!("{packet:?}: {:?} ({error})", status.phase),
+        None => log::debug!("{:?} received={}", status.phase, status.received),
+    }
+}
+
+impl Link<'_> {
+    fn write_frame(&mut self, frame: &Frame) -> Result<(), String> {
+        let mut encoded = [0u8; MAX_ENCODED_FRAME_SIZE];
+        let encoded_len = frame
+            .encode(&mut encoded)
+            .map_err(|error| format!("Failed to encode IMCP frame: {error}"))?;
+        self.port
+            .write_all(&encoded[..encoded_len])
+            .map_err(|error| format!("Failed to write IMCP frame: {error}"))?;
+        self.port
+            .flush()
+            .map_err(|error| format!("Failed to flush IMCP frame: {error}"))
+    }
+
+    fn send_set(&mut self, address: u8, version: u8, packet: &AppPacketKind) -> Result<(), String> {
+        let payload = encode_set_packet_with_version(version, packet)
+            .map_err(|error| format!("Failed to encode {packet:?}: {error}"))?;
+        self.write_frame(&Frame::new(
+            Address::Unicast(address),
+            IMCP_MASTER_ADDRESS,
+            FramePayload::Set(payload),
+        ))
+    }
+
+    /// `Set` frame の内容を `select` が値を返すまで待つ
+    fn wait_for<T>(
+        &mut self,
+        timeout: Duration,
+        mut select: impl FnMut(&Frame, &[u8]) -> Option<T>,
+    ) -> Result<Option<T>, String> {
+        self.wait_for_frame(timeout, |frame| match frame.payload() {
+            FramePayload::Set(payload) => select(frame, payload.as_slice()),
+            _ => None,
+        })
+    }
+
+    /// 受信した `Set` frame を ACK しながら、`select` が値を返すまで待つ
+    fn wait_for_frame<T>(
+        &mut self,
+        timeout: Duration,
+        mut select: impl FnMut(&Frame) -> Option<T>,
+    ) -> Result<Option<T>, String> {
+        let started_at = Instant::now();
+        let mut serial_buffer = [0u8; 64];
+
+        while started_at.elapsed() < timeout {
+            match self.port.read(&mut serial_buffer) {
+                Ok(bytes_read) if bytes_read > 0 => {
+                    if let Err(error) = self.parser.write_data(&serial_buffer[..bytes_read]) {
+                        log::warn!("{error}");
+                    }
+                    while let Some(frame) = self.parser.next_frame() {
+                        match frame {
+                            Ok(frame) => {
+                                log::trace!("{frame:?}");
+                                if matches!(frame.payload(), FramePayload::Set(_)) {
+                                    self.write_frame(&Frame::new(
+                                        Address::Unicast(frame.from_address()),
+                                        IMCP_MASTER_ADDRESS,
+                                        FramePayload::Ack(frame.to_address().as_byte()),
+                                    ))?;
+                                }
+                                if let Some(selected) = select(&frame) {
+                                    return Ok(Some(selected));
+                                }
+                            }
+                            Err(error) => log::warn!("{error}"),
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(error) if error.kind() == io::ErrorKind::TimedOut => {}
+                Err(error) => return Err(format!("Port reading error: {error}")),
+            }
+        }
+        Ok(None)
+    }
+}